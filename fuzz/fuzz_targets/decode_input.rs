@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nalgebra::DVector;
+use num_bigint::BigInt;
+use tiptoe_rs::fuzzing::decode_input;
+
+// Recovered records are arbitrary BigInts when a server misbehaves
+fuzz_target!(|data: &[u8]| {
+    let words: Vec<BigInt> = data.chunks(9).map(BigInt::from_signed_bytes_le).collect();
+    if let Ok(record) = decode_input(&DVector::from_vec(words)) {
+        assert!(!record.contains('\0'));
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tiptoe_rs::fuzzing::deserialize_matrix;
+use tiptoe_rs::network::MatrixResponse;
+
+// Hints, A and hint diffs as they arrive over the wire
+fuzz_target!(|data: &[u8]| {
+    let Ok(response) = serde_json::from_slice::<MatrixResponse>(data) else {
+        return;
+    };
+    if let Ok(matrix) = deserialize_matrix(&response) {
+        assert_eq!(matrix.shape(), (response.rows, response.cols));
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tiptoe_rs::fuzzing::deserialize_params;
+use tiptoe_rs::network::ParamsData;
+
+// Params as a server publishes them
+fuzz_target!(|data: &[u8]| {
+    let Ok(params) = serde_json::from_slice::<ParamsData>(data) else {
+        return;
+    };
+    if let Ok(parsed) = deserialize_params(&params) {
+        assert_eq!(parsed.m, params.m);
+    }
+});
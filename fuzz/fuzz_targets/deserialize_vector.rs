@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tiptoe_rs::fuzzing::deserialize_vector;
+
+// Query and response vectors as they arrive over the wire
+fuzz_target!(|data: &[u8]| {
+    let Ok(elements) = serde_json::from_slice::<Vec<String>>(data) else {
+        return;
+    };
+    if let Ok(vector) = deserialize_vector(&elements) {
+        assert_eq!(vector.len(), elements.len());
+    }
+});
@@ -0,0 +1,258 @@
+use anyhow::Result;
+use nalgebra::DVector;
+use num_bigint::BigInt;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{fmt, str::FromStr};
+
+use crate::{
+    config::ServerConfig,
+    embedding::BertEmbedder,
+    error::PirError,
+    quantization::Quantization,
+    report::{BenchReport, ReportBuilder},
+    similarity::{cosine, score},
+};
+
+// Ranks recall is measured at
+pub const RECALL_AT: [usize; 2] = [1, 3];
+// Recall a scheme may lose against unquantized embeddings before it counts as a
+// regression
+pub const DEFAULT_TOLERANCE: f64 = 0.02;
+// Measures of unquantized cosine ranking, which every scheme is held to
+pub const REFERENCE: &str = "f32";
+
+// How embedding values become integers for scoring. `Signed(bits)` is the scheme
+// served, `Quantization::new(bits - 2)`; `Unsigned(bits)` shifts [-1, 1] onto
+// [0, 2^bits - 1] first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    Signed(u32),
+    Unsigned(u32),
+}
+
+pub const DEFAULT_SCHEMES: [Scheme; 3] =
+    [Scheme::Unsigned(8), Scheme::Signed(8), Scheme::Signed(12)];
+
+impl Scheme {
+    pub fn quantize(&self, values: &[f32]) -> DVector<BigInt> {
+        match *self {
+            Self::Signed(bits) => Quantization::new(bits - 2).quantize(values),
+            Self::Unsigned(bits) => {
+                let max = ((1u64 << bits) - 1) as f64;
+                DVector::from_iterator(
+                    values.len(),
+                    values.iter().map(|&value| {
+                        let shifted = (value.clamp(-1.0, 1.0) as f64 + 1.0) / 2.0;
+                        BigInt::from((shifted * max).round() as u64)
+                    }),
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Signed(bits) => write!(f, "i{}", bits),
+            Self::Unsigned(bits) => write!(f, "u{}", bits),
+        }
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = PirError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PirError::InvalidInput(format!("Invalid quantization scheme '{}'", s));
+        let (signed, bits) = match s.split_at_checked(1).ok_or_else(invalid)? {
+            ("i", bits) => (true, bits),
+            ("u", bits) => (false, bits),
+            _ => return Err(invalid()),
+        };
+        let bits: u32 = bits.parse().map_err(|_| invalid())?;
+        // Signed schemes need a sign bit and a bit of scale beyond 1.0
+        match (signed, bits) {
+            (true, 3..=32) => Ok(Self::Signed(bits)),
+            (false, 1..=32) => Ok(Self::Unsigned(bits)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// Queries with the document each should retrieve, over their own small corpus
+#[derive(Deserialize)]
+pub struct LabeledSet {
+    pub documents: Vec<Value>,
+    pub queries: Vec<LabeledQuery>,
+}
+
+#[derive(Deserialize)]
+pub struct LabeledQuery {
+    pub query: String,
+    // Index into `documents`
+    pub expected: usize,
+}
+
+impl LabeledSet {
+    pub fn load(path: &str) -> Result<Self> {
+        let set: Self = serde_json::from_slice(&std::fs::read(path)?)?;
+        if set.queries.is_empty() {
+            return Err(PirError::InvalidInput(format!("No labeled queries in {}", path)).into());
+        }
+        if let Some(query) = set
+            .queries
+            .iter()
+            .find(|query| query.expected >= set.documents.len())
+        {
+            return Err(PirError::InvalidInput(format!(
+                "Query '{}' expects document {} of {}",
+                query.query,
+                query.expected,
+                set.documents.len()
+            ))
+            .into());
+        }
+        Ok(set)
+    }
+}
+
+// A labeled set embedded once, then scored under any number of schemes
+pub struct Embedded {
+    documents: Vec<Vec<f32>>,
+    queries: Vec<(Vec<f32>, usize)>,
+}
+
+impl Embedded {
+    pub fn new(documents: Vec<Vec<f32>>, queries: Vec<(Vec<f32>, usize)>) -> Self {
+        Self { documents, queries }
+    }
+
+    // Embeds documents and queries as a rebuild and a client would, with the server
+    // config's templates and prefixes
+    pub fn embed(set: &LabeledSet) -> Result<Self> {
+        let config = ServerConfig::from_env()?;
+        let embedder = BertEmbedder::new()?;
+        let documents =
+            embedder.embed_documents_raw(&set.documents, &config.templates, &config.prefixes)?;
+        let queries = set
+            .queries
+            .iter()
+            .map(|query| {
+                let embedding = embedder.embed_raw(&config.prefixes.prefix_query(&query.query))?;
+                Ok((embedding, query.expected))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self::new(documents, queries))
+    }
+
+    // Recall of unquantized cosine ranking and of each scheme, as measures named
+    // "{scheme}.top{k}"
+    pub fn evaluate(&self, schemes: &[Scheme]) -> BenchReport {
+        let mut report = ReportBuilder::new("quantization_accuracy");
+        self.record(&mut report, REFERENCE, |query, document| {
+            cosine(&self.queries[query].0, &self.documents[document])
+        });
+        for scheme in schemes {
+            let quantize = |embeddings: Vec<&Vec<f32>>| -> Vec<DVector<BigInt>> {
+                embeddings
+                    .into_iter()
+                    .map(|embedding| scheme.quantize(embedding))
+                    .collect()
+            };
+            let documents = quantize(self.documents.iter().collect());
+            let queries = quantize(self.queries.iter().map(|(query, _)| query).collect());
+            self.record(&mut report, &scheme.to_string(), |query, document| {
+                score(&queries[query], &documents[document])
+            });
+        }
+        report.build()
+    }
+
+    // Counts a hit at each rank for every query whose expected document scores within
+    // it; ties go against the expected document
+    fn record<S: PartialOrd>(
+        &self,
+        report: &mut ReportBuilder,
+        name: &str,
+        score: impl Fn(usize, usize) -> S,
+    ) {
+        for (query, &(_, expected)) in self.queries.iter().enumerate() {
+            let scores: Vec<S> = (0..self.documents.len())
+                .map(|document| score(query, document))
+                .collect();
+            let rank = scores
+                .iter()
+                .enumerate()
+                .filter(|&(document, other)| document != expected && *other >= scores[expected])
+                .count();
+            for k in RECALL_AT {
+                report.record_hit(&format!("{}.top{}", name, k), rank < k);
+            }
+        }
+    }
+}
+
+// Measures on which a scheme's recall fell more than `tolerance` below unquantized
+// cosine ranking
+pub fn regressions(report: &BenchReport, tolerance: f64) -> Vec<String> {
+    report
+        .recall
+        .iter()
+        .filter_map(|(measure, &recall)| {
+            let (scheme, rank) = measure.split_once('.')?;
+            if scheme == REFERENCE {
+                return None;
+            }
+            let reference = *report.recall.get(&format!("{}.{}", REFERENCE, rank))?;
+            (recall < reference - tolerance).then(|| {
+                format!(
+                    "{} recall {:.3} is more than {} below unquantized {:.3}",
+                    measure, recall, tolerance, reference
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::{similarity::normalize, utils::seeded_rng};
+
+    #[test]
+    fn test_quantization_schemes_against_unquantized() {
+        assert_eq!("u8".parse::<Scheme>().unwrap(), Scheme::Unsigned(8));
+        assert_eq!("i12".parse::<Scheme>().unwrap(), Scheme::Signed(12));
+        assert!("i2".parse::<Scheme>().is_err());
+        assert!("f32".parse::<Scheme>().is_err());
+        assert_eq!(Scheme::Signed(8).to_string(), "i8");
+
+        // Queries are their document with a little noise
+        let mut rng = seeded_rng(Some(7));
+        let mut unit = |base: Option<&Vec<f32>>| {
+            let mut v: Vec<f32> = (0..32)
+                .map(|i| base.map_or(0.0, |base| base[i]) + rng.random_range(-0.2..0.2))
+                .collect();
+            normalize(&mut v);
+            v
+        };
+        let documents: Vec<Vec<f32>> = (0..40).map(|_| unit(None)).collect();
+        let queries = (0..40)
+            .map(|i| (unit(Some(&documents[i])), i))
+            .collect::<Vec<_>>();
+        let embedded = Embedded::new(documents, queries);
+
+        let report = embedded.evaluate(&[Scheme::Signed(12), Scheme::Unsigned(1)]);
+        assert_eq!(report.recall["f32.top1"], 1.0);
+        assert_eq!(report.recall["i12.top1"], 1.0);
+        // One bit per value cannot tell these documents apart
+        let failures = regressions(&report, DEFAULT_TOLERANCE);
+        assert!(!failures.is_empty());
+        assert!(failures.iter().all(|failure| failure.starts_with("u1.")));
+        assert!(regressions(&report, 1.0).is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::{auth, error::PirError};
+
+// Path of a file access records are appended to, one JSON object per line; unset
+// disables the audit log
+const AUDIT_LOG_ENV_VAR: &str = "TIPTOE_AUDIT_LOG";
+
+// Query contents, which must never reach a log. Deliberately implements neither Debug
+// nor Display, so formatting a request that holds one fails to compile; the contents
+// are only reachable through `expose`.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Sealed<T>(T);
+
+impl<T> Sealed<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+// One request as the audit log records it. Only sizes and timings of the payloads are
+// kept, never their contents.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AccessRecord {
+    // Milliseconds since the Unix epoch at which the request arrived
+    pub timestamp_ms: u64,
+    // Tenant of the request's API key, when the server has a policy and knows the key
+    pub tenant: Option<String>,
+    pub namespace: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub latency_ms: f64,
+}
+
+pub struct AuditLog {
+    file: Mutex<File>,
+    // Recorded with every request
+    namespace: String,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, namespace: String) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            namespace,
+        })
+    }
+
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(path) = std::env::var(AUDIT_LOG_ENV_VAR) else {
+            return Ok(None);
+        };
+        Self::open(Path::new(&path), auth::namespace())
+            .map(Some)
+            .map_err(|e| {
+                PirError::InvalidInput(format!("Cannot open audit log {}: {}", path, e)).into()
+            })
+    }
+
+    // Appends `record` as a single line, so concurrent requests never interleave
+    pub fn record(&self, record: &AccessRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_are_appended_as_json_lines() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("tiptoe-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let record = AccessRecord {
+            timestamp_ms: 1_700_000_000_000,
+            tenant: Some("research".to_string()),
+            namespace: "stocks".to_string(),
+            method: "POST".to_string(),
+            path: "/query".to_string(),
+            status: 200,
+            request_bytes: 4096,
+            response_bytes: 2048,
+            latency_ms: 12.5,
+        };
+        let log = AuditLog::open(&path, "stocks".to_string())?;
+        assert_eq!(log.namespace(), record.namespace);
+        log.record(&record)?;
+        log.record(&AccessRecord {
+            tenant: None,
+            status: 401,
+            ..record.clone()
+        })?;
+
+        let contents = std::fs::read_to_string(&path)?;
+        let lines: Vec<AccessRecord> = contents
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], record);
+        assert_eq!((lines[1].tenant.as_deref(), lines[1].status), (None, 401));
+
+        // Sealed values serialize as what they hold, for the wire only
+        let sealed = Sealed::new(vec!["12".to_string()]);
+        assert_eq!(serde_json::to_string(&sealed)?, r#"["12"]"#);
+        assert_eq!(sealed.expose(), &["12".to_string()]);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
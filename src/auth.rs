@@ -0,0 +1,505 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::error::PirError;
+
+// Path of a JSON policy mapping API keys to what they may do; unset disables
+// authorization
+const POLICY_ENV_VAR: &str = "TIPTOE_AUTH_POLICY";
+// Name of the corpus this server serves, matched against the policy's namespaces
+const NAMESPACE_ENV_VAR: &str = "TIPTOE_NAMESPACE";
+const DEFAULT_NAMESPACE: &str = "default";
+// Grants every namespace
+const ANY_NAMESPACE: &str = "*";
+pub const API_KEY_HEADER: &str = "x-tiptoe-api-key";
+// Hex digits of the key hash that name tenants without a `tenant` name
+const TENANT_HASH_DIGITS: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    // Params, hints, layouts and PIR queries
+    Query,
+    // Everything under `/admin` and `/debug`
+    Admin,
+}
+
+impl Operation {
+    pub fn of_path(path: &str) -> Self {
+        let admin = ["/admin", "/debug"].iter().any(|prefix| {
+            path.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        if admin {
+            Self::Admin
+        } else {
+            Self::Query
+        }
+    }
+}
+
+// What one tenant's key may do, and how much of it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Grant {
+    pub namespaces: Vec<String>,
+    pub operations: Vec<Operation>,
+    // Name the tenant's usage is reported under
+    #[serde(default)]
+    pub tenant: Option<String>,
+    // Requests per second, in bursts of up to a second's worth
+    #[serde(default)]
+    pub qps: Option<f64>,
+    // Response bytes per second, likewise
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    // Unix time from which the key is refused
+    #[serde(default)]
+    pub expires: Option<u64>,
+}
+
+impl Grant {
+    pub fn authorize(&self, namespace: &str, operation: Operation, now: u64) -> Result<(), Denial> {
+        if self.expires.is_some_and(|expires| now >= expires) {
+            return Err(Denial::Expired);
+        }
+        let namespace_granted = self
+            .namespaces
+            .iter()
+            .any(|granted| granted == namespace || granted == ANY_NAMESPACE);
+        if namespace_granted && self.operations.contains(&operation) {
+            Ok(())
+        } else {
+            Err(Denial::Forbidden)
+        }
+    }
+}
+
+// Grants by hex SHA-256 of the API key, so the policy file holds no usable keys
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Policy {
+    pub keys: HashMap<String, Grant>,
+}
+
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Denial {
+    MissingKey,
+    UnknownKey,
+    Forbidden,
+    Expired,
+    // Over the tenant's request or bandwidth quota; answered with 429
+    OverQuota,
+}
+
+impl Policy {
+    pub fn authorize(
+        &self,
+        key: Option<&str>,
+        namespace: &str,
+        operation: Operation,
+        now: u64,
+    ) -> Result<(), Denial> {
+        let key = key.ok_or(Denial::MissingKey)?;
+        let grant = self.keys.get(&hash_key(key)).ok_or(Denial::UnknownKey)?;
+        grant.authorize(namespace, operation, now)
+    }
+}
+
+// Token bucket refilled at `rate` per second and holding at most a second's worth.
+// Costs charged after the fact can take it below zero, refusing requests until it
+// refills.
+#[derive(Clone, Debug)]
+struct Bucket {
+    rate: f64,
+    level: f64,
+    // Seconds since the Unix epoch
+    updated: f64,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            level: rate.max(1.0),
+            updated: 0.0,
+        }
+    }
+
+    fn refill(&mut self, now: f64) {
+        let elapsed = (now - self.updated).max(0.0);
+        self.level = (self.level + elapsed * self.rate).min(self.rate.max(1.0));
+        self.updated = self.updated.max(now);
+    }
+}
+
+// One tenant's quota state and what it has used
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TenantUsage {
+    pub tenant: String,
+    // Requests admitted and response bytes sent to them
+    pub requests: u64,
+    pub bytes: u64,
+    // Requests refused for going over quota
+    pub throttled: u64,
+    pub expires: Option<u64>,
+    // Quota left right now, for tenants with one
+    pub requests_left: Option<f64>,
+    pub bytes_left: Option<f64>,
+}
+
+struct Usage {
+    requests: Option<Bucket>,
+    bytes: Option<Bucket>,
+    usage: TenantUsage,
+}
+
+impl Usage {
+    fn new(hash: &str, grant: &Grant) -> Self {
+        let tenant = grant
+            .tenant
+            .clone()
+            .unwrap_or_else(|| hash[..TENANT_HASH_DIGITS].to_string());
+        Self {
+            requests: grant.qps.map(Bucket::new),
+            bytes: grant
+                .bandwidth
+                .map(|bandwidth| Bucket::new(bandwidth as f64)),
+            usage: TenantUsage {
+                tenant,
+                requests: 0,
+                bytes: 0,
+                throttled: 0,
+                expires: grant.expires,
+                requests_left: None,
+                bytes_left: None,
+            },
+        }
+    }
+
+    // Takes one request from the quota, if a request and a byte are left
+    fn admit(&mut self, now: f64) -> Result<(), Denial> {
+        for bucket in [&mut self.requests, &mut self.bytes].into_iter().flatten() {
+            bucket.refill(now);
+        }
+        let allowed = self.requests.as_ref().is_none_or(|b| b.level >= 1.0)
+            && self.bytes.as_ref().is_none_or(|b| b.level > 0.0);
+        if !allowed {
+            self.usage.throttled += 1;
+            return Err(Denial::OverQuota);
+        }
+        if let Some(requests) = &mut self.requests {
+            requests.level -= 1.0;
+        }
+        self.usage.requests += 1;
+        Ok(())
+    }
+
+    fn charge(&mut self, bytes: u64, now: f64) {
+        if let Some(bucket) = &mut self.bytes {
+            bucket.refill(now);
+            bucket.level -= bytes as f64;
+        }
+        self.usage.bytes += bytes;
+    }
+
+    fn report(&mut self, now: f64) -> TenantUsage {
+        for bucket in [&mut self.requests, &mut self.bytes].into_iter().flatten() {
+            bucket.refill(now);
+        }
+        TenantUsage {
+            requests_left: self.requests.as_ref().map(|b| b.level),
+            bytes_left: self.bytes.as_ref().map(|b| b.level),
+            ..self.usage.clone()
+        }
+    }
+}
+
+// Denied requests by reason. Nothing about the requester is recorded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DenialStats {
+    pub missing_key: u64,
+    pub unknown_key: u64,
+    pub forbidden: u64,
+    #[serde(default)]
+    pub expired: u64,
+    #[serde(default)]
+    pub over_quota: u64,
+}
+
+// A server's policy together with its namespace, denial counters and the quota state
+// of each tenant. Tenants are isolated: one going over its quota never slows another.
+pub struct Authorizer {
+    policy: Policy,
+    namespace: String,
+    missing_key: AtomicU64,
+    unknown_key: AtomicU64,
+    forbidden: AtomicU64,
+    expired: AtomicU64,
+    over_quota: AtomicU64,
+    // By key hash, like the policy
+    usage: HashMap<String, Mutex<Usage>>,
+}
+
+impl Authorizer {
+    pub fn new(policy: Policy, namespace: String) -> Self {
+        let usage = policy
+            .keys
+            .iter()
+            .map(|(hash, grant)| (hash.clone(), Mutex::new(Usage::new(hash, grant))))
+            .collect();
+        Self {
+            policy,
+            namespace,
+            missing_key: AtomicU64::new(0),
+            unknown_key: AtomicU64::new(0),
+            forbidden: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            over_quota: AtomicU64::new(0),
+            usage,
+        }
+    }
+
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(path) = std::env::var(POLICY_ENV_VAR) else {
+            return Ok(None);
+        };
+        let policy = serde_json::from_str(&std::fs::read_to_string(&path)?).map_err(|e| {
+            PirError::InvalidInput(format!("Invalid authorization policy {}: {}", path, e))
+        })?;
+        Ok(Some(Self::new(policy, namespace())))
+    }
+
+    pub fn authorize(&self, key: Option<&str>, path: &str) -> Result<(), Denial> {
+        self.authorize_at(key, path, since_epoch())
+    }
+
+    // Authorizes a request made `now` after the Unix epoch and takes it from the
+    // tenant's quota
+    pub fn authorize_at(&self, key: Option<&str>, path: &str, now: Duration) -> Result<(), Denial> {
+        let result = self.admit(key, Operation::of_path(path), now);
+        if let Err(denial) = result {
+            let counter = match denial {
+                Denial::MissingKey => &self.missing_key,
+                Denial::UnknownKey => &self.unknown_key,
+                Denial::Forbidden => &self.forbidden,
+                Denial::Expired => &self.expired,
+                Denial::OverQuota => &self.over_quota,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn admit(&self, key: Option<&str>, operation: Operation, now: Duration) -> Result<(), Denial> {
+        let hash = hash_key(key.ok_or(Denial::MissingKey)?);
+        let grant = self.policy.keys.get(&hash).ok_or(Denial::UnknownKey)?;
+        grant.authorize(&self.namespace, operation, now.as_secs())?;
+        self.usage[&hash].lock().unwrap().admit(now.as_secs_f64())
+    }
+
+    // Name of the tenant holding `key`, if it is in the policy
+    pub fn tenant(&self, key: &str) -> Option<String> {
+        let usage = self.usage.get(&hash_key(key))?;
+        let tenant = usage.lock().unwrap().usage.tenant.clone();
+        Some(tenant)
+    }
+
+    // Charges the response to an admitted request against its tenant's bandwidth
+    pub fn charge(&self, key: &str, bytes: u64) {
+        self.charge_at(key, bytes, since_epoch());
+    }
+
+    pub fn charge_at(&self, key: &str, bytes: u64, now: Duration) {
+        if let Some(usage) = self.usage.get(&hash_key(key)) {
+            usage.lock().unwrap().charge(bytes, now.as_secs_f64());
+        }
+    }
+
+    pub fn denials(&self) -> DenialStats {
+        DenialStats {
+            missing_key: self.missing_key.load(Ordering::Relaxed),
+            unknown_key: self.unknown_key.load(Ordering::Relaxed),
+            forbidden: self.forbidden.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            over_quota: self.over_quota.load(Ordering::Relaxed),
+        }
+    }
+
+    // Usage and quota left of every tenant, by tenant name
+    pub fn tenants(&self) -> Vec<TenantUsage> {
+        let now = since_epoch().as_secs_f64();
+        let mut tenants: Vec<TenantUsage> = self
+            .usage
+            .values()
+            .map(|usage| usage.lock().unwrap().report(now))
+            .collect();
+        tenants.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        tenants
+    }
+}
+
+// Name of the corpus this server serves
+pub fn namespace() -> String {
+    std::env::var(NAMESPACE_ENV_VAR).unwrap_or_else(|_| DEFAULT_NAMESPACE.to_string())
+}
+
+fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_checks_namespace_and_operation() {
+        let grant = |namespaces: &[&str], operations: &[Operation]| Grant {
+            namespaces: namespaces.iter().map(|n| n.to_string()).collect(),
+            operations: operations.to_vec(),
+            tenant: None,
+            qps: None,
+            bandwidth: None,
+            expires: None,
+        };
+        let policy = Policy {
+            keys: HashMap::from([
+                (hash_key("reader"), grant(&["stocks"], &[Operation::Query])),
+                (
+                    hash_key("operator"),
+                    grant(&["*"], &[Operation::Query, Operation::Admin]),
+                ),
+            ]),
+        };
+        let authorizer = Authorizer::new(policy, "stocks".to_string());
+
+        assert_eq!(authorizer.authorize(Some("reader"), "/query"), Ok(()));
+        assert_eq!(
+            authorizer.authorize(Some("operator"), "/admin/rebuild"),
+            Ok(())
+        );
+        assert_eq!(
+            authorizer.authorize(Some("reader"), "/admin/stats"),
+            Err(Denial::Forbidden)
+        );
+        assert_eq!(
+            authorizer.authorize(Some("guess"), "/query"),
+            Err(Denial::UnknownKey)
+        );
+        assert_eq!(authorizer.authorize(None, "/hint"), Err(Denial::MissingKey));
+
+        let news = Authorizer::new(authorizer.policy.clone(), "news".to_string());
+        assert_eq!(
+            news.authorize(Some("reader"), "/query"),
+            Err(Denial::Forbidden)
+        );
+        assert_eq!(news.authorize(Some("operator"), "/query"), Ok(()));
+
+        assert_eq!(
+            authorizer.denials(),
+            DenialStats {
+                missing_key: 1,
+                unknown_key: 1,
+                forbidden: 1,
+                ..DenialStats::default()
+            }
+        );
+
+        assert_eq!(Operation::of_path("/debug/rows"), Operation::Admin);
+        assert_eq!(Operation::of_path("/admin"), Operation::Admin);
+        assert_eq!(Operation::of_path("/debugger"), Operation::Query);
+    }
+
+    #[test]
+    fn test_tenants_have_separate_quotas() {
+        let tenant = |name: &str, qps, bandwidth, expires| Grant {
+            namespaces: vec!["*".to_string()],
+            operations: vec![Operation::Query],
+            tenant: Some(name.to_string()),
+            qps,
+            bandwidth,
+            expires,
+        };
+        let policy = Policy {
+            keys: HashMap::from([
+                (hash_key("small"), tenant("small", Some(2.0), None, None)),
+                (hash_key("large"), tenant("large", None, Some(1000), None)),
+                (hash_key("old"), tenant("old", None, None, Some(100))),
+            ]),
+        };
+        let authorizer = Authorizer::new(policy, "stocks".to_string());
+        let at = |secs: f64| Duration::from_secs_f64(secs);
+
+        assert_eq!(
+            authorizer.authorize_at(Some("small"), "/query", at(10.0)),
+            Ok(())
+        );
+        assert_eq!(
+            authorizer.authorize_at(Some("small"), "/query", at(10.0)),
+            Ok(())
+        );
+        assert_eq!(
+            authorizer.authorize_at(Some("small"), "/query", at(10.1)),
+            Err(Denial::OverQuota)
+        );
+        // Another tenant is unaffected, and the quota refills
+        assert_eq!(
+            authorizer.authorize_at(Some("large"), "/query", at(10.1)),
+            Ok(())
+        );
+        assert_eq!(
+            authorizer.authorize_at(Some("small"), "/query", at(10.6)),
+            Ok(())
+        );
+
+        // Bandwidth is charged after the response, refusing requests until repaid
+        authorizer.charge_at("large", 3000, at(10.2));
+        assert_eq!(
+            authorizer.authorize_at(Some("large"), "/query", at(11.0)),
+            Err(Denial::OverQuota)
+        );
+        assert_eq!(
+            authorizer.authorize_at(Some("large"), "/query", at(12.5)),
+            Ok(())
+        );
+
+        assert_eq!(
+            authorizer.authorize_at(Some("old"), "/query", at(99.0)),
+            Ok(())
+        );
+        assert_eq!(
+            authorizer.authorize_at(Some("old"), "/query", at(100.0)),
+            Err(Denial::Expired)
+        );
+
+        let denials = authorizer.denials();
+        assert_eq!((denials.over_quota, denials.expired), (2, 1));
+        let tenants = authorizer.tenants();
+        let names: Vec<&str> = tenants.iter().map(|t| t.tenant.as_str()).collect();
+        assert_eq!(names, ["large", "old", "small"]);
+        assert_eq!(
+            (tenants[0].requests, tenants[0].bytes, tenants[0].throttled),
+            (2, 3000, 1)
+        );
+        assert_eq!((tenants[2].requests, tenants[2].throttled), (3, 1));
+        assert_eq!(tenants[1].requests_left, None);
+    }
+}
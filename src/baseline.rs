@@ -0,0 +1,76 @@
+use instant_distance::{Builder, HnswMap, Point, Search};
+
+use crate::clustering::DistanceMetric;
+
+// Plaintext approximate nearest-neighbour index over the same embeddings the PIR
+// database serves. Used as ground truth to measure how much retrieval accuracy is
+// lost to quantization, cluster routing, and the argmax over PIR scores.
+pub struct BaselineIndex {
+    map: HnswMap<Embedding, usize>,
+    metric: DistanceMetric,
+}
+
+#[derive(Clone)]
+struct Embedding {
+    values: Vec<f32>,
+    metric: DistanceMetric,
+}
+
+impl Point for Embedding {
+    fn distance(&self, other: &Self) -> f32 {
+        self.metric.distance(&self.values, &other.values)
+    }
+}
+
+impl BaselineIndex {
+    pub fn build(embeddings: &[Vec<f32>], metric: DistanceMetric, seed: Option<u64>) -> Self {
+        let points = embeddings
+            .iter()
+            .map(|values| Embedding {
+                values: values.clone(),
+                metric,
+            })
+            .collect();
+        let rows = (0..embeddings.len()).collect();
+
+        let mut builder = Builder::default();
+        if let Some(seed) = seed {
+            builder = builder.seed(seed);
+        }
+        Self {
+            map: builder.build(points, rows),
+            metric,
+        }
+    }
+
+    // Rows of the `k` nearest embeddings, closest first
+    pub fn nearest(&self, query: &[f32], k: usize) -> Vec<usize> {
+        let query = Embedding {
+            values: query.to_vec(),
+            metric: self.metric,
+        };
+        let mut search = Search::default();
+        self.map
+            .search(&query, &mut search)
+            .take(k)
+            .map(|item| *item.value)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nearest_matches_exact_search() {
+        let embeddings: Vec<Vec<f32>> = (0..100)
+            .map(|i| vec![(i % 10) as f32, (i / 10) as f32])
+            .collect();
+        let index = BaselineIndex::build(&embeddings, DistanceMetric::Euclidean, Some(0));
+
+        assert_eq!(index.nearest(&[3.1, 7.2], 1), vec![73]);
+        assert_eq!(index.nearest(&[0.0, 0.0], 3)[0], 0);
+        assert_eq!(index.nearest(&[0.0, 0.0], 3).len(), 3);
+    }
+}
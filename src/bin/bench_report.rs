@@ -0,0 +1,115 @@
+use anyhow::Result;
+use tiptoe_rs::{
+    accuracy::{
+        regressions, Embedded, LabeledSet, Scheme, DEFAULT_SCHEMES, DEFAULT_TOLERANCE, RECALL_AT,
+        REFERENCE,
+    },
+    error::PirError,
+    report::{compare, BenchReport},
+};
+
+const USAGE: &str = "Usage: bench-report compare <before.json> <after.json> [--json]\n       \
+     bench-report quantization <labeled.json> [--schemes u8,i8,i12] [--tolerance 0.02] [--json]";
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["compare", before, after, ref rest @ ..] => run_compare(before, after, rest),
+        ["quantization", labeled, ref rest @ ..] => run_quantization(labeled, rest),
+        _ => Err(PirError::InvalidInput(USAGE.to_string()).into()),
+    }
+}
+
+fn run_compare(before: &str, after: &str, rest: &[&str]) -> Result<()> {
+    let (before, after) = (BenchReport::load(before)?, BenchReport::load(after)?);
+    let comparison = compare(&before, &after);
+    if rest.contains(&"--json") {
+        println!("{}", serde_json::to_string_pretty(&comparison)?);
+        return Ok(());
+    }
+
+    if before.name != after.name {
+        println!(
+            "Comparing different benchmarks: {} and {}",
+            before.name, after.name
+        );
+    }
+    for (name, (old, new)) in &comparison.settings {
+        println!("setting {}: {:?} -> {:?}", name, old, new);
+    }
+    let show = |value: Option<f64>| value.map_or("-".to_string(), |value| format!("{:.4}", value));
+    for change in &comparison.metrics {
+        println!(
+            "{:<24} {:>14} {:>14} {:>9}",
+            change.metric,
+            show(change.before),
+            show(change.after),
+            change
+                .change
+                .map_or(String::new(), |change| format!("{:+.1}%", change * 100.0))
+        );
+    }
+    Ok(())
+}
+
+// Scores a labeled query set under each quantization scheme and fails if any loses
+// more recall against unquantized embeddings than the tolerance allows
+fn run_quantization(labeled: &str, rest: &[&str]) -> Result<()> {
+    let option = |name: &str| {
+        rest.iter()
+            .position(|arg| *arg == name)
+            .and_then(|i| rest.get(i + 1).copied())
+    };
+    let schemes = match option("--schemes") {
+        Some(schemes) => schemes
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Scheme>, _>>()?,
+        None => DEFAULT_SCHEMES.to_vec(),
+    };
+    let tolerance = match option("--tolerance") {
+        Some(tolerance) => tolerance
+            .parse()
+            .map_err(|_| PirError::InvalidInput(format!("Invalid tolerance: {}", tolerance)))?,
+        None => DEFAULT_TOLERANCE,
+    };
+
+    let report = Embedded::embed(&LabeledSet::load(labeled)?)?.evaluate(&schemes);
+    if let Some(path) = report.save()? {
+        println!("Report written to {}", path);
+    }
+    if rest.contains(&"--json") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        let names =
+            std::iter::once(REFERENCE.to_string()).chain(schemes.iter().map(Scheme::to_string));
+        for name in names {
+            let recall: Vec<String> = RECALL_AT
+                .iter()
+                .map(|k| {
+                    format!(
+                        "top{} {:.4}",
+                        k,
+                        report.recall[&format!("{}.top{}", name, k)]
+                    )
+                })
+                .collect();
+            println!("{:<6} {}", name, recall.join("  "));
+        }
+    }
+
+    let failures = regressions(&report, tolerance);
+    if failures.is_empty() {
+        return Ok(());
+    }
+    for failure in &failures {
+        eprintln!("{}", failure);
+    }
+    Err(PirError::InvalidInput(format!(
+        "{} quantization measures regressed beyond {}",
+        failures.len(),
+        tolerance
+    ))
+    .into())
+}
@@ -0,0 +1,7 @@
+use anyhow::Result;
+use tiptoe_rs::embed_service;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    embed_service::run(3002).await
+}
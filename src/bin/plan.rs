@@ -0,0 +1,28 @@
+use anyhow::Result;
+use tiptoe_rs::{
+    error::PirError,
+    planner::{plan, PlanRequest},
+};
+
+// Usage: plan <num_records> <record_bytes> <budget_bytes>
+fn main() -> Result<()> {
+    let args: Vec<usize> = std::env::args()
+        .skip(1)
+        .map(|arg| arg.parse())
+        .collect::<Result<_, _>>()?;
+
+    let [num_records, record_bytes, budget_bytes] = args[..] else {
+        return Err(PirError::InvalidInput(
+            "Usage: plan <num_records> <record_bytes> <budget_bytes>".to_string(),
+        )
+        .into());
+    };
+
+    let chosen = plan(&PlanRequest {
+        num_records,
+        record_bytes,
+        budget_bytes,
+    })?;
+    println!("{}", serde_json::to_string_pretty(&chosen)?);
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use anyhow::Result;
+use nalgebra::DMatrix;
+use num_bigint::BigInt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    server::SimplePirDatabase,
+    utils::{fnv1a, FNV_OFFSET},
+};
+
+// Target false-positive rate of the membership filter
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+// Shape of a Bloom filter; published so clients can locate a key's bits
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct BloomParams {
+    pub num_bits: usize,
+    pub num_hashes: usize,
+}
+
+impl BloomParams {
+    pub fn for_items(n: usize, false_positive_rate: f64) -> Self {
+        let n = n.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-n * false_positive_rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round() as usize;
+        Self {
+            num_bits: num_bits.max(1),
+            num_hashes: num_hashes.max(1),
+        }
+    }
+
+    // Bits set for `key`, by double hashing. Keys are compared case-insensitively.
+    // Uses FNV rather than std's hasher so the positions are stable across builds.
+    pub fn positions(&self, key: &str) -> Vec<usize> {
+        let key = key.trim().to_lowercase();
+        let h1 = fnv1a(key.as_bytes(), FNV_OFFSET);
+        let h2 = fnv1a(key.as_bytes(), h1) | 1;
+        (0..self.num_hashes as u64)
+            .map(|i| (h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits as u64) as usize)
+            .collect()
+    }
+
+    // Bits are packed column-major into a square matrix so one PIR round fetches a column
+    pub fn side(&self) -> usize {
+        (self.num_bits as f64).sqrt().ceil() as usize
+    }
+
+    // (column, row) of `bit` in the packed matrix
+    pub fn locate(&self, bit: usize) -> (usize, usize) {
+        (bit / self.side(), bit % self.side())
+    }
+}
+
+pub struct BloomFilter {
+    params: BloomParams,
+    bits: Vec<bool>,
+}
+
+impl BloomFilter {
+    pub fn new(params: BloomParams) -> Self {
+        Self {
+            params,
+            bits: vec![false; params.num_bits],
+        }
+    }
+
+    pub fn from_keys<S: AsRef<str>>(keys: &[S]) -> Self {
+        let mut filter = Self::new(BloomParams::for_items(keys.len(), FALSE_POSITIVE_RATE));
+        for key in keys {
+            filter.insert(key.as_ref());
+        }
+        filter
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.params.positions(key) {
+            self.bits[bit] = true;
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.params
+            .positions(key)
+            .into_iter()
+            .all(|bit| self.bits[bit])
+    }
+
+    pub fn to_matrix(&self) -> DMatrix<BigInt> {
+        let side = self.params.side();
+        let mut out = DMatrix::zeros(side, side);
+        for (bit, _) in self.bits.iter().enumerate().filter(|(_, &set)| set) {
+            let (column, row) = self.params.locate(bit);
+            out[(row, column)] = BigInt::from(1);
+        }
+        out
+    }
+}
+
+// Bloom filter over document keys, served as its own PIR database
+pub struct Membership {
+    pub params: BloomParams,
+    pub db: SimplePirDatabase,
+}
+
+impl Membership {
+    pub fn build<S: AsRef<str>>(keys: &[S]) -> Result<Self> {
+        let filter = BloomFilter::from_keys(keys);
+        let mut db = SimplePirDatabase::new(DMatrix::zeros(1, 1));
+        db.update_db(filter.to_matrix())?;
+        Ok(Self {
+            params: filter.params,
+            db,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter_membership_and_layout() {
+        let keys: Vec<String> = (0..200).map(|i| format!("Asset {}", i)).collect();
+        let filter = BloomFilter::from_keys(&keys);
+
+        assert!(keys.iter().all(|key| filter.contains(key)));
+        assert!(filter.contains("  ASSET 7 "));
+        let false_positives = (200..2200)
+            .filter(|i| filter.contains(&format!("Asset {}", i)))
+            .count();
+        assert!(false_positives < 60);
+
+        let matrix = filter.to_matrix();
+        for bit in filter.params.positions("Asset 42") {
+            let (column, row) = filter.params.locate(bit);
+            assert_eq!(matrix[(row, column)], BigInt::from(1));
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{crypto::RecordKey, documents::DocumentId};
+
+// A decoded result as the client returned it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CachedResult {
+    pub record: String,
+    pub epoch: u64,
+    pub id: Option<DocumentId>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    // Tick of the last lookup or insert, for least-recently-used eviction
+    used: u64,
+    result: CachedResult,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Contents {
+    salt: [u8; 32],
+    tick: u64,
+    entries: HashMap<String, Entry>,
+}
+
+// LRU cache of decoded results on the client, so repeating a query within an epoch
+// costs no PIR rounds. Entries are filed under a hash of the query and epochs keyed
+// with a random salt, so the cache holds no query text. Memory-only unless opened
+// with `persistent`, which keeps it encrypted on disk.
+pub struct ResultCache {
+    capacity: usize,
+    contents: Contents,
+    // Where the cache is saved and the key it is encrypted with
+    file: Option<(PathBuf, RecordKey)>,
+}
+
+impl ResultCache {
+    pub fn in_memory(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            contents: Contents {
+                salt: rand::random(),
+                tick: 0,
+                entries: HashMap::new(),
+            },
+            file: None,
+        }
+    }
+
+    // Loads the cache saved at `path`, or starts an empty one there. Everything,
+    // salt included, is encrypted with `key`.
+    pub fn persistent<P: AsRef<Path>>(path: P, key: RecordKey, capacity: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut cache = Self::in_memory(capacity);
+        if path.exists() {
+            let sealed = fs::read_to_string(&path)?;
+            cache.contents = serde_json::from_str(&key.decrypt(&sealed)?)?;
+        }
+        cache.file = Some((path, key));
+        Ok(cache)
+    }
+
+    fn key(&self, query: &str, epochs: &[u64]) -> String {
+        let mut hasher = blake3::Hasher::new_keyed(&self.contents.salt);
+        for epoch in epochs {
+            hasher.update(&epoch.to_le_bytes());
+        }
+        hasher.update(query.as_bytes());
+        hasher.finalize().to_hex().to_string()
+    }
+
+    pub fn get(&mut self, query: &str, epochs: &[u64]) -> Option<CachedResult> {
+        let key = self.key(query, epochs);
+        self.contents.tick += 1;
+        let tick = self.contents.tick;
+        let entry = self.contents.entries.get_mut(&key)?;
+        entry.used = tick;
+        Some(entry.result.clone())
+    }
+
+    pub fn insert(&mut self, query: &str, epochs: &[u64], result: CachedResult) -> Result<()> {
+        let key = self.key(query, epochs);
+        self.contents.tick += 1;
+        if !self.contents.entries.contains_key(&key) && self.contents.entries.len() >= self.capacity
+        {
+            let oldest = self
+                .contents
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.contents.entries.remove(&oldest);
+            }
+        }
+        self.contents.entries.insert(
+            key,
+            Entry {
+                used: self.contents.tick,
+                result,
+            },
+        );
+        self.save()
+    }
+
+    pub fn len(&self) -> usize {
+        self.contents.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.entries.is_empty()
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some((path, key)) = &self.file {
+            fs::write(path, key.encrypt(&serde_json::to_string(&self.contents)?)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(record: &str) -> CachedResult {
+        CachedResult {
+            record: record.to_string(),
+            epoch: 1,
+            id: None,
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction_and_persistence() -> Result<()> {
+        let mut cache = ResultCache::in_memory(2);
+        cache.insert("Tesla", &[1], result("tesla"))?;
+        cache.insert("Apple", &[1], result("apple"))?;
+        assert_eq!(cache.get("Tesla", &[1]), Some(result("tesla")));
+        assert_eq!(cache.get("Tesla", &[2]), None);
+
+        // Apple is the least recently used
+        cache.insert("Bitcoin", &[1], result("bitcoin"))?;
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("Apple", &[1]), None);
+        assert!(cache.get("Tesla", &[1]).is_some());
+
+        let path = std::env::temp_dir().join(format!("tiptoe-cache-{}.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let key = RecordKey::generate();
+        let mut cache = ResultCache::persistent(&path, key.clone(), 4)?;
+        cache.insert("Tesla", &[1, 7], result("tesla"))?;
+        assert!(!fs::read_to_string(&path)?.contains("tesla"));
+
+        let mut reopened = ResultCache::persistent(&path, key, 4)?;
+        assert_eq!(reopened.get("Tesla", &[1, 7]), Some(result("tesla")));
+        assert!(ResultCache::persistent(&path, RecordKey::generate(), 4).is_err());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
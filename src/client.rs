@@ -1,17 +1,79 @@
 use anyhow::Result;
+use ed25519_dalek::VerifyingKey;
+use futures_util::future::join_all;
 use nalgebra::{DMatrix, DVector};
 use num_bigint::BigInt;
-use num_traits::One;
-use simplepir::{generate_query, recover, SimplePIRParams};
-use std::cmp::Ordering;
+use num_traits::{One, Zero};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::task::JoinHandle;
 
+#[cfg(feature = "baseline")]
+use crate::baseline::BaselineIndex;
+#[cfg(feature = "ohttp")]
+use crate::relay::{Relay, RelayedTransport};
+#[cfg(feature = "websocket")]
+use crate::session::SessionTransport;
 use crate::{
-    embedding::BertEmbedder,
+    bloom::BloomParams,
+    cache::{CachedResult, ResultCache},
+    clustering::find_closest_centroid,
+    correction::QueryCorrection,
+    crypto::RecordKey,
+    diagnostics::{params_hash, widen_k, Diagnostics},
+    documents::{find_row, DocumentId},
+    embed_service::Embedder,
+    embedding::{check_model, fuse_embeddings, reformulations, EmbeddingModel},
     error::PirError,
-    network::{AsyncDatabase, RemoteDatabase},
-    server::{Database, EmbeddingDatabase, EncodingDatabase},
+    filters::{FilterFields, FilteredQuery},
+    integrity::{DatabaseDigest, PinStore},
+    network::{
+        centroids_data, in_session, AsyncDatabase, CentroidsData, HttpTransport, RemoteDatabase,
+        Transport, DEADLINE,
+    },
+    packing::{unpack_value, BlockLayout, PackedLayout},
+    partitions::PartitionManifest,
+    pir::{self, SimplePIRParams},
+    quantization::{Calibration, Quantization},
+    server::{Database, EmbeddingDatabase, EncodingDatabase, SimplePirDatabase},
+    tiering::{merge, HotInfo},
+    utils::{check_query_dim, decode_input, encode_record, fit_query},
+    watcher::{Threshold, Watcher},
 };
 
+// Anything a single PIR round can be run against
+trait PirEndpoint {
+    async fn respond(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>>;
+    // Recovered answer to `query`, computed by the server from the client's secret
+    async fn respond_assisted(
+        &self,
+        query: &DVector<BigInt>,
+        secret: &DVector<BigInt>,
+    ) -> Result<DVector<BigInt>>;
+    // Plaintext answer to the unencrypted query `v`, see `Client::with_plaintext_mode`
+    async fn respond_plaintext(&self, v: &DVector<BigInt>) -> Result<DVector<BigInt>>;
+    async fn params(&self) -> Result<SimplePIRParams>;
+    async fn hint(&self) -> Result<DMatrix<BigInt>>;
+    async fn a(&self) -> Result<DMatrix<BigInt>>;
+    // Digest to check downloads against; None for local databases, which download nothing
+    async fn digest(&self) -> Result<Option<DatabaseDigest>>;
+    // Server the database is fetched from, if it is remote
+    fn origin(&self) -> Option<String>;
+    // This database as of its server's current epoch, which every request of a round
+    // then goes to
+    async fn pinned(&self) -> Result<ClusterConnection<'_>>;
+    // Where downloads for the database are kept; None for local databases
+    fn cache_key(&self) -> Option<String>;
+}
+
 // Each database can be either local or remote
 pub enum DatabaseConnection<T> {
     Local(T),
@@ -29,6 +91,289 @@ impl<T: Database> DatabaseConnection<T> {
         }
     }
 
+    async fn epoch(&self) -> Result<u64> {
+        match self {
+            Self::Local(db) => Ok(db.epoch()),
+            Self::Remote(db) => db.get_epoch().await,
+        }
+    }
+
+    async fn centroids(&self) -> Result<Option<CentroidsData>> {
+        match self {
+            Self::Local(db) => Ok(centroids_data(db)),
+            Self::Remote(db) => db.get_centroids().await,
+        }
+    }
+
+    // Rows of the documents in cluster `id`, in the order its answers score them
+    async fn members(&self, id: usize) -> Result<Vec<usize>> {
+        match self {
+            Self::Local(db) => db
+                .clustering()
+                .filter(|clustering| id < clustering.centroids.len())
+                .map(|clustering| clustering.members_of(id))
+                .ok_or_else(|| PirError::Database(format!("Unknown cluster {}", id)).into()),
+            Self::Remote(db) => db.cluster(id).get_members().await,
+        }
+    }
+
+    async fn partitions(&self) -> Result<Option<PartitionManifest>> {
+        match self {
+            Self::Local(db) => Ok(db.partitions().cloned()),
+            Self::Remote(db) => db.get_partitions().await,
+        }
+    }
+
+    async fn membership(&self) -> Result<(BloomParams, ClusterConnection<'_>)> {
+        let missing = || PirError::Database("Membership filter not served".to_string());
+        match self {
+            Self::Local(db) => db
+                .membership()
+                .map(|membership| (membership.params, ClusterConnection::Local(&membership.db)))
+                .ok_or_else(|| missing().into()),
+            Self::Remote(db) => {
+                let params = db.get_membership().await?.ok_or_else(missing)?;
+                Ok((params, ClusterConnection::Remote(db.membership())))
+            }
+        }
+    }
+
+    async fn document_ids(&self) -> Result<Vec<DocumentId>> {
+        Ok(self.documents().await?.1)
+    }
+
+    // The epoch together with its document ids, in one request
+    async fn documents(&self) -> Result<(u64, Vec<DocumentId>)> {
+        match self {
+            Self::Local(db) => Ok((db.epoch(), db.document_ids().to_vec())),
+            Self::Remote(db) => db.get_documents().await,
+        }
+    }
+
+    async fn hot(&self) -> Result<Option<(HotInfo, ClusterConnection<'_>)>> {
+        match self {
+            Self::Local(db) => Ok(db
+                .hot()
+                .map(|hot| (hot.info(), ClusterConnection::Local(&hot.db)))),
+            Self::Remote(db) => Ok(db
+                .get_hot()
+                .await?
+                .map(|info| (info, ClusterConnection::Remote(db.hot())))),
+        }
+    }
+
+    async fn packed(&self) -> Result<(PackedLayout, ClusterConnection<'_>)> {
+        let missing = || PirError::Database("Packed values not served".to_string());
+        match self {
+            Self::Local(db) => db
+                .hot()
+                .and_then(|hot| hot.packed.as_ref())
+                .map(|packed| (packed.layout.clone(), ClusterConnection::Local(&packed.db)))
+                .ok_or_else(|| missing().into()),
+            Self::Remote(db) => {
+                let hot = db.hot();
+                let layout = hot.get_packed().await?.ok_or_else(missing)?;
+                Ok((layout, ClusterConnection::Remote(hot.packed())))
+            }
+        }
+    }
+
+    async fn blocks(&self) -> Result<(BlockLayout, ClusterConnection<'_>)> {
+        let missing = || PirError::Database("Record blocks not served".to_string());
+        match self {
+            Self::Local(db) => db
+                .blocks()
+                .map(|blocks| (blocks.layout.clone(), ClusterConnection::Local(&blocks.db)))
+                .ok_or_else(|| missing().into()),
+            Self::Remote(db) => {
+                let layout = db.get_blocks().await?.ok_or_else(missing)?;
+                Ok((layout, ClusterConnection::Remote(db.blocks())))
+            }
+        }
+    }
+
+    async fn dead_rows(&self) -> Result<BTreeSet<usize>> {
+        match self {
+            Self::Local(db) => Ok(db.dead_rows().clone()),
+            Self::Remote(db) => db.get_dead_rows().await,
+        }
+    }
+
+    // Servers that predate published quantization used the default one
+    async fn quantization(&self) -> Result<Quantization> {
+        let quantization = match self {
+            Self::Local(db) => db.quantization(),
+            Self::Remote(db) => db.get_quantization().await?,
+        };
+        Ok(quantization.unwrap_or_default())
+    }
+
+    async fn calibration(&self) -> Result<Calibration> {
+        let calibration = match self {
+            Self::Local(db) => db.calibration(),
+            Self::Remote(db) => db.get_calibration().await?,
+        };
+        match calibration {
+            Some(calibration) => Ok(calibration),
+            None => Ok(Calibration::from_scale(&self.quantization().await?)),
+        }
+    }
+
+    async fn query_correction(&self) -> Result<Option<QueryCorrection>> {
+        match self {
+            Self::Local(db) => Ok(db.query_correction()),
+            Self::Remote(db) => db.get_query_correction().await,
+        }
+    }
+
+    // Servers that predate prefixes embedded queries without one
+    async fn query_prefix(&self) -> Result<String> {
+        let prefix = match self {
+            Self::Local(db) => db.query_prefix(),
+            Self::Remote(db) => db.get_query_prefix().await?,
+        };
+        Ok(prefix.unwrap_or_default())
+    }
+
+    async fn query_dim(&self) -> Result<Option<usize>> {
+        match self {
+            Self::Local(db) => Ok(db.query_dim()),
+            Self::Remote(db) => db.get_query_dim().await,
+        }
+    }
+
+    async fn embedding_model(&self) -> Result<Option<EmbeddingModel>> {
+        match self {
+            Self::Local(db) => Ok(db.embedding_model()),
+            Self::Remote(db) => db.get_embedding_model().await,
+        }
+    }
+
+    fn cluster(&self, id: usize) -> Result<ClusterConnection<'_>> {
+        match self {
+            Self::Local(db) => db
+                .cluster(id)
+                .map(ClusterConnection::Local)
+                .ok_or_else(|| PirError::Database(format!("Unknown cluster {}", id)).into()),
+            Self::Remote(db) => Ok(ClusterConnection::Remote(db.cluster(id))),
+        }
+    }
+
+    fn partition(&self, id: usize) -> Result<ClusterConnection<'_>> {
+        match self {
+            Self::Local(db) => db
+                .partition(id)
+                .map(ClusterConnection::Local)
+                .ok_or_else(|| PirError::Database(format!("Unknown partition {}", id)).into()),
+            Self::Remote(db) => Ok(ClusterConnection::Remote(db.partition(id))),
+        }
+    }
+}
+
+impl<T: Database> PirEndpoint for DatabaseConnection<T> {
+    async fn respond(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>> {
+        match self {
+            Self::Local(db) => db
+                .respond(query)
+                .map_err(|e| PirError::Database(format!("Response failed: {}", e)).into()),
+            Self::Remote(db) => db.respond(query).await,
+        }
+    }
+
+    async fn respond_assisted(
+        &self,
+        query: &DVector<BigInt>,
+        secret: &DVector<BigInt>,
+    ) -> Result<DVector<BigInt>> {
+        match self {
+            Self::Local(db) => Ok(pir::recover(
+                db.params(),
+                db.hint(),
+                secret,
+                &self.respond(query).await?,
+            )),
+            Self::Remote(db) => db.respond_assisted(query, secret).await,
+        }
+    }
+
+    async fn respond_plaintext(&self, v: &DVector<BigInt>) -> Result<DVector<BigInt>> {
+        match self {
+            Self::Local(db) => db.database().answer_plaintext(v),
+            Self::Remote(db) => db.respond_plaintext(v).await,
+        }
+    }
+
+    async fn params(&self) -> Result<SimplePIRParams> {
+        match self {
+            Self::Local(db) => Ok(db.params().clone()),
+            Self::Remote(db) => db.get_params().await,
+        }
+    }
+
+    async fn hint(&self) -> Result<DMatrix<BigInt>> {
+        match self {
+            Self::Local(db) => Ok(db.hint().clone()),
+            Self::Remote(db) => db.get_hint().await,
+        }
+    }
+
+    async fn a(&self) -> Result<DMatrix<BigInt>> {
+        match self {
+            Self::Local(db) => Ok(db.a().clone()),
+            Self::Remote(db) => db.get_a().await,
+        }
+    }
+
+    async fn digest(&self) -> Result<Option<DatabaseDigest>> {
+        match self {
+            Self::Local(_) => Ok(None),
+            Self::Remote(db) => Ok(Some(db.get_digest().await?)),
+        }
+    }
+
+    fn origin(&self) -> Option<String> {
+        match self {
+            Self::Local(_) => None,
+            Self::Remote(db) => db.origin(),
+        }
+    }
+
+    async fn pinned(&self) -> Result<ClusterConnection<'_>> {
+        match self {
+            Self::Local(db) => Ok(ClusterConnection::Local(db.database())),
+            Self::Remote(db) => Ok(ClusterConnection::Remote(
+                db.at_epoch(db.get_server_epoch().await?),
+            )),
+        }
+    }
+
+    fn cache_key(&self) -> Option<String> {
+        match self {
+            Self::Local(_) => None,
+            Self::Remote(db) => Some(remote_key(db.as_ref())),
+        }
+    }
+}
+
+// A smaller database served next to the main one: a single cluster's or time
+// window's slice of the embedding database, the membership filter, the hot tier or
+// its packed values
+enum ClusterConnection<'a> {
+    Local(&'a SimplePirDatabase),
+    Remote(Box<dyn AsyncDatabase>),
+}
+
+impl ClusterConnection<'_> {
+    // Params with the epoch of the database they belong to, in one request
+    async fn versioned_params(&self) -> Result<(u64, SimplePIRParams)> {
+        match self {
+            Self::Local(db) => Ok((db.epoch(), db.params().clone())),
+            Self::Remote(db) => db.get_versioned_params().await,
+        }
+    }
+}
+
+impl PirEndpoint for ClusterConnection<'_> {
     async fn respond(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>> {
         match self {
             Self::Local(db) => db
@@ -38,6 +383,29 @@ impl<T: Database> DatabaseConnection<T> {
         }
     }
 
+    async fn respond_assisted(
+        &self,
+        query: &DVector<BigInt>,
+        secret: &DVector<BigInt>,
+    ) -> Result<DVector<BigInt>> {
+        match self {
+            Self::Local(db) => Ok(pir::recover(
+                db.params(),
+                db.hint(),
+                secret,
+                &self.respond(query).await?,
+            )),
+            Self::Remote(db) => db.respond_assisted(query, secret).await,
+        }
+    }
+
+    async fn respond_plaintext(&self, v: &DVector<BigInt>) -> Result<DVector<BigInt>> {
+        match self {
+            Self::Local(db) => db.answer_plaintext(v),
+            Self::Remote(db) => db.respond_plaintext(v).await,
+        }
+    }
+
     async fn params(&self) -> Result<SimplePIRParams> {
         match self {
             Self::Local(db) => Ok(db.params().clone()),
@@ -58,13 +426,239 @@ impl<T: Database> DatabaseConnection<T> {
             Self::Remote(db) => db.get_a().await,
         }
     }
+
+    async fn digest(&self) -> Result<Option<DatabaseDigest>> {
+        match self {
+            Self::Local(_) => Ok(None),
+            Self::Remote(db) => Ok(Some(db.get_digest().await?)),
+        }
+    }
+
+    fn origin(&self) -> Option<String> {
+        match self {
+            Self::Local(_) => None,
+            Self::Remote(db) => db.origin(),
+        }
+    }
+
+    async fn pinned(&self) -> Result<ClusterConnection<'_>> {
+        match self {
+            Self::Local(db) => Ok(ClusterConnection::Local(db)),
+            Self::Remote(db) => Ok(ClusterConnection::Remote(
+                db.at_epoch(db.get_server_epoch().await?),
+            )),
+        }
+    }
+
+    fn cache_key(&self) -> Option<String> {
+        match self {
+            Self::Local(_) => None,
+            Self::Remote(db) => Some(remote_key(db.as_ref())),
+        }
+    }
+}
+
+// Cache key of a remote database: its server and route
+fn remote_key(db: &dyn AsyncDatabase) -> String {
+    format!("{}{}", db.origin().unwrap_or_default(), db.path())
+}
+
+// Params, A and hint of one database at one epoch, reused by every round against it
+// until the epoch changes
+struct PreparedRound {
+    epoch: u64,
+    params: SimplePIRParams,
+    a: DMatrix<BigInt>,
+    hint: DMatrix<BigInt>,
+}
+
+// A and hint of one database at one epoch, downloading in the background while
+// degraded-mode rounds are answered without them. Checked against the digest only
+// once a round takes them up.
+struct HintDownload {
+    epoch: u64,
+    task: JoinHandle<Result<(DMatrix<BigInt>, DMatrix<BigInt>)>>,
+}
+
+// Cost of the most recent private query. Byte counts are the sizes of the BigInt
+// payloads exchanged (query up; answer, hint and A down), independent of transport.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueryStats {
+    pub upload_bytes: usize,
+    pub download_bytes: usize,
+    pub embed_ms: f64,
+    pub respond_ms: f64,
+    pub recover_ms: f64,
+    pub rounds: usize,
+    // Oldest epoch any of the rounds was answered from, as read from the params each
+    // round downloads anyway
+    #[serde(default)]
+    pub oldest_epoch: Option<u64>,
+}
+
+impl QueryStats {
+    // Adds the cost of rounds run alongside these. Times of concurrent rounds add up
+    // too, so they measure work rather than latency.
+    fn add(&mut self, other: &QueryStats) {
+        self.upload_bytes += other.upload_bytes;
+        self.download_bytes += other.download_bytes;
+        self.embed_ms += other.embed_ms;
+        self.respond_ms += other.respond_ms;
+        self.recover_ms += other.recover_ms;
+        self.rounds += other.rounds;
+        if let Some(epoch) = other.oldest_epoch {
+            self.answered_at(epoch);
+        }
+    }
+
+    fn answered_at(&mut self, epoch: u64) {
+        self.oldest_epoch = Some(self.oldest_epoch.map_or(epoch, |oldest| oldest.min(epoch)));
+    }
+}
+
+fn payload_bytes<'a>(values: impl Iterator<Item = &'a BigInt>) -> usize {
+    values.map(|x| (x.bits() as usize).div_ceil(8).max(1)).sum()
+}
+
+fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+// Answers older than this are flagged as stale unless overridden
+const DEFAULT_STALENESS_THRESHOLD: Duration = Duration::from_secs(60);
+// Candidates fetched per requested result by `query_filtered`
+const FILTER_SCAN_FACTOR: usize = 4;
+
+// Recovered record together with the epoch of the database that served it and
+// the stable id to re-fetch it by after later rebuilds
+#[derive(Clone, Debug)]
+pub struct QueryResult {
+    pub data: DVector<BigInt>,
+    pub epoch: SystemTime,
+    pub stale: bool,
+    pub id: Option<DocumentId>,
+}
+
+impl QueryResult {
+    fn new(
+        data: DVector<BigInt>,
+        epoch: u64,
+        id: Option<DocumentId>,
+        staleness_threshold: Duration,
+    ) -> Self {
+        let epoch = UNIX_EPOCH + Duration::from_secs(epoch);
+        let mut result = Self {
+            data,
+            epoch,
+            stale: false,
+            id,
+        };
+        result.stale = result.age() > staleness_threshold;
+        result
+    }
+
+    // Time elapsed since the last successful data refresh on the server
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.epoch)
+            .unwrap_or_default()
+    }
+
+    // Also flags the result stale when the oldest database its query went through, e.g.
+    // the embedding database that ranked it, is older than `staleness_threshold`
+    fn lagging(mut self, oldest_epoch: Option<u64>, staleness_threshold: Duration) -> Self {
+        if let Some(epoch) = oldest_epoch {
+            let oldest = UNIX_EPOCH + Duration::from_secs(epoch);
+            let age = SystemTime::now().duration_since(oldest).unwrap_or_default();
+            self.stale |= age > staleness_threshold;
+        }
+        self
+    }
+}
+
+// What a query for several results did fetch when some of its fetches failed.
+// Attached as context to the error, so `error.downcast_ref::<PartialResults>()`
+// recovers the results that arrived.
+#[derive(Clone, Debug)]
+pub struct PartialResults {
+    // Fetched results, best first
+    pub results: Vec<QueryResult>,
+    // Rank among the requested results and cause of every failed fetch
+    pub failed: Vec<(usize, String)>,
+}
+
+impl fmt::Display for PartialResults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Fetched {} of {} results",
+            self.results.len(),
+            self.results.len() + self.failed.len()
+        )
+    }
+}
+
+// Rows of the embedding database ranked for a query, kept so later pages are fetched
+// without scoring again. Only valid for the epoch they were scored in.
+struct Ranking {
+    query: String,
+    epoch: u64,
+    rows: Vec<usize>,
+}
+
+// Candidates ranked just below the results last returned, fetched ahead of being asked
+// for. Only valid for the encoding epoch they were ranked against.
+#[derive(Default)]
+struct Prefetch {
+    epoch: u64,
+    // Rows still to fetch, best first
+    pending: Vec<usize>,
+    // Fetched rows, with the hot tier epoch their hot fields are from
+    fetched: HashMap<usize, (Option<u64>, QueryResult)>,
 }
 
 // Unified client that works with both local and remote databases
 pub struct Client {
     embedding_db: DatabaseConnection<EmbeddingDatabase>,
     encoding_db: DatabaseConnection<EncodingDatabase>,
-    embedder: BertEmbedder,
+    embedder: Embedder,
+    staleness_threshold: Duration,
+    // Decrypts records when the encoding database stores them encrypted
+    record_key: Option<RecordKey>,
+    // Scores the fused embedding of each query's reformulations instead of the query alone
+    query_fusion: bool,
+    // Most recent `query_page` ranking
+    ranking: Mutex<Option<Ranking>>,
+    // Results of earlier `query` calls, by query and epoch
+    cache: Option<Mutex<ResultCache>>,
+    // PIR rounds still to be answered in degraded mode
+    assisted_rounds: AtomicUsize,
+    // Downloads for each remote database's latest epoch, by `cache_key`
+    prepared: Mutex<HashMap<String, Arc<PreparedRound>>>,
+    // Downloads started by degraded-mode rounds, by `cache_key`
+    hint_downloads: Mutex<HashMap<String, HintDownload>>,
+    // Skips PIR altogether, see `with_plaintext_mode`
+    plaintext: bool,
+    // Servers must sign their digests with the matching key when set
+    verifying_key: Option<VerifyingKey>,
+    // Signing keys trusted on first use, per server
+    pins: Option<Mutex<PinStore>>,
+    last_stats: Mutex<QueryStats>,
+    last_diagnostics: Mutex<Option<Diagnostics>>,
+    // Candidates to prefetch after each `query_top_k` or `query_page`
+    prefetch_rows: usize,
+    prefetch: Mutex<Prefetch>,
+    // Document fields `query_filtered` checks
+    filter_fields: FilterFields,
+    // Embedding databases of other models whose scores are fused with the main one's
+    ensemble: Vec<EnsembleMember>,
+}
+
+// The same corpus indexed under another embedding model
+struct EnsembleMember {
+    db: DatabaseConnection<EmbeddingDatabase>,
+    embedder: Embedder,
+    weight: f64,
 }
 
 impl Client {
@@ -72,7 +666,24 @@ impl Client {
         Ok(Self {
             embedding_db: DatabaseConnection::Local(EmbeddingDatabase::new()?),
             encoding_db: DatabaseConnection::Local(EncodingDatabase::new()?),
-            embedder: BertEmbedder::new()?,
+            embedder: Embedder::local()?,
+            staleness_threshold: DEFAULT_STALENESS_THRESHOLD,
+            record_key: None,
+            query_fusion: false,
+            ranking: Mutex::new(None),
+            cache: None,
+            assisted_rounds: AtomicUsize::new(0),
+            prepared: Mutex::new(HashMap::new()),
+            hint_downloads: Mutex::new(HashMap::new()),
+            plaintext: false,
+            verifying_key: None,
+            pins: None,
+            last_stats: Mutex::new(QueryStats::default()),
+            last_diagnostics: Mutex::new(None),
+            prefetch_rows: 0,
+            prefetch: Mutex::new(Prefetch::default()),
+            filter_fields: FilterFields::default(),
+            ensemble: Vec::new(),
         })
     }
 
@@ -80,10 +691,405 @@ impl Client {
         Ok(Self {
             embedding_db: DatabaseConnection::Remote(Box::new(RemoteDatabase::new(embedding_url))),
             encoding_db: DatabaseConnection::Remote(Box::new(RemoteDatabase::new(encoding_url))),
-            embedder: BertEmbedder::new()?,
+            embedder: Embedder::local()?,
+            staleness_threshold: DEFAULT_STALENESS_THRESHOLD,
+            record_key: None,
+            query_fusion: false,
+            ranking: Mutex::new(None),
+            cache: None,
+            assisted_rounds: AtomicUsize::new(0),
+            prepared: Mutex::new(HashMap::new()),
+            hint_downloads: Mutex::new(HashMap::new()),
+            plaintext: false,
+            verifying_key: None,
+            pins: None,
+            last_stats: Mutex::new(QueryStats::default()),
+            last_diagnostics: Mutex::new(None),
+            prefetch_rows: 0,
+            prefetch: Mutex::new(Prefetch::default()),
+            filter_fields: FilterFields::default(),
+            ensemble: Vec::new(),
         })
     }
 
+    // Like `new_remote`, for servers that enforce an authorization policy
+    pub fn new_authenticated(
+        embedding_url: String,
+        encoding_url: String,
+        api_key: String,
+    ) -> Result<Self> {
+        Self::from_transports(
+            Arc::new(HttpTransport::new(embedding_url).with_api_key(api_key.clone())),
+            Arc::new(HttpTransport::new(encoding_url).with_api_key(api_key)),
+        )
+    }
+
+    // Remote databases reached through any `Transport`, e.g. `InProcessTransport`
+    pub fn from_transports(
+        embedding: Arc<dyn Transport>,
+        encoding: Arc<dyn Transport>,
+    ) -> Result<Self> {
+        Ok(Self::from_transports_with_embedder(
+            embedding,
+            encoding,
+            Embedder::local()?,
+        ))
+    }
+
+    // Like `from_transports`, embedding queries with `embedder`, e.g. an
+    // `Embedder::service(url)` shared by thin clients instead of a model loaded here
+    pub fn from_transports_with_embedder(
+        embedding: Arc<dyn Transport>,
+        encoding: Arc<dyn Transport>,
+        embedder: Embedder,
+    ) -> Self {
+        Self {
+            embedding_db: DatabaseConnection::Remote(Box::new(RemoteDatabase::with_transport(
+                embedding,
+            ))),
+            encoding_db: DatabaseConnection::Remote(Box::new(RemoteDatabase::with_transport(
+                encoding,
+            ))),
+            embedder,
+            staleness_threshold: DEFAULT_STALENESS_THRESHOLD,
+            record_key: None,
+            query_fusion: false,
+            ranking: Mutex::new(None),
+            cache: None,
+            assisted_rounds: AtomicUsize::new(0),
+            prepared: Mutex::new(HashMap::new()),
+            hint_downloads: Mutex::new(HashMap::new()),
+            plaintext: false,
+            verifying_key: None,
+            pins: None,
+            last_stats: Mutex::new(QueryStats::default()),
+            last_diagnostics: Mutex::new(None),
+            prefetch_rows: 0,
+            prefetch: Mutex::new(Prefetch::default()),
+            filter_fields: FilterFields::default(),
+            ensemble: Vec::new(),
+        }
+    }
+
+    // Like `new_remote`, but queries to both servers go through an OHTTP relay
+    #[cfg(feature = "ohttp")]
+    pub fn new_relayed(embedding_url: String, encoding_url: String, relay: Relay) -> Result<Self> {
+        let relay = Arc::new(relay);
+        Self::from_transports(
+            Arc::new(RelayedTransport::new(
+                HttpTransport::new(embedding_url),
+                Arc::clone(&relay),
+            )),
+            Arc::new(RelayedTransport::new(
+                HttpTransport::new(encoding_url),
+                relay,
+            )),
+        )
+    }
+
+    // Like `new_remote`, but queries travel over one persistent WebSocket per server
+    #[cfg(feature = "websocket")]
+    pub async fn new_session(embedding_url: String, encoding_url: String) -> Result<Self> {
+        Self::from_transports(
+            Arc::new(SessionTransport::connect(embedding_url).await?),
+            Arc::new(SessionTransport::connect(encoding_url).await?),
+        )
+    }
+
+    pub fn with_staleness_threshold(mut self, threshold: Duration) -> Self {
+        self.staleness_threshold = threshold;
+        self
+    }
+
+    pub fn with_record_key(mut self, key: RecordKey) -> Self {
+        self.record_key = Some(key);
+        self
+    }
+
+    // Degraded mode: the next `rounds` PIR rounds send the query's secret to the server,
+    // which recovers the answer so no hint has to be downloaded first. Each of them
+    // starts downloading its database's A and hint in the background, so the first
+    // private round after them finds them ready. This gives up
+    // privacy for those rounds: the server learns exactly what they asked for. Meant
+    // for a fresh client's first queries on a slow link, never for sensitive ones.
+    pub fn with_degraded_mode(self, rounds: usize) -> Self {
+        self.assisted_rounds.store(rounds, AtomicOrdering::Relaxed);
+        self
+    }
+
+    // Degraded-mode rounds left
+    pub fn assisted_rounds(&self) -> usize {
+        self.assisted_rounds.load(AtomicOrdering::Relaxed)
+    }
+
+    // Plaintext mode, INSECURE and for development only: every round sends its query
+    // vector unencrypted and gets the database's plaintext answer back, through the
+    // same embedding, quantization and ranking as a PIR round. A retrieval bug that
+    // persists here is in the ML layer; one that goes away is in the PIR layer. The
+    // server learns every query, and only serves these with TIPTOE_PLAINTEXT_MODE=1.
+    pub fn with_plaintext_mode(mut self) -> Self {
+        self.plaintext = true;
+        self
+    }
+
+    fn assisted_round(&self) -> bool {
+        self.assisted_rounds
+            .fetch_update(AtomicOrdering::Relaxed, AtomicOrdering::Relaxed, |rounds| {
+                rounds.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    // Runs one PIR round and recovers the database's answer to the plaintext query `v`.
+    // Every request of the round goes to the epoch serving when it started, so a rebuild
+    // in between fails the round instead of mixing epochs. Params, A and hint are
+    // downloaded once per epoch and checked against the server's digest then. In
+    // degraded mode the round skips the hint and has the server recover the answer; in
+    // plaintext mode it skips PIR and the server answers `v` itself.
+    async fn pir_round<D: PirEndpoint>(
+        &self,
+        db: &D,
+        v: DVector<BigInt>,
+        stats: &mut QueryStats,
+    ) -> Result<DVector<BigInt>> {
+        let key = db.cache_key();
+        let db = db.pinned().await?;
+        let (epoch, params) = db.versioned_params().await?;
+        stats.answered_at(epoch);
+        if self.plaintext {
+            let v = fit_query(v, params.m)?;
+            stats.upload_bytes += payload_bytes(v.iter());
+            stats.rounds += 1;
+            let started = Instant::now();
+            let result = db.respond_plaintext(&v).await?;
+            stats.respond_ms += elapsed_ms(started);
+            stats.download_bytes += payload_bytes(result.iter());
+            return Ok(result);
+        }
+
+        if self.assisted_round() {
+            if let (Some(key), ClusterConnection::Remote(remote)) = (&key, &db) {
+                self.download_hint(key, epoch, remote.as_ref());
+            }
+            let a = match self.cached(key.as_deref(), epoch) {
+                Some(prepared) => prepared.a.clone(),
+                None => {
+                    let a = db.a().await?;
+                    stats.download_bytes += payload_bytes(a.iter());
+                    a
+                }
+            };
+            let (s, query) = pir::query(&params, &fit_query(v, params.m)?, &a);
+            stats.upload_bytes += payload_bytes(query.iter());
+            stats.rounds += 1;
+            let started = Instant::now();
+            let result = db.respond_assisted(&query, &s).await?;
+            stats.respond_ms += elapsed_ms(started);
+            stats.upload_bytes += payload_bytes(s.iter());
+            stats.download_bytes += payload_bytes(result.iter());
+            return Ok(result);
+        }
+
+        let prepared = match self.cached(key.as_deref(), epoch) {
+            Some(prepared) => prepared,
+            None => self.prepare(&db, key, epoch, params, stats).await?,
+        };
+        let (s, query) = pir::query(
+            &prepared.params,
+            &fit_query(v, prepared.params.m)?,
+            &prepared.a,
+        );
+        stats.upload_bytes += payload_bytes(query.iter());
+        stats.rounds += 1;
+
+        let started = Instant::now();
+        let response = db.respond(&query).await?;
+        stats.respond_ms += elapsed_ms(started);
+
+        let started = Instant::now();
+        let result = pir::recover(&prepared.params, &prepared.hint, &s, &response);
+        stats.recover_ms += elapsed_ms(started);
+
+        stats.download_bytes += payload_bytes(response.iter());
+        Ok(result)
+    }
+
+    fn cached(&self, key: Option<&str>, epoch: u64) -> Option<Arc<PreparedRound>> {
+        self.prepared
+            .lock()
+            .unwrap()
+            .get(key?)
+            .filter(|prepared| prepared.epoch == epoch)
+            .cloned()
+    }
+
+    // Starts downloading A and the hint of `db` at `epoch` unless that is already under
+    // way, replacing a download of an earlier epoch's
+    fn download_hint(&self, key: &str, epoch: u64, db: &dyn AsyncDatabase) {
+        let mut downloads = self.hint_downloads.lock().unwrap();
+        if downloads
+            .get(key)
+            .is_some_and(|download| download.epoch == epoch)
+        {
+            return;
+        }
+        let db = db.boxed_clone();
+        let task = tokio::spawn(async move { tokio::try_join!(db.get_a(), db.get_hint()) });
+        if let Some(stale) = downloads.insert(key.to_string(), HintDownload { epoch, task }) {
+            stale.task.abort();
+        }
+    }
+
+    // A and hint of `db` at `epoch`, from a background download under `key` if one was
+    // started, otherwise downloaded now
+    async fn fetch_hint(
+        &self,
+        db: &ClusterConnection<'_>,
+        key: Option<&str>,
+        epoch: u64,
+    ) -> Result<(DMatrix<BigInt>, DMatrix<BigInt>)> {
+        let download = key.and_then(|key| self.hint_downloads.lock().unwrap().remove(key));
+        match download {
+            Some(download) if download.epoch == epoch => match download.task.await {
+                Ok(Ok(downloaded)) => return Ok(downloaded),
+                // A failed background download is retried in the foreground
+                Ok(Err(_)) | Err(_) => {}
+            },
+            Some(stale) => stale.task.abort(),
+            None => {}
+        }
+        tokio::try_join!(db.a(), db.hint())
+    }
+
+    // Downloads A and the hint of `db` at `epoch` and checks them against its digest,
+    // keeping them under `key` in place of any earlier epoch's
+    async fn prepare(
+        &self,
+        db: &ClusterConnection<'_>,
+        key: Option<String>,
+        epoch: u64,
+        params: SimplePIRParams,
+        stats: &mut QueryStats,
+    ) -> Result<Arc<PreparedRound>> {
+        let (a, hint) = self.fetch_hint(db, key.as_deref(), epoch).await?;
+        if let Some(digest) = db.digest().await? {
+            // An explicitly configured key takes precedence over pins
+            let pinned = match (&self.pins, db.origin()) {
+                (Some(pins), Some(origin)) if self.verifying_key.is_none() => pins
+                    .lock()
+                    .unwrap()
+                    .check(&origin, digest.public_key.as_deref())?,
+                _ => None,
+            };
+            let verifying_key = self.verifying_key.as_ref().or(pinned.as_ref());
+            digest.verify(&params, &hint, &a, verifying_key)?;
+        }
+        stats.download_bytes += payload_bytes(a.iter()) + payload_bytes(hint.iter());
+
+        let prepared = Arc::new(PreparedRound {
+            epoch,
+            params,
+            a,
+            hint,
+        });
+        if let Some(key) = key {
+            self.prepared
+                .lock()
+                .unwrap()
+                .insert(key, Arc::clone(&prepared));
+        }
+        Ok(prepared)
+    }
+
+    // Rejects downloads whose digest is not signed by `key`, see `integrity`
+    pub fn with_verifying_key(mut self, key: VerifyingKey) -> Self {
+        self.verifying_key = Some(key);
+        self
+    }
+
+    // Pins each server's signing key the first time it is seen and refuses a different
+    // one afterwards, unless `pins` allows identity changes
+    pub fn with_pinning(mut self, pins: PinStore) -> Self {
+        self.pins = Some(Mutex::new(pins));
+        self
+    }
+
+    pub fn with_cache(mut self, cache: ResultCache) -> Self {
+        self.cache = Some(Mutex::new(cache));
+        self
+    }
+
+    // Makes every query also embed its subject without template phrasing such as
+    // "How is ... performing today?". Still one PIR round per query.
+    pub fn with_query_fusion(mut self, enabled: bool) -> Self {
+        self.query_fusion = enabled;
+        self
+    }
+
+    // After each `query_top_k` or `query_page`, remembers the next `rows` ranked
+    // candidates for `prefetch` to fetch ahead of time
+    pub fn with_prefetch(mut self, rows: usize) -> Self {
+        self.prefetch_rows = rows;
+        self
+    }
+
+    // Also scores every query against the embedding database behind `transport`, built
+    // over the same corpus with another model, and fuses the two. `embedder` must embed
+    // with that database's model. Each database costs a PIR round per query.
+    pub fn with_ensemble_member(
+        mut self,
+        transport: Arc<dyn Transport>,
+        embedder: Embedder,
+        weight: f64,
+    ) -> Self {
+        self.ensemble.push(EnsembleMember {
+            db: DatabaseConnection::Remote(Box::new(RemoteDatabase::with_transport(transport))),
+            embedder,
+            weight,
+        });
+        self
+    }
+
+    // Fields holding the asset class and quote time `query_filtered` filters on
+    pub fn with_filter_fields(mut self, fields: FilterFields) -> Self {
+        self.filter_fields = fields;
+        self
+    }
+
+    // Cost of the most recent `query`, `query_top_k`, `query_page`, `fetch_by_id`,
+    // `query_value` or `contains` call
+    pub fn last_stats(&self) -> QueryStats {
+        *self.last_stats.lock().unwrap()
+    }
+
+    fn record_stats(&self, stats: QueryStats) {
+        *self.last_stats.lock().unwrap() = stats;
+    }
+
+    // Scores and epochs behind the most recent `query`, `query_fused` or
+    // `query_stream`; see `Diagnostics`. None after an answer from the cache.
+    pub fn last_diagnostics(&self) -> Option<Diagnostics> {
+        self.last_diagnostics.lock().unwrap().clone()
+    }
+
+    // Attaches the diagnostics of the current query to `error`
+    fn diagnose(&self, error: anyhow::Error) -> anyhow::Error {
+        let diagnostics = self.last_diagnostics.lock().unwrap().clone();
+        match diagnostics {
+            Some(diagnostics) => {
+                let diagnostics = diagnostics.with_cause(&error);
+                *self.last_diagnostics.lock().unwrap() = Some(diagnostics.clone());
+                error.context(diagnostics)
+            }
+            None => error,
+        }
+    }
+
+    // Watches the record best matching `query` until `field` crosses `threshold`
+    pub fn watch(&self, query: &str, field: &str, threshold: Threshold) -> Watcher<'_> {
+        Watcher::new(self, query, field, threshold)
+    }
+
     #[allow(dead_code)]
     pub(crate) async fn update(&mut self) -> Result<()> {
         self.encoding_db.update().await?;
@@ -91,137 +1097,763 @@ impl Client {
         Ok(())
     }
 
-    fn adjust_embedding(embedding: DVector<BigInt>, m: usize) -> DVector<BigInt> {
-        match embedding.len().cmp(&m) {
-            Ordering::Equal => embedding,
-            Ordering::Less => {
-                let mut new_embedding = DVector::zeros(m);
-                new_embedding
-                    .rows_mut(0, embedding.len())
-                    .copy_from(&embedding);
-                new_embedding
+    // Privately scores documents against `query`, returning (document index, score) pairs.
+    // When the database is clustered only the nearest cluster is scored, so the server
+    // learns the cluster id but nothing finer. Deleted rows are left out.
+    async fn scores(&self, query: &str, stats: &mut QueryStats) -> Result<Vec<(usize, BigInt)>> {
+        let queries = if self.query_fusion {
+            reformulations(query)
+        } else {
+            vec![query.to_string()]
+        };
+        self.fused_scores(&queries, stats).await
+    }
+
+    // Like `scores`, for the mean of the embeddings of several phrasings of one query
+    async fn fused_scores(
+        &self,
+        queries: &[String],
+        stats: &mut QueryStats,
+    ) -> Result<Vec<(usize, BigInt)>> {
+        let scores = self
+            .model_scores(&self.embedding_db, &self.embedder, queries, stats)
+            .await?;
+        if self.ensemble.is_empty() {
+            return Ok(scores);
+        }
+        self.ensemble_scores(scores, queries, stats).await
+    }
+
+    // Fuses `scores` with the ensemble's: the weighted mean of each model's calibrated
+    // cosine, over the models that scored the document (the main one with weight 1),
+    // mapped back to the main database's score scale. Documents are matched by id, so
+    // the databases may order them differently; ones the main database lacks are dropped.
+    async fn ensemble_scores(
+        &self,
+        scores: Vec<(usize, BigInt)>,
+        queries: &[String],
+        stats: &mut QueryStats,
+    ) -> Result<Vec<(usize, BigInt)>> {
+        let calibration = self.embedding_db.calibration().await?;
+        let ids = self.embedding_db.document_ids().await?;
+        let rows: HashMap<&DocumentId, usize> =
+            ids.iter().enumerate().map(|(row, id)| (id, row)).collect();
+        let mut fused: BTreeMap<usize, (f64, f64)> = scores
+            .iter()
+            .map(|(row, score)| (*row, (calibration.cosine(score), 1.0)))
+            .collect();
+        for member in &self.ensemble {
+            let scores = self
+                .model_scores(&member.db, &member.embedder, queries, stats)
+                .await?;
+            let member_calibration = member.db.calibration().await?;
+            let member_ids = member.db.document_ids().await?;
+            for (index, score) in scores {
+                let Some(&row) = member_ids.get(index).and_then(|id| rows.get(id)) else {
+                    continue;
+                };
+                let (sum, weight) = fused.entry(row).or_insert((0.0, 0.0));
+                *sum += member.weight * member_calibration.cosine(&score);
+                *weight += member.weight;
             }
-            Ordering::Greater => embedding.rows(0, m).into(),
         }
+        Ok(fused
+            .into_iter()
+            .filter(|(_, (_, weight))| *weight > 0.0)
+            .map(|(row, (sum, weight))| (row, calibration.score(sum / weight)))
+            .collect())
+    }
+
+    // Scores `queries` against one embedding database, embedding them with `embedder`
+    async fn model_scores(
+        &self,
+        db: &DatabaseConnection<EmbeddingDatabase>,
+        embedder: &Embedder,
+        queries: &[String],
+        stats: &mut QueryStats,
+    ) -> Result<Vec<(usize, BigInt)>> {
+        let (raw_embedding, embedding) = self.embed_for(db, embedder, queries, stats).await?;
+        let dead = db.dead_rows().await?;
+        let scores: Vec<(usize, BigInt)> = match db.centroids().await? {
+            Some(centroids) => {
+                let cluster =
+                    find_closest_centroid(&raw_embedding, &centroids.centroids, centroids.metric);
+                let members = db.members(cluster).await?;
+                let scores = self
+                    .pir_round(&db.cluster(cluster)?, embedding, stats)
+                    .await?;
+                members.into_iter().zip(scores.iter().cloned()).collect()
+            }
+            None => {
+                let scores = self.pir_round(db, embedding, stats).await?;
+                scores.iter().cloned().enumerate().collect()
+            }
+        };
+        Ok(scores
+            .into_iter()
+            .filter(|(index, _)| !dead.contains(index))
+            .collect())
     }
 
-    pub async fn query(&self, query: &str) -> Result<DVector<BigInt>> {
-        let embedding = self
-            .embedder
-            .embed_text(query)
+    // The fused embedding of `queries`, raw and quantized for the embedding database
+    async fn embed_query(
+        &self,
+        queries: &[String],
+        stats: &mut QueryStats,
+    ) -> Result<(Vec<f32>, DVector<BigInt>)> {
+        self.embed_for(&self.embedding_db, &self.embedder, queries, stats)
+            .await
+    }
+
+    // Like `embed_query`, for `db` with `embedder`
+    async fn embed_for(
+        &self,
+        db: &DatabaseConnection<EmbeddingDatabase>,
+        embedder: &Embedder,
+        queries: &[String],
+        stats: &mut QueryStats,
+    ) -> Result<(Vec<f32>, DVector<BigInt>)> {
+        if queries.is_empty() {
+            return Err(PirError::InvalidInput("No queries to fuse".to_string()).into());
+        }
+        let quantization = db.quantization().await?;
+        let mod_power = (db.params().await?.p.bits() - 1) as u32;
+
+        let prefix = db.query_prefix().await?;
+        let queries: Vec<String> = queries
+            .iter()
+            .map(|query| format!("{}{}", prefix, query))
+            .collect();
+
+        let started = Instant::now();
+        let raw_embeddings = embedder
+            .embed_all(&queries)
+            .await
             .map_err(|e| PirError::Embedding(format!("Text embedding failed: {}", e)))?;
+        // Servers switch models between epochs, which only shows in their params. A
+        // service's model is known once it has embedded the queries.
+        if let Some(model) = embedder.model() {
+            check_model(&model, db.embedding_model().await?.as_ref())?;
+        }
+        let mut raw_embedding = fuse_embeddings(&raw_embeddings);
+        if let Some(correction) = db.query_correction().await? {
+            raw_embedding = correction.apply(&raw_embedding)?;
+        }
+        check_query_dim(raw_embedding.len(), db.query_dim().await?)?;
+        quantization.validate(raw_embedding.len(), mod_power)?;
+        let embedding = quantization.quantize(&raw_embedding);
+        stats.embed_ms += elapsed_ms(started);
+        Ok((raw_embedding, embedding))
+    }
 
-        // Query embedding database
-        let embedding_params = self.embedding_db.params().await?;
-        let adjusted_embedding = Self::adjust_embedding(embedding, embedding_params.m);
-        let (s_embedding, query_embedding) = generate_query(
-            &embedding_params,
-            &adjusted_embedding,
-            &self.embedding_db.a().await?,
-        );
+    // Like `scores`, but for a date-filtered query against a partitioned database only
+    // the partitions overlapping its dates are scored, so the server learns which time
+    // windows are searched. Other queries are scored as usual.
+    async fn dated_scores(
+        &self,
+        query: &FilteredQuery,
+        stats: &mut QueryStats,
+    ) -> Result<Vec<(usize, BigInt)>> {
+        let dated = query.after.is_some() || query.before.is_some();
+        let manifest = match self.embedding_db.partitions().await? {
+            Some(manifest) if dated => manifest,
+            _ => return self.scores(&query.text, stats).await,
+        };
+        let queries = if self.query_fusion {
+            reformulations(&query.text)
+        } else {
+            vec![query.text.clone()]
+        };
+        let (_, embedding) = self.embed_query(&queries, stats).await?;
+        let dead = self.embedding_db.dead_rows().await?;
+        let mut scores = Vec::new();
+        for id in manifest.covering(query.after, query.before) {
+            let partition = self.embedding_db.partition(id)?;
+            let answer = self.pir_round(&partition, embedding.clone(), stats).await?;
+            scores.extend(
+                manifest.partitions[id]
+                    .rows
+                    .iter()
+                    .copied()
+                    .zip(answer.iter().cloned())
+                    .filter(|(index, _)| !dead.contains(index)),
+            );
+        }
+        Ok(scores)
+    }
 
-        let response_embedding = self.embedding_db.respond(&query_embedding).await?;
-        let result_embedding = recover(
-            &self.embedding_db.hint().await?,
-            &s_embedding,
-            &response_embedding,
-            &embedding_params,
-        );
+    // Decodes a recovered record, decrypting it when a record key is set
+    fn decode_record(&self, data: &DVector<BigInt>) -> Result<String> {
+        let record = decode_input(data)?;
+        match &self.record_key {
+            Some(key) => key.decrypt(&record),
+            None => Ok(record),
+        }
+    }
+
+    // Privately retrieves the encoded document at `index`. `ids` is the epoch's full
+    // id list, downloaded as a whole so it reveals nothing about the row fetched.
+    // With a hot tier the same row is fetched from it too and its fields overlaid,
+    // and the result is dated by the hot tier's epoch.
+    async fn fetch(
+        &self,
+        index: usize,
+        epoch: u64,
+        ids: &[DocumentId],
+        stats: &mut QueryStats,
+    ) -> Result<QueryResult> {
+        let hot = self.encoding_db.hot().await?;
+        let hot_epoch = hot.as_ref().map(|(info, _)| info.epoch);
+        if let Some(result) = self.take_prefetched(index, epoch, hot_epoch) {
+            return Ok(result);
+        }
+        let mut one_hot = DVector::zeros(index + 1);
+        one_hot[index] = BigInt::one();
+
+        let mut result = self
+            .pir_round(&self.encoding_db, one_hot.clone(), stats)
+            .await?;
+        let mut epoch = epoch;
+        match hot {
+            Some((info, tier)) => {
+                let hot = self.pir_round(&tier, one_hot, stats).await?;
+                let record = merge(&self.decode_record(&result)?, &self.decode_record(&hot)?)?;
+                result = encode_record(&record)?;
+                epoch = info.epoch;
+            }
+            None if self.record_key.is_some() => {
+                result = encode_record(&self.decode_record(&result)?)?;
+            }
+            None => {}
+        }
+        Ok(QueryResult::new(
+            result,
+            epoch,
+            ids.get(index).copied(),
+            self.staleness_threshold,
+        ))
+    }
+
+    // A prefetched result for `index`, if it was fetched in `epoch` with the current
+    // hot fields
+    fn take_prefetched(
+        &self,
+        index: usize,
+        epoch: u64,
+        hot_epoch: Option<u64>,
+    ) -> Option<QueryResult> {
+        let mut prefetch = self.prefetch.lock().unwrap();
+        if prefetch.epoch != epoch {
+            return None;
+        }
+        match prefetch.fetched.remove(&index) {
+            Some((fetched_hot_epoch, result)) if fetched_hot_epoch == hot_epoch => Some(result),
+            _ => None,
+        }
+    }
+
+    // Queues the candidates ranked after the results just returned, keeping whatever
+    // was already fetched of them
+    fn plan_prefetch(&self, epoch: u64, next: impl Iterator<Item = usize>) {
+        if self.prefetch_rows == 0 {
+            return;
+        }
+        let next: Vec<usize> = next.take(self.prefetch_rows).collect();
+        let mut prefetch = self.prefetch.lock().unwrap();
+        if prefetch.epoch != epoch {
+            *prefetch = Prefetch {
+                epoch,
+                ..Prefetch::default()
+            };
+        }
+        prefetch.fetched.retain(|row, _| next.contains(row));
+        let pending = next
+            .into_iter()
+            .filter(|row| !prefetch.fetched.contains_key(row))
+            .collect();
+        prefetch.pending = pending;
+    }
+
+    // Fetches the candidates queued by the last `query_top_k` or `query_page` (see
+    // `with_prefetch`), so asking for the next result costs no PIR round. Meant to run
+    // in the background, e.g. while a UI shows the current results; returns how many
+    // rows it fetched. Does nothing once the encoding database has been rebuilt, and
+    // rows that fail to fetch are simply fetched again when asked for.
+    pub async fn prefetch(&self) -> Result<usize> {
+        let (epoch, rows) = {
+            let prefetch = self.prefetch.lock().unwrap();
+            (prefetch.epoch, prefetch.pending.clone())
+        };
+        if rows.is_empty() {
+            return Ok(0);
+        }
+        let (current, ids) = self.encoding_db.documents().await?;
+        if current != epoch {
+            return Ok(0);
+        }
+        let hot_epoch = self.encoding_db.hot().await?.map(|(info, _)| info.epoch);
+        let fetched = join_all(rows.iter().map(|&row| {
+            let ids = &ids;
+            async move {
+                let result = self
+                    .fetch(row, epoch, ids, &mut QueryStats::default())
+                    .await;
+                (row, result)
+            }
+        }))
+        .await;
+
+        let mut prefetch = self.prefetch.lock().unwrap();
+        // A later query may have queued other rows in the meantime
+        if prefetch.epoch != epoch {
+            return Ok(0);
+        }
+        let mut count = 0;
+        for (row, result) in fetched {
+            let Ok(result) = result else { continue };
+            if let Some(position) = prefetch.pending.iter().position(|&pending| pending == row) {
+                prefetch.pending.remove(position);
+                prefetch.fetched.insert(row, (hot_epoch, result));
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // Fetches `rows` concurrently, keeping their order. If any fetch fails the first
+    // failure is returned, with whatever was fetched attached as `PartialResults`.
+    async fn fetch_all(
+        &self,
+        rows: &[usize],
+        epoch: u64,
+        ids: &[DocumentId],
+        stats: &mut QueryStats,
+    ) -> Result<Vec<QueryResult>> {
+        let fetched = join_all(rows.iter().map(|&index| async move {
+            let mut row_stats = QueryStats::default();
+            let result = self.fetch(index, epoch, ids, &mut row_stats).await;
+            (result, row_stats)
+        }))
+        .await;
+
+        for (_, row_stats) in &fetched {
+            stats.add(row_stats);
+        }
+        let mut results = Vec::with_capacity(rows.len());
+        let mut failed = Vec::new();
+        let mut first_error = None;
+        for (rank, (result, _)) in fetched.into_iter().enumerate() {
+            match result {
+                Ok(result) => {
+                    results.push(result.lagging(stats.oldest_epoch, self.staleness_threshold))
+                }
+                Err(error) => {
+                    failed.push((rank, format!("{:#}", error)));
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+        match first_error {
+            Some(error) => Err(error.context(PartialResults { results, failed })),
+            None => Ok(results),
+        }
+    }
+
+    // Privately re-fetches a document returned by an earlier query, wherever the
+    // current epoch placed it. The returned id carries the current content hash.
+    pub async fn fetch_by_id(&self, id: &DocumentId) -> Result<QueryResult> {
+        let (epoch, ids) = self.encoding_db.documents().await?;
+        let dead = self.encoding_db.dead_rows().await?;
+        let index = find_row(&ids, id)
+            .filter(|index| !dead.contains(index))
+            .ok_or_else(|| {
+                PirError::InvalidInput(format!("Document {} is no longer in the corpus", id))
+            })?;
+
+        let mut stats = QueryStats::default();
+        let result = self.fetch(index, epoch, &ids, &mut stats).await?;
+        self.record_stats(stats);
+        Ok(result)
+    }
+
+    // Privately tests whether a document with this key (its name) exists, without
+    // fetching it. Always runs one PIR round per filter hash so the number of rounds
+    // reveals nothing. False positives occur at the filter's rate; false negatives never.
+    pub async fn contains(&self, key: &str) -> Result<bool> {
+        let (params, filter) = self.encoding_db.membership().await?;
+
+        let mut stats = QueryStats::default();
+        let mut found = true;
+        for bit in params.positions(key) {
+            let (column, row) = params.locate(bit);
+            let mut one_hot = DVector::zeros(column + 1);
+            one_hot[column] = BigInt::one();
+
+            let bits = self.pir_round(&filter, one_hot, &mut stats).await?;
+            found &= bits.get(row).is_some_and(|b| !b.is_zero());
+        }
+        self.record_stats(stats);
+        Ok(found)
+    }
+
+    // Privately looks up the packed numeric field (e.g. the price) of the document named
+    // `symbol`. One round fetches a column of packed values instead of a whole record.
+    // Returns None when the document has no numeric value for the field.
+    pub async fn query_value(&self, symbol: &str) -> Result<Option<f64>> {
+        let (layout, packed) = self.encoding_db.packed().await?;
+        let (column, row) = layout
+            .locate(symbol)
+            .ok_or_else(|| PirError::InvalidInput(format!("Unknown symbol '{}'", symbol)))?;
+        let mut one_hot = DVector::zeros(column + 1);
+        one_hot[column] = BigInt::one();
+
+        let mut stats = QueryStats::default();
+        let values = self.pir_round(&packed, one_hot, &mut stats).await?;
+        self.record_stats(stats);
+        Ok(values.get(row).and_then(unpack_value))
+    }
 
-        // Convert to one-hot vector
-        let result_vec = {
-            let mut vec = DVector::zeros(result_embedding.len());
-            let max_idx = result_embedding
-                .iter()
-                .enumerate()
-                .max_by_key(|(_i, val)| (*val).clone())
-                .ok_or_else(|| PirError::InvalidInput("Empty embedding result".to_string()))?
-                .0;
-            vec[max_idx] = BigInt::one();
-            vec
+    // Privately computes the similarity of `query` to every document, as (document
+    // index, recovered inner product) pairs in row order. Scores are quantized inner
+    // products, not cosines. With clustering only the nearest cluster's documents are
+    // scored; deleted rows are left out. Nothing is fetched from the encoding database.
+    pub async fn score_all(&self, query: &str) -> Result<Vec<(usize, BigInt)>> {
+        let mut stats = QueryStats::default();
+        let scores = self.scores(query, &mut stats).await?;
+        self.record_stats(stats);
+        Ok(scores)
+    }
+
+    // Maps scores from `score_all` to approximate cosine similarity, e.g. to drop
+    // results below a threshold
+    pub async fn calibration(&self) -> Result<Calibration> {
+        self.embedding_db.calibration().await
+    }
+
+    // Runs as one session, so a cluster behind a load balancer answers every request
+    // from the node that answered the first
+    pub async fn query(&self, query: &str) -> Result<QueryResult> {
+        in_session(self.run_query(query)).await
+    }
+
+    async fn run_query(&self, query: &str) -> Result<QueryResult> {
+        let Some(cache) = &self.cache else {
+            let mut stats = QueryStats::default();
+            let scores = self.scores(query, &mut stats).await?;
+            return self.fetch_best(query, scores, stats).await;
         };
 
-        // Query encoding database
-        let encoding_params = self.encoding_db.params().await?;
-        let adjusted_result = Self::adjust_embedding(result_vec, encoding_params.m);
-        let (s, query) = generate_query(
-            &encoding_params,
-            &adjusted_result,
-            &self.encoding_db.a().await?,
-        );
+        // Any rebuild or hot-tier refresh can change the answer, so all epochs are
+        // part of the key
+        let epochs = [
+            self.embedding_db.epoch().await?,
+            self.encoding_db.epoch().await?,
+            match self.encoding_db.hot().await? {
+                Some((info, _)) => info.epoch,
+                None => 0,
+            },
+        ];
+        let cached = cache.lock().unwrap().get(query, &epochs);
+        if let Some(cached) = cached {
+            self.record_stats(QueryStats::default());
+            *self.last_diagnostics.lock().unwrap() = None;
+            return Ok(QueryResult::new(
+                encode_record(&cached.record)?,
+                cached.epoch,
+                cached.id,
+                self.staleness_threshold,
+            ));
+        }
+
+        let mut stats = QueryStats::default();
+        let scores = self.scores(query, &mut stats).await?;
+        let result = self.fetch_best(query, scores, stats).await?;
+        let cached = CachedResult {
+            record: decode_input(&result.data).map_err(|e| self.diagnose(e))?,
+            epoch: result
+                .epoch
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            id: result.id,
+        };
+        cache.lock().unwrap().insert(query, &epochs, cached)?;
+        Ok(result)
+    }
+
+    // Retrieves the best match for several phrasings of the same request, scored as one
+    // query with their mean embedding
+    pub async fn query_fused(&self, queries: &[String]) -> Result<QueryResult> {
+        let mut stats = QueryStats::default();
+        let scores = self.fused_scores(queries, &mut stats).await?;
+        self.fetch_best(&queries.join(" | "), scores, stats).await
+    }
 
-        let response = self.encoding_db.respond(&query).await?;
-        let result = recover(
-            &self.encoding_db.hint().await?,
-            &s,
-            &response,
-            &encoding_params,
+    // Fetches the best scoring row, recording the diagnostics of `query` first so a
+    // failed fetch carries them
+    async fn fetch_best(
+        &self,
+        query: &str,
+        scores: Vec<(usize, BigInt)>,
+        mut stats: QueryStats,
+    ) -> Result<QueryResult> {
+        let (epoch, ids) = self.encoding_db.documents().await?;
+        // Only the scoring rounds have run so far
+        let scored_epoch = stats.oldest_epoch.unwrap_or_default();
+        let diagnostics = Diagnostics::new(
+            query,
+            &scores,
+            &ids,
+            scored_epoch,
+            epoch,
+            params_hash(&self.embedding_db.params().await?),
         );
+        let index = diagnostics.top.first().map(|best| best.row);
+        *self.last_diagnostics.lock().unwrap() = Some(diagnostics);
+        let index = index.ok_or_else(|| {
+            self.diagnose(PirError::InvalidInput("Empty embedding result".to_string()).into())
+        })?;
 
+        let result = self
+            .fetch(index, epoch, &ids, &mut stats)
+            .await
+            .map_err(|e| self.diagnose(e))?
+            .lagging(stats.oldest_epoch, self.staleness_threshold);
+        self.record_stats(stats);
         Ok(result)
     }
 
-    pub async fn query_top_k(&self, query: &str, k: usize) -> Result<Vec<DVector<BigInt>>> {
+    // Like `query`, but gives up after `timeout`. In-flight HTTP requests are dropped,
+    // and each request tells the server the remaining budget so it can drop the work too.
+    pub async fn query_with_deadline(&self, query: &str, timeout: Duration) -> Result<QueryResult> {
+        let deadline = Instant::now() + timeout;
+        tokio::time::timeout(timeout, DEADLINE.scope(deadline, self.query(query)))
+            .await
+            .unwrap_or_else(|_| {
+                Err(
+                    PirError::Cancelled(format!("Query exceeded its {:?} deadline", timeout))
+                        .into(),
+                )
+            })
+    }
+
+    // Retrieves the best match for `query`, passing each decoded chunk to `on_chunk` as
+    // soon as the PIR response carrying it is recovered. Records are currently stored in
+    // a single column of the encoding database, so each record arrives as one chunk.
+    pub async fn query_stream<F: FnMut(&str)>(&self, query: &str, mut on_chunk: F) -> Result<()> {
+        let result = self.query(query).await?;
+        on_chunk(&decode_input(&result.data).map_err(|e| self.diagnose(e))?);
+        Ok(())
+    }
+
+    // The `k` best matches for `query`, best first. Their records are fetched
+    // concurrently; if any fetch fails the error carries the rest as `PartialResults`.
+    pub async fn query_top_k(&self, query: &str, k: usize) -> Result<Vec<QueryResult>> {
+        self.top_k(query, k, k).await
+    }
+
+    // Like `query_top_k`, but fetches more results, up to `max_k`, while the next match
+    // scores nearly as well as the best one. Ambiguous queries then get every close
+    // candidate, and clear ones only pay for `k` fetches.
+    pub async fn query_adaptive_k(
+        &self,
+        query: &str,
+        k: usize,
+        max_k: usize,
+    ) -> Result<Vec<QueryResult>> {
+        self.top_k(query, k, max_k).await
+    }
+
+    async fn top_k(&self, query: &str, k: usize, max_k: usize) -> Result<Vec<QueryResult>> {
         if k == 0 {
             return Err(PirError::InvalidInput("k must be greater than 0".to_string()).into());
         }
 
-        let embedding = self
-            .embedder
-            .embed_text(query)
-            .map_err(|e| PirError::Embedding(format!("Text embedding failed: {}", e)))?;
-        let embedding_params = self.embedding_db.params().await?;
-        let encoding_params = self.encoding_db.params().await?;
-
-        let (s_embedding, query_embedding) = generate_query(
-            &embedding_params,
-            &Self::adjust_embedding(embedding, embedding_params.m),
-            &self.embedding_db.a().await?,
-        );
+        let mut stats = QueryStats::default();
+        let mut scores = self.scores(query, &mut stats).await?;
+        if scores.is_empty() {
+            return Err(PirError::InvalidInput("No results found".to_string()).into());
+        }
+        scores.sort_by(|(_i1, v1), (_i2, v2)| v2.cmp(v1));
+        let k = widen_k(&scores, k, max_k);
 
-        let response_embedding = self.embedding_db.respond(&query_embedding).await?;
-        let result_embedding = recover(
-            &self.embedding_db.hint().await?,
-            &s_embedding,
-            &response_embedding,
-            &embedding_params,
-        );
+        let (epoch, ids) = self.encoding_db.documents().await?;
+        let rows: Vec<usize> = scores.iter().take(k).map(|&(index, _)| index).collect();
+        let results = self.fetch_all(&rows, epoch, &ids, &mut stats).await?;
+        self.plan_prefetch(epoch, scores.iter().skip(k).map(|&(index, _)| index));
 
-        let top_indices: Vec<usize> = {
-            let mut indexed_values: Vec<(usize, &BigInt)> =
-                result_embedding.iter().enumerate().collect();
-            indexed_values.sort_by(|(_i1, v1), (_i2, v2)| v2.cmp(v1));
-            indexed_values.into_iter().map(|(i, _val)| i).collect()
-        };
+        self.record_stats(stats);
+        Ok(results)
+    }
 
-        if top_indices.is_empty() {
-            return Err(PirError::InvalidInput("No results found".to_string()).into());
+    // The `k` best matches for `query.text` that pass its class and date filters, best
+    // first; its namespace is left to `filters::Namespaces`. The best
+    // `FILTER_SCAN_FACTOR * k` candidates are always fetched and filtered here, so the
+    // servers see the same number of fetches however selective the filters are. On a
+    // partitioned database only the time windows its dates overlap are scored.
+    // Returns fewer than `k` results when too few candidates pass.
+    pub async fn query_filtered(
+        &self,
+        query: &FilteredQuery,
+        k: usize,
+    ) -> Result<Vec<QueryResult>> {
+        if k == 0 {
+            return Err(PirError::InvalidInput("k must be greater than 0".to_string()).into());
         }
 
-        let mut results = Vec::with_capacity(k);
-        for &idx in top_indices.iter().take(k) {
-            let mut vec = DVector::zeros(result_embedding.len());
-            vec[idx] = BigInt::one();
+        let mut stats = QueryStats::default();
+        let mut scores = self.dated_scores(query, &mut stats).await?;
+        scores.sort_by(|(_i1, v1), (_i2, v2)| v2.cmp(v1));
+        let (epoch, ids) = self.encoding_db.documents().await?;
+        let rows: Vec<usize> = scores
+            .iter()
+            .take(k.saturating_mul(FILTER_SCAN_FACTOR))
+            .map(|&(index, _)| index)
+            .collect();
+        let candidates = self.fetch_all(&rows, epoch, &ids, &mut stats).await?;
 
-            let (s, query) = generate_query(
-                &encoding_params,
-                &Self::adjust_embedding(vec, encoding_params.m),
-                &self.encoding_db.a().await?,
-            );
+        let results = candidates
+            .into_iter()
+            .filter(|result| {
+                decode_input(&result.data)
+                    .ok()
+                    .and_then(|text| serde_json::from_str(text.trim_end_matches('\0')).ok())
+                    .is_some_and(|document| query.matches(&document, &self.filter_fields))
+            })
+            .take(k)
+            .collect();
+        self.record_stats(stats);
+        Ok(results)
+    }
 
-            let response = self.encoding_db.respond(&query).await?;
-            let result = recover(
-                &self.encoding_db.hint().await?,
-                &s,
-                &response,
-                &encoding_params,
-            );
+    // The best match for `query` together with its neighbours in the encoding database,
+    // in row order, from one PIR round against the block database. Blocks are built at
+    // rebuild time, so hot fields are as of the last rebuild. Deleted rows are left out.
+    pub async fn query_block(&self, query: &str) -> Result<Vec<QueryResult>> {
+        let mut stats = QueryStats::default();
+        let scores = self.scores(query, &mut stats).await?;
+        let best = scores
+            .iter()
+            .max_by(|(_i1, v1), (_i2, v2)| v1.cmp(v2))
+            .map(|&(index, _)| index)
+            .ok_or_else(|| PirError::InvalidInput("No results found".to_string()))?;
+
+        let (layout, blocks) = self.encoding_db.blocks().await?;
+        let (epoch, ids) = self.encoding_db.documents().await?;
+        let dead = self.encoding_db.dead_rows().await?;
+        let block = layout.block_of(best);
+        let mut one_hot = DVector::zeros(block + 1);
+        one_hot[block] = BigInt::one();
+        let column = self.pir_round(&blocks, one_hot, &mut stats).await?;
+
+        let results = layout
+            .rows(block)
+            .zip(layout.split(block, &column))
+            .filter(|(row, _)| !dead.contains(row))
+            .map(|(row, record)| {
+                let record = match self.record_key {
+                    Some(_) => encode_record(&self.decode_record(&record)?)?,
+                    None => record,
+                };
+                Ok(QueryResult::new(
+                    record,
+                    epoch,
+                    ids.get(row).copied(),
+                    self.staleness_threshold,
+                )
+                .lagging(stats.oldest_epoch, self.staleness_threshold))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.record_stats(stats);
+        Ok(results)
+    }
 
-            results.push(result);
+    // Retrieves results `page * page_size` to `(page + 1) * page_size` for `query`, best
+    // first. The scores of the last query paged through are cached, so further pages only
+    // cost their record fetches until the embedding database is rebuilt. A clustered
+    // database only ranks the query's cluster, so pages past it are empty.
+    pub async fn query_page(
+        &self,
+        query: &str,
+        page: usize,
+        page_size: usize,
+    ) -> Result<Vec<QueryResult>> {
+        if page_size == 0 {
+            return Err(
+                PirError::InvalidInput("page_size must be greater than 0".to_string()).into(),
+            );
         }
 
+        let mut stats = QueryStats::default();
+        let scored_epoch = self.embedding_db.epoch().await?;
+        let cached = self
+            .ranking
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|ranking| ranking.query == query && ranking.epoch == scored_epoch)
+            .map(|ranking| ranking.rows.clone());
+        let rows = match cached {
+            Some(rows) => {
+                stats.answered_at(scored_epoch);
+                rows
+            }
+            None => {
+                let mut scores = self.scores(query, &mut stats).await?;
+                scores.sort_by(|(_i1, v1), (_i2, v2)| v2.cmp(v1));
+                let rows: Vec<usize> = scores.into_iter().map(|(index, _)| index).collect();
+                *self.ranking.lock().unwrap() = Some(Ranking {
+                    query: query.to_string(),
+                    epoch: scored_epoch,
+                    rows: rows.clone(),
+                });
+                rows
+            }
+        };
+
+        let (epoch, ids) = self.encoding_db.documents().await?;
+        let start = page.saturating_mul(page_size);
+        let page_rows: Vec<usize> = rows.iter().copied().skip(start).take(page_size).collect();
+        let results = self.fetch_all(&page_rows, epoch, &ids, &mut stats).await?;
+        self.plan_prefetch(
+            epoch,
+            rows.into_iter().skip(start.saturating_add(page_size)),
+        );
+
+        self.record_stats(stats);
         Ok(results)
     }
+
+    // Fraction of queries whose PIR top-1 row is also the plaintext ANN top-1
+    #[cfg(feature = "baseline")]
+    pub async fn baseline_agreement(
+        &self,
+        queries: &[String],
+        index: &BaselineIndex,
+    ) -> Result<f64> {
+        let prefix = self.embedding_db.query_prefix().await?;
+        let mut matches = 0;
+        for query in queries {
+            let raw_embedding = self
+                .embedder
+                .embed_raw(&format!("{}{}", prefix, query))
+                .await
+                .map_err(|e| PirError::Embedding(format!("Text embedding failed: {}", e)))?;
+            let expected = index.nearest(&raw_embedding, 1);
+
+            let (row, _score) = self
+                .scores(query, &mut QueryStats::default())
+                .await?
+                .into_iter()
+                .max_by(|(_i1, v1), (_i2, v2)| v1.cmp(v2))
+                .ok_or_else(|| PirError::InvalidInput("Empty embedding result".to_string()))?;
+
+            if expected.first() == Some(&row) {
+                matches += 1;
+            }
+        }
+        Ok(matches as f64 / queries.len().max(1) as f64)
+    }
 }
 
 #[cfg(test)]
@@ -229,8 +1861,9 @@ mod tests {
     use crate::utils::decode_input;
 
     use super::*;
-    use rand::prelude::IndexedRandom;
-    use serde_json::Value;
+    use crate::market::{Locale, MarketData};
+    use crate::report::ReportBuilder;
+    use rand::{prelude::IndexedRandom, rngs::StdRng, SeedableRng};
     use strsim::jaro_winkler;
     use tokio::test;
 
@@ -249,9 +1882,10 @@ mod tests {
             for name in &names {
                 println!("\nQuerying {}...", name);
                 let result = client.query(name).await?;
-                println!("Raw result: {:?}", result);
+                println!("Raw result: {:?}", result.data);
+                println!("Query stats: {:?}", client.last_stats());
 
-                let output = decode_input(&result)?;
+                let output = decode_input(&result.data)?;
                 println!("Decoded output: {:?}", output);
             }
         }
@@ -331,33 +1965,44 @@ mod tests {
         let mut single_error_count = 0;
         let mut topk_success_count = 0;
         let mut topk_error_count = 0;
-        let mut rng = rand::rng();
+        let mut report = ReportBuilder::new("retrieval_accuracy");
+        // Fixed seed so accuracy numbers are comparable between runs
+        let mut rng = StdRng::seed_from_u64(0);
 
         for i in 0..3 {
             println!("\nUpdate iteration {}...", i + 1);
             client.update().await?;
+            if let DatabaseConnection::Local(db) = &client.embedding_db {
+                if let Some(quality) = db.cluster_quality() {
+                    report.record_cluster_quality(quality);
+                }
+            }
 
             for name in symbols.iter() {
                 let template = query_templates.choose(&mut rng).unwrap();
                 let query = template.replace("{name}", name);
 
                 // Test single query
+                let single_before = single_success_count;
+                let started = Instant::now();
                 match client.query(&query).await {
                     Ok(result) => {
-                        println!("Single query raw result: {:?}", result);
-                        match decode_input(&result) {
+                        report.record_query(elapsed_ms(started), &client.last_stats());
+                        println!("Single query raw result: {:?}", result.data);
+                        match decode_input(&result.data) {
                             Ok(output) => {
                                 println!("Single query decoded output: {:?}", output);
 
-                                if let Ok(json_output) = serde_json::from_str::<Value>(&output) {
-                                    let received_name =
-                                        json_output["name"].as_str().unwrap_or("").trim();
+                                if let Ok(data) = MarketData::parse(&output) {
+                                    let received_name = data.name.trim();
 
                                     if names_match(received_name, name) {
                                         single_success_count += 1;
                                         println!(
-                                            "Single query matched: '{}' with '{}'",
-                                            received_name, name
+                                            "Single query matched: '{}' with '{}' at {:?}",
+                                            received_name,
+                                            name,
+                                            data.display_price(Locale::EnUs)
                                         );
                                     } else {
                                         single_error_count += 1;
@@ -380,22 +2025,26 @@ mod tests {
                     }
                 }
 
+                report.record_hit("top1", single_success_count > single_before);
+
                 // Test top-k query
+                let topk_before = topk_success_count;
                 match client.query_top_k(&query, k).await {
                     Ok(results) => {
-                        println!("Top-k query raw results: {:?}", results);
+                        println!(
+                            "Top-k query raw results: {:?}",
+                            results.iter().map(|r| &r.data).collect::<Vec<_>>()
+                        );
                         let mut found_match = false;
                         let mut match_position = None;
 
                         for (idx, result) in results.iter().enumerate() {
-                            match decode_input(result) {
+                            match decode_input(&result.data) {
                                 Ok(output) => {
                                     println!("Top-k decoded output {}: {:?}", idx, output);
 
-                                    if let Ok(json_output) = serde_json::from_str::<Value>(&output)
-                                    {
-                                        let received_name =
-                                            json_output["name"].as_str().unwrap_or("").trim();
+                                    if let Ok(data) = MarketData::parse(&output) {
+                                        let received_name = data.name.trim();
 
                                         if names_match(received_name, name) {
                                             found_match = true;
@@ -423,7 +2072,10 @@ mod tests {
                             println!("Expected name {} not found in top {} results", name, k);
                             println!(
                                 "Top-k results: {:?}",
-                                results.iter().map(decode_input).collect::<Vec<_>>()
+                                results
+                                    .iter()
+                                    .map(|r| decode_input(&r.data))
+                                    .collect::<Vec<_>>()
                             );
                         }
                     }
@@ -433,6 +2085,8 @@ mod tests {
                     }
                 }
 
+                report.record_hit(&format!("top{}", k), topk_success_count > topk_before);
+
                 // Print current stats
                 let single_total = single_success_count + single_error_count;
                 let topk_total = topk_success_count + topk_error_count;
@@ -483,6 +2137,51 @@ mod tests {
             (topk_success_count as f64 / (topk_success_count + topk_error_count) as f64) * 100.0
         );
 
+        if let Some(path) = report.build().save()? {
+            println!("\nReport written to {}", path);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "baseline")]
+    #[test]
+    async fn bench_baseline_agreement() -> Result<()> {
+        let mut client = Client::new_local()?;
+        client.update().await?;
+
+        let queries: Vec<String> = [
+            "Apple",
+            "Tesla",
+            "Micron Technology",
+            "NASDAQ Composite",
+            "EUR/USD",
+            "CBOE Volatility Index",
+            "SPDR S&P 500",
+            "Bitcoin USD",
+            "Ethereum USD",
+        ]
+        .iter()
+        .map(|name| format!("What is the latest price of {}?", name))
+        .collect();
+
+        let DatabaseConnection::Local(db) = &client.embedding_db else {
+            unreachable!("local client");
+        };
+        let index = db
+            .baseline()
+            .ok_or_else(|| PirError::Database("Baseline index not built".to_string()))?;
+
+        let agreement = client.baseline_agreement(&queries, index).await?;
+        println!(
+            "PIR top-1 agrees with HNSW baseline: {:.2}%",
+            agreement * 100.0
+        );
+
+        let mut report = ReportBuilder::new("baseline_agreement").build();
+        report.recall.insert("baseline_top1".to_string(), agreement);
+        if let Some(path) = report.save()? {
+            println!("Report written to {}", path);
+        }
         Ok(())
     }
 }
@@ -0,0 +1,901 @@
+use anyhow::Result;
+use candle::{Device, Tensor};
+use rand::{rngs::StdRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{cell::OnceCell, collections::HashMap, fs, path::Path, sync::OnceLock};
+
+use crate::{
+    error::PirError,
+    similarity::{cosine, normalize},
+    utils::seeded_rng,
+};
+
+// Rounds of centroid refinement under the size cap
+const BALANCE_ROUNDS: usize = 10;
+// How far a cluster may grow beyond the average cluster size before spilling
+const SIZE_SLACK: f64 = 1.25;
+// Re-cluster once the mean point-to-centroid distance grows this much past its last fit
+const DRIFT_THRESHOLD: f32 = 1.5;
+// Points sampled for the silhouette score, which is quadratic in the sample size
+const SILHOUETTE_SAMPLES: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum DistanceMetric {
+    Euclidean,
+    // Spherical k-means: centroids are kept on the unit sphere
+    Cosine,
+}
+
+impl DistanceMetric {
+    fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Self::Euclidean => squared_distance(a, b),
+            Self::Cosine => 1.0 - cosine(a, b) as f32,
+        }
+    }
+}
+
+pub struct KMeansConfig {
+    // Defaults to roughly sqrt(n) clusters when unset
+    pub n_clusters: Option<usize>,
+    pub max_iters: usize,
+    // Stop once no centroid moves further than this (squared distance)
+    pub tolerance: f32,
+    pub metric: DistanceMetric,
+    // Fixes centroid seeding for reproducible builds
+    pub seed: Option<u64>,
+}
+
+impl Default for KMeansConfig {
+    fn default() -> Self {
+        Self {
+            n_clusters: None,
+            max_iters: 100,
+            tolerance: 1e-6,
+            metric: DistanceMetric::Euclidean,
+            seed: None,
+        }
+    }
+}
+
+impl KMeansConfig {
+    pub fn num_clusters(&self, n: usize) -> usize {
+        self.n_clusters
+            .unwrap_or_else(|| default_num_clusters(n))
+            .clamp(1, n.max(1))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Clustering {
+    pub centroids: Vec<Vec<f32>>,
+    pub assignments: Vec<usize>,
+    pub metric: DistanceMetric,
+}
+
+impl Clustering {
+    pub fn sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![0; self.centroids.len()];
+        for &cluster in &self.assignments {
+            sizes[cluster] += 1;
+        }
+        sizes
+    }
+
+    // Indices of the points assigned to `cluster`, in their original order
+    pub fn members_of(&self, cluster: usize) -> Vec<usize> {
+        self.assignments
+            .iter()
+            .enumerate()
+            .filter(|(_, &c)| c == cluster)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+// Tiptoe uses roughly sqrt(n) clusters of roughly sqrt(n) documents each
+pub fn default_num_clusters(n: usize) -> usize {
+    ((n as f64).sqrt().ceil() as usize).max(1)
+}
+
+pub fn default_max_cluster_size(n: usize, k: usize) -> usize {
+    ((n as f64 / k as f64) * SIZE_SLACK).ceil() as usize
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+fn argmin(distances: &[f32]) -> usize {
+    distances
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+pub fn find_closest_centroid(
+    point: &[f32],
+    centroids: &[Vec<f32>],
+    metric: DistanceMetric,
+) -> usize {
+    let distances: Vec<f32> = centroids
+        .iter()
+        .map(|centroid| metric.distance(point, centroid))
+        .collect();
+    argmin(&distances)
+}
+
+// The device distances are computed on, set up once per process: the GPU when built
+// with the `cuda` feature and one is available, else the CPU
+static DEVICE: OnceLock<Result<Device, String>> = OnceLock::new();
+// Why distances are computed without candle, once it has failed to set up or run
+static FALLBACK: OnceLock<String> = OnceLock::new();
+
+// Why clustering distances are being computed one pair at a time instead of as a
+// matrix product, if they are
+pub fn distance_fallback() -> Option<&'static str> {
+    FALLBACK.get().map(String::as_str)
+}
+
+// Points whose distances to centroids are taken over several rounds. They are uploaded
+// to the device the first time and stay there, while the centroids change every round.
+struct Points<'a> {
+    rows: &'a [Vec<f32>],
+    tensor: OnceCell<Tensor>,
+}
+
+impl<'a> Points<'a> {
+    fn new(rows: &'a [Vec<f32>]) -> Self {
+        Self {
+            rows,
+            tensor: OnceCell::new(),
+        }
+    }
+
+    fn tensor(&self, device: &Device) -> candle::Result<&Tensor> {
+        if let Some(tensor) = self.tensor.get() {
+            return Ok(tensor);
+        }
+        let dim = self.rows[0].len();
+        let tensor = Tensor::from_vec(self.rows.concat(), (self.rows.len(), dim), device)?;
+        Ok(self.tensor.get_or_init(|| tensor))
+    }
+}
+
+// All point-to-centroid distances from a single matrix product
+fn tensor_distances(
+    points: &Points,
+    centroids: &[Vec<f32>],
+    metric: DistanceMetric,
+) -> Result<Vec<Vec<f32>>, String> {
+    let device = DEVICE
+        .get_or_init(|| Device::cuda_if_available(0).map_err(|e| format!("no device: {}", e)))
+        .as_ref()?;
+    matrix_distances(points, centroids, metric, device).map_err(|e| e.to_string())
+}
+
+fn matrix_distances(
+    points: &Points,
+    centroids: &[Vec<f32>],
+    metric: DistanceMetric,
+    device: &Device,
+) -> candle::Result<Vec<Vec<f32>>> {
+    let dim = centroids[0].len();
+    let x = points.tensor(device)?;
+    let c = Tensor::from_vec(centroids.concat(), (centroids.len(), dim), device)?;
+    let dots = x.matmul(&c.t()?)?;
+
+    let distances = match metric {
+        // |x|^2 - 2 x.c + |c|^2, clamped at zero against rounding error
+        DistanceMetric::Euclidean => {
+            let x_norms = x.sqr()?.sum_keepdim(1)?;
+            let c_norms = c.sqr()?.sum_keepdim(1)?.t()?;
+            x_norms
+                .broadcast_add(&c_norms)?
+                .broadcast_sub(&(dots * 2.0)?)?
+                .relu()?
+        }
+        DistanceMetric::Cosine => {
+            let x_norms = x.sqr()?.sum_keepdim(1)?.sqrt()?;
+            let c_norms = c.sqr()?.sum_keepdim(1)?.sqrt()?.t()?;
+            let norms = (x_norms.broadcast_mul(&c_norms)? + 1e-12)?;
+            dots.broadcast_div(&norms)?.affine(-1.0, 1.0)?
+        }
+    };
+
+    distances.to_vec2::<f32>()
+}
+
+// Row i holds the distances from point i to every centroid
+fn distance_matrix(
+    points: &Points,
+    centroids: &[Vec<f32>],
+    metric: DistanceMetric,
+) -> Vec<Vec<f32>> {
+    if points.rows.is_empty() || centroids.is_empty() {
+        return vec![Vec::new(); points.rows.len()];
+    }
+
+    // A device that failed once is not tried again, see `distance_fallback`
+    let distances = match FALLBACK.get() {
+        Some(_) => None,
+        None => tensor_distances(points, centroids, metric)
+            .inspect_err(|e| {
+                let _ = FALLBACK.set(e.clone());
+            })
+            .ok(),
+    };
+    distances.unwrap_or_else(|| {
+        points
+            .rows
+            .iter()
+            .map(|point| {
+                centroids
+                    .iter()
+                    .map(|centroid| metric.distance(point, centroid))
+                    .collect()
+            })
+            .collect()
+    })
+}
+
+// Mean of the points assigned to each cluster (projected back onto the unit sphere for
+// cosine); empty clusters keep their previous centroid
+fn update_centroids(
+    points: &[Vec<f32>],
+    assignments: &[usize],
+    previous: &[Vec<f32>],
+    metric: DistanceMetric,
+) -> Vec<Vec<f32>> {
+    let mut sums = vec![vec![0.0; points[0].len()]; previous.len()];
+    let mut counts = vec![0usize; previous.len()];
+
+    for (point, &cluster) in points.iter().zip(assignments) {
+        for (sum, &x) in sums[cluster].iter_mut().zip(point) {
+            *sum += x;
+        }
+        counts[cluster] += 1;
+    }
+
+    sums.into_iter()
+        .zip(counts)
+        .zip(previous)
+        .map(|((sum, count), prev)| {
+            if count == 0 {
+                prev.clone()
+            } else {
+                let mut centroid: Vec<f32> = sum.into_iter().map(|x| x / count as f32).collect();
+                if metric == DistanceMetric::Cosine {
+                    normalize(&mut centroid);
+                }
+                centroid
+            }
+        })
+        .collect()
+}
+
+fn assign(points: &Points, centroids: &[Vec<f32>], metric: DistanceMetric) -> Vec<usize> {
+    distance_matrix(points, centroids, metric)
+        .iter()
+        .map(|distances| argmin(distances))
+        .collect()
+}
+
+// k-means++ seeding: each new centroid is drawn with probability proportional to its
+// distance from the nearest centroid chosen so far
+fn kmeans_plus_plus<R: Rng>(
+    points: &[Vec<f32>],
+    k: usize,
+    metric: DistanceMetric,
+    rng: &mut R,
+) -> Vec<Vec<f32>> {
+    let mut centroids = vec![points[rng.random_range(0..points.len())].clone()];
+    let mut distances: Vec<f32> = points
+        .iter()
+        .map(|point| metric.distance(point, &centroids[0]))
+        .collect();
+
+    while centroids.len() < k {
+        let total: f32 = distances.iter().sum();
+        let next = if total > 0.0 {
+            let mut target = rng.random::<f32>() * total;
+            distances
+                .iter()
+                .position(|&d| {
+                    target -= d;
+                    target <= 0.0
+                })
+                .unwrap_or(points.len() - 1)
+        } else {
+            rng.random_range(0..points.len())
+        };
+
+        let centroid = points[next].clone();
+        for (distance, point) in distances.iter_mut().zip(points) {
+            *distance = distance.min(metric.distance(point, &centroid));
+        }
+        centroids.push(centroid);
+    }
+
+    centroids
+}
+
+pub fn get_centroids(points: &[Vec<f32>], config: &KMeansConfig) -> Clustering {
+    if points.is_empty() {
+        return Clustering {
+            centroids: Vec::new(),
+            assignments: Vec::new(),
+            metric: config.metric,
+        };
+    }
+
+    let k = config.num_clusters(points.len());
+    let mut rng = seeded_rng(config.seed);
+    let mut centroids = kmeans_plus_plus(points, k, config.metric, &mut rng);
+    let uploaded = Points::new(points);
+    let mut assignments = assign(&uploaded, &centroids, config.metric);
+
+    for _ in 0..config.max_iters {
+        let updated = update_centroids(points, &assignments, &centroids, config.metric);
+        let shift = centroids
+            .iter()
+            .zip(&updated)
+            .map(|(old, new)| squared_distance(old, new))
+            .fold(0.0, f32::max);
+
+        centroids = updated;
+        assignments = assign(&uploaded, &centroids, config.metric);
+        if shift < config.tolerance {
+            break;
+        }
+    }
+
+    Clustering {
+        centroids,
+        assignments,
+        metric: config.metric,
+    }
+}
+
+// Assigns every point to its closest centroid that still has room. Points that would
+// lose the most by moving (largest gap between nearest and second-nearest centroid) are
+// placed first, so overflow spills from the points cheapest to move.
+pub fn balanced_assign(
+    points: &[Vec<f32>],
+    centroids: &[Vec<f32>],
+    max_cluster_size: usize,
+    metric: DistanceMetric,
+) -> Result<Vec<usize>> {
+    assign_capped(&Points::new(points), centroids, max_cluster_size, metric)
+}
+
+fn assign_capped(
+    points: &Points,
+    centroids: &[Vec<f32>],
+    max_cluster_size: usize,
+    metric: DistanceMetric,
+) -> Result<Vec<usize>> {
+    let n = points.rows.len();
+    if centroids.len() * max_cluster_size < n {
+        return Err(PirError::InvalidInput(format!(
+            "{} clusters of at most {} cannot hold {} points",
+            centroids.len(),
+            max_cluster_size,
+            n
+        ))
+        .into());
+    }
+
+    let preferences: Vec<Vec<(usize, f32)>> = distance_matrix(points, centroids, metric)
+        .into_iter()
+        .map(|row| {
+            let mut distances: Vec<(usize, f32)> = row.into_iter().enumerate().collect();
+            distances.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+            distances
+        })
+        .collect();
+
+    let regret = |prefs: &[(usize, f32)]| match prefs {
+        [(_, first), (_, second), ..] => second - first,
+        _ => 0.0,
+    };
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| regret(&preferences[b]).total_cmp(&regret(&preferences[a])));
+
+    let mut sizes = vec![0; centroids.len()];
+    let mut assignments = vec![0; n];
+    for i in order {
+        let (cluster, _) = preferences[i]
+            .iter()
+            .find(|(cluster, _)| sizes[*cluster] < max_cluster_size)
+            .ok_or_else(|| PirError::Database("No cluster with spare capacity".to_string()))?;
+        sizes[*cluster] += 1;
+        assignments[i] = *cluster;
+    }
+
+    Ok(assignments)
+}
+
+// k-means whose clusters never exceed `max_cluster_size`, bounding the worst-case
+// per-cluster query cost
+pub fn balanced_kmeans(
+    points: &[Vec<f32>],
+    config: &KMeansConfig,
+    max_cluster_size: usize,
+) -> Result<Clustering> {
+    if points.is_empty() {
+        return Err(PirError::InvalidInput("Cannot cluster an empty set".to_string()).into());
+    }
+
+    let centroids = get_centroids(points, config).centroids;
+    refine_balanced(points, centroids, max_cluster_size, config.metric)
+}
+
+// Alternates capped assignment and centroid updates starting from `centroids`
+pub fn refine_balanced(
+    points: &[Vec<f32>],
+    mut centroids: Vec<Vec<f32>>,
+    max_cluster_size: usize,
+    metric: DistanceMetric,
+) -> Result<Clustering> {
+    let uploaded = Points::new(points);
+    let mut assignments = assign_capped(&uploaded, &centroids, max_cluster_size, metric)?;
+
+    for _ in 0..BALANCE_ROUNDS {
+        centroids = update_centroids(points, &assignments, &centroids, metric);
+        let next = assign_capped(&uploaded, &centroids, max_cluster_size, metric)?;
+        if next == assignments {
+            break;
+        }
+        assignments = next;
+    }
+
+    Ok(Clustering {
+        centroids,
+        assignments,
+        metric,
+    })
+}
+
+// Sum of distances from each point to its assigned centroid (squared for Euclidean)
+pub fn inertia(points: &[Vec<f32>], clustering: &Clustering) -> f32 {
+    points
+        .iter()
+        .zip(&clustering.assignments)
+        .map(|(point, &cluster)| {
+            clustering
+                .metric
+                .distance(point, &clustering.centroids[cluster])
+        })
+        .sum()
+}
+
+// Mean distance from each point to its assigned centroid
+pub fn mean_cost(points: &[Vec<f32>], clustering: &Clustering) -> f32 {
+    if points.is_empty() {
+        return 0.0;
+    }
+    inertia(points, clustering) / points.len() as f32
+}
+
+// Mean silhouette coefficient over a random sample of at most `SILHOUETTE_SAMPLES` points.
+// Ranges from -1 (misassigned) to 1 (tight, well-separated clusters).
+pub fn silhouette<R: Rng>(points: &[Vec<f32>], clustering: &Clustering, rng: &mut R) -> f32 {
+    let sample = rand::seq::index::sample(rng, points.len(), SILHOUETTE_SAMPLES.min(points.len()))
+        .into_vec();
+    let dissimilarity = |a: &[f32], b: &[f32]| match clustering.metric {
+        DistanceMetric::Euclidean => squared_distance(a, b).sqrt(),
+        DistanceMetric::Cosine => clustering.metric.distance(a, b),
+    };
+
+    let scores: Vec<f32> = sample
+        .iter()
+        .filter_map(|&i| {
+            let own = clustering.assignments[i];
+            let mut totals = vec![(0.0f32, 0usize); clustering.centroids.len()];
+            for &j in sample.iter().filter(|&&j| j != i) {
+                let entry = &mut totals[clustering.assignments[j]];
+                entry.0 += dissimilarity(&points[i], &points[j]);
+                entry.1 += 1;
+            }
+
+            let (own_total, own_count) = totals[own];
+            if own_count == 0 {
+                // Singleton clusters score 0 by convention
+                return Some(0.0);
+            }
+            let a = own_total / own_count as f32;
+            let b = totals
+                .iter()
+                .enumerate()
+                .filter(|&(cluster, &(_, count))| cluster != own && count > 0)
+                .map(|(_, &(total, count))| total / count as f32)
+                .min_by(f32::total_cmp)?;
+            Some((b - a) / a.max(b).max(f32::EPSILON))
+        })
+        .collect();
+
+    if scores.is_empty() {
+        0.0
+    } else {
+        scores.iter().sum::<f32>() / scores.len() as f32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ClusterQuality {
+    pub inertia: f32,
+    pub silhouette: f32,
+}
+
+impl ClusterQuality {
+    pub fn measure(points: &[Vec<f32>], clustering: &Clustering, seed: Option<u64>) -> Self {
+        Self {
+            inertia: inertia(points, clustering),
+            silhouette: silhouette(points, clustering, &mut seeded_rng(seed)),
+        }
+    }
+}
+
+// Cluster assignments persisted by document id so updates don't reshuffle every cluster
+#[derive(Serialize, Deserialize)]
+pub struct ClusterState {
+    centroids: Vec<Vec<f32>>,
+    metric: DistanceMetric,
+    assignments: HashMap<String, usize>,
+    // Mean cost right after the last full clustering; 0 until there is a cost to
+    // compare against
+    baseline_cost: f32,
+}
+
+impl ClusterState {
+    pub fn new(clustering: &Clustering, ids: &[String], points: &[Vec<f32>]) -> Self {
+        Self {
+            centroids: clustering.centroids.clone(),
+            metric: clustering.metric,
+            assignments: ids
+                .iter()
+                .cloned()
+                .zip(clustering.assignments.iter().copied())
+                .collect(),
+            baseline_cost: mean_cost(points, clustering),
+        }
+    }
+
+    // The same centroids and baseline with `clustering`'s assignments, after documents
+    // were placed by `reassign`. Takes the current cost as the baseline if there is none.
+    pub fn updated(&self, clustering: &Clustering, ids: &[String], points: &[Vec<f32>]) -> Self {
+        let mut state = Self::new(clustering, ids, points);
+        if self.baseline_cost > 0.0 {
+            state.baseline_cost = self.baseline_cost;
+        }
+        state
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    // Keeps known documents in their stored cluster and places new ones in the nearest
+    // existing centroid. Returns None when the result overflows `max_cluster_size` or has
+    // drifted too far from the stored centroids, signalling a full re-clustering.
+    pub fn reassign(
+        &self,
+        ids: &[String],
+        points: &[Vec<f32>],
+        metric: DistanceMetric,
+        max_cluster_size: usize,
+    ) -> Option<Clustering> {
+        if metric != self.metric || self.centroids.is_empty() {
+            return None;
+        }
+
+        let assignments = ids
+            .iter()
+            .zip(points)
+            .map(|(id, point)| match self.assignments.get(id) {
+                Some(&cluster) if cluster < self.centroids.len() => cluster,
+                _ => find_closest_centroid(point, &self.centroids, metric),
+            })
+            .collect();
+        let clustering = Clustering {
+            centroids: self.centroids.clone(),
+            assignments,
+            metric,
+        };
+
+        let balanced = clustering
+            .sizes()
+            .iter()
+            .all(|&size| size <= max_cluster_size);
+        // A zero baseline, e.g. from points that sat on their centroids, is no baseline
+        let drifted = self.baseline_cost > 0.0
+            && mean_cost(points, &clustering) > self.baseline_cost * DRIFT_THRESHOLD;
+        (balanced && !drifted).then_some(clustering)
+    }
+}
+
+// Mini-batch k-means (Sculley, 2010). Batches are folded in as they arrive, so the full
+// corpus never has to be revisited to fit the centroids, and `finish_balanced` places
+// each point in a single pass.
+pub struct MiniBatchKMeans {
+    k: usize,
+    metric: DistanceMetric,
+    centroids: Vec<Vec<f32>>,
+    counts: Vec<usize>,
+    // Points buffered until there are enough to seed k centroids
+    pending: Vec<Vec<f32>>,
+    rng: StdRng,
+}
+
+impl MiniBatchKMeans {
+    pub fn new(k: usize, metric: DistanceMetric) -> Self {
+        Self {
+            k: k.max(1),
+            metric,
+            centroids: Vec::new(),
+            counts: Vec::new(),
+            pending: Vec::new(),
+            rng: seeded_rng(None),
+        }
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.rng = seeded_rng(seed);
+        self
+    }
+
+    pub fn partial_fit(&mut self, batch: &[Vec<f32>]) {
+        if self.centroids.is_empty() {
+            self.pending.extend_from_slice(batch);
+            if self.pending.len() >= self.k {
+                let pending = std::mem::take(&mut self.pending);
+                self.seed(&pending);
+                self.step(&pending);
+            }
+            return;
+        }
+        self.step(batch);
+    }
+
+    fn seed(&mut self, points: &[Vec<f32>]) {
+        let k = self.k.min(points.len());
+        self.centroids = kmeans_plus_plus(points, k, self.metric, &mut self.rng);
+        self.counts = vec![0; k];
+    }
+
+    // Moves each centroid toward its assigned points with a per-centroid learning rate
+    // of 1 / (points seen so far)
+    fn step(&mut self, batch: &[Vec<f32>]) {
+        let assignments = assign(&Points::new(batch), &self.centroids, self.metric);
+        for (point, cluster) in batch.iter().zip(assignments) {
+            self.counts[cluster] += 1;
+            let eta = 1.0 / self.counts[cluster] as f32;
+            for (c, &x) in self.centroids[cluster].iter_mut().zip(point) {
+                *c += eta * (x - *c);
+            }
+            if self.metric == DistanceMetric::Cosine {
+                normalize(&mut self.centroids[cluster]);
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<Vec<f32>> {
+        if self.centroids.is_empty() && !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            self.seed(&pending);
+            self.step(&pending);
+        }
+        self.centroids
+    }
+
+    // The fitted centroids with every point assigned once under the cap. Unlike
+    // `refine_balanced`, centroids are not updated from the assignment and the points
+    // are not assigned again.
+    pub fn finish_balanced(
+        self,
+        points: &[Vec<f32>],
+        max_cluster_size: usize,
+    ) -> Result<Clustering> {
+        let metric = self.metric;
+        let centroids = self.finish();
+        let assignments = balanced_assign(points, &centroids, max_cluster_size, metric)?;
+        Ok(Clustering {
+            centroids,
+            assignments,
+            metric,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity::norm;
+
+    #[test]
+    fn test_balanced_kmeans_respects_cap() -> Result<()> {
+        let points: Vec<Vec<f32>> = vec![
+            vec![0.0, 0.0],
+            vec![0.1, 0.0],
+            vec![0.0, 0.1],
+            vec![0.1, 0.1],
+            vec![0.2, 0.0],
+            vec![0.0, 0.2],
+            vec![10.0, 10.0],
+            vec![10.1, 10.0],
+        ];
+
+        let config = KMeansConfig {
+            n_clusters: Some(2),
+            ..Default::default()
+        };
+
+        let clustering = get_centroids(&points, &config);
+        assert_eq!(clustering.centroids.len(), 2);
+        let mut sizes = clustering.sizes();
+        sizes.sort();
+        assert_eq!(sizes, vec![2, 6]);
+
+        let clustering = balanced_kmeans(&points, &config, 4)?;
+        assert_eq!(clustering.assignments.len(), points.len());
+        assert_eq!(clustering.sizes(), vec![4, 4]);
+
+        assert!(balanced_kmeans(&points, &config, 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_clustering_is_reproducible() {
+        let points: Vec<Vec<f32>> = (0..50)
+            .map(|i| vec![(i * 37 % 11) as f32, (i * 53 % 7) as f32])
+            .collect();
+        let config = KMeansConfig {
+            n_clusters: Some(5),
+            seed: Some(42),
+            ..Default::default()
+        };
+
+        let first = get_centroids(&points, &config);
+        let second = get_centroids(&points, &config);
+        assert_eq!(first.centroids, second.centroids);
+        assert_eq!(first.assignments, second.assignments);
+    }
+
+    #[test]
+    fn test_cosine_centroids_are_normalized() {
+        let points: Vec<Vec<f32>> = vec![
+            vec![1.0, 0.0],
+            vec![2.0, 0.1],
+            vec![3.0, -0.1],
+            vec![0.0, 1.0],
+            vec![0.1, 5.0],
+        ];
+        let config = KMeansConfig {
+            n_clusters: Some(2),
+            metric: DistanceMetric::Cosine,
+            ..Default::default()
+        };
+
+        let clustering = get_centroids(&points, &config);
+        for centroid in &clustering.centroids {
+            assert!((norm(centroid) - 1.0).abs() < 1e-4);
+        }
+        // Direction, not magnitude, decides membership
+        assert_eq!(clustering.assignments[0], clustering.assignments[2]);
+        assert_eq!(clustering.assignments[3], clustering.assignments[4]);
+        assert_ne!(clustering.assignments[0], clustering.assignments[3]);
+    }
+
+    #[test]
+    fn test_quality_metrics() {
+        let points: Vec<Vec<f32>> = vec![
+            vec![0.0, 0.0],
+            vec![0.0, 1.0],
+            vec![10.0, 0.0],
+            vec![10.0, 1.0],
+        ];
+        let good = Clustering {
+            centroids: vec![vec![0.0, 0.5], vec![10.0, 0.5]],
+            assignments: vec![0, 0, 1, 1],
+            metric: DistanceMetric::Euclidean,
+        };
+        let bad = Clustering {
+            centroids: vec![vec![5.0, 0.0], vec![5.0, 1.0]],
+            assignments: vec![0, 1, 0, 1],
+            metric: DistanceMetric::Euclidean,
+        };
+
+        assert!((inertia(&points, &good) - 1.0).abs() < 1e-5);
+        assert!(inertia(&points, &good) < inertia(&points, &bad));
+        let mut rng = seeded_rng(Some(0));
+        assert!(silhouette(&points, &good, &mut rng) > 0.8);
+        assert!(silhouette(&points, &bad, &mut rng) < 0.0);
+    }
+
+    #[test]
+    fn test_cluster_state_reassign() {
+        let points: Vec<Vec<f32>> = vec![vec![0.0, 0.0], vec![0.1, 0.0], vec![10.0, 10.0]];
+        let ids: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        let clustering = Clustering {
+            centroids: vec![vec![0.05, 0.0], vec![10.0, 10.0]],
+            assignments: vec![0, 0, 1],
+            metric: DistanceMetric::Euclidean,
+        };
+        let state = ClusterState::new(&clustering, &ids, &points);
+
+        // A new document lands in its nearest existing cluster
+        let mut grown_ids = ids.clone();
+        grown_ids.push("d".to_string());
+        let mut grown_points = points.clone();
+        grown_points.push(vec![10.0, 10.05]);
+        let reused = state
+            .reassign(&grown_ids, &grown_points, DistanceMetric::Euclidean, 2)
+            .unwrap();
+        assert_eq!(reused.assignments, vec![0, 0, 1, 1]);
+
+        // Overflowing the cap forces a full re-clustering
+        assert!(state
+            .reassign(&grown_ids, &grown_points, DistanceMetric::Euclidean, 1)
+            .is_none());
+
+        // The updated state remembers the new document and keeps the baseline
+        let updated = state.updated(&reused, &grown_ids, &grown_points);
+        assert_eq!(updated.assignments["d"], 1);
+        assert_eq!(updated.baseline_cost, state.baseline_cost);
+
+        // Points on their centroids leave no baseline, which must not count as drift
+        let exact = Clustering {
+            centroids: vec![vec![0.0, 0.0], vec![10.0, 10.0]],
+            assignments: vec![0, 1],
+            metric: DistanceMetric::Euclidean,
+        };
+        let pair = vec!["a".to_string(), "c".to_string()];
+        let state = ClusterState::new(&exact, &pair, &exact.centroids);
+        assert_eq!(state.baseline_cost, 0.0);
+        assert!(state
+            .reassign(&ids, &points, DistanceMetric::Euclidean, 2)
+            .is_some());
+        let updated = state.updated(&exact, &pair, &points[..2]);
+        assert!(updated.baseline_cost > 0.0);
+    }
+
+    #[test]
+    fn test_mini_batch_kmeans() {
+        let points: Vec<Vec<f32>> = (0..200)
+            .map(|i| {
+                let offset = if i % 2 == 0 { 0.0 } else { 10.0 };
+                vec![offset + (i % 7) as f32 * 0.01, offset]
+            })
+            .collect();
+
+        let mut kmeans = MiniBatchKMeans::new(2, DistanceMetric::Euclidean);
+        for batch in points.chunks(16) {
+            kmeans.partial_fit(batch);
+        }
+        let mut centroids = kmeans.finish();
+        centroids.sort_by(|a, b| a[1].total_cmp(&b[1]));
+
+        assert_eq!(centroids.len(), 2);
+        assert!(centroids[0][1].abs() < 0.5);
+        assert!((centroids[1][1] - 10.0).abs() < 0.5);
+
+        // One assignment pass separates the two groups and respects the cap
+        let mut kmeans = MiniBatchKMeans::new(2, DistanceMetric::Euclidean).with_seed(Some(1));
+        for batch in points.chunks(16) {
+            kmeans.partial_fit(batch);
+        }
+        let clustering = kmeans.finish_balanced(&points, 100).unwrap();
+        assert_eq!(clustering.sizes(), vec![100, 100]);
+        assert_ne!(clustering.assignments[0], clustering.assignments[1]);
+    }
+}
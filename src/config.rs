@@ -0,0 +1,415 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{
+    correction::CorrectionConfig,
+    error::PirError,
+    fred::FredConfig,
+    partitions::PartitionConfig,
+    pool::RebuildLimits,
+    source::CorpusSource,
+    stream::Exchange,
+    templates::{Prefixes, TextTemplates},
+    validation::Validation,
+};
+
+// Path of the server's TOML config; unset runs with the defaults. Re-read on SIGHUP
+// and `POST /admin/reload-config`.
+const CONFIG_ENV_VAR: &str = "TIPTOE_CONFIG";
+
+// Settings a running server can pick up without a restart
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Full rebuilds re-embed every document, so they only run this often unless the
+    // document set changes
+    pub cold_rebuild_interval_secs: u64,
+    // The hot tier's fast-changing fields are refreshed much more often
+    pub hot_refresh_interval_secs: u64,
+    // Feeds merged into the corpus, each fetched on its own schedule. Empty reads the
+    // single source given by `TIPTOE_CORPUS_URL`.
+    pub sources: Vec<SourceConfig>,
+    // Checks every fetched document must pass before it is encoded
+    pub validation: Validation,
+    // Live exchange feeds whose latest ticks override fetched prices on each hot
+    // refresh. Only run with the exchange-stream feature.
+    pub streams: Vec<StreamConfig>,
+    // What text is embedded for each document; picked up by the next rebuild
+    pub templates: TextTemplates,
+    // Instructions the embedding model expects before queries and documents; the
+    // query prefix reaches clients through `/params`
+    pub prefixes: Prefixes,
+    // Time windows the embedding database is split into, and how many are kept;
+    // unset serves every document from one database
+    pub partitions: Option<PartitionConfig>,
+    // How long a database replaced by a rebuild or restore keeps answering under
+    // `/epochs/{epoch}`, so clients can finish queries begun against it; 0 drops it
+    // at once
+    pub epoch_grace_secs: u64,
+    // What queries that arrive while a rebuild or restore swaps databases get
+    pub swap: SwapPolicy,
+    // Keeps rebuilds from crowding out queries; applies from the next rebuild
+    pub rebuild: RebuildLimits,
+    // Correction fitted at each rebuild and applied by clients to query embeddings;
+    // unset sends queries as embedded
+    pub query_correction: Option<CorrectionConfig>,
+}
+
+// Queries are encrypted for the epoch the client fetched params at, so one that
+// arrives as its database is being replaced can only be answered by that database
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SwapPolicy {
+    // Answer it from the database serving when it arrived, which is kept until every
+    // such query is done even past `epoch_grace_secs`
+    #[default]
+    Complete,
+    // Refuse it with 503 `epoch_changing` and Retry-After, so clients fetch the new
+    // params instead of queueing behind the swap
+    Reject,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct StreamConfig {
+    pub name: String,
+    pub exchange: Exchange,
+    // Overrides the exchange's public feed, e.g. for a sandbox
+    #[serde(default)]
+    pub url: Option<String>,
+    // Exchange symbol to the name of the document whose price it sets
+    pub symbols: BTreeMap<String, String>,
+    #[serde(default = "default_stream_field")]
+    pub field: String,
+    // Ticks older than this are ignored, so a dead connection falls back to the
+    // fetched prices
+    #[serde(default = "default_max_tick_age_secs")]
+    pub max_tick_age_secs: u64,
+}
+
+fn default_stream_field() -> String {
+    "currentPrice".to_string()
+}
+
+fn default_max_tick_age_secs() -> u64 {
+    60
+}
+
+impl StreamConfig {
+    pub fn max_tick_age(&self) -> Duration {
+        Duration::from_secs(self.max_tick_age_secs)
+    }
+}
+
+// One feed of documents, e.g. prices every minute and news every 10 minutes. Exactly
+// one of `script`, `url` and `fred` is set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    // Names its snapshot file, so it is limited to letters, digits, `-` and `_`
+    pub name: String,
+    #[serde(default)]
+    pub script: Option<String>,
+    // As in `TIPTOE_CORPUS_URL`
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub fred: Option<FredConfig>,
+    pub interval_secs: u64,
+    // Tried in order whenever the providers before them fail or return nothing valid
+    #[serde(default)]
+    pub fallbacks: Vec<ProviderConfig>,
+    // Queue a full rebuild as soon as its documents change. Otherwise changes reach
+    // clients with the next hot refresh (hot fields, added or removed documents) or
+    // scheduled rebuild (everything else).
+    #[serde(default)]
+    pub rebuild: bool,
+}
+
+// A fallback provider of a source, given like the source's own
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct ProviderConfig {
+    #[serde(default)]
+    pub script: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub fred: Option<FredConfig>,
+}
+
+impl ProviderConfig {
+    fn corpus_source(&self, source: &str) -> Result<CorpusSource> {
+        match (&self.script, &self.url, &self.fred) {
+            (Some(script), None, None) => Ok(CorpusSource::Script(script.clone())),
+            (None, Some(url), None) => CorpusSource::from_url(url),
+            (None, None, Some(fred)) => Ok(CorpusSource::Fred(fred.clone())),
+            _ => Err(PirError::InvalidInput(format!(
+                "Providers of source {} need exactly one of script, url and fred",
+                source
+            ))
+            .into()),
+        }
+    }
+}
+
+impl SourceConfig {
+    // The source's own provider followed by its fallbacks, in priority order
+    pub fn providers(&self) -> Result<Vec<CorpusSource>> {
+        let primary = ProviderConfig {
+            script: self.script.clone(),
+            url: self.url.clone(),
+            fred: self.fred.clone(),
+        };
+        std::iter::once(&primary)
+            .chain(&self.fallbacks)
+            .map(|provider| provider.corpus_source(&self.name))
+            .collect()
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let valid_name = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(
+                PirError::InvalidInput(format!("Invalid source name {:?}", self.name)).into(),
+            );
+        }
+        if self.interval_secs == 0 {
+            return Err(PirError::InvalidInput(format!(
+                "Interval of source {} must be at least 1s",
+                self.name
+            ))
+            .into());
+        }
+        self.providers().map(|_| ())
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            cold_rebuild_interval_secs: 10 * 60,
+            hot_refresh_interval_secs: 15,
+            sources: Vec::new(),
+            validation: Validation::default(),
+            streams: Vec::new(),
+            templates: TextTemplates::default(),
+            prefixes: Prefixes::default(),
+            partitions: None,
+            epoch_grace_secs: 60,
+            swap: SwapPolicy::default(),
+            rebuild: RebuildLimits::default(),
+            query_correction: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn parse(config: &str) -> Result<Self> {
+        let config: Self = toml::from_str(config)
+            .map_err(|e| PirError::InvalidInput(format!("Invalid server config: {}", e)))?;
+        if config.cold_rebuild_interval_secs == 0 || config.hot_refresh_interval_secs == 0 {
+            return Err(
+                PirError::InvalidInput("Update intervals must be at least 1s".to_string()).into(),
+            );
+        }
+        for (i, source) in config.sources.iter().enumerate() {
+            source.validate()?;
+            if config.sources[..i]
+                .iter()
+                .any(|other| other.name == source.name)
+            {
+                return Err(PirError::InvalidInput(format!(
+                    "Source {} is configured twice",
+                    source.name
+                ))
+                .into());
+            }
+        }
+        if let Some(stream) = config.streams.iter().find(|s| s.symbols.is_empty()) {
+            return Err(
+                PirError::InvalidInput(format!("Stream {} has no symbols", stream.name)).into(),
+            );
+        }
+        config.templates.validate()?;
+        if let Some(partitions) = &config.partitions {
+            partitions.validate()?;
+        }
+        config.rebuild.validate()?;
+        if let Some(correction) = &config.query_correction {
+            correction.validate()?;
+        }
+        Ok(config)
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(CONFIG_ENV_VAR) {
+            Ok(path) => Self::parse(&std::fs::read_to_string(path)?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn cold_rebuild_interval(&self) -> Duration {
+        Duration::from_secs(self.cold_rebuild_interval_secs)
+    }
+
+    pub fn hot_refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.hot_refresh_interval_secs)
+    }
+
+    pub fn epoch_grace(&self) -> Duration {
+        Duration::from_secs(self.epoch_grace_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partitions::Window;
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        assert_eq!(ServerConfig::parse("")?, ServerConfig::default());
+
+        let config = ServerConfig::parse("hot_refresh_interval_secs = 5")?;
+        assert_eq!(config.hot_refresh_interval(), Duration::from_secs(5));
+        assert_eq!(config.cold_rebuild_interval(), Duration::from_secs(600));
+        assert_eq!(config.epoch_grace(), Duration::from_secs(60));
+        assert_eq!(
+            ServerConfig::parse("epoch_grace_secs = 0")?.epoch_grace(),
+            Duration::ZERO
+        );
+        assert_eq!(config.swap, SwapPolicy::Complete);
+        assert_eq!(
+            ServerConfig::parse("swap = \"reject\"")?.swap,
+            SwapPolicy::Reject
+        );
+        assert!(ServerConfig::parse("swap = \"wait\"").is_err());
+
+        let config = ServerConfig::parse("[rebuild]\nduty_cycle = 0.25\nyield_to_queries = 8")?;
+        assert_eq!(config.rebuild.duty_cycle, 0.25);
+        assert_eq!(config.rebuild.yield_to_queries, Some(8));
+        assert!(ServerConfig::parse("[rebuild]\nduty_cycle = 1.5").is_err());
+        let config =
+            ServerConfig::parse("[prefixes]\nquery = \"query: \"\ndocument = \"passage: \"")?;
+        assert_eq!(config.prefixes.prefix_query("Tesla"), "query: Tesla");
+        assert_eq!(config.prefixes.prefix_document("Tesla"), "passage: Tesla");
+
+        assert!(ServerConfig::parse("hot_refresh_interval_secs = 0").is_err());
+        assert!(ServerConfig::parse("hot_refresh_intervl_secs = 5").is_err());
+
+        let config = ServerConfig::parse(
+            r#"
+            [[sources]]
+            name = "prices"
+            script = "src/python/stocks.py"
+            interval_secs = 60
+
+            [[sources]]
+            name = "news"
+            url = "file:///data/news.json"
+            interval_secs = 600
+            fallbacks = [{ url = "file:///backup/news.json" }]
+
+            [validation]
+            timestamp_field = "quotedAt"
+            "#,
+        )?;
+        assert_eq!(config.sources.len(), 2);
+        assert_eq!(config.sources[1].interval(), Duration::from_secs(600));
+        assert_eq!(config.sources[1].providers()?.len(), 2);
+        assert_eq!(config.validation.price_fields, ["currentPrice"]);
+
+        let config = ServerConfig::parse(
+            r#"
+            [[sources]]
+            name = "macro"
+            interval_secs = 86400
+            fred.series = [
+                { id = "CPIAUCSL", name = "US Consumer Price Index" },
+                { id = "UNRATE", name = "US Unemployment Rate", template = "{name}: {value}%" },
+            ]
+            "#,
+        )?;
+        assert_eq!(config.sources[0].fred.as_ref().unwrap().series.len(), 2);
+        assert!(matches!(
+            config.sources[0].providers()?[0],
+            CorpusSource::Fred(_)
+        ));
+
+        let config = ServerConfig::parse(
+            r#"
+            [[streams]]
+            name = "crypto"
+            exchange = "coinbase"
+            symbols = { "BTC-USD" = "Bitcoin USD", "ETH-USD" = "Ethereum USD" }
+            "#,
+        )?;
+        assert_eq!(config.streams[0].exchange, Exchange::Coinbase);
+        assert_eq!(config.streams[0].field, "currentPrice");
+        assert_eq!(config.streams[0].max_tick_age(), Duration::from_secs(60));
+        let no_symbols = "[[streams]]\nname = \"crypto\"\nexchange = \"binance\"\nsymbols = {}";
+        assert!(ServerConfig::parse(no_symbols).is_err());
+        assert!(ServerConfig::parse(
+            r#"
+            [[sources]]
+            name = "news"
+            url = "file:///data/news.json"
+            interval_secs = 600
+            fallbacks = [{}]
+            "#
+        )
+        .is_err());
+
+        let source = |name: &str, script: Option<&str>, url: Option<&str>| {
+            format!(
+                "[[sources]]\nname = {:?}\ninterval_secs = 60\n{}{}",
+                name,
+                script
+                    .map(|s| format!("script = {:?}\n", s))
+                    .unwrap_or_default(),
+                url.map(|u| format!("url = {:?}\n", u)).unwrap_or_default(),
+            )
+        };
+        assert!(ServerConfig::parse(&source("prices", None, None)).is_err());
+        assert!(ServerConfig::parse(&source("prices", Some("a.py"), Some("file:///b"))).is_err());
+        assert!(ServerConfig::parse(&source("../prices", Some("a.py"), None)).is_err());
+        let twice = source("prices", Some("a.py"), None).repeat(2);
+        assert!(ServerConfig::parse(&twice).is_err());
+
+        let config = ServerConfig::parse(
+            r#"
+            [templates]
+            default = "{name} is trading at {price}"
+            classes.Currency = "{name} exchange rate is {price}, {change} today"
+            fields = ["name", "summary.headline"]
+            "#,
+        )?;
+        assert_eq!(config.templates.class_field, "sector");
+        assert_eq!(config.templates.classes.len(), 1);
+        assert_eq!(config.templates.fields, vec!["name", "summary.headline"]);
+        assert!(ServerConfig::parse("[templates]\ndefault = \"{name\"").is_err());
+
+        let config = ServerConfig::parse("[partitions]\nwindow = \"weekly\"\nretain = 4")?;
+        let partitions = config.partitions.unwrap();
+        assert_eq!(partitions.window, Window::Weekly);
+        assert_eq!(partitions.time_field, "quotedAt");
+        assert!(ServerConfig::parse("[partitions]\nwindow = \"daily\"\nretain = 0").is_err());
+        assert!(ServerConfig::parse("[partitions]\nwindow = \"hourly\"\nretain = 1").is_err());
+        Ok(())
+    }
+}
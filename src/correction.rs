@@ -0,0 +1,236 @@
+use anyhow::Result;
+use rand::seq::index::sample;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    error::PirError,
+    templates::{validate_template, TextTemplates},
+    utils::seeded_rng,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum CorrectionMode {
+    // Shift queries by the difference between the document and query means
+    #[default]
+    Center,
+    // Also rescale each dimension by a least-squares fit of document on query values
+    Linear,
+}
+
+// How to fit the correction applied to query embeddings. Queries are phrased
+// differently from the text embedded for documents, so their embeddings sit apart from
+// the documents they ask for; the correction moves them back, fitted on sample queries
+// rendered for sampled documents.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct CorrectionConfig {
+    pub mode: CorrectionMode,
+    // Sample queries, with placeholders filled in like text templates,
+    // e.g. "What is the latest price of {name}?"
+    pub queries: Vec<String>,
+    // Documents each sample query is rendered for
+    pub documents: usize,
+}
+
+impl Default for CorrectionConfig {
+    fn default() -> Self {
+        Self {
+            mode: CorrectionMode::default(),
+            queries: Vec::new(),
+            documents: 128,
+        }
+    }
+}
+
+impl CorrectionConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.queries.is_empty() || self.documents == 0 {
+            return Err(PirError::InvalidInput(
+                "Query correction needs sample queries and documents".to_string(),
+            )
+            .into());
+        }
+        for query in &self.queries {
+            validate_template(query)?;
+        }
+        Ok(())
+    }
+
+    // Fits the correction for `documents`, whose embeddings are `embeddings`, by
+    // embedding each sample query for a random subset of them with `embed`
+    pub fn fit<F>(
+        &self,
+        documents: &[Value],
+        embeddings: &[Vec<f32>],
+        templates: &TextTemplates,
+        seed: Option<u64>,
+        embed: F,
+    ) -> Result<Option<QueryCorrection>>
+    where
+        F: Fn(&str) -> Result<Vec<f32>>,
+    {
+        let mut rng = seeded_rng(seed);
+        let chosen = sample(
+            &mut rng,
+            documents.len(),
+            self.documents.min(documents.len()),
+        );
+        let mut queries = Vec::new();
+        let mut targets = Vec::new();
+        for index in chosen {
+            for query in &self.queries {
+                queries.push(embed(&templates.render_with(query, &documents[index])?)?);
+                targets.push(embeddings[index].clone());
+            }
+        }
+        Ok(QueryCorrection::fit(self.mode, &queries, &targets))
+    }
+}
+
+// Affine map applied to query embeddings before they are quantized, fitted when the
+// embedding database is built and published with its params. Each value becomes
+// `scale * value + offset` and the result is L2-normalized again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct QueryCorrection {
+    pub mode: CorrectionMode,
+    pub scale: Vec<f32>,
+    pub offset: Vec<f32>,
+    // Query/document pairs it was fitted on
+    pub samples: usize,
+}
+
+impl QueryCorrection {
+    // None without pairs or when their widths differ
+    pub fn fit(mode: CorrectionMode, queries: &[Vec<f32>], documents: &[Vec<f32>]) -> Option<Self> {
+        let dim = queries.first()?.len();
+        if queries.len() != documents.len()
+            || queries.iter().chain(documents).any(|row| row.len() != dim)
+        {
+            return None;
+        }
+
+        let n = queries.len() as f64;
+        let mean =
+            |rows: &[Vec<f32>], i: usize| rows.iter().map(|row| row[i] as f64).sum::<f64>() / n;
+        let query_means: Vec<f64> = (0..dim).map(|i| mean(queries, i)).collect();
+        let document_means: Vec<f64> = (0..dim).map(|i| mean(documents, i)).collect();
+        let slopes = match mode {
+            CorrectionMode::Center => vec![1.0; dim],
+            CorrectionMode::Linear => {
+                let moments: Vec<(f64, f64)> = (0..dim)
+                    .map(|i| {
+                        queries
+                            .iter()
+                            .zip(documents)
+                            .fold((0.0, 0.0), |(cov, var), (q, d)| {
+                                let dq = q[i] as f64 - query_means[i];
+                                (cov + dq * (d[i] as f64 - document_means[i]), var + dq * dq)
+                            })
+                    })
+                    .collect();
+                // Shrunk towards 1 by the average variance, so dimensions the samples
+                // barely cover are left nearly as they are
+                let shrink = moments.iter().map(|(_, var)| var).sum::<f64>() / dim as f64;
+                moments
+                    .iter()
+                    .map(|(cov, var)| (cov + shrink) / (var + shrink).max(f64::EPSILON))
+                    .collect()
+            }
+        };
+
+        Some(Self {
+            mode,
+            offset: (0..dim)
+                .map(|i| (document_means[i] - slopes[i] * query_means[i]) as f32)
+                .collect(),
+            scale: slopes.into_iter().map(|slope| slope as f32).collect(),
+            samples: queries.len(),
+        })
+    }
+
+    pub fn apply(&self, embedding: &[f32]) -> Result<Vec<f32>> {
+        if embedding.len() != self.scale.len() {
+            return Err(PirError::InvalidInput(format!(
+                "Query correction is for {}-dimensional embeddings, got {}",
+                self.scale.len(),
+                embedding.len()
+            ))
+            .into());
+        }
+        let corrected: Vec<f32> = embedding
+            .iter()
+            .zip(self.scale.iter().zip(&self.offset))
+            .map(|(value, (scale, offset))| scale * value + offset)
+            .collect();
+        let norm = corrected.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm <= f32::EPSILON {
+            return Ok(embedding.to_vec());
+        }
+        Ok(corrected.into_iter().map(|x| x / norm).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_correction_moves_queries_to_documents() -> Result<()> {
+        // Queries are the documents pushed along a shared direction
+        let documents = vec![
+            vec![0.6, 0.8, 0.0],
+            vec![0.8, 0.6, 0.0],
+            vec![1.0, 0.0, 0.0],
+        ];
+        let queries: Vec<Vec<f32>> = documents
+            .iter()
+            .map(|d: &Vec<f32>| vec![d[0], d[1], 0.5])
+            .collect();
+        let correction = QueryCorrection::fit(CorrectionMode::Center, &queries, &documents)
+            .expect("pairs given");
+        assert_eq!(correction.samples, 3);
+        for (query, document) in queries.iter().zip(&documents) {
+            let corrected = correction.apply(query)?;
+            for (a, b) in corrected.iter().zip(document) {
+                assert!((a - b).abs() < 1e-5, "{:?} vs {:?}", corrected, document);
+            }
+        }
+        assert!(correction.apply(&[1.0, 0.0]).is_err());
+
+        let linear = QueryCorrection::fit(CorrectionMode::Linear, &queries, &documents)
+            .expect("pairs given");
+        assert!(linear.apply(&queries[0])?[2].abs() < 1e-5);
+        assert!(QueryCorrection::fit(CorrectionMode::Center, &[], &[]).is_none());
+
+        let config = CorrectionConfig {
+            queries: vec!["What is {name} at?".to_string()],
+            ..CorrectionConfig::default()
+        };
+        config.validate()?;
+        let names = [json!({"name": "Apple"}), json!({"name": "Tesla"})];
+        let fitted = config
+            .fit(
+                &names,
+                &documents[..2],
+                &TextTemplates::default(),
+                Some(1),
+                |text| {
+                    Ok(if text.contains("Apple") {
+                        queries[0].clone()
+                    } else {
+                        queries[1].clone()
+                    })
+                },
+            )?
+            .expect("documents given");
+        assert_eq!(fitted.samples, 2);
+        assert!(CorrectionConfig::default().validate().is_err());
+        Ok(())
+    }
+}
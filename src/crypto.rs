@@ -0,0 +1,99 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+use crate::error::PirError;
+
+// Base64 key used to encrypt records while building the encoding database.
+// It is only read at build time; clients are given the same key out of band.
+const RECORD_KEY_ENV_VAR: &str = "TIPTOE_RECORD_KEY";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+// Symmetric key for records stored in the encoding database, so the serving node
+// only ever holds ciphertext
+#[derive(Clone)]
+pub struct RecordKey(Key);
+
+impl RecordKey {
+    pub fn generate() -> Self {
+        Self(*Key::from_slice(&rand::random::<[u8; KEY_LEN]>()))
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self> {
+        let bytes = STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| PirError::Crypto(format!("Invalid record key: {}", e)))?;
+        if bytes.len() != KEY_LEN {
+            return Err(PirError::Crypto(format!(
+                "Record key must be {} bytes, got {}",
+                KEY_LEN,
+                bytes.len()
+            ))
+            .into());
+        }
+        Ok(Self(*Key::from_slice(&bytes)))
+    }
+
+    pub fn to_base64(&self) -> String {
+        STANDARD.encode(self.0)
+    }
+
+    pub fn from_env() -> Result<Option<Self>> {
+        std::env::var(RECORD_KEY_ENV_VAR)
+            .ok()
+            .map(|encoded| Self::from_base64(&encoded))
+            .transpose()
+    }
+
+    // Encrypts a record into base64(nonce || ciphertext) so it can be encoded like text
+    pub fn encrypt(&self, record: &str) -> Result<String> {
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(Nonce::from_slice(&nonce), record.as_bytes())
+            .map_err(|e| PirError::Crypto(format!("Record encryption failed: {}", e)))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(STANDARD.encode(sealed))
+    }
+
+    pub fn decrypt(&self, sealed: &str) -> Result<String> {
+        let sealed = STANDARD
+            .decode(sealed.trim_end_matches('\0'))
+            .map_err(|e| PirError::Crypto(format!("Malformed encrypted record: {}", e)))?;
+        if sealed.len() < NONCE_LEN {
+            return Err(PirError::Crypto("Encrypted record is truncated".to_string()).into());
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let record = ChaCha20Poly1305::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|e| PirError::Crypto(format!("Record decryption failed: {}", e)))?;
+        Ok(String::from_utf8(record)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_roundtrip() -> Result<()> {
+        let key = RecordKey::generate();
+        let record = r#"{"name": "Tesla, Inc.", "currentPrice": 250.1}"#;
+
+        let sealed = key.encrypt(record)?;
+        assert!(!sealed.contains("Tesla"));
+        assert_ne!(sealed, key.encrypt(record)?);
+        assert_eq!(key.decrypt(&sealed)?, record);
+
+        let restored = RecordKey::from_base64(&key.to_base64())?;
+        assert_eq!(restored.decrypt(&sealed)?, record);
+        assert!(RecordKey::generate().decrypt(&sealed).is_err());
+        Ok(())
+    }
+}
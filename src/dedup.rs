@@ -0,0 +1,139 @@
+use serde_json::Value;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+// Documents whose estimated Jaccard similarity reaches this are collapsed. Records
+// are short, so a changed digit in a price already costs a tenth of their shingles.
+const NEAR_DUPLICATE_THRESHOLD: f64 = 0.8;
+const SHINGLE_SIZE: usize = 2;
+const NUM_HASHES: usize = 128;
+// LSH banding: documents are only compared when some band of their signatures matches
+const ROWS_PER_BAND: usize = 4;
+
+pub struct Deduplicated {
+    pub documents: Vec<Value>,
+    // For each input document, the row of the document it was collapsed into
+    pub canonical: Vec<usize>,
+}
+
+impl Deduplicated {
+    pub fn collapsed(&self) -> usize {
+        self.canonical.len() - self.documents.len()
+    }
+}
+
+// Collapses exact and near-duplicate documents, keeping the first occurrence.
+// Only depends on document text, so every server ingesting the same corpus
+// ends up with the same rows.
+pub fn collapse_duplicates(documents: Vec<Value>) -> Deduplicated {
+    let mut kept = Vec::new();
+    let mut signatures: Vec<Vec<u64>> = Vec::new();
+    let mut exact: HashMap<String, usize> = HashMap::new();
+    let mut buckets: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+    let mut canonical = Vec::with_capacity(documents.len());
+
+    for document in documents {
+        let text = document_text(&document);
+        if let Some(&row) = exact.get(&text) {
+            canonical.push(row);
+            continue;
+        }
+
+        let signature = minhash(&text);
+        let bands: Vec<(usize, u64)> = signature
+            .chunks(ROWS_PER_BAND)
+            .map(hash_of)
+            .enumerate()
+            .collect();
+        let near = bands
+            .iter()
+            .filter_map(|band| buckets.get(band))
+            .flatten()
+            .copied()
+            .find(|&row| similarity(&signature, &signatures[row]) >= NEAR_DUPLICATE_THRESHOLD);
+
+        let row = near.unwrap_or_else(|| {
+            let row = kept.len();
+            for band in bands {
+                buckets.entry(band).or_default().push(row);
+            }
+            kept.push(document);
+            signatures.push(signature);
+            row
+        });
+        exact.insert(text, row);
+        canonical.push(row);
+    }
+
+    Deduplicated {
+        documents: kept,
+        canonical,
+    }
+}
+
+// Field values only, so the shared JSON keys don't make every record look alike
+fn document_text(document: &Value) -> String {
+    match document {
+        Value::Object(fields) => fields
+            .values()
+            .map(document_text)
+            .collect::<Vec<_>>()
+            .join(" "),
+        Value::String(s) => s.trim().to_lowercase(),
+        other => other.to_string(),
+    }
+}
+
+fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn minhash(text: &str) -> Vec<u64> {
+    let chars: Vec<char> = text.chars().collect();
+    let shingles: Vec<String> = chars
+        .windows(SHINGLE_SIZE.min(chars.len()).max(1))
+        .map(|w| w.iter().collect())
+        .collect();
+
+    (0..NUM_HASHES)
+        .map(|seed| {
+            shingles
+                .iter()
+                .map(|shingle| hash_of(&(seed, shingle)))
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect()
+}
+
+// Fraction of matching signature slots, an estimate of Jaccard similarity
+fn similarity(a: &[u64], b: &[u64]) -> f64 {
+    a.iter().zip(b).filter(|(x, y)| x == y).count() as f64 / a.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_collapse_duplicates() {
+        let documents = vec![
+            json!({"name": "Vanguard S&P 500 ETF", "currentPrice": 512.3}),
+            json!({"name": "Tesla, Inc.", "currentPrice": 250.1}),
+            json!({"name": "Vanguard S&P 500 ETF", "currentPrice": 512.3}),
+            json!({"name": "Vanguard S&P 500 ETF", "currentPrice": 512.31}),
+            json!({"name": "Apple Inc.", "currentPrice": 180.2}),
+            json!({"name": "Tesla, Inc.", "currentPrice": 250.1}),
+        ];
+
+        let deduplicated = collapse_duplicates(documents);
+        assert_eq!(deduplicated.documents.len(), 3);
+        assert_eq!(deduplicated.canonical, vec![0, 1, 0, 0, 2, 1]);
+        assert_eq!(deduplicated.collapsed(), 3);
+    }
+}
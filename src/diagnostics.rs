@@ -0,0 +1,215 @@
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::{
+    documents::DocumentId,
+    pir::SimplePIRParams,
+    utils::{fnv1a, FNV_OFFSET},
+};
+
+// Rows reported for a query
+const TOP_ROWS: usize = 5;
+// The best match is ambiguous when the runner-up scores within this fraction of it
+const AMBIGUOUS_MARGIN: f64 = 0.01;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScoredRow {
+    pub row: usize,
+    // Id of the document in the row, as of the encoding database's epoch
+    pub id: Option<DocumentId>,
+    // Recovered inner product
+    pub score: f64,
+}
+
+// What the client saw while answering a query, for bug reports such as "query returns
+// the wrong asset". Kept for the most recent query and attached as context to errors
+// from its fetch, so `error.downcast_ref::<Diagnostics>()` finds it. Holds the query
+// text, so only share it with whoever may see the query.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostics {
+    pub query: String,
+    // Best scoring rows, best first
+    pub top: Vec<ScoredRow>,
+    // Score of the best row minus the runner-up's; None with fewer than two rows
+    pub margin: Option<f64>,
+    pub embedding_epoch: u64,
+    pub encoding_epoch: u64,
+    // Hash of the embedding database's params, so reports from clients with different
+    // params are told apart
+    pub params_hash: String,
+    // Why the result could not be fetched or decoded, if it could not
+    pub cause: Option<String>,
+}
+
+// How many of the best `scores` (sorted best first) to fetch: at least `k`, widened up
+// to `max_k` while the next score is ambiguously close to the best one
+pub fn widen_k(scores: &[(usize, BigInt)], k: usize, max_k: usize) -> usize {
+    let score = |rank: usize| scores[rank].1.to_f64().unwrap_or(f64::NAN);
+    let k = k.min(scores.len());
+    let Some(best) = scores.first().map(|_| score(0)) else {
+        return k;
+    };
+    let mut widened = k;
+    while widened < max_k.min(scores.len())
+        && best - score(widened) <= AMBIGUOUS_MARGIN * best.abs()
+    {
+        widened += 1;
+    }
+    widened
+}
+
+pub fn params_hash(params: &SimplePIRParams) -> String {
+    let params = format!("{}:{}:{}:{}", params.m, params.n, params.q, params.p);
+    format!("{:016x}", fnv1a(params.as_bytes(), FNV_OFFSET))
+}
+
+impl Diagnostics {
+    pub fn new(
+        query: &str,
+        scores: &[(usize, BigInt)],
+        ids: &[DocumentId],
+        embedding_epoch: u64,
+        encoding_epoch: u64,
+        params_hash: String,
+    ) -> Self {
+        let mut ranked: Vec<&(usize, BigInt)> = scores.iter().collect();
+        ranked.sort_by(|(_i1, v1), (_i2, v2)| v2.cmp(v1));
+        let top: Vec<ScoredRow> = ranked
+            .into_iter()
+            .take(TOP_ROWS)
+            .map(|(row, score)| ScoredRow {
+                row: *row,
+                id: ids.get(*row).copied(),
+                score: score.to_f64().unwrap_or(f64::NAN),
+            })
+            .collect();
+        let margin = match top.as_slice() {
+            [best, second, ..] => Some(best.score - second.score),
+            _ => None,
+        };
+        Self {
+            query: query.to_string(),
+            top,
+            margin,
+            embedding_epoch,
+            encoding_epoch,
+            params_hash,
+            cause: None,
+        }
+    }
+
+    // Whether the runner-up scored so close to the best match that small changes to the
+    // query or corpus could swap them
+    pub fn ambiguous(&self) -> bool {
+        match (self.margin, self.top.first()) {
+            (Some(margin), Some(best)) => margin <= AMBIGUOUS_MARGIN * best.score.abs(),
+            _ => false,
+        }
+    }
+
+    pub fn with_cause(mut self, error: &anyhow::Error) -> Self {
+        self.cause = Some(format!("{:#}", error));
+        self
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Query {:?} at epochs {}/{} (params {}): ",
+            self.query, self.embedding_epoch, self.encoding_epoch, self.params_hash
+        )?;
+        match self.top.first() {
+            Some(best) => write!(f, "best row {} scored {}", best.row, best.score)?,
+            None => write!(f, "no rows scored")?,
+        }
+        if let Some(margin) = self.margin {
+            write!(f, ", margin {}", margin)?;
+        }
+        if self.ambiguous() {
+            write!(f, " (ambiguous)")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnostics_rank_and_flag_ambiguity() {
+        let ids: Vec<DocumentId> = ["Tesla, Inc.", "Apple Inc.", "Bitcoin USD"]
+            .iter()
+            .map(|name| DocumentId::derive(name, name))
+            .collect();
+        let scores = |values: [i64; 3]| -> Vec<(usize, BigInt)> {
+            values.into_iter().map(BigInt::from).enumerate().collect()
+        };
+
+        let clear = Diagnostics::new(
+            "Tesla",
+            &scores([9000, 4000, 1000]),
+            &ids,
+            3,
+            4,
+            "0".to_string(),
+        );
+        assert_eq!(clear.top[0].row, 0);
+        assert_eq!(clear.top[0].id, Some(ids[0]));
+        assert_eq!(clear.top[1].row, 1);
+        assert_eq!(clear.margin, Some(5000.0));
+        assert!(!clear.ambiguous());
+
+        let close = Diagnostics::new(
+            "Apple",
+            &scores([9000, 9050, 1000]),
+            &ids[..1],
+            3,
+            4,
+            "0".to_string(),
+        );
+        assert_eq!(close.top[0].row, 1);
+        assert_eq!(close.top[0].id, None);
+        assert!(close.ambiguous());
+        assert!(close.to_string().ends_with("margin 50 (ambiguous)"));
+
+        let error = anyhow::anyhow!("invalid utf-8");
+        let failed = clear.clone().with_cause(&error);
+        assert_eq!(failed.cause.as_deref(), Some("invalid utf-8"));
+        let error = error.context(failed.clone());
+        assert_eq!(error.downcast_ref::<Diagnostics>(), Some(&failed));
+
+        let single = Diagnostics::new(
+            "Tesla",
+            &scores([1, 2, 3])[..1],
+            &ids,
+            0,
+            0,
+            "0".to_string(),
+        );
+        assert_eq!(single.margin, None);
+        assert!(!single.ambiguous());
+    }
+
+    #[test]
+    fn test_k_widens_over_near_ties() {
+        let scores = |values: &[i64]| -> Vec<(usize, BigInt)> {
+            values
+                .iter()
+                .map(|&v| BigInt::from(v))
+                .enumerate()
+                .collect()
+        };
+        let tied = scores(&[10000, 9995, 9990, 9950, 5000]);
+        assert_eq!(widen_k(&tied, 1, 10), 4);
+        assert_eq!(widen_k(&tied, 1, 2), 2);
+        assert_eq!(widen_k(&tied, 5, 10), 5);
+        assert_eq!(widen_k(&scores(&[10000, 8000, 7000]), 1, 3), 1);
+        assert_eq!(widen_k(&scores(&[10000, 9999]), 3, 3), 2);
+        assert_eq!(widen_k(&[], 3, 5), 0);
+    }
+}
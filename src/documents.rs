@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{
+    error::PirError,
+    utils::{fnv1a, FNV_OFFSET},
+};
+
+// Identifies a document independently of the row it lands in after a rebuild.
+// `key` hashes the source key (the document name), `content` hashes the record,
+// so the same document keeps its key across rebuilds while edits change its content hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct DocumentId {
+    pub key: u64,
+    pub content: u64,
+}
+
+impl DocumentId {
+    pub fn derive(source_key: &str, content: &str) -> Self {
+        Self {
+            key: fnv1a(source_key.as_bytes(), FNV_OFFSET),
+            content: fnv1a(content.as_bytes(), FNV_OFFSET),
+        }
+    }
+
+    // Same source document, possibly a different version of it
+    pub fn same_document(&self, other: &DocumentId) -> bool {
+        self.key == other.key
+    }
+}
+
+impl fmt::Display for DocumentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}-{:016x}", self.key, self.content)
+    }
+}
+
+impl FromStr for DocumentId {
+    type Err = PirError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PirError::InvalidInput(format!("Invalid document id '{}'", s));
+        let (key, content) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            key: u64::from_str_radix(key, 16).map_err(|_| invalid())?,
+            content: u64::from_str_radix(content, 16).map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl From<DocumentId> for String {
+    fn from(id: DocumentId) -> Self {
+        id.to_string()
+    }
+}
+
+impl TryFrom<String> for DocumentId {
+    type Error = PirError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Row of the document with the same source key as `id`, if it is still in the corpus
+pub fn find_row(ids: &[DocumentId], id: &DocumentId) -> Option<usize> {
+    ids.iter().position(|candidate| candidate.same_document(id))
+}
+
+// Fingerprint of an epoch's id -> row mapping, so clients can tell when to refetch it
+pub fn mapping_digest(ids: &[DocumentId]) -> u64 {
+    ids.iter().fold(FNV_OFFSET, |hash, id| {
+        let hash = fnv1a(&id.key.to_le_bytes(), hash);
+        fnv1a(&id.content.to_le_bytes(), hash)
+    })
+}
+
+// Share of dead rows above which a database compacts its matrix
+pub const COMPACTION_THRESHOLD: f64 = 0.1;
+
+// Source keys of deleted documents. A tombstone outlives rebuilds until the source
+// itself stops returning the document, so deletions never wait on the source.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Tombstones {
+    keys: BTreeSet<u64>,
+}
+
+impl Tombstones {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, id: &DocumentId) -> bool {
+        self.keys.insert(id.key)
+    }
+
+    pub fn contains(&self, id: &DocumentId) -> bool {
+        self.keys.contains(&id.key)
+    }
+
+    // Forgets tombstones for documents the source no longer returns
+    pub fn prune(&mut self, ids: &[DocumentId]) -> bool {
+        let live: HashSet<u64> = ids.iter().map(|id| id.key).collect();
+        let before = self.keys.len();
+        self.keys.retain(|key| live.contains(key));
+        self.keys.len() != before
+    }
+}
+
+// Whether enough of `rows` rows are dead to be worth rewriting the matrix
+pub fn needs_compaction(dead: usize, rows: usize) -> bool {
+    dead > 0 && dead as f64 > COMPACTION_THRESHOLD * rows as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_ids_survive_reordering() -> anyhow::Result<()> {
+        let tesla = DocumentId::derive("Tesla, Inc.", r#"{"currentPrice":250.1}"#);
+        let repriced = DocumentId::derive("Tesla, Inc.", r#"{"currentPrice":251.7}"#);
+        let apple = DocumentId::derive("Apple Inc.", r#"{"currentPrice":180.2}"#);
+
+        assert!(tesla.same_document(&repriced));
+        assert_ne!(tesla, repriced);
+        assert_eq!(tesla.to_string().parse::<DocumentId>()?, tesla);
+        assert_eq!(serde_json::to_string(&tesla)?, format!("\"{}\"", tesla));
+
+        let before = [tesla, apple];
+        let after = [apple, repriced];
+        assert_eq!(find_row(&before, &tesla), Some(0));
+        assert_eq!(find_row(&after, &tesla), Some(1));
+        assert_ne!(mapping_digest(&before), mapping_digest(&after));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tombstones_until_source_drops_document() {
+        let tesla = DocumentId::derive("Tesla, Inc.", r#"{"currentPrice":250.1}"#);
+        let repriced = DocumentId::derive("Tesla, Inc.", r#"{"currentPrice":251.7}"#);
+        let apple = DocumentId::derive("Apple Inc.", r#"{"currentPrice":180.2}"#);
+
+        let mut tombstones = Tombstones::default();
+        assert!(tombstones.insert(&tesla));
+        assert!(!tombstones.insert(&tesla));
+        assert!(tombstones.contains(&repriced));
+        assert!(!tombstones.contains(&apple));
+
+        assert!(!tombstones.prune(&[apple, repriced]));
+        assert!(tombstones.prune(&[apple]));
+        assert!(!tombstones.contains(&tesla));
+
+        assert!(!needs_compaction(0, 10));
+        assert!(!needs_compaction(1, 10));
+        assert!(needs_compaction(2, 10));
+    }
+}
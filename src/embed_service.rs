@@ -0,0 +1,165 @@
+use anyhow::Result;
+use axum::{extract::State, http::StatusCode, Json, Router};
+use reqwest::Client as HttpClient;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    embedding::{BertEmbedder, EmbeddingModel},
+    error::PirError,
+};
+
+// Texts embedded per `/embed` request at most
+const MAX_TEXTS: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+pub struct EmbedRequest {
+    pub texts: Vec<String>,
+}
+
+// Unquantized, L2-normalized embeddings, one per text and in the same order
+#[derive(Serialize, Deserialize)]
+pub struct EmbedResponse {
+    pub embeddings: Vec<Vec<f32>>,
+    // Model the service embedded them with; None from services that predate it
+    #[serde(default)]
+    pub model: Option<EmbeddingModel>,
+}
+
+// Embeds query text for a client, either with a model loaded in-process or through an
+// embedding service shared by thin clients on the same network
+pub enum Embedder {
+    Local(Box<BertEmbedder>),
+    Service(EmbeddingService),
+}
+
+impl Embedder {
+    pub fn local() -> Result<Self> {
+        Ok(Self::Local(Box::new(BertEmbedder::new()?)))
+    }
+
+    pub fn service(url: String) -> Self {
+        Self::Service(EmbeddingService::new(url))
+    }
+
+    // Model texts are embedded with, when it is known here. A service reports its
+    // model with each response, so it is known once a text has been embedded.
+    pub fn model(&self) -> Option<EmbeddingModel> {
+        match self {
+            Self::Local(embedder) => Some(embedder.model().clone()),
+            Self::Service(service) => service.model(),
+        }
+    }
+
+    pub async fn embed_raw(&self, text: &str) -> Result<Vec<f32>> {
+        let mut embeddings = self.embed_all(&[text.to_string()]).await?;
+        embeddings
+            .pop()
+            .ok_or_else(|| PirError::Embedding("No embedding returned".to_string()).into())
+    }
+
+    // Embeds every text, in one request to a service
+    pub async fn embed_all(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        match self {
+            Self::Local(embedder) => texts.iter().map(|text| embedder.embed_raw(text)).collect(),
+            Self::Service(service) => service.embed(texts).await,
+        }
+    }
+}
+
+// Client of an `embedding-service` process. It sees every query in the clear, so it
+// belongs in the same trust domain as the clients using it, never on the PIR servers.
+pub struct EmbeddingService {
+    client: HttpClient,
+    url: String,
+    // As the last response reported it
+    model: Mutex<Option<EmbeddingModel>>,
+}
+
+impl EmbeddingService {
+    pub fn new(url: String) -> Self {
+        Self::with_client(url, HttpClient::new())
+    }
+
+    pub fn with_client(url: String, client: HttpClient) -> Self {
+        Self {
+            client,
+            url,
+            model: Mutex::new(None),
+        }
+    }
+
+    pub fn model(&self) -> Option<EmbeddingModel> {
+        self.model.lock().unwrap().clone()
+    }
+
+    pub async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for chunk in texts.chunks(MAX_TEXTS) {
+            let response: EmbedResponse = self
+                .client
+                .post(format!("{}/embed", self.url))
+                .json(&EmbedRequest {
+                    texts: chunk.to_vec(),
+                })
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            if response.embeddings.len() != chunk.len() {
+                return Err(PirError::Embedding(format!(
+                    "Embedding service returned {} embeddings for {} texts",
+                    response.embeddings.len(),
+                    chunk.len()
+                ))
+                .into());
+            }
+            *self.model.lock().unwrap() = response.model;
+            embeddings.extend(response.embeddings);
+        }
+        Ok(embeddings)
+    }
+}
+
+// Serves `/embed` with `embedder`. Texts are never logged.
+fn router(embedder: BertEmbedder) -> Router {
+    Router::new()
+        .route("/embed", axum::routing::post(handle_embed))
+        .with_state(Arc::new(embedder))
+}
+
+async fn handle_embed(
+    State(embedder): State<Arc<BertEmbedder>>,
+    Json(request): Json<EmbedRequest>,
+) -> Result<Json<EmbedResponse>, StatusCode> {
+    if request.texts.len() > MAX_TEXTS {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let model = embedder.model().clone();
+    // The model runs on the blocking pool, off the threads serving I/O
+    let embeddings = tokio::task::spawn_blocking(move || {
+        request
+            .texts
+            .iter()
+            .map(|text| embedder.embed_raw(text))
+            .collect::<Result<Vec<_>>>()
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(EmbedResponse {
+        embeddings,
+        model: Some(model),
+    }))
+}
+
+pub async fn run(port: u16) -> Result<()> {
+    let app = router(BertEmbedder::new()?);
+    let addr = format!("0.0.0.0:{}", port).parse()?;
+    println!("Starting embedding service on {}", addr);
+    axum_server::bind(addr)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
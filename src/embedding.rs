@@ -3,25 +3,270 @@ use candle::{Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::models::bert::{BertModel, Config, DTYPE};
 use hf_hub::{api::sync::Api, Repo, RepoType};
-use nalgebra::{DMatrix, DVector};
-use num_bigint::BigInt;
-use num_traits::One;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::BTreeMap;
 use tokenizers::Tokenizer;
 
+use crate::{
+    error::PirError,
+    similarity::{normalize, normalize_rows},
+    templates::{Prefixes, TextTemplates},
+};
+
+// Hugging Face BERT model to embed with. Clients and servers must use the same one.
+const MODEL_ENV_VAR: &str = "TIPTOE_EMBEDDING_MODEL";
+const REVISION_ENV_VAR: &str = "TIPTOE_EMBEDDING_REVISION";
+// How token embeddings become one: "mean" (default) or "cls", as bge models expect
+const POOLING_ENV_VAR: &str = "TIPTOE_EMBEDDING_POOLING";
+// Which tokens of a text too long for the model are kept: "head" (default), "tail" or
+// "head_tail"
+const TRUNCATION_ENV_VAR: &str = "TIPTOE_EMBEDDING_TRUNCATION";
+// Tokens embedded per text, special tokens included; defaults to the model's limit
+const MAX_TOKENS_ENV_VAR: &str = "TIPTOE_EMBEDDING_MAX_TOKENS";
+// Where a server keeps the model a re-embed switched it to, which it loads on restart in
+// place of the configured one
+const SWITCHED_MODEL_ENV_VAR: &str = "TIPTOE_SWITCHED_MODEL";
+const DEFAULT_SWITCHED_MODEL_PATH: &str = "embedding_model.json";
+// Texts of the same token count embedded in one forward pass
+const EMBED_BATCH: usize = 32;
+const DEFAULT_MODEL: &str = "sentence-transformers/all-MiniLM-L6-v2";
+// The default model's safetensors weights are only published on this revision
+const DEFAULT_REVISION: &str = "refs/pr/21";
+
+// Boilerplate around the subject of a query, matched case-insensitively. It carries
+// no information about which document is wanted but still pulls the embedding around.
+const QUERY_PREFIXES: [&str; 9] = [
+    "tell me about",
+    "what is the latest price of",
+    "what's the latest price of",
+    "how is",
+    "how are",
+    "give me details on",
+    "fetch data for",
+    "what is happening with",
+    "what's happening with",
+];
+const QUERY_SUFFIXES: [&str; 3] = ["performing today", "doing today", "today"];
+
+// What is left of `query` once `affix` is removed from its start (or end)
+fn strip_affix<'a>(query: &'a str, affix: &str, prefix: bool) -> Option<&'a str> {
+    let (head, tail) = if prefix {
+        (query.get(..affix.len())?, query.get(affix.len()..)?)
+    } else {
+        let split = query.len().checked_sub(affix.len())?;
+        (query.get(split..)?, query.get(..split)?)
+    };
+    head.eq_ignore_ascii_case(affix).then_some(tail)
+}
+
+// `query` followed by its subject with the template phrasing stripped, if any was
+pub fn reformulations(query: &str) -> Vec<String> {
+    let mut subject = query.trim().trim_end_matches(['?', '.', '!']).trim_end();
+    if let Some(rest) = QUERY_PREFIXES
+        .iter()
+        .find_map(|prefix| strip_affix(subject, prefix, true))
+    {
+        subject = rest.trim_start();
+    }
+    if let Some(rest) = QUERY_SUFFIXES
+        .iter()
+        .find_map(|suffix| strip_affix(subject, suffix, false))
+    {
+        subject = rest.trim_end();
+    }
+
+    let mut queries = vec![query.to_string()];
+    if !subject.is_empty() && subject != query {
+        queries.push(subject.to_string());
+    }
+    queries
+}
+
+// Mean of L2-normalized embeddings, normalized again so it scores like a single query
+pub fn fuse_embeddings(embeddings: &[Vec<f32>]) -> Vec<f32> {
+    let mut fused = vec![0.0f32; embeddings.first().map_or(0, Vec::len)];
+    for embedding in embeddings {
+        for (sum, value) in fused.iter_mut().zip(embedding) {
+            *sum += value;
+        }
+    }
+    normalize(&mut fused);
+    fused
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Truncation {
+    // Keep the start of the text
+    #[default]
+    Head,
+    // Keep the end, where updates to a document usually go
+    Tail,
+    // Keep the start and the end, half the budget each
+    HeadTail,
+}
+
+impl Truncation {
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(TRUNCATION_ENV_VAR).as_deref() {
+            Err(_) | Ok("head") => Ok(Self::Head),
+            Ok("tail") => Ok(Self::Tail),
+            Ok("head_tail") => Ok(Self::HeadTail),
+            Ok(other) => Err(PirError::InvalidInput(format!(
+                "Invalid {}: {}",
+                TRUNCATION_ENV_VAR, other
+            ))
+            .into()),
+        }
+    }
+
+    // Cuts `tokens`, which start and end with one special token each ([CLS] and [SEP]),
+    // down to `budget` tokens, keeping both special tokens
+    pub fn apply(&self, tokens: &[u32], budget: usize) -> Vec<u32> {
+        if tokens.len() <= budget || tokens.len() < 2 || budget < 2 {
+            return tokens.to_vec();
+        }
+        let (first, inner, last) = (
+            tokens[0],
+            &tokens[1..tokens.len() - 1],
+            tokens[tokens.len() - 1],
+        );
+        let keep = budget - 2;
+        let head = match self {
+            Self::Head => keep,
+            Self::Tail => 0,
+            Self::HeadTail => keep.div_ceil(2),
+        };
+        let mut truncated = Vec::with_capacity(budget);
+        truncated.push(first);
+        truncated.extend_from_slice(&inner[..head]);
+        truncated.extend_from_slice(&inner[inner.len() - (keep - head)..]);
+        truncated.push(last);
+        truncated
+    }
+}
+
+// The model documents and queries are embedded with. Servers publish theirs, so
+// clients can tell when they embed queries with another.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct EmbeddingModel {
+    // Hugging Face BERT model id
+    pub model: String,
+    // Defaults to the pinned revision for the default model and `main` for others
+    #[serde(default)]
+    pub revision: Option<String>,
+    // Pool with the first ([CLS]) token instead of the mean of all tokens
+    #[serde(default)]
+    pub cls_pooling: bool,
+}
+
+impl Default for EmbeddingModel {
+    fn default() -> Self {
+        Self {
+            model: DEFAULT_MODEL.to_string(),
+            revision: None,
+            cls_pooling: false,
+        }
+    }
+}
+
+impl EmbeddingModel {
+    pub fn from_env() -> Result<Self> {
+        let cls_pooling = match std::env::var(POOLING_ENV_VAR).as_deref() {
+            Err(_) | Ok("mean") => false,
+            Ok("cls") => true,
+            Ok(other) => {
+                return Err(PirError::InvalidInput(format!(
+                    "Invalid {}: {}",
+                    POOLING_ENV_VAR, other
+                ))
+                .into())
+            }
+        };
+        Ok(Self {
+            model: std::env::var(MODEL_ENV_VAR).unwrap_or_else(|_| DEFAULT_MODEL.to_string()),
+            revision: std::env::var(REVISION_ENV_VAR).ok(),
+            cls_pooling,
+        })
+    }
+
+    // The model a server embeds documents with: the one it last switched to, if any,
+    // otherwise the configured one
+    pub fn for_server() -> Result<Self> {
+        match std::fs::read(switched_model_path()) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::from_env(),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Makes this the model `for_server` returns from now on
+    pub fn save_switched(&self) -> Result<()> {
+        std::fs::write(switched_model_path(), serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    // The same model with its revision filled in, so equal models compare equal
+    fn resolved(mut self) -> Self {
+        if self.revision.is_none() {
+            self.revision = Some(if self.model == DEFAULT_MODEL {
+                DEFAULT_REVISION.to_string()
+            } else {
+                "main".to_string()
+            });
+        }
+        self
+    }
+}
+
+fn switched_model_path() -> String {
+    std::env::var(SWITCHED_MODEL_ENV_VAR)
+        .unwrap_or_else(|_| DEFAULT_SWITCHED_MODEL_PATH.to_string())
+}
+
+// Checks the model queries are embedded with against the one the server embedded its
+// documents with. Servers that don't publish one are taken at their word.
+pub fn check_model(model: &EmbeddingModel, expected: Option<&EmbeddingModel>) -> Result<()> {
+    match expected {
+        Some(expected) if expected != model => Err(PirError::Embedding(format!(
+            "Queries are embedded with {} but the server's documents with {}; client and \
+             server must use the same model",
+            model.model, expected.model
+        ))
+        .into()),
+        _ => Ok(()),
+    }
+}
+
 pub struct BertEmbedder {
     model: BertModel,
     tokenizer: Tokenizer,
     device: Device,
+    spec: EmbeddingModel,
+    truncation: Truncation,
+    max_tokens: usize,
 }
 
 impl BertEmbedder {
     pub fn new() -> Result<Self> {
+        Self::load(EmbeddingModel::from_env()?)
+    }
+
+    pub fn load(spec: EmbeddingModel) -> Result<Self> {
         let device = Device::cuda_if_available(0)?;
-        let model_id = "sentence-transformers/all-MiniLM-L6-v2".to_string();
-        let revision = "refs/pr/21".to_string();
+        let spec = spec.resolved();
+        if spec.model.is_empty() {
+            return Err(PirError::InvalidInput("Empty embedding model id".to_string()).into());
+        }
 
-        let repo = Repo::with_revision(model_id, RepoType::Model, revision);
+        let truncation = Truncation::from_env()?;
+
+        let repo = Repo::with_revision(
+            spec.model.clone(),
+            RepoType::Model,
+            spec.revision.clone().unwrap_or_default(),
+        );
         let (config_filename, tokenizer_filename, weights_filename) = {
             let api = Api::new()?;
             let api = api.repo(repo);
@@ -32,6 +277,22 @@ impl BertEmbedder {
         };
 
         let config = std::fs::read_to_string(config_filename)?;
+        let model_limit = serde_json::from_str::<Value>(&config)?["max_position_embeddings"]
+            .as_u64()
+            .unwrap_or(512) as usize;
+        let max_tokens = match std::env::var(MAX_TOKENS_ENV_VAR) {
+            Ok(max_tokens) => match max_tokens.parse::<usize>() {
+                Ok(max_tokens) if (2..=model_limit).contains(&max_tokens) => max_tokens,
+                _ => {
+                    return Err(PirError::InvalidInput(format!(
+                        "Invalid {}: {} (the model takes 2 to {} tokens)",
+                        MAX_TOKENS_ENV_VAR, max_tokens, model_limit
+                    ))
+                    .into())
+                }
+            },
+            Err(_) => model_limit,
+        };
         let config: Config = serde_json::from_str(&config)?;
         let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
 
@@ -44,104 +305,161 @@ impl BertEmbedder {
             model,
             tokenizer,
             device,
+            spec,
+            truncation,
+            max_tokens,
         })
     }
 
-    fn normalize_l2(&self, v: &Tensor) -> Result<Tensor> {
-        Ok(v.broadcast_div(&v.sqr()?.sum_keepdim(1)?.sqrt()?)?)
+    pub fn model(&self) -> &EmbeddingModel {
+        &self.spec
     }
 
-    fn embedding_to_bigint(&self, embeddings: &Tensor) -> Result<DVector<BigInt>> {
-        let embeddings = embeddings.squeeze(0)?;
-        let values = embeddings.to_vec1::<f32>()?;
-
-        let quantized: Vec<BigInt> = values.iter().map(|&x| f32_to_bigint(x)).collect();
-
-        Ok(DVector::from_vec(quantized))
+    // Unquantized, L2-normalized embeddings for each JSON value
+    pub fn embed_json_array_raw(&self, json: &[Value]) -> Result<Vec<Vec<f32>>> {
+        let texts: Vec<String> = json.iter().map(Value::to_string).collect();
+        self.embed_texts_raw(&texts)
     }
 
-    pub fn embed_json_array(&self, json: &[Value]) -> Result<DMatrix<BigInt>> {
-        let embeddings = json
+    // As `embed_json_array_raw`, but embeds each document's text from `templates`,
+    // behind the document prefix
+    pub fn embed_documents_raw(
+        &self,
+        documents: &[Value],
+        templates: &TextTemplates,
+        prefixes: &Prefixes,
+    ) -> Result<Vec<Vec<f32>>> {
+        let texts: Vec<String> = documents
             .iter()
-            .map(|v| self.embed_text(&v.to_string()))
-            .collect::<Result<Vec<_>>>()?;
-
-        let dim = std::cmp::max(embeddings[0].nrows(), embeddings.len());
-        let mut out = DMatrix::zeros(dim, dim);
-
-        for (i, embedding) in embeddings.iter().enumerate() {
-            out.row_mut(i).copy_from_slice(embedding.as_slice());
-        }
+            .map(|document| prefixes.prefix_document(&templates.render(document)))
+            .collect();
+        self.embed_texts_raw(&texts)
+    }
 
-        Ok(out)
+    pub fn embed_raw(&self, text: &str) -> Result<Vec<f32>> {
+        let mut embeddings = self.embed_texts_raw(&[text.to_string()])?;
+        Ok(embeddings.remove(0))
     }
 
-    pub fn embed_text(&self, text: &str) -> Result<DVector<BigInt>> {
-        let tokens = self
+    // Unquantized, L2-normalized embeddings for each of `texts`, in order. Texts with
+    // the same token count are embedded together, so no padding (which the model has
+    // no mask for) changes what a text embeds to.
+    pub fn embed_texts_raw(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let encodings = self
             .tokenizer
-            .encode(text, true)
-            .map_err(E::msg)?
-            .get_ids()
-            .to_vec();
-
-        let token_ids = Tensor::new(&tokens[..], &self.device)?.unsqueeze(0)?;
-        let token_type_ids = token_ids.zeros_like()?;
-
-        let embeddings = self.model.forward(&token_ids, &token_type_ids)?;
-
-        let (_n_sentence, n_tokens, _hidden_size) = embeddings.dims3()?;
-        let embeddings = (embeddings.sum(1)? / (n_tokens as f64))?;
-
-        let embeddings = self.normalize_l2(&embeddings)?;
+            .encode_batch(texts.to_vec(), true)
+            .map_err(E::msg)?;
+        let mut by_length: BTreeMap<usize, Vec<(usize, Vec<u32>)>> = BTreeMap::new();
+        for (index, encoding) in encodings.iter().enumerate() {
+            let tokens = self.truncation.apply(encoding.get_ids(), self.max_tokens);
+            by_length
+                .entry(tokens.len())
+                .or_default()
+                .push((index, tokens));
+        }
 
-        self.embedding_to_bigint(&embeddings)
+        let mut embeddings = vec![Vec::new(); texts.len()];
+        for (n_tokens, group) in by_length {
+            for batch in group.chunks(EMBED_BATCH) {
+                let ids: Vec<u32> = batch
+                    .iter()
+                    .flat_map(|(_, tokens)| tokens.iter().copied())
+                    .collect();
+                let token_ids = Tensor::from_vec(ids, (batch.len(), n_tokens), &self.device)?;
+                let token_type_ids = token_ids.zeros_like()?;
+
+                let output = self.model.forward(&token_ids, &token_type_ids)?;
+                let pooled = if self.spec.cls_pooling {
+                    output.narrow(1, 0, 1)?.squeeze(1)?
+                } else {
+                    (output.sum(1)? / (n_tokens as f64))?
+                };
+
+                let rows = normalize_rows(&pooled)?.to_vec2::<f32>()?;
+                for ((index, _), row) in batch.iter().zip(rows) {
+                    embeddings[*index] = row;
+                }
+            }
+        }
+        Ok(embeddings)
     }
 }
 
-fn f32_to_bigint(value: f32) -> BigInt {
-    if value.is_nan() || value.is_infinite() {
-        panic!("Cannot convert NaN or infinite values to BigInt");
-    }
+#[cfg(test)]
+mod tests {
+    use nalgebra::DVector;
+    use num_bigint::BigInt;
+    use num_traits::One;
 
-    let (mantissa, exponent, sign) = {
-        let bits = value.to_bits(); // Get raw IEEE 754 representation
-        let sign = if bits >> 31 == 1 { -1 } else { 1 };
-        let exponent = ((bits >> 23) & 0xFF) as i32 - 127; // Unbiased exponent
-        let mantissa = (bits & 0x7FFFFF) | 0x800000; // Add implicit leading 1
-        (mantissa, exponent, sign)
+    use crate::{
+        pir,
+        utils::{decode_input, encode_data},
     };
 
-    let mut big_mantissa = BigInt::from(mantissa);
+    use super::*;
 
-    if exponent >= 0 {
-        big_mantissa <<= exponent as usize; // Multiply by 2^exponent
-    } else {
-        let denominator = BigInt::one() << (-exponent as usize); // Divide by 2^(-exponent)
-        big_mantissa /= denominator;
+    #[test]
+    fn test_reformulations_and_fusion() {
+        assert_eq!(
+            reformulations("How is Tesla performing today?"),
+            vec!["How is Tesla performing today?", "Tesla"]
+        );
+        assert_eq!(
+            reformulations("tell me about SPDR S&P 500"),
+            vec!["tell me about SPDR S&P 500", "SPDR S&P 500"]
+        );
+        assert_eq!(reformulations("Bitcoin"), vec!["Bitcoin"]);
+        assert_eq!(reformulations("How is?"), vec!["How is?"]);
+
+        let fused = fuse_embeddings(&[vec![1.0, 0.0], vec![0.0, 1.0]]);
+        assert!((fused[0] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert_eq!(fused[0], fused[1]);
+        assert_eq!(fuse_embeddings(&[vec![0.6, 0.8]]), vec![0.6, 0.8]);
     }
 
-    if sign == -1 {
-        big_mantissa = -big_mantissa;
+    #[test]
+    fn test_truncation() {
+        let tokens: Vec<u32> = (0..10).collect();
+        assert_eq!(Truncation::Head.apply(&tokens, 6), vec![0, 1, 2, 3, 4, 9]);
+        assert_eq!(Truncation::Tail.apply(&tokens, 6), vec![0, 5, 6, 7, 8, 9]);
+        assert_eq!(
+            Truncation::HeadTail.apply(&tokens, 6),
+            vec![0, 1, 2, 7, 8, 9]
+        );
+        assert_eq!(Truncation::HeadTail.apply(&tokens, 5), vec![0, 1, 2, 8, 9]);
+        assert_eq!(Truncation::Tail.apply(&tokens, 10), tokens);
+        assert_eq!(Truncation::Head.apply(&tokens, 2), vec![0, 9]);
     }
 
-    big_mantissa
-}
-
-#[cfg(test)]
-mod tests {
-    use num_traits::One;
-    use simplepir::{gen_hint, gen_params, generate_query, process_query, recover};
-
-    use crate::utils::{decode_input, encode_data};
-
-    use super::*;
-
     #[test]
     fn test_embedding_shape() -> Result<()> {
         let embedder = BertEmbedder::new()?;
-        let embedding = embedder.embed_text("test text")?;
-
-        assert_eq!(embedding.nrows(), 384);
+        let embedding = embedder.embed_raw("test text")?;
+
+        assert_eq!(embedding.len(), 384);
+
+        // Revisions are filled in, so the default model matches however it was named
+        let model = EmbeddingModel::default().resolved();
+        assert_eq!(embedder.model(), &model);
+        check_model(embedder.model(), Some(&model))?;
+        check_model(embedder.model(), None)?;
+        let upgrade = EmbeddingModel {
+            model: "BAAI/bge-small-en-v1.5".to_string(),
+            revision: None,
+            cls_pooling: true,
+        };
+        assert!(check_model(embedder.model(), Some(&upgrade.resolved())).is_err());
+
+        // Batching texts of equal and different lengths embeds each as on its own
+        let texts = ["test text", "more test", "a somewhat longer test text"].map(String::from);
+        let batched = embedder.embed_texts_raw(&texts)?;
+        for (text, embedding) in texts.iter().zip(&batched) {
+            let single = embedder.embed_raw(text)?;
+            assert!(single
+                .iter()
+                .zip(embedding)
+                .all(|(a, b)| (a - b).abs() < 1e-5));
+        }
         Ok(())
     }
 
@@ -174,11 +492,11 @@ mod tests {
             result
         };
 
-        let params = gen_params(matrix_height, 2048, 64);
-        let (hint, a) = gen_hint(&params, &d);
-        let (s, query) = generate_query(&params, &v, &a);
-        let answer = process_query(&d, &query, params.q);
-        let result = recover(&hint, &s, &answer, &params);
+        let params = pir::params(matrix_height, 2048, 64);
+        let (hint, a) = pir::setup(&params, &d);
+        let (s, query) = pir::query(&params, &v, &a);
+        let answer = pir::answer(&params, &d, &query);
+        let result = pir::recover(&params, &hint, &s, &answer);
 
         println!("result: {:?}", result);
         println!("expected: {:?}", expected);
@@ -41,6 +41,16 @@ pub enum PirError {
 
     #[error("Tensor operation error: {0}")]
     TensorError(String),
+
+    #[error("Encryption error: {0}")]
+    Crypto(String),
+
+    #[error("Cancelled: {0}")]
+    Cancelled(String),
+
+    // The server is replacing the database a query was encrypted for
+    #[error("Epoch {epoch} is being replaced; retry after {retry_after_secs}s")]
+    EpochChanging { epoch: u64, retry_after_secs: u64 },
 }
 
 // Implement From trait for common error conversions
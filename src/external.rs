@@ -0,0 +1,62 @@
+use anyhow::Result;
+use std::sync::Arc;
+
+use crate::{
+    audit::Sealed,
+    network::{HttpTransport, MatrixResponse, ParamsData, QueryRequest, Transport},
+};
+
+// Client side of queries encrypted by another SimplePIR implementation, e.g. a
+// JS/WASM library. Everything is exchanged in the servers' JSON wire format:
+//
+// - `{database}/params`: `m` (query length, the database's columns), `n` (LWE
+//   dimension), and the moduli `q` and `p` as decimal strings
+// - `{database}/a` and `{database}/hint`: `{rows, cols, data}`, with `data` the
+//   matrix's elements in column-major order as decimal strings. A is sent in full;
+//   there is no seed to expand.
+// - `POST {database}/query` with `{"query": [...]}`: `m` decimal strings in [0, q),
+//   answered with `{"response": [...]}`, one decimal string per row of the hint
+//
+// `database` is empty for a server's main database, or e.g. `/clusters/3`, `/hot` or
+// `/membership` for the smaller ones.
+pub struct ExternalDatabase {
+    transport: Arc<dyn Transport>,
+    database: String,
+}
+
+impl ExternalDatabase {
+    pub fn new(base_url: String, database: &str) -> Self {
+        Self::with_transport(Arc::new(HttpTransport::new(base_url)), database)
+    }
+
+    pub fn with_transport(transport: Arc<dyn Transport>, database: &str) -> Self {
+        Self {
+            transport,
+            database: database.trim_end_matches('/').to_string(),
+        }
+    }
+
+    // The params and A a query is encrypted with
+    pub async fn setup(&self) -> Result<(ParamsData, MatrixResponse)> {
+        let params = self.transport.get_params(&self.database).await?;
+        let a = self.transport.get_a(&self.database).await?;
+        Ok((params, a))
+    }
+
+    // Answers are recovered with this
+    pub async fn hint(&self) -> Result<MatrixResponse> {
+        self.transport.get_hint(&self.database).await
+    }
+
+    // Sends an already encrypted query and returns the server's raw answer. The
+    // server refuses queries that are not exactly `m` elements long.
+    pub async fn submit(&self, query: Vec<String>) -> Result<Vec<String>> {
+        let request = QueryRequest {
+            query: Sealed::new(query),
+            secret: None,
+            plaintext: false,
+        };
+        let response = self.transport.send_query(&self.database, &request).await?;
+        Ok(response.response)
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::{
+    client::{Client, QueryResult},
+    error::PirError,
+};
+
+// A query with filters, written as `class:crypto after:2024-06 bitcoin price`.
+// Recognized filters are `ns:` (or `namespace:`), `class:`, `after:` and `before:`;
+// every other word, including ones with colons such as "10:30", is free text and
+// drives the embedding search. Filters are applied on the client, so the servers
+// never see them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilteredQuery {
+    pub text: String,
+    // Which corpus to search, for clients of several; see `Namespaces`
+    pub namespace: Option<String>,
+    // Prefix of the document's asset class, ignoring case: "crypto" matches
+    // "Cryptocurrency"
+    pub class: Option<String>,
+    // Unix seconds. `after:2024-06` keeps documents quoted from the start of June 2024
+    // on, `before:2024-06` ones quoted before it.
+    pub after: Option<u64>,
+    pub before: Option<u64>,
+}
+
+// Fields of a document the filters are checked against
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterFields {
+    // Asset class, as in `TextTemplates::class_field`
+    pub class_field: String,
+    // Unix time in seconds the document was quoted at
+    pub time_field: String,
+}
+
+impl Default for FilterFields {
+    fn default() -> Self {
+        Self {
+            class_field: "sector".to_string(),
+            time_field: "quotedAt".to_string(),
+        }
+    }
+}
+
+// Unix seconds at the start of a `YYYY`, `YYYY-MM` or `YYYY-MM-DD` date, in UTC.
+// Years are bounded so the arithmetic below cannot overflow.
+fn parse_date(date: &str) -> Result<u64> {
+    let invalid = || PirError::InvalidInput(format!("Invalid date {:?}", date));
+    let mut parts = date.split('-').map(|part| part.parse::<i64>());
+    let year = parts.next().ok_or_else(invalid)?.map_err(|_| invalid())?;
+    let month = parts
+        .next()
+        .transpose()
+        .map_err(|_| invalid())?
+        .unwrap_or(1);
+    let day = parts
+        .next()
+        .transpose()
+        .map_err(|_| invalid())?
+        .unwrap_or(1);
+    if parts.next().is_some()
+        || !(1970..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+    {
+        return Err(invalid().into());
+    }
+    // Days since 1970-01-01 from a civil date (Howard Hinnant's algorithm)
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    u64::try_from(days * 86_400).map_err(|_| invalid().into())
+}
+
+impl FilteredQuery {
+    pub fn parse(query: &str) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut text = Vec::new();
+        for word in query.split_whitespace() {
+            let filter = word.split_once(':').filter(|(_, value)| !value.is_empty());
+            match filter {
+                Some(("ns" | "namespace", value)) => parsed.namespace = Some(value.to_string()),
+                Some(("class", value)) => parsed.class = Some(value.to_lowercase()),
+                Some(("after", value)) => parsed.after = Some(parse_date(value)?),
+                Some(("before", value)) => parsed.before = Some(parse_date(value)?),
+                _ => text.push(word),
+            }
+        }
+        if text.is_empty() {
+            return Err(PirError::InvalidInput(format!("No search text in {:?}", query)).into());
+        }
+        parsed.text = text.join(" ");
+        Ok(parsed)
+    }
+
+    // Whether `document` passes the class and date filters. Documents without the
+    // field a filter needs fail it.
+    pub fn matches(&self, document: &Value, fields: &FilterFields) -> bool {
+        let class_matches = self.class.as_ref().is_none_or(|class| {
+            document[&fields.class_field]
+                .as_str()
+                .is_some_and(|value| value.to_lowercase().starts_with(class.as_str()))
+        });
+        let dated = self.after.is_some() || self.before.is_some();
+        let time_matches = !dated
+            || document[&fields.time_field].as_u64().is_some_and(|time| {
+                self.after.is_none_or(|after| time >= after)
+                    && self.before.is_none_or(|before| time < before)
+            });
+        class_matches && time_matches
+    }
+}
+
+// Clients of several corpora by namespace, e.g. one per server pair, picked by each
+// query's `ns:` filter
+pub struct Namespaces {
+    clients: HashMap<String, Client>,
+    // Searched by queries without a namespace
+    default: String,
+}
+
+impl Namespaces {
+    pub fn new(default: String, client: Client) -> Self {
+        Self {
+            clients: HashMap::from([(default.clone(), client)]),
+            default,
+        }
+    }
+
+    pub fn with_namespace(mut self, namespace: String, client: Client) -> Self {
+        self.clients.insert(namespace, client);
+        self
+    }
+
+    pub fn client(&self, namespace: Option<&str>) -> Result<&Client> {
+        let namespace = namespace.unwrap_or(&self.default);
+        self.clients.get(namespace).ok_or_else(|| {
+            PirError::InvalidInput(format!("Unknown namespace '{}'", namespace)).into()
+        })
+    }
+
+    // Parses `query` and answers it with `Client::query_filtered` on the client of
+    // its namespace
+    pub async fn query(&self, query: &str, k: usize) -> Result<Vec<QueryResult>> {
+        let query = FilteredQuery::parse(query)?;
+        self.client(query.namespace.as_deref())?
+            .query_filtered(&query, k)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_and_match_filters() -> Result<()> {
+        let query = FilteredQuery::parse("class:Crypto after:2024-06 bitcoin price at 10:30")?;
+        assert_eq!(query.text, "bitcoin price at 10:30");
+        assert_eq!(query.class.as_deref(), Some("crypto"));
+        assert_eq!(query.after, Some(1_717_200_000));
+        assert_eq!(query.before, None);
+        assert_eq!(parse_date("1970")?, 0);
+        assert_eq!(parse_date("2024-03-01")?, 1_709_251_200);
+        assert!(parse_date("2024-13").is_err());
+        assert_eq!(parse_date("9999-12-31")?, 253_402_214_400);
+        assert!(parse_date("1969-12-31").is_err());
+        assert!(FilteredQuery::parse("after:9999999999999999 bitcoin").is_err());
+        assert!(FilteredQuery::parse("after:June bitcoin").is_err());
+        assert!(FilteredQuery::parse("ns:news class:crypto").is_err());
+        assert_eq!(
+            FilteredQuery::parse("ns:news tesla")?.namespace.as_deref(),
+            Some("news")
+        );
+
+        let fields = FilterFields::default();
+        let bitcoin =
+            json!({"name": "Bitcoin USD", "sector": "Cryptocurrency", "quotedAt": 1_720_000_000});
+        let apple =
+            json!({"name": "Apple Inc.", "sector": "Technology", "quotedAt": 1_720_000_000});
+        let old =
+            json!({"name": "Bitcoin USD", "sector": "Cryptocurrency", "quotedAt": 1_700_000_000});
+        let undated = json!({"name": "Bitcoin USD", "sector": "Cryptocurrency"});
+        assert!(query.matches(&bitcoin, &fields));
+        assert!(!query.matches(&apple, &fields));
+        assert!(!query.matches(&old, &fields));
+        assert!(!query.matches(&undated, &fields));
+        assert!(FilteredQuery::parse("bitcoin")?.matches(&undated, &fields));
+        Ok(())
+    }
+}
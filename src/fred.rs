@@ -0,0 +1,235 @@
+use anyhow::Result;
+use reqwest::Client as HttpClient;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{error::PirError, source::block_on_thread};
+
+// API key for FRED (https://fred.stlouisfed.org/docs/api/api_key.html); kept out of
+// the config file like the other secrets
+const API_KEY_ENV_VAR: &str = "TIPTOE_FRED_API_KEY";
+const BASE_URL: &str = "https://api.stlouisfed.org/fred";
+// Enough history to find a previous value past FRED's "." placeholders
+const OBSERVATIONS: usize = 10;
+const DEFAULT_TEMPLATE: &str =
+    "{name} ({title}) was {value} {units} on {date}, {change} from {previous}";
+
+// Economic indicators from the Federal Reserve's FRED API, one document per series
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct FredConfig {
+    pub series: Vec<FredSeries>,
+    // Overrides the API's base URL, e.g. for a caching proxy
+    #[serde(default)]
+    pub base_url: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct FredSeries {
+    // FRED series id, e.g. `CPIAUCSL` or `UNRATE`
+    pub id: String,
+    // Name of the document the series becomes, e.g. "US Consumer Price Index"
+    pub name: String,
+    // Text of the document's `summary` field. {name}, {title}, {value}, {previous},
+    // {change}, {units}, {frequency} and {date} are filled in.
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+// Metadata of a series, as returned by `/fred/series`
+#[derive(Deserialize)]
+struct SeriesInfo {
+    title: String,
+    units: String,
+    frequency: String,
+    #[serde(default)]
+    seasonal_adjustment: String,
+    #[serde(default)]
+    last_updated: String,
+}
+
+#[derive(Deserialize)]
+struct SeriesResponse {
+    seriess: Vec<SeriesInfo>,
+}
+
+#[derive(Deserialize)]
+struct Observation {
+    date: String,
+    // Decimal string, or "." where the value is missing
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct ObservationsResponse {
+    observations: Vec<Observation>,
+}
+
+impl FredConfig {
+    // Series that fail are skipped; the fetch only fails if all of them do
+    pub fn fetch(&self) -> Result<Vec<Value>> {
+        let api_key = std::env::var(API_KEY_ENV_VAR).map_err(|_| {
+            PirError::InvalidInput(format!("{} must be set to read FRED", API_KEY_ENV_VAR))
+        })?;
+        let base_url = self.base_url.as_deref().unwrap_or(BASE_URL);
+
+        block_on_thread(async {
+            let client = HttpClient::new();
+            let mut documents = Vec::new();
+            for series in &self.series {
+                match fetch_series(&client, base_url, &api_key, series).await {
+                    Ok(document) => documents.push(document),
+                    Err(e) => eprintln!("Skipping FRED series {}: {:?}", series.id, e),
+                }
+            }
+            if documents.is_empty() && !self.series.is_empty() {
+                return Err(PirError::Database("No FRED series could be read".to_string()).into());
+            }
+            Ok(documents)
+        })
+    }
+}
+
+async fn fetch_series(
+    client: &HttpClient,
+    base_url: &str,
+    api_key: &str,
+    series: &FredSeries,
+) -> Result<Value> {
+    let params = [
+        ("series_id", series.id.as_str()),
+        ("api_key", api_key),
+        ("file_type", "json"),
+    ];
+    let info: SeriesResponse = client
+        .get(format!("{}/series", base_url))
+        .query(&params)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let info = info
+        .seriess
+        .into_iter()
+        .next()
+        .ok_or_else(|| PirError::Database(format!("Unknown FRED series {}", series.id)))?;
+
+    let limit = OBSERVATIONS.to_string();
+    let observations: ObservationsResponse = client
+        .get(format!("{}/series/observations", base_url))
+        .query(&params)
+        .query(&[("sort_order", "desc"), ("limit", limit.as_str())])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    document(series, &info, &observations.observations)
+}
+
+// Latest value of the series with its previous one and metadata. Observations are
+// newest first.
+fn document(series: &FredSeries, info: &SeriesInfo, observations: &[Observation]) -> Result<Value> {
+    let mut values = observations.iter().filter_map(|observation| {
+        let value: f64 = observation.value.parse().ok()?;
+        value
+            .is_finite()
+            .then_some((observation.date.as_str(), value))
+    });
+    let (date, value) = values
+        .next()
+        .ok_or_else(|| PirError::Database(format!("FRED series {} has no values", series.id)))?;
+    let previous = values.next().map(|(_, previous)| previous);
+
+    let change = previous.map(|previous| value - previous);
+    let summary = series
+        .template
+        .as_deref()
+        .unwrap_or(DEFAULT_TEMPLATE)
+        .replace("{name}", &series.name)
+        .replace("{title}", &info.title)
+        .replace("{value}", &value.to_string())
+        .replace(
+            "{previous}",
+            &previous.map_or("n/a".to_string(), |p| p.to_string()),
+        )
+        .replace(
+            "{change}",
+            &change.map_or("n/a".to_string(), |c| format!("{:+}", c)),
+        )
+        .replace("{units}", &info.units)
+        .replace("{frequency}", &info.frequency)
+        .replace("{date}", date);
+
+    Ok(json!({
+        "name": series.name,
+        "series": series.id,
+        "title": info.title,
+        "value": value,
+        "previousValue": previous,
+        "change": change,
+        "date": date,
+        "units": info.units,
+        "frequency": info.frequency,
+        "seasonalAdjustment": info.seasonal_adjustment,
+        "lastUpdated": info.last_updated,
+        "summary": summary,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_skips_missing_observations() -> Result<()> {
+        let series = FredSeries {
+            id: "UNRATE".to_string(),
+            name: "US Unemployment Rate".to_string(),
+            template: Some("{name}: {value}{units} in {date}".to_string()),
+        };
+        let info = SeriesInfo {
+            title: "Unemployment Rate".to_string(),
+            units: "%".to_string(),
+            frequency: "Monthly".to_string(),
+            seasonal_adjustment: "Seasonally Adjusted".to_string(),
+            last_updated: "2024-06-07".to_string(),
+        };
+        let observation = |date: &str, value: &str| Observation {
+            date: date.to_string(),
+            value: value.to_string(),
+        };
+        let observations = [
+            observation("2024-06-01", "."),
+            observation("2024-05-01", "4.0"),
+            observation("2024-04-01", "3.9"),
+        ];
+
+        let indicator = document(&series, &info, &observations)?;
+        assert_eq!(indicator["name"], "US Unemployment Rate");
+        assert_eq!(indicator["value"], 4.0);
+        assert_eq!(indicator["previousValue"], 3.9);
+        assert_eq!(indicator["date"], "2024-05-01");
+        assert_eq!(
+            indicator["summary"],
+            "US Unemployment Rate: 4% in 2024-05-01"
+        );
+
+        let series = FredSeries {
+            template: None,
+            ..series
+        };
+        let indicator = document(&series, &info, &observations[..2])?;
+        assert!(indicator["previousValue"].is_null());
+        assert!(indicator["summary"]
+            .as_str()
+            .unwrap()
+            .ends_with("n/a from n/a"));
+        assert!(document(&series, &info, &observations[..1]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+use anyhow::Result;
+use nalgebra::{DMatrix, DVector};
+use num_bigint::BigInt;
+
+use crate::network::{MatrixResponse, ParamsData};
+use crate::pir::SimplePIRParams;
+
+// Parsers of untrusted input, exposed to the cargo-fuzz targets in fuzz/. Only built
+// with `--cfg fuzzing`, which cargo-fuzz sets.
+pub use crate::utils::decode_input;
+
+pub fn deserialize_vector(vec: &[String]) -> Result<DVector<BigInt>> {
+    crate::network::deserialize_vector(vec)
+}
+
+pub fn deserialize_matrix(response: &MatrixResponse) -> Result<DMatrix<BigInt>> {
+    crate::network::deserialize_matrix(response)
+}
+
+pub fn deserialize_params(data: &ParamsData) -> Result<SimplePIRParams> {
+    crate::network::deserialize_params(data)
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    body::{to_bytes, Body},
+    http::{header::CONTENT_TYPE, Method, Request},
+    Router,
+};
+use serde::de::DeserializeOwned;
+use tower::ServiceExt;
+
+use crate::{
+    error::PirError,
+    network::{MatrixResponse, ParamsData, QueryRequest, QueryResponse, Transport},
+};
+
+// Transport that calls a server's router directly instead of going over TCP, so a
+// client and both servers can run in one process, e.g. in integration tests
+pub struct InProcessTransport {
+    router: Router,
+}
+
+impl InProcessTransport {
+    // `router` is usually `network::router(db)`
+    pub fn new(router: Router) -> Self {
+        Self { router }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: Method, path: &str, body: Body) -> Result<T> {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)?;
+        let response = self.router.clone().oneshot(request).await?;
+        if !response.status().is_success() {
+            return Err(
+                PirError::Database(format!("{} returned {}", path, response.status())).into(),
+            );
+        }
+        let bytes = to_bytes(response.into_body(), usize::MAX).await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.call(Method::GET, path, Body::empty()).await
+    }
+}
+
+#[async_trait]
+impl Transport for InProcessTransport {
+    async fn send_query(&self, database: &str, request: &QueryRequest) -> Result<QueryResponse> {
+        let body = Body::from(serde_json::to_vec(request)?);
+        self.call(Method::POST, &format!("{}/query", database), body)
+            .await
+    }
+
+    async fn get_params(&self, database: &str) -> Result<ParamsData> {
+        self.get(&format!("{}/params", database)).await
+    }
+
+    async fn get_hint(&self, database: &str) -> Result<MatrixResponse> {
+        self.get(&format!("{}/hint", database)).await
+    }
+
+    async fn get_a(&self, database: &str) -> Result<MatrixResponse> {
+        self.get(&format!("{}/a", database)).await
+    }
+
+    async fn get_json(&self, path: &str) -> Result<serde_json::Value> {
+        self.get(path).await
+    }
+}
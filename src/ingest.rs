@@ -0,0 +1,223 @@
+use anyhow::Result;
+use nalgebra::DMatrix;
+use num_bigint::BigInt;
+use serde_json::Value;
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::mpsc::Receiver;
+
+use crate::{
+    embedding::BertEmbedder,
+    error::PirError,
+    quantization::Quantization,
+    server::SimplePirDatabase,
+    templates::{Prefixes, TextTemplates},
+};
+
+const DEFAULT_BATCH_SIZE: usize = 256;
+// Ingests started by this process, so concurrent ones spill to different files
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+// A temporary file no other ingest, in this process or another, writes to
+pub fn spill_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "tiptoe-ingest-{}-{}.bin",
+        std::process::id(),
+        SPILLS.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+// Appends embedding rows to a file instead of keeping them in memory.
+// Rows are stored as raw f32 and only quantized once, when the matrix is built.
+pub struct MatrixBuilder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    rows: usize,
+    cols: usize,
+}
+
+impl MatrixBuilder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok(Self {
+            path,
+            writer,
+            rows: 0,
+            cols: 0,
+        })
+    }
+
+    pub fn append(&mut self, row: &[f32]) -> Result<()> {
+        if self.rows == 0 {
+            self.cols = row.len();
+        } else if row.len() != self.cols {
+            return Err(PirError::InvalidInput(format!(
+                "Row has {} columns, expected {}",
+                row.len(),
+                self.cols
+            ))
+            .into());
+        }
+
+        for value in row {
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    // Reads the rows back into a matrix laid out like `Quantization::quantize_rows`
+    // and removes the backing file
+    pub fn finish(self, quantization: &Quantization) -> Result<DMatrix<BigInt>> {
+        let Self {
+            path,
+            mut writer,
+            rows,
+            cols,
+        } = self;
+        if rows == 0 {
+            return Err(PirError::Database("No rows were ingested".to_string()).into());
+        }
+        writer.flush()?;
+        drop(writer);
+
+        let mut out = DMatrix::zeros(rows, cols.max(1));
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut row = vec![0f32; cols];
+        let mut bytes = [0u8; 4];
+        for i in 0..rows {
+            for value in row.iter_mut() {
+                reader.read_exact(&mut bytes)?;
+                *value = f32::from_le_bytes(bytes);
+            }
+            for (j, &value) in row.iter().enumerate() {
+                out[(i, j)] = quantization.quantize_value(value);
+            }
+        }
+
+        drop(reader);
+        fs::remove_file(&path)?;
+        Ok(out)
+    }
+}
+
+// Builds an embedding database from a stream of documents. Embeddings are spilled to
+// disk as each batch is embedded, so only one batch of documents is held while the
+// corpus streams in; the quantized matrix is still built in memory for the hint.
+pub struct Ingestor {
+    embedder: Arc<BertEmbedder>,
+    builder: MatrixBuilder,
+    batch_size: usize,
+    quantization: Quantization,
+    templates: TextTemplates,
+    prefixes: Prefixes,
+}
+
+impl Ingestor {
+    pub fn new(embedder: Arc<BertEmbedder>, path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            embedder,
+            builder: MatrixBuilder::create(path)?,
+            batch_size: DEFAULT_BATCH_SIZE,
+            quantization: Quantization::default(),
+            templates: TextTemplates::default(),
+            prefixes: Prefixes::default(),
+        })
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn quantization(mut self, quantization: Quantization) -> Self {
+        self.quantization = quantization;
+        self
+    }
+
+    pub fn templates(mut self, templates: TextTemplates) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    pub fn prefixes(mut self, prefixes: Prefixes) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    // Builds the database, returning it with the width of the embeddings it holds.
+    // Embedding and hint generation run on blocking threads.
+    pub async fn ingest(
+        mut self,
+        mut documents: Receiver<Value>,
+    ) -> Result<(SimplePirDatabase, usize)> {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        while let Some(document) = documents.recv().await {
+            batch.push(document);
+            if batch.len() == self.batch_size {
+                let full = std::mem::replace(&mut batch, Vec::with_capacity(self.batch_size));
+                self.flush(full).await?;
+            }
+        }
+        self.flush(batch).await?;
+
+        let (builder, quantization) = (self.builder, self.quantization);
+        tokio::task::spawn_blocking(move || {
+            let dim = builder.cols;
+            let data = builder.finish(&quantization)?;
+            let mut db = SimplePirDatabase::new(DMatrix::zeros(1, 1));
+            db.set_mod_power(quantization.mod_power(dim)?);
+            db.update_db(data)?;
+            Ok((db, dim))
+        })
+        .await?
+    }
+
+    async fn flush(&mut self, batch: Vec<Value>) -> Result<()> {
+        let embedder = Arc::clone(&self.embedder);
+        let (templates, prefixes) = (self.templates.clone(), self.prefixes.clone());
+        let embeddings = tokio::task::spawn_blocking(move || {
+            embedder.embed_documents_raw(&batch, &templates, &prefixes)
+        })
+        .await?
+        .map_err(|e| PirError::Embedding(e.to_string()))?;
+        for embedding in &embeddings {
+            self.builder.append(embedding)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix_builder_matches_in_memory_layout() -> Result<()> {
+        let rows = vec![vec![0.5, -0.25, 0.125], vec![-1.0, 0.75, 0.0]];
+        let path = spill_path();
+        assert_ne!(path, spill_path());
+
+        let mut builder = MatrixBuilder::create(&path)?;
+        for row in &rows {
+            builder.append(row)?;
+        }
+        assert!(builder.append(&[1.0]).is_err());
+
+        let quantization = Quantization::default();
+        assert_eq!(
+            builder.finish(&quantization)?,
+            quantization.quantize_rows(&rows)
+        );
+        assert!(!path.exists());
+        Ok(())
+    }
+}
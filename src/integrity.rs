@@ -0,0 +1,284 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use nalgebra::DMatrix;
+use num_bigint::BigInt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::PirError, pir::SimplePIRParams};
+
+// Base64 Ed25519 secret key the servers sign their digests with. Clients are given
+// the matching public key out of band.
+const SIGNING_KEY_ENV_VAR: &str = "TIPTOE_SIGNING_KEY";
+
+// SHA-256 over everything a client downloads to build and recover queries for one
+// epoch of a database, optionally signed by the server
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DatabaseDigest {
+    pub epoch: u64,
+    // Hex-encoded
+    pub digest: String,
+    // Base64 Ed25519 signature over the digest
+    #[serde(default)]
+    pub signature: Option<String>,
+    // Base64 public key the signature verifies with, for clients that pin it on first use
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+fn hash_matrix(hasher: &mut Sha256, matrix: &DMatrix<BigInt>) {
+    hasher.update((matrix.nrows() as u64).to_le_bytes());
+    hasher.update((matrix.ncols() as u64).to_le_bytes());
+    for x in matrix.iter() {
+        let bytes = x.to_signed_bytes_le();
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    }
+}
+
+fn hash(
+    epoch: u64,
+    params: &SimplePIRParams,
+    hint: &DMatrix<BigInt>,
+    a: &DMatrix<BigInt>,
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(epoch.to_le_bytes());
+    hasher.update(format!(
+        "{}:{}:{}:{}",
+        params.m, params.n, params.q, params.p
+    ));
+    hash_matrix(&mut hasher, hint);
+    hash_matrix(&mut hasher, a);
+    hasher.finalize().into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn signing_key_from_env() -> Result<Option<SigningKey>> {
+    std::env::var(SIGNING_KEY_ENV_VAR)
+        .ok()
+        .map(|encoded| {
+            let bytes: [u8; 32] = STANDARD
+                .decode(encoded.trim())
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| {
+                    PirError::Crypto(format!("{} must be 32 base64 bytes", SIGNING_KEY_ENV_VAR))
+                })?;
+            Ok(SigningKey::from_bytes(&bytes))
+        })
+        .transpose()
+}
+
+pub fn verifying_key_from_base64(encoded: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| PirError::Crypto("Verifying key must be 32 base64 bytes".to_string()))?;
+    Ok(VerifyingKey::from_bytes(&bytes)
+        .map_err(|e| PirError::Crypto(format!("Invalid verifying key: {}", e)))?)
+}
+
+impl DatabaseDigest {
+    pub fn compute(
+        epoch: u64,
+        params: &SimplePIRParams,
+        hint: &DMatrix<BigInt>,
+        a: &DMatrix<BigInt>,
+        signing_key: Option<&SigningKey>,
+    ) -> Self {
+        let digest = hash(epoch, params, hint, a);
+        Self {
+            epoch,
+            digest: to_hex(&digest),
+            signature: signing_key.map(|key| STANDARD.encode(key.sign(&digest).to_bytes())),
+            public_key: signing_key.map(|key| STANDARD.encode(key.verifying_key().to_bytes())),
+        }
+    }
+
+    // Checks downloaded params, hint and A against this digest, and the digest against
+    // its signature when a key is given. Catches truncated or tampered transfers, which
+    // would otherwise recover garbage.
+    pub fn verify(
+        &self,
+        params: &SimplePIRParams,
+        hint: &DMatrix<BigInt>,
+        a: &DMatrix<BigInt>,
+        verifying_key: Option<&VerifyingKey>,
+    ) -> Result<()> {
+        let digest = hash(self.epoch, params, hint, a);
+        if to_hex(&digest) != self.digest {
+            return Err(PirError::Crypto(format!(
+                "Downloaded params, hint or A do not match the digest of epoch {}",
+                self.epoch
+            ))
+            .into());
+        }
+
+        let Some(key) = verifying_key else {
+            return Ok(());
+        };
+        let signature = self
+            .signature
+            .as_ref()
+            .and_then(|signature| STANDARD.decode(signature).ok())
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| PirError::Crypto("Digest is not signed".to_string()))?;
+        key.verify(&digest, &signature)
+            .map_err(|_| PirError::Crypto("Digest signature is invalid".to_string()).into())
+    }
+}
+
+// Trust-on-first-use pins of each server's signing key, kept in a JSON file. The
+// first key a server presents is trusted and saved; a different key later, or none,
+// is refused unless identity changes are allowed.
+pub struct PinStore {
+    path: PathBuf,
+    pins: BTreeMap<String, String>,
+    allow_changes: bool,
+}
+
+impl PinStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let pins = match fs::read_to_string(&path) {
+            Ok(pins) => serde_json::from_str(&pins)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            pins,
+            allow_changes: false,
+        })
+    }
+
+    // Re-pins a changed key with a warning instead of refusing it, e.g. after a planned
+    // key rotation
+    pub fn allow_identity_changes(mut self, allow: bool) -> Self {
+        self.allow_changes = allow;
+        self
+    }
+
+    pub fn pinned(&self, origin: &str) -> Option<&str> {
+        self.pins.get(origin).map(String::as_str)
+    }
+
+    // Key to verify `origin`'s digests with, given the key it presents now
+    pub fn check(&mut self, origin: &str, presented: Option<&str>) -> Result<Option<VerifyingKey>> {
+        match (self.pins.get(origin).cloned(), presented) {
+            (None, None) => return Ok(None),
+            (None, Some(key)) => {
+                eprintln!("Pinning signing key {} of {} on first use", key, origin);
+            }
+            (Some(pinned), Some(key)) if pinned == key => {
+                return verifying_key_from_base64(key).map(Some);
+            }
+            (Some(pinned), presented) => {
+                let presented = presented.unwrap_or("none");
+                if !self.allow_changes {
+                    return Err(PirError::Crypto(format!(
+                        "Signing key of {} changed from {} to {} since it was pinned; \
+                         allow identity changes or remove its pin if this is expected",
+                        origin, pinned, presented
+                    ))
+                    .into());
+                }
+                eprintln!(
+                    "WARNING: signing key of {} changed from {} to {}; re-pinning",
+                    origin, pinned, presented
+                );
+            }
+        }
+
+        match presented {
+            Some(key) => {
+                let verifying_key = verifying_key_from_base64(key)?;
+                self.pins.insert(origin.to_string(), key.to_string());
+                self.save()?;
+                Ok(Some(verifying_key))
+            }
+            None => {
+                self.pins.remove(origin);
+                self.save()?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        Ok(fs::write(
+            &self.path,
+            serde_json::to_string_pretty(&self.pins)?,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pir;
+
+    #[test]
+    fn test_digest_detects_tampering() -> Result<()> {
+        let data = DMatrix::from_fn(4, 4, |i, j| BigInt::from(i * 4 + j));
+        let params = pir::params(4, 4, 64);
+        let (hint, a) = pir::setup(&params, &data);
+        let key = SigningKey::from_bytes(&[7; 32]);
+
+        let digest = DatabaseDigest::compute(1, &params, &hint, &a, Some(&key));
+        digest.verify(&params, &hint, &a, Some(&key.verifying_key()))?;
+
+        let mut tampered = hint.clone();
+        tampered[(3, 3)] += 1;
+        assert!(digest.verify(&params, &tampered, &a, None).is_err());
+
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert!(digest.verify(&params, &hint, &a, Some(&other)).is_err());
+        let unsigned = DatabaseDigest::compute(1, &params, &hint, &a, None);
+        unsigned.verify(&params, &hint, &a, None)?;
+        assert!(unsigned
+            .verify(&params, &hint, &a, Some(&key.verifying_key()))
+            .is_err());
+
+        let encoded = STANDARD.encode(key.verifying_key().to_bytes());
+        assert_eq!(verifying_key_from_base64(&encoded)?, key.verifying_key());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pins_refuse_changed_keys() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("tiptoe-pins-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let first = STANDARD.encode(SigningKey::from_bytes(&[7; 32]).verifying_key().to_bytes());
+        let second = STANDARD.encode(SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes());
+        let origin = "http://localhost:3000";
+
+        let mut pins = PinStore::open(&path)?;
+        assert!(pins.check(origin, Some(&first))?.is_some());
+        assert!(pins.check(origin, Some(&first))?.is_some());
+
+        let mut pins = PinStore::open(&path)?;
+        assert_eq!(pins.pinned(origin), Some(first.as_str()));
+        assert!(pins.check(origin, Some(&second)).is_err());
+        assert!(pins.check(origin, None).is_err());
+        assert_eq!(pins.check("http://localhost:3001", None)?, None);
+
+        let mut pins = PinStore::open(&path)?.allow_identity_changes(true);
+        assert!(pins.check(origin, Some(&second))?.is_some());
+        assert_eq!(PinStore::open(&path)?.pinned(origin), Some(second.as_str()));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{embedding::EmbeddingModel, error::PirError, pool::Throttle};
+
+// Jobs remembered for the admin API, including finished ones
+const JOB_HISTORY: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running { percent: u8 },
+    Completed,
+    Failed { error: String },
+    Cancelled,
+}
+
+impl JobStatus {
+    fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            Self::Completed | Self::Failed { .. } | Self::Cancelled
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct JobInfo {
+    pub id: u64,
+    pub status: JobStatus,
+    // Model a re-embedding job switches the documents to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<EmbeddingModel>,
+}
+
+// Handle shared between a database rebuild and the admin API
+pub struct RebuildJob {
+    id: u64,
+    status: Mutex<JobStatus>,
+    cancelled: AtomicBool,
+    throttle: OnceLock<Throttle>,
+    model: Option<EmbeddingModel>,
+}
+
+impl RebuildJob {
+    fn new(id: u64, model: Option<EmbeddingModel>) -> Self {
+        Self {
+            id,
+            status: Mutex::new(JobStatus::Queued),
+            cancelled: AtomicBool::new(false),
+            throttle: OnceLock::new(),
+            model,
+        }
+    }
+
+    // For rebuilds that nobody is monitoring
+    pub fn detached() -> Self {
+        Self::new(0, None)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Model to embed the documents with instead of the one currently used, for
+    // re-embedding jobs
+    pub fn model(&self) -> Option<&EmbeddingModel> {
+        self.model.as_ref()
+    }
+
+    pub fn status(&self) -> JobStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn info(&self) -> JobInfo {
+        JobInfo {
+            id: self.id,
+            status: self.status(),
+            model: self.model.clone(),
+        }
+    }
+
+    // Slows the job at its checkpoints from now on; only the first throttle applies
+    pub fn throttle(&self, throttle: Throttle) {
+        let _ = self.throttle.set(throttle);
+    }
+
+    // Records that `done` out of `total` units of work are complete.
+    // Fails once the job has been cancelled, so rebuilds stop at the next checkpoint.
+    pub fn progress(&self, done: usize, total: usize) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(
+                PirError::Cancelled(format!("Rebuild job {} was cancelled", self.id)).into(),
+            );
+        }
+        let percent = (done * 100 / total.max(1)).min(100) as u8;
+        *self.status.lock().unwrap() = JobStatus::Running { percent };
+        if let Some(throttle) = self.throttle.get() {
+            throttle.pause();
+        }
+        Ok(())
+    }
+
+    // Returns false if the job had already finished
+    pub fn cancel(&self) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if status.is_finished() {
+            return false;
+        }
+        if *status == JobStatus::Queued {
+            *status = JobStatus::Cancelled;
+        }
+        self.cancelled.store(true, Ordering::Relaxed);
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn finish(&self, result: &Result<()>) {
+        *self.status.lock().unwrap() = match result {
+            Ok(()) => JobStatus::Completed,
+            Err(_) if self.is_cancelled() => JobStatus::Cancelled,
+            Err(e) => JobStatus::Failed {
+                error: e.to_string(),
+            },
+        };
+    }
+}
+
+// Outcomes of finished rebuilds. A failed rebuild leaves the last good databases
+// serving, so these are what shows that the data has stopped moving.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RebuildHealth {
+    // Failures since the last successful rebuild
+    pub consecutive_failures: u64,
+    pub total_failures: u64,
+    // Unix timestamp (seconds) of the last successful rebuild
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl RebuildHealth {
+    // Counts a finished job; cancelled and unfinished ones are neither success nor failure
+    pub fn record(&mut self, status: &JobStatus, now: u64) {
+        match status {
+            JobStatus::Completed => {
+                self.consecutive_failures = 0;
+                self.last_success = Some(now);
+            }
+            JobStatus::Failed { error } => {
+                self.consecutive_failures += 1;
+                self.total_failures += 1;
+                self.last_error = Some(error.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+// Rebuild jobs in submission order. The receiving end is drained by a single worker.
+pub struct JobQueue {
+    sender: UnboundedSender<Arc<RebuildJob>>,
+    jobs: Mutex<VecDeque<Arc<RebuildJob>>>,
+    next_id: AtomicU64,
+}
+
+impl JobQueue {
+    pub fn new() -> (Self, UnboundedReceiver<Arc<RebuildJob>>) {
+        let (sender, receiver) = unbounded_channel();
+        let queue = Self {
+            sender,
+            jobs: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(1),
+        };
+        (queue, receiver)
+    }
+
+    // Queues a rebuild, or returns the one already waiting to run
+    pub fn enqueue(&self) -> JobInfo {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.iter().find(|job| job.status() == JobStatus::Queued) {
+            return job.info();
+        }
+        self.push(&mut jobs, None)
+    }
+
+    // Queues a rebuild that embeds the documents with `model`, or returns the one
+    // already waiting to switch to it
+    pub fn enqueue_reembed(&self, model: EmbeddingModel) -> JobInfo {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs
+            .iter()
+            .find(|job| job.status() == JobStatus::Queued && job.model() == Some(&model))
+        {
+            return job.info();
+        }
+        self.push(&mut jobs, Some(model))
+    }
+
+    // Queues a rebuild only if none is waiting or running, so a schedule that fires
+    // mid-rebuild skips that tick rather than starting another right after
+    pub fn enqueue_if_idle(&self) -> Option<JobInfo> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.iter().any(|job| !job.status().is_finished()) {
+            return None;
+        }
+        Some(self.push(&mut jobs, None))
+    }
+
+    fn push(&self, jobs: &mut VecDeque<Arc<RebuildJob>>, model: Option<EmbeddingModel>) -> JobInfo {
+        let job = Arc::new(RebuildJob::new(
+            self.next_id.fetch_add(1, Ordering::Relaxed),
+            model,
+        ));
+        jobs.push_back(Arc::clone(&job));
+        while jobs.len() > JOB_HISTORY && jobs.front().is_some_and(|job| job.status().is_finished())
+        {
+            jobs.pop_front();
+        }
+
+        // Only fails once the worker has shut down, in which case nothing will run it anyway
+        let _ = self.sender.send(Arc::clone(&job));
+        job.info()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<RebuildJob>> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().find(|job| job.id() == id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().map(|job| job.info()).collect()
+    }
+
+    pub fn latest(&self) -> Option<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.back().map(|job| job.info())
+    }
+
+    // Whether a rebuild is waiting or running
+    pub fn is_busy(&self) -> bool {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().any(|job| !job.status().is_finished())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_lifecycle() -> Result<()> {
+        let (queue, mut receiver) = JobQueue::new();
+
+        let first = queue.enqueue();
+        assert_eq!(first.status, JobStatus::Queued);
+        // A second request while one is waiting reuses it
+        assert_eq!(queue.enqueue().id, first.id);
+
+        let job = receiver.try_recv()?;
+        job.progress(1, 4)?;
+        assert_eq!(job.status(), JobStatus::Running { percent: 25 });
+
+        let second = queue.enqueue();
+        assert_ne!(second.id, first.id);
+        assert!(queue.get(second.id).unwrap().cancel());
+        assert_eq!(queue.latest().unwrap().status, JobStatus::Cancelled);
+
+        assert!(job.cancel());
+        assert!(job.progress(2, 4).is_err());
+        job.finish(&Err(PirError::Cancelled("test".to_string()).into()));
+        assert_eq!(job.status(), JobStatus::Cancelled);
+        assert!(!job.cancel());
+        assert_eq!(queue.list().len(), 2);
+
+        // Scheduled rebuilds skip their tick until the last one has finished
+        let (queue, mut receiver) = JobQueue::new();
+        let scheduled = queue.enqueue_if_idle().unwrap();
+        assert!(queue.enqueue_if_idle().is_none());
+        let job = receiver.try_recv()?;
+        job.progress(0, 1)?;
+        assert!(queue.enqueue_if_idle().is_none());
+        job.finish(&Ok(()));
+        assert_ne!(queue.enqueue_if_idle().unwrap().id, scheduled.id);
+
+        // Re-embeddings only reuse a queued job switching to the same model
+        let model = EmbeddingModel {
+            model: "BAAI/bge-small-en-v1.5".to_string(),
+            revision: None,
+            cls_pooling: true,
+        };
+        let reembed = queue.enqueue_reembed(model.clone());
+        assert_eq!(reembed.model.as_ref(), Some(&model));
+        assert_eq!(queue.enqueue_reembed(model.clone()).id, reembed.id);
+        assert_ne!(
+            queue.enqueue_reembed(EmbeddingModel::default()).id,
+            reembed.id
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_health() {
+        let mut health = RebuildHealth::default();
+        let failed = JobStatus::Failed {
+            error: "stocks script exited with 1".to_string(),
+        };
+        health.record(&failed, 10);
+        health.record(&JobStatus::Cancelled, 20);
+        health.record(&failed, 30);
+        assert_eq!(health.consecutive_failures, 2);
+        assert_eq!(health.last_success, None);
+        assert_eq!(
+            health.last_error.as_deref(),
+            Some("stocks script exited with 1")
+        );
+
+        health.record(&JobStatus::Completed, 40);
+        health.record(&failed, 50);
+        assert_eq!(health.consecutive_failures, 1);
+        assert_eq!(health.total_failures, 3);
+        assert_eq!(health.last_success, Some(40));
+    }
+}
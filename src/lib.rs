@@ -1,7 +1,54 @@
+pub mod accuracy;
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "baseline")]
+pub mod baseline;
+pub mod bloom;
+pub mod cache;
 pub mod client;
+pub mod clustering;
+pub mod config;
+pub mod correction;
+pub mod crypto;
+pub mod diagnostics;
+pub mod documents;
+pub mod embed_service;
 pub mod error;
+pub mod external;
+pub mod filters;
+pub mod fred;
+#[cfg(fuzzing)]
+pub mod fuzzing;
+pub mod in_process;
+pub mod integrity;
+pub mod jobs;
+pub mod market;
 pub mod network;
+pub mod packing;
+pub mod partitions;
+pub mod planner;
+pub mod pool;
+pub mod quantization;
+#[cfg(feature = "ohttp")]
+pub mod relay;
+pub mod replay;
+pub mod replicas;
+pub mod report;
+pub mod selftest;
 pub mod server;
+#[cfg(feature = "websocket")]
+pub mod session;
+pub mod similarity;
+pub mod snapshot;
+pub mod source;
+pub mod stream;
+pub mod templates;
+pub mod tiering;
+pub mod validation;
+pub mod watcher;
 
+mod dedup;
 mod embedding;
+mod ingest;
+mod pir;
 mod utils;
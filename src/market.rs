@@ -0,0 +1,265 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::str::FromStr;
+
+use crate::error::PirError;
+
+// Locale of the display strings stored next to numeric fields, e.g. `de-DE`
+const LOCALE_ENV_VAR: &str = "TIPTOE_LOCALE";
+// Machine-readable fields of a market record
+pub const PRICE_FIELD: &str = "currentPrice";
+pub const CURRENCY_FIELD: &str = "currency";
+pub const CHANGE_FIELD: &str = "changePercent";
+// Display forms derived from them
+pub const DISPLAY_PRICE_FIELD: &str = "displayPrice";
+pub const DISPLAY_CHANGE_FIELD: &str = "displayChange";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    EnUs,
+    EnGb,
+    DeDe,
+    FrFr,
+    JaJp,
+}
+
+impl FromStr for Locale {
+    type Err = PirError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.replace('_', "-").to_lowercase().as_str() {
+            "en-us" => Ok(Self::EnUs),
+            "en-gb" => Ok(Self::EnGb),
+            "de-de" => Ok(Self::DeDe),
+            "fr-fr" => Ok(Self::FrFr),
+            "ja-jp" => Ok(Self::JaJp),
+            _ => Err(PirError::InvalidInput(format!("Unsupported locale {}", s))),
+        }
+    }
+}
+
+impl Locale {
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(LOCALE_ENV_VAR) {
+            Ok(locale) => Ok(locale.parse()?),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    // Decimal separator and thousands separator
+    fn separators(&self) -> (char, char) {
+        match self {
+            Self::EnUs | Self::EnGb | Self::JaJp => ('.', ','),
+            Self::DeDe => (',', '.'),
+            // Narrow no-break space
+            Self::FrFr => (',', '\u{202f}'),
+        }
+    }
+
+    // "$1.00" rather than "1,00 $"
+    fn symbol_first(&self) -> bool {
+        matches!(self, Self::EnUs | Self::EnGb | Self::JaJp)
+    }
+}
+
+fn currency_symbol(code: &str) -> Option<&'static str> {
+    match code {
+        "USD" => Some("$"),
+        "EUR" => Some("€"),
+        "GBP" => Some("£"),
+        "JPY" => Some("¥"),
+        "CAD" => Some("CA$"),
+        "AUD" => Some("A$"),
+        "HKD" => Some("HK$"),
+        _ => None,
+    }
+}
+
+// Minor units shown for a price; sub-unit quotes such as exchange rates get more
+fn decimals(price: f64, currency: Option<&str>) -> usize {
+    match currency {
+        Some("JPY") => 0,
+        _ if price.abs() < 10.0 => 4,
+        _ => 2,
+    }
+}
+
+pub fn format_number(value: f64, decimals: usize, locale: Locale) -> String {
+    let (decimal, thousands) = locale.separators();
+    let formatted = format!("{:.*}", decimals, value.abs());
+    let (whole, fraction) = formatted.split_once('.').unwrap_or((&formatted, ""));
+
+    let mut grouped = String::new();
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i) % 3 == 0 {
+            grouped.push(thousands);
+        }
+        grouped.push(digit);
+    }
+    if !fraction.is_empty() {
+        grouped.push(decimal);
+        grouped.push_str(fraction);
+    }
+    if value < 0.0 && formatted.chars().any(|c| c.is_ascii_digit() && c != '0') {
+        grouped.insert(0, '-');
+    }
+    grouped
+}
+
+pub fn format_price(price: f64, currency: Option<&str>, locale: Locale) -> String {
+    let number = format_number(price.abs(), decimals(price, currency), locale);
+    let sign = if price < 0.0 { "-" } else { "" };
+    let Some(code) = currency else {
+        return format!("{}{}", sign, number);
+    };
+    match (currency_symbol(code), locale.symbol_first()) {
+        (Some(symbol), true) => format!("{}{}{}", sign, symbol, number),
+        (Some(symbol), false) => format!("{}{} {}", sign, number, symbol),
+        (None, true) => format!("{}{} {}", sign, code, number),
+        (None, false) => format!("{}{} {}", sign, number, code),
+    }
+}
+
+pub fn format_percent(change: f64, locale: Locale) -> String {
+    let sign = if change > 0.0 { "+" } else { "" };
+    let number = format_number(change, 2, locale);
+    match locale {
+        Locale::DeDe | Locale::FrFr => format!("{}{}\u{a0}%", sign, number),
+        _ => format!("{}{}%", sign, number),
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    let number = match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    };
+    number.filter(|number| number.is_finite())
+}
+
+// Typed view of a market record
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarketData {
+    pub name: String,
+    pub price: Option<f64>,
+    // ISO 4217 code the price is quoted in
+    pub currency: Option<String>,
+    pub change_percent: Option<f64>,
+}
+
+impl MarketData {
+    pub fn from_record(record: &Value) -> Option<Self> {
+        Some(Self {
+            name: record.get("name")?.as_str()?.to_string(),
+            price: record.get(PRICE_FIELD).and_then(number),
+            currency: record
+                .get(CURRENCY_FIELD)
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            change_percent: record.get(CHANGE_FIELD).and_then(number),
+        })
+    }
+
+    // From a record as the client returns it
+    pub fn parse(record: &str) -> Result<Self> {
+        let record: Value = serde_json::from_str(record.trim_end_matches('\0'))?;
+        Self::from_record(&record)
+            .ok_or_else(|| PirError::Encoding("Record has no name".to_string()).into())
+    }
+
+    pub fn display_price(&self, locale: Locale) -> Option<String> {
+        self.price
+            .map(|price| format_price(price, self.currency.as_deref(), locale))
+    }
+
+    pub fn display_change(&self, locale: Locale) -> Option<String> {
+        self.change_percent
+            .map(|change| format_percent(change, locale))
+    }
+}
+
+// Stores numeric prices and percent changes as JSON numbers, with their display forms
+// alongside. Idempotent, so it can be re-applied after prices change.
+pub fn annotate(documents: &mut [Value], locale: Locale) {
+    for document in documents.iter_mut() {
+        let Some(data) = MarketData::from_record(document) else {
+            continue;
+        };
+        let Value::Object(object) = document else {
+            continue;
+        };
+        if let Some(price) = data.price {
+            object.insert(PRICE_FIELD.to_string(), price.into());
+        }
+        if let Some(change) = data.change_percent {
+            object.insert(CHANGE_FIELD.to_string(), change.into());
+        }
+        for (field, display) in [
+            (DISPLAY_PRICE_FIELD, data.display_price(locale)),
+            (DISPLAY_CHANGE_FIELD, data.display_change(locale)),
+        ] {
+            match display {
+                Some(display) => object.insert(field.to_string(), display.into()),
+                None => object.remove(field),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_locale_formatting_and_annotation() -> Result<()> {
+        assert_eq!(format_price(1234.5, Some("USD"), Locale::EnUs), "$1,234.50");
+        assert_eq!(
+            format_price(1234.5, Some("EUR"), Locale::DeDe),
+            "1.234,50 €"
+        );
+        assert_eq!(
+            format_price(1234.5, Some("EUR"), Locale::FrFr),
+            "1\u{202f}234,50 €"
+        );
+        assert_eq!(
+            format_price(151234.0, Some("JPY"), Locale::JaJp),
+            "¥151,234"
+        );
+        assert_eq!(format_price(1.0823, Some("USD"), Locale::EnGb), "$1.0823");
+        assert_eq!(format_price(-3.5, Some("CHF"), Locale::EnUs), "-CHF 3.5000");
+        assert_eq!(format_price(250.1, None, Locale::EnUs), "250.10");
+        assert_eq!(format_percent(1.234, Locale::EnUs), "+1.23%");
+        assert_eq!(format_percent(-0.5, Locale::DeDe), "-0,50\u{a0}%");
+        assert_eq!(format_percent(-0.001, Locale::EnUs), "0.00%");
+        assert_eq!("de_DE".parse::<Locale>()?, Locale::DeDe);
+        assert!("xx-XX".parse::<Locale>().is_err());
+
+        let mut documents = vec![
+            json!({
+                "name": "Tesla, Inc.",
+                "currentPrice": "250.1",
+                "currency": "USD",
+                "changePercent": -1.5
+            }),
+            json!({"name": "US Unemployment Rate", "value": 4.0}),
+        ];
+        annotate(&mut documents, Locale::EnUs);
+        assert_eq!(documents[0]["currentPrice"], 250.1);
+        assert_eq!(documents[0]["displayPrice"], "$250.10");
+        assert_eq!(documents[0]["displayChange"], "-1.50%");
+        assert!(documents[1].get("displayPrice").is_none());
+
+        documents[0]["currentPrice"] = json!(251.0);
+        annotate(&mut documents, Locale::EnUs);
+        assert_eq!(documents[0]["displayPrice"], "$251.00");
+
+        let data = MarketData::parse(&format!("{}\0\0", documents[0]))?;
+        assert_eq!(data.name, "Tesla, Inc.");
+        assert_eq!(data.price, Some(251.0));
+        assert_eq!(data.currency.as_deref(), Some("USD"));
+        Ok(())
+    }
+}
@@ -1,251 +1,4111 @@
 use anyhow::Result;
 use async_trait::async_trait;
+#[cfg(feature = "websocket")]
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::{
-    extract::State,
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{DefaultBodyLimit, FromRequest, Path, Query, Request, State},
+    http::{
+        header::{
+            ACCEPT, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE,
+            RANGE, RETRY_AFTER,
+        },
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use nalgebra::{DMatrix, DVector};
 use num_bigint::BigInt;
 use num_traits::One;
-use reqwest::Client as HttpClient;
-use serde::{Deserialize, Serialize};
-use simplepir::{gen_params, generate_query, recover, SimplePIRParams};
-use std::{str::FromStr, sync::Arc, time::Duration};
-use tokio::{sync::RwLock, time::interval};
+use reqwest::{Client as HttpClient, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{mpsc::UnboundedReceiver, watch, Notify, RwLock},
+    time::interval,
+};
+use tower_http::timeout::TimeoutLayer;
+#[cfg(feature = "openapi")]
+use utoipa::OpenApi;
+#[cfg(feature = "openapi")]
+use utoipa_swagger_ui::SwaggerUi;
+
+#[cfg(feature = "websocket")]
+use crate::session::{SessionFrame, SessionRequest, SessionTarget};
+#[cfg(feature = "exchange-stream")]
+use crate::stream::run_stream;
+use crate::{
+    audit::{AccessRecord, AuditLog, Sealed},
+    auth::{Authorizer, Denial, DenialStats, Operation, TenantUsage, API_KEY_HEADER},
+    bloom::BloomParams,
+    clustering::{distance_fallback, ClusterQuality, DistanceMetric},
+    config::{ServerConfig, SourceConfig, SwapPolicy},
+    correction::QueryCorrection,
+    documents::{mapping_digest, DocumentId},
+    embedding::{check_model, BertEmbedder, EmbeddingModel},
+    error::PirError,
+    integrity::DatabaseDigest,
+    jobs::{JobInfo, JobQueue, JobStatus, RebuildHealth, RebuildJob},
+    packing::{BlockLayout, PackedLayout},
+    partitions::PartitionManifest,
+    pir::{self, SimplePIRParams},
+    pool::{ComputePool, PoolError, RebuildThreads, Throttle},
+    quantization::{Calibration, Quantization},
+    selftest,
+    server::{refresh_hot_tier, Database, DatabaseStats, HotRefresh, SimplePirDatabase},
+    snapshot::{parse_content_range, parse_range, Snapshot, Upload, SNAPSHOT_CONTENT_TYPE},
+    source::{refresh_snapshot, SNAPSHOT_DIR},
+    stream::TickStore,
+    tiering::HotInfo,
+    utils::{check_query_dim, fit_query, to_word, word_modulus},
+};
+
+// Connection reuse for the HTTP client shared by every RemoteDatabase
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+// Upper bound on any request; clients can ask for less with the deadline header
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Set to 1 to serve `/debug/rows`; only honored with an authorization policy
+const DEBUG_ROWS_ENV_VAR: &str = "TIPTOE_DEBUG_ROWS";
+// Sent with 503 when the compute pool is full or nothing has been built yet
+const RETRY_AFTER_SECS: u64 = 1;
+// Consecutive failed rebuilds after which `/ready` fails even though the last good
+// databases are still served; unset keeps it ready
+const MAX_REBUILD_FAILURES_ENV_VAR: &str = "TIPTOE_MAX_REBUILD_FAILURES";
+// Set to 1 to self-test every rebuilt database before it replaces the served one
+const SELFTEST_ENV_VAR: &str = "TIPTOE_SELFTEST";
+// Set to 1 to answer plaintext queries. INSECURE: clients in plaintext mode send their
+// query embeddings and records in the clear. For development only.
+const PLAINTEXT_MODE_ENV_VAR: &str = "TIPTOE_PLAINTEXT_MODE";
+// Largest chunk of a snapshot bundle `/admin/restore` takes per request
+const MAX_RESTORE_CHUNK: usize = 64 << 20;
+// Time the client is still willing to wait for the response, in milliseconds
+pub const DEADLINE_HEADER: &str = "x-tiptoe-deadline-ms";
+// Names this server's node, shared by the embedding and encoding servers running on
+// it; unset issues no session tokens
+const NODE_ID_ENV_VAR: &str = "TIPTOE_NODE_ID";
+// Session token of the node that answered a query's first request to a server, sent back
+// with the rest of its requests to that server so load balancers can keep them on that node
+pub const SESSION_HEADER: &str = "x-tiptoe-session";
+
+tokio::task_local! {
+    // Deadline of the query running on this task, forwarded with each request it makes
+    pub(crate) static DEADLINE: Instant;
+    // Session tokens of the query running on this task, by the base URL of the server
+    // that issued each
+    static SESSION: RefCell<BTreeMap<String, String>>;
+}
+
+// Accepted and returned on every route in place of JSON when the client asks for it
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+// PIR query bodies sent as little-endian u64 words, one per column, instead of a
+// QueryRequest; answered from the words without parsing them into BigInts
+pub const WORDS_CONTENT_TYPE: &str = "application/x-tiptoe-words";
 
-use crate::{embedding::BertEmbedder, server::Database};
+// Served at `/openapi.json`, with Swagger UI at `/docs`
+#[cfg(feature = "openapi")]
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    info(
+        title = "tiptoe-rs",
+        description = "Private search over SimplePIR. Query, hint and A payloads are BigInts encoded as decimal strings; matrices are column-major."
+    ),
+    paths(
+        handle_query,
+        handle_params,
+        handle_hint,
+        handle_hint_diff,
+        handle_a,
+        handle_digest,
+        handle_centroids,
+        handle_documents,
+        handle_documents_digest,
+        handle_ready,
+        handle_status,
+        handle_stats,
+        handle_jobs,
+        handle_rebuild,
+        handle_reembed,
+        handle_selftest,
+        handle_cancel_job,
+        handle_reload_config,
+        handle_snapshot,
+        handle_restore,
+        handle_restore_status,
+        handle_delete_document,
+        handle_debug_rows,
+        handle_cluster_query,
+        handle_cluster_members,
+        handle_cluster_params,
+        handle_cluster_hint,
+        handle_cluster_a,
+        handle_cluster_digest,
+        handle_partitions,
+        handle_partition_query,
+        handle_partition_params,
+        handle_partition_hint,
+        handle_partition_a,
+        handle_partition_digest,
+        handle_hot,
+        handle_hot_query,
+        handle_hot_params,
+        handle_hot_hint,
+        handle_hot_a,
+        handle_hot_digest,
+        handle_packed,
+        handle_packed_query,
+        handle_packed_params,
+        handle_packed_hint,
+        handle_packed_a,
+        handle_packed_digest,
+        handle_membership,
+        handle_membership_query,
+        handle_membership_params,
+        handle_membership_hint,
+        handle_membership_a,
+        handle_membership_digest,
+        handle_blocks,
+        handle_blocks_query,
+        handle_blocks_params,
+        handle_blocks_hint,
+        handle_blocks_a,
+        handle_blocks_digest,
+        handle_epoch_get,
+        handle_epoch_query
+    )
+)]
+pub struct ApiDoc;
 
 // Shared state for server
 pub struct ServerState<T: Database + Send + Sync> {
     db: RwLock<T>,
+    // The database the last rebuild or restore replaced, still answering under
+    // `/epochs/{epoch}` for the configured grace period
+    previous: RwLock<Option<T>>,
+    jobs: JobQueue,
+    // Woken when deletions push the dead rows past the compaction threshold
+    compaction: Notify,
+    // Epoch of the served database, watched by open sessions
+    epoch: watch::Sender<u64>,
+    auth: Option<Authorizer>,
+    // Watched by the rebuild and refresh schedules, which pick up reloads immediately
+    config: watch::Sender<ServerConfig>,
+    // Latest prices from exchange streams, applied on each hot refresh
+    ticks: Arc<TickStore>,
+    // Whether `/debug/rows` is mounted
+    debug_rows: bool,
+    // Whether plaintext queries are answered
+    plaintext: bool,
+    // Where queries are answered, off the runtime threads
+    pool: ComputePool,
+    rebuilds: Mutex<RebuildHealth>,
+    max_rebuild_failures: Option<u64>,
+    audit: Option<AuditLog>,
+    // Last snapshot bundle served, with its ETag
+    snapshot: Mutex<Option<(String, Bytes)>>,
+    // Snapshot bundle being uploaded to `/admin/restore`
+    upload: Mutex<Upload>,
+    // Unpinned queries in flight, by the epoch serving when they arrived
+    arrivals: Arrivals,
+    // Set while a swap waits for and holds the database locks
+    swapping: AtomicBool,
+    // Node named in the session tokens this server issues
+    node: Option<String>,
+    // Model rebuilds embed documents with since the last re-embedding; until then the
+    // configured one
+    model: Mutex<Option<EmbeddingModel>>,
+}
+
+// Counts of queries in flight by arrival epoch. The served epoch only moves under the
+// same lock, so a query either counts towards the epoch being retired or arrives at
+// the new one.
+#[derive(Default)]
+struct Arrivals {
+    counts: Mutex<BTreeMap<u64, usize>>,
+    drained: Notify,
+}
+
+// One query counted in `Arrivals` until dropped
+struct Arrival<'a> {
+    arrivals: &'a Arrivals,
+    epoch: u64,
+}
+
+impl Arrivals {
+    fn enter<'a>(&'a self, epoch: &watch::Sender<u64>) -> Arrival<'a> {
+        let mut counts = self.counts.lock().unwrap();
+        let epoch = *epoch.borrow();
+        *counts.entry(epoch).or_default() += 1;
+        Arrival {
+            arrivals: self,
+            epoch,
+        }
+    }
+
+    // Moves the served epoch to `next`, returning how many queries that arrived at the
+    // one it replaces are still in flight
+    fn advance(&self, epoch: &watch::Sender<u64>, next: u64) -> usize {
+        let counts = self.counts.lock().unwrap();
+        let retired = epoch.send_replace(next);
+        counts.get(&retired).copied().unwrap_or(0)
+    }
+
+    // Waits until no query that arrived at `epoch` is in flight
+    async fn drain(&self, epoch: u64) {
+        loop {
+            let drained = self.drained.notified();
+            if !self.counts.lock().unwrap().contains_key(&epoch) {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+impl Drop for Arrival<'_> {
+    fn drop(&mut self) {
+        let mut counts = self.arrivals.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.epoch) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.epoch);
+                self.arrivals.drained.notify_waiters();
+            }
+        }
+    }
+}
+
+// Request/Response types
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct QueryRequest {
+    // Serialized BigInt vector
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+    pub(crate) query: Sealed<Vec<String>>,
+    // The client's secret, sent only in degraded mode so the server can recover the
+    // answer itself. With it the server learns what was queried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<Vec<String>>))]
+    pub(crate) secret: Option<Sealed<Vec<String>>>,
+    // Set in plaintext mode: `query` is the unencrypted query vector, answered over the
+    // integers with no PIR at all. Only servers in plaintext mode accept it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) plaintext: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct QueryResponse {
+    pub(crate) response: Vec<String>,
+    // Recovered answer, for requests that carried their secret, or the plaintext answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) recovered: Option<Vec<String>>,
+    // Row with the highest plaintext answer, for plaintext requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) best: Option<usize>,
+}
+
+// Answers `request` with `response` from a database with this hint and params,
+// recovering it too when the client sent its secret
+fn query_response(
+    request: &QueryRequest,
+    response: &DVector<BigInt>,
+    hint: &DMatrix<BigInt>,
+    params: &SimplePIRParams,
+) -> Result<QueryResponse, StatusCode> {
+    let recovered = match &request.secret {
+        Some(secret) => {
+            let secret =
+                deserialize_vector(secret.expose()).map_err(|_| StatusCode::BAD_REQUEST)?;
+            if secret.len() != params.n {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            Some(serialize_vector(&pir::recover(
+                params, hint, &secret, response,
+            )))
+        }
+        None => None,
+    };
+    Ok(QueryResponse {
+        response: serialize_vector(response),
+        recovered,
+        best: None,
+    })
+}
+
+// Row with the highest plaintext answer; ties go to the first row
+fn best_row(answer: &DVector<BigInt>) -> Option<usize> {
+    answer
+        .iter()
+        .enumerate()
+        .rev()
+        .max_by(|(_, a), (_, b)| a.cmp(b))
+        .map(|(row, _)| row)
+}
+
+// Answers a plaintext request with the database's rows times the query, skipping PIR
+fn plaintext_response(
+    database: &SimplePirDatabase,
+    query: &DVector<BigInt>,
+) -> Result<QueryResponse, StatusCode> {
+    let answer = database
+        .answer_plaintext(query)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let best = best_row(&answer);
+    Ok(QueryResponse {
+        response: Vec::new(),
+        recovered: Some(serialize_vector(&answer)),
+        best,
+    })
+}
+
+// Why a PIR query went unanswered
+enum QueryError {
+    Status(StatusCode),
+    // The compute pool is full; answered with 503 and Retry-After
+    Busy,
+    // The database the query arrived at is being or has been replaced; answered with
+    // 503, Retry-After and an `EpochChangingResponse`
+    EpochChanging(u64),
+}
+
+// Body of the 503 a query gets under `SwapPolicy::Reject` when the database it
+// arrived at is being replaced, or once that database is no longer served
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct EpochChangingResponse {
+    // Always "epoch_changing"
+    pub error: String,
+    // Epoch the query arrived at
+    pub epoch: u64,
+}
+
+const EPOCH_CHANGING: &str = "epoch_changing";
+
+impl From<StatusCode> for QueryError {
+    fn from(status: StatusCode) -> Self {
+        Self::Status(status)
+    }
+}
+
+impl From<PoolError> for QueryError {
+    fn from(error: PoolError) -> Self {
+        match error {
+            PoolError::Saturated => Self::Busy,
+            PoolError::Panicked => Self::Status(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+impl IntoResponse for QueryError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Status(status) => status.into_response(),
+            Self::Busy => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+            )
+                .into_response(),
+            Self::EpochChanging(epoch) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+                Json(EpochChangingResponse {
+                    error: EPOCH_CHANGING.to_string(),
+                    epoch,
+                }),
+            )
+                .into_response(),
+        }
+    }
+}
+
+// A PIR query body: a QueryRequest, or the bare query as words
+enum QueryBody {
+    Request(QueryRequest),
+    Words(Bytes),
+}
+
+impl<S: Send + Sync> FromRequest<S> for QueryBody {
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if has_media_type(request.headers().get(CONTENT_TYPE), WORDS_CONTENT_TYPE) {
+            let words = Bytes::from_request(request, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Self::Words(words));
+        }
+        let Json(request) = Json::<QueryRequest>::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Ok(Self::Request(request))
+    }
+}
+
+// Answers `body` on the compute pool from the database `select` picks, under the
+// read lock
+async fn answer<T, F>(
+    state: &Arc<ServerState<T>>,
+    body: QueryBody,
+    select: F,
+) -> Result<Json<QueryResponse>, QueryError>
+where
+    T: Database + Send + Sync + 'static,
+    F: for<'a> FnOnce(&'a T) -> Result<&'a SimplePirDatabase, StatusCode> + Send + 'static,
+{
+    answer_at(state, None, body, select).await
+}
+
+// `answer` from the database serving `epoch`, or for None the one the swap policy
+// picks: the database serving when the query arrived, or under `SwapPolicy::Reject`
+// the current one as long as nothing was swapped in since
+async fn answer_at<T, F>(
+    state: &Arc<ServerState<T>>,
+    epoch: Option<u64>,
+    body: QueryBody,
+    select: F,
+) -> Result<Json<QueryResponse>, QueryError>
+where
+    T: Database + Send + Sync + 'static,
+    F: for<'a> FnOnce(&'a T) -> Result<&'a SimplePirDatabase, StatusCode> + Send + 'static,
+{
+    let policy = state.config.borrow().swap;
+    let arrival = epoch.is_none().then(|| state.arrivals.enter(&state.epoch));
+    let arrived = arrival.as_ref().map(|arrival| arrival.epoch);
+    if let Some(arrived) = arrived {
+        if policy == SwapPolicy::Reject && state.swapping.load(Ordering::Acquire) {
+            return Err(QueryError::EpochChanging(arrived));
+        }
+    }
+    let (target, required) = match policy {
+        SwapPolicy::Complete => (epoch.or(arrived), None),
+        SwapPolicy::Reject => (epoch, arrived),
+    };
+    // A database other than the one required is answered like a retired epoch
+    let swapped = move |db: &T| required.is_some_and(|required| db.epoch() != required);
+
+    let pool_state = Arc::clone(state);
+    let answered = match body {
+        QueryBody::Request(request) => {
+            if request.plaintext && !state.plaintext {
+                return Err(QueryError::Status(StatusCode::FORBIDDEN));
+            }
+            let query =
+                deserialize_vector(request.query.expose()).map_err(|_| StatusCode::BAD_REQUEST)?;
+            state
+                .pool
+                .run(move || {
+                    with_database_blocking(&pool_state, target, |db| {
+                        if swapped(db) {
+                            return Err(StatusCode::GONE);
+                        }
+                        let database = select(db)?;
+                        if request.plaintext {
+                            return plaintext_response(database, &query);
+                        }
+                        // Queries encrypted elsewhere may not fit the database
+                        if query.len() != database.dims().1 {
+                            return Err(StatusCode::BAD_REQUEST);
+                        }
+                        let response = database
+                            .respond(&query)
+                            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                        query_response(&request, &response, database.hint(), database.params())
+                    })
+                })
+                .await?
+        }
+        QueryBody::Words(words) => {
+            state
+                .pool
+                .run(move || {
+                    with_database_blocking(&pool_state, target, |db| {
+                        if swapped(db) {
+                            return Err(StatusCode::GONE);
+                        }
+                        let database = select(db)?;
+                        if !database.accepts_words() {
+                            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                        }
+                        let response = database
+                            .respond_words(&words)
+                            .map_err(|_| StatusCode::BAD_REQUEST)?;
+                        Ok(QueryResponse {
+                            response: serialize_vector(&response),
+                            recovered: None,
+                            best: None,
+                        })
+                    })
+                })
+                .await?
+        }
+    };
+    drop(arrival);
+    // Only pinned queries asked for an epoch by name; the rest learn it is changing
+    let response = answered.map_err(|status| match (status, arrived) {
+        (StatusCode::GONE, Some(arrived)) => QueryError::EpochChanging(arrived),
+        (status, _) => QueryError::Status(status),
+    })?;
+    Ok(Json(response))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ParamsData {
+    // Query length, i.e. the database's columns
+    pub m: usize,
+    // LWE dimension
+    pub n: usize,
+    pub q: String,
+    pub p: String,
+    pub epoch: u64,
+    #[serde(default)]
+    clusters: Vec<ClusterDims>,
+    // Set by embedding databases; queries must be quantized the same way
+    #[serde(default)]
+    quantization: Option<Quantization>,
+    // Fitted by embedding databases at build time
+    #[serde(default)]
+    calibration: Option<Calibration>,
+    // Fitted by embedding databases configured with `query_correction`; applied to
+    // query embeddings before they are quantized
+    #[serde(default)]
+    correction: Option<QueryCorrection>,
+    // Set by embedding databases; put in front of query text before it is embedded
+    #[serde(default)]
+    query_prefix: Option<String>,
+    // Set by embedding databases; query embeddings must be exactly this wide
+    #[serde(default)]
+    query_dim: Option<usize>,
+    // Set by embedding databases; queries must be embedded with this model
+    #[serde(default)]
+    model: Option<EmbeddingModel>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ClusterDims {
+    rows: usize,
+    cols: usize,
+}
+
+// Public cluster layout: what a client needs to pick its nearest cluster, and nothing
+// about which document is in which cluster
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CentroidsData {
+    pub(crate) centroids: Vec<Vec<f32>>,
+    // Documents in each cluster
+    pub(crate) sizes: Vec<usize>,
+    // Length of each centroid, which query embeddings must match
+    pub(crate) dim: usize,
+    pub(crate) metric: DistanceMetric,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct StatusResponse {
+    epoch: u64,
+    clusters: usize,
+    cluster_quality: Option<ClusterQuality>,
+    // Why clustering distances fell back to the scalar path, if they did
+    #[serde(default)]
+    distance_fallback: Option<String>,
+    // Most recent rebuild job, if any has been queued
+    rebuild: Option<JobInfo>,
+    // Requests refused by the authorization policy, if one is set
+    denials: Option<DenialStats>,
+    // Usage and quota left of each tenant in the policy
+    #[serde(default)]
+    tenants: Vec<TenantUsage>,
+    rebuilds: RebuildHealth,
+}
+
+// Answered with 503 until a database has been built, or once rebuilds have failed
+// more than TIPTOE_MAX_REBUILD_FAILURES times in a row
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ReadyResponse {
+    pub ready: bool,
+    // Epoch of the databases being served, 0 before the first successful rebuild
+    pub epoch: u64,
+    // A rebuild is running and competing with queries for the CPU
+    #[serde(default)]
+    pub rebuilding: bool,
+    pub rebuilds: RebuildHealth,
+    // Node named in this server's session tokens, if it issues any
+    #[serde(default)]
+    pub node: Option<String>,
+}
+
+// Stable id of the document in each row; changes with every rebuild
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DocumentsResponse {
+    epoch: u64,
+    digest: String,
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<String>))]
+    ids: Vec<DocumentId>,
+    // Deleted rows that are still in the matrix; clients skip them
+    #[serde(default)]
+    dead_rows: BTreeSet<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DebugRow {
+    row: usize,
+    #[cfg_attr(feature = "openapi", schema(value_type = String))]
+    id: DocumentId,
+    // The document's name, as hashed into the id's key
+    key: String,
+    // Cluster the row is served from, on clustered databases
+    cluster: Option<usize>,
+    dead: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DebugRowsResponse {
+    epoch: u64,
+    rows: Vec<DebugRow>,
+}
+
+// Progress of a snapshot upload. An interrupted upload resumes with the chunk
+// starting at `received`.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RestoreStatus {
+    received: usize,
+    // Size of the bundle being uploaded, if an upload is in progress
+    total: Option<usize>,
+    // Set once the whole bundle has arrived and is being served
+    restored: bool,
+    epoch: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DeleteResponse {
+    dead_rows: usize,
+    compaction_pending: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DigestResponse {
+    epoch: u64,
+    digest: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct StatsResponse {
+    databases: Vec<DatabaseStats>,
+    // Data, hint and A across all databases
+    total_bytes: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct MatrixResponse {
+    pub rows: usize,
+    pub cols: usize,
+    // Column-major
+    pub data: Vec<String>,
+}
+
+// Helper functions for serialization
+pub(crate) fn serialize_vector(vec: &DVector<BigInt>) -> Vec<String> {
+    vec.iter().map(|x| x.to_string()).collect()
+}
+
+// A query as the body of a WORDS_CONTENT_TYPE request to a database with `params`.
+// None if its modulus exceeds 64 bits, in which case it only accepts QueryRequests.
+pub fn serialize_words(vec: &DVector<BigInt>, params: &SimplePIRParams) -> Option<Vec<u8>> {
+    let q = BigInt::from(word_modulus(params)?);
+    let words = vec
+        .iter()
+        .map(|x| to_word(x, &q))
+        .collect::<Option<Vec<u64>>>()?;
+    Some(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+}
+
+// Queries and answers come from the other side of the connection, so malformed
+// elements are an error rather than a panic
+pub(crate) fn deserialize_vector(vec: &[String]) -> Result<DVector<BigInt>> {
+    let values = vec
+        .iter()
+        .map(|x| {
+            x.parse()
+                .map_err(|_| PirError::Encoding("Invalid vector element".to_string()))
+        })
+        .collect::<Result<Vec<BigInt>, _>>()?;
+    Ok(DVector::from_vec(values))
+}
+
+fn serialize_matrix(matrix: &DMatrix<BigInt>) -> MatrixResponse {
+    MatrixResponse {
+        rows: matrix.nrows(),
+        cols: matrix.ncols(),
+        data: matrix.iter().map(|x| x.to_string()).collect(),
+    }
+}
+
+// Like vectors, matrices come from the server, so a bad element or a shape that doesn't
+// match the data is an error
+pub(crate) fn deserialize_matrix(response: &MatrixResponse) -> Result<DMatrix<BigInt>> {
+    parse_matrix(response.rows, response.cols, &response.data)
+}
+
+// A `rows` x `cols` matrix from decimal strings in column-major order
+pub(crate) fn parse_matrix(rows: usize, cols: usize, data: &[String]) -> Result<DMatrix<BigInt>> {
+    if rows.checked_mul(cols) != Some(data.len()) {
+        return Err(PirError::Encoding("Matrix shape does not match its data".to_string()).into());
+    }
+    let data = data
+        .iter()
+        .map(|x| {
+            x.parse()
+                .map_err(|_| PirError::Encoding("Invalid matrix element".to_string()))
+        })
+        .collect::<Result<Vec<BigInt>, _>>()?;
+    Ok(DMatrix::from_vec(rows, cols, data))
+}
+
+// Hint rows that changed between two epochs sharing the same A. A client holding the
+// hint of `from` overwrites each row in `rows` with the matching row of `values` to
+// get the hint of `epoch`.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct HintDiff {
+    pub from: u64,
+    pub epoch: u64,
+    pub rows: Vec<usize>,
+    pub values: MatrixResponse,
+}
+
+impl HintDiff {
+    pub fn apply(&self, hint: &mut DMatrix<BigInt>) -> Result<()> {
+        let values = deserialize_matrix(&self.values)?;
+        if values.nrows() != self.rows.len()
+            || values.ncols() != hint.ncols()
+            || self.rows.iter().any(|&row| row >= hint.nrows())
+        {
+            return Err(PirError::Database("Hint diff does not fit the hint".to_string()).into());
+        }
+        for (i, &row) in self.rows.iter().enumerate() {
+            hint.set_row(row, &values.row(i));
+        }
+        Ok(())
+    }
+}
+
+fn serialize_params(
+    params: &SimplePIRParams,
+    epoch: u64,
+    cluster_dims: Vec<(usize, usize)>,
+) -> ParamsData {
+    ParamsData {
+        m: params.m,
+        n: params.n,
+        q: params.q.to_string(),
+        p: params.p.to_string(),
+        epoch,
+        clusters: cluster_dims
+            .into_iter()
+            .map(|(rows, cols)| ClusterDims { rows, cols })
+            .collect(),
+        quantization: None,
+        calibration: None,
+        correction: None,
+        query_prefix: None,
+        query_dim: None,
+        model: None,
+    }
+}
+
+// Params of a server's main database, with what clients need to build queries for it
+fn main_params<T: Database>(db: &T) -> ParamsData {
+    ParamsData {
+        quantization: db.quantization(),
+        calibration: db.calibration(),
+        correction: db.query_correction(),
+        query_prefix: db.query_prefix(),
+        query_dim: db.query_dim(),
+        model: db.embedding_model(),
+        ..serialize_params(db.params(), db.epoch(), db.cluster_dims())
+    }
+}
+
+// The plaintext modulus is 2^mod_power, so anything but a power of two of at least 2 is
+// rejected rather than rounded
+pub(crate) fn deserialize_params(data: &ParamsData) -> Result<SimplePIRParams> {
+    let p = BigInt::from_str(&data.p)
+        .map_err(|_| PirError::Encoding("Invalid plaintext modulus".to_string()))?;
+    let mod_power = p.bits().saturating_sub(1);
+    if mod_power == 0 || p != BigInt::from(1) << mod_power {
+        return Err(
+            PirError::Encoding("Plaintext modulus must be a power of two".to_string()).into(),
+        );
+    }
+    let mod_power = u32::try_from(mod_power)
+        .map_err(|_| PirError::Encoding("Plaintext modulus is too large".to_string()))?;
+    Ok(pir::params(data.m, data.n, mod_power))
+}
+
+fn server_state<T: Database + Send + Sync>(
+    db: T,
+) -> (Arc<ServerState<T>>, UnboundedReceiver<Arc<RebuildJob>>) {
+    let (jobs, queued) = JobQueue::new();
+    // Refuse to serve at all rather than serve unprotected with a broken policy
+    let auth = Authorizer::from_env().expect("Failed to load authorization policy");
+    // The row listing is an admin route, so without a policy there is nothing to
+    // protect it
+    let debug_rows = std::env::var(DEBUG_ROWS_ENV_VAR).is_ok_and(|value| value == "1");
+    if debug_rows && auth.is_none() {
+        eprintln!(
+            "Not serving /debug/rows: {} requires an authorization policy",
+            DEBUG_ROWS_ENV_VAR
+        );
+    }
+    if auth.is_none() {
+        eprintln!("Not serving /admin/restore: it requires an authorization policy");
+    }
+    let plaintext = std::env::var(PLAINTEXT_MODE_ENV_VAR).is_ok_and(|value| value == "1");
+    if plaintext {
+        eprintln!(
+            "WARNING: {} is set; plaintext queries reveal exactly what clients search for",
+            PLAINTEXT_MODE_ENV_VAR
+        );
+    }
+    let state = Arc::new(ServerState {
+        epoch: watch::channel(db.epoch()).0,
+        db: RwLock::new(db),
+        previous: RwLock::new(None),
+        jobs,
+        compaction: Notify::new(),
+        debug_rows: debug_rows && auth.is_some(),
+        plaintext,
+        auth,
+        config: watch::channel(ServerConfig::from_env().expect("Failed to load server config")).0,
+        ticks: Arc::new(TickStore::default()),
+        pool: ComputePool::from_env().expect("Invalid compute pool settings"),
+        rebuilds: Mutex::new(RebuildHealth::default()),
+        max_rebuild_failures: std::env::var(MAX_REBUILD_FAILURES_ENV_VAR)
+            .ok()
+            .map(|value| {
+                value
+                    .trim()
+                    .parse()
+                    .expect("Invalid TIPTOE_MAX_REBUILD_FAILURES")
+            }),
+        audit: AuditLog::from_env().expect("Failed to open audit log"),
+        snapshot: Mutex::new(None),
+        upload: Mutex::new(Upload::default()),
+        arrivals: Arrivals::default(),
+        swapping: AtomicBool::new(false),
+        node: std::env::var(NODE_ID_ENV_VAR).ok().inspect(|node| {
+            // Sent in a header as is
+            assert!(
+                !node.is_empty() && node.bytes().all(|b| b.is_ascii_graphic()),
+                "Invalid TIPTOE_NODE_ID"
+            );
+        }),
+        model: Mutex::new(None),
+    });
+    (state, queued)
+}
+
+// Restarts `interval` with a new period, first firing one period from now rather than
+// immediately, so a reload does not trigger an extra rebuild. Unchanged periods are
+// left alone.
+fn reschedule(interval: &mut tokio::time::Interval, period: Duration) {
+    if interval.period() != period {
+        *interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    }
+}
+
+// Runs the tasks `spawn` starts for the part of the config `select` picks, restarting
+// them only when a reload changes that part
+async fn supervise<K, F>(
+    mut config: watch::Receiver<ServerConfig>,
+    select: fn(&ServerConfig) -> K,
+    spawn: F,
+) where
+    K: PartialEq + Send,
+    F: Fn(&K) -> Vec<tokio::task::JoinHandle<()>>,
+{
+    loop {
+        let current = select(&config.borrow_and_update());
+        let tasks = spawn(&current);
+        loop {
+            if config.changed().await.is_err() {
+                return;
+            }
+            if select(&config.borrow_and_update()) != current {
+                break;
+            }
+        }
+        for task in tasks {
+            task.abort();
+        }
+    }
+}
+
+// Fetches one source into its snapshot on the source's own schedule. Failures are only
+// logged: the source keeps serving its last snapshot and the others are unaffected.
+async fn refresh_source<T: Database + Send + Sync>(
+    state: Arc<ServerState<T>>,
+    source: SourceConfig,
+) {
+    let mut interval = tokio::time::interval(source.interval());
+    loop {
+        interval.tick().await;
+        let fetch = source.clone();
+        // Checked against the current config, so reloaded rules apply from the next fetch
+        let validation = state.config.borrow().validation.clone();
+        let refresh = tokio::task::spawn_blocking(move || {
+            refresh_snapshot(std::path::Path::new(SNAPSHOT_DIR), &fetch, &validation)
+        })
+        .await;
+        match refresh {
+            Ok(Ok(true)) if source.rebuild => {
+                state.jobs.enqueue();
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("Error refreshing source {}: {:?}", source.name, e),
+            Err(e) => eprintln!("Refresh of source {} panicked: {:?}", source.name, e),
+        }
+    }
+}
+
+// Re-reads the config and hands it to the schedules. On error the old one stays in
+// effect.
+fn reload_config<T: Database + Send + Sync>(state: &ServerState<T>) -> Result<ServerConfig> {
+    let config = ServerConfig::from_env()?;
+    state.config.send_if_modified(|current| {
+        let modified = *current != config;
+        *current = config.clone();
+        modified
+    });
+    println!("Reloaded config: {:?}", config);
+    Ok(config)
+}
+
+// Router serving `db` without the background rebuilds, hot refreshes and compaction
+// of `run_server`, for calling a server in-process
+pub fn router<T: Database + Send + Sync + 'static>(db: T) -> Router {
+    routes(server_state(db).0)
+}
+
+pub async fn run_server<T: Database + Send + Sync + 'static>(db: T, port: u16) {
+    let rebuild_threads = RebuildThreads::from_env().expect("Invalid rebuild thread limit");
+    let (state, mut queued) = server_state(db);
+
+    #[cfg(unix)]
+    {
+        let reload_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    eprintln!("Cannot listen for SIGHUP, config reloads disabled: {:?}", e);
+                    return;
+                }
+            };
+            while hangups.recv().await.is_some() {
+                if let Err(e) = reload_config(&reload_state) {
+                    eprintln!("Error reloading config: {:?}", e);
+                }
+            }
+        });
+    }
+
+    // Periodic rebuilds go through the same queue as ones requested via the admin API.
+    // A tick that finds a rebuild waiting or running is skipped.
+    let schedule_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        let mut config = schedule_state.config.subscribe();
+        let mut interval =
+            tokio::time::interval(config.borrow_and_update().cold_rebuild_interval());
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    schedule_state.jobs.enqueue_if_idle();
+                }
+                Ok(()) = config.changed() => {
+                    let period = config.borrow_and_update().cold_rebuild_interval();
+                    reschedule(&mut interval, period);
+                }
+            }
+        }
+    });
+
+    // Between rebuilds only the hot tier is refreshed. If documents were added or
+    // removed the rows no longer line up, so a full rebuild is queued instead.
+    let hot_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        let mut config = hot_state.config.subscribe();
+        let mut interval = tokio::time::interval(config.borrow_and_update().hot_refresh_interval());
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                Ok(()) = config.changed() => {
+                    let period = config.borrow_and_update().hot_refresh_interval();
+                    reschedule(&mut interval, period);
+                    continue;
+                }
+            }
+            if hot_state.jobs.is_busy() {
+                continue;
+            }
+
+            let (ids, dead, fields) = {
+                let db = hot_state.db.read().await;
+                (
+                    db.document_ids().to_vec(),
+                    db.dead_rows().clone(),
+                    db.hot().map(|hot| hot.fields.clone()),
+                )
+            };
+            let served = ids.clone();
+            let ticks = Arc::clone(&hot_state.ticks);
+            let refresh = tokio::task::spawn_blocking(move || {
+                refresh_hot_tier(&ids, &dead, fields.as_deref(), &ticks)
+            })
+            .await;
+
+            match refresh {
+                Ok(Ok(HotRefresh::Current(Some(tier)))) => {
+                    let mut db = hot_state.db.write().await;
+                    // A rebuild or compaction may have moved the rows in the meantime
+                    if db.document_ids() == served {
+                        db.set_hot(*tier);
+                    }
+                }
+                Ok(Ok(HotRefresh::Current(None))) => {}
+                Ok(Ok(HotRefresh::Stale)) => {
+                    hot_state.jobs.enqueue();
+                }
+                Ok(Err(e)) => eprintln!("Error refreshing hot tier: {:?}", e),
+                Err(e) => eprintln!("Hot tier refresh panicked: {:?}", e),
+            }
+        }
+    });
+
+    // Configured sources are each fetched on their own schedule into their snapshots,
+    // which the next hot refresh or rebuild serves. Their schedules restart only when
+    // the sources themselves change on a reload.
+    let sources_state = Arc::clone(&state);
+    tokio::spawn(supervise(
+        state.config.subscribe(),
+        |config| config.sources.clone(),
+        move |sources| {
+            sources
+                .iter()
+                .map(|source| {
+                    tokio::spawn(refresh_source(Arc::clone(&sources_state), source.clone()))
+                })
+                .collect()
+        },
+    ));
+
+    // Exchange streams keep their latest ticks in memory until the next hot refresh
+    #[cfg(feature = "exchange-stream")]
+    {
+        let ticks = Arc::clone(&state.ticks);
+        tokio::spawn(supervise(
+            state.config.subscribe(),
+            |config| config.streams.clone(),
+            move |streams| {
+                streams
+                    .iter()
+                    .map(|stream| tokio::spawn(run_stream(stream.clone(), Arc::clone(&ticks))))
+                    .collect()
+            },
+        ));
+    }
+    #[cfg(not(feature = "exchange-stream"))]
+    if !state.config.borrow().streams.is_empty() {
+        eprintln!("Ignoring configured streams: built without the exchange-stream feature");
+    }
+
+    // A rebuild that fails its self-test counts as failed, so the last good databases
+    // keep serving
+    let self_test = std::env::var(SELFTEST_ENV_VAR).is_ok_and(|value| value == "1");
+    let update_state = Arc::clone(&state);
+    let update_threads = rebuild_threads.clone();
+    tokio::spawn(async move {
+        while let Some(job) = queued.recv().await {
+            if job.is_cancelled() {
+                continue;
+            }
+            println!("Starting database update (job {})...", job.id());
+            job.throttle(Throttle::new(
+                update_state.config.borrow().rebuild.clone(),
+                update_state.pool.clone(),
+            ));
+
+            // The new database keeps the served one's A where it can, so clients patch
+            // their hint rather than downloading it again
+            let base = update_state.db.read().await.database().hint_base();
+            // Re-embeddings switch every later rebuild to their model once they succeed
+            let model = job
+                .model()
+                .cloned()
+                .or_else(|| update_state.model.lock().unwrap().clone());
+            let build_job = Arc::clone(&job);
+            let threads = update_threads.clone();
+            let result = match tokio::task::spawn_blocking(move || {
+                // Build a new instance using T::new() followed by T::rebuild(), while the
+                // served one keeps answering
+                threads.install(|| {
+                    let instance = match model {
+                        Some(model) => T::with_embedding_model(model),
+                        None => T::new(),
+                    };
+                    instance.and_then(|mut instance| {
+                        if let Some(base) = base {
+                            instance.set_hint_base(base);
+                        }
+                        instance.rebuild(&build_job)?;
+                        if self_test {
+                            let report = selftest::run(&instance)?;
+                            println!(
+                                "Self-test passed for {} databases in {:.0} ms",
+                                report.checked.len(),
+                                report.elapsed_ms
+                            );
+                        }
+                        Ok(instance)
+                    })
+                })
+            })
+            .await
+            {
+                Ok(Ok(new_instance)) => {
+                    swap_in(&update_state, new_instance).await;
+                    if let Some(model) = job.model() {
+                        println!("Now embedding documents with {}", model.model);
+                        if let Err(e) = model.save_switched() {
+                            eprintln!(
+                                "Error saving the embedding model, a restart reverts it: {:?}",
+                                e
+                            );
+                        }
+                        *update_state.model.lock().unwrap() = Some(model.clone());
+                    }
+                    Ok(())
+                }
+                Ok(Err(e)) => Err(e),
+                Err(e) => {
+                    Err(PirError::Database(format!("Blocking task panicked: {:?}", e)).into())
+                }
+            };
+
+            match &result {
+                Ok(()) => println!("Database update complete!"),
+                Err(e) => eprintln!(
+                    "Error building new database, still serving epoch {}: {:?}",
+                    *update_state.epoch.borrow(),
+                    e
+                ),
+            }
+            job.finish(&result);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            update_state
+                .rebuilds
+                .lock()
+                .unwrap()
+                .record(&job.status(), now);
+        }
+    });
+
+    // Compaction builds the compacted database from a snapshot of the served one, off the
+    // lock like a rebuild, and swaps it in only if no rebuild or restore got there first
+    let compaction_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        loop {
+            compaction_state.compaction.notified().await;
+            let (snapshot, epoch) = {
+                let db = compaction_state.db.read().await;
+                if !db.needs_compaction() {
+                    continue;
+                }
+                println!("Compacting {} dead rows...", db.dead_rows().len());
+                (db.snapshot(), db.epoch())
+            };
+            let model = compaction_state.model.lock().unwrap().clone();
+            let threads = rebuild_threads.clone();
+            let compacted = tokio::task::spawn_blocking(move || {
+                threads.install(|| {
+                    let mut instance = match model {
+                        Some(model) => T::with_embedding_model(model),
+                        None => T::new(),
+                    }?;
+                    instance.restore(snapshot?)?;
+                    instance.compact()?;
+                    Ok::<_, anyhow::Error>(instance)
+                })
+            })
+            .await;
+            match compacted {
+                Ok(Ok(instance)) => match swap_in_over(&compaction_state, instance, epoch).await {
+                    Some(_) => println!("Compaction complete!"),
+                    None => println!("Epoch {} was replaced while compacting it", epoch),
+                },
+                Ok(Err(e)) => eprintln!("Error compacting database: {:?}", e),
+                Err(e) => eprintln!("Compaction panicked: {:?}", e),
+            }
+        }
+    });
+
+    let app = routes(state);
+    let addr = format!("0.0.0.0:{}", port).parse().unwrap();
+    println!("Starting server on {}", addr);
+
+    axum_server::bind(addr)
+        .serve(app.into_make_service())
+        .await
+        .unwrap();
+}
+
+// Starts serving `db`, returning its epoch. The database it replaces keeps answering
+// under `/epochs/{epoch}` until the configured grace period runs out or another swap
+// replaces it in turn. Under `SwapPolicy::Complete` it is also kept, whatever the grace
+// period, until the queries that arrived while it was served are answered.
+async fn swap_in<T: Database + Send + Sync + 'static>(state: &Arc<ServerState<T>>, db: T) -> u64 {
+    install(state, db, None)
+        .await
+        .expect("an unconditional swap always installs")
+}
+
+// `swap_in` for a database built from `epoch`, such as a compacted one. Nothing is swapped
+// unless `epoch` is still the one served; rows deleted from it in the meantime are deleted
+// from `db` before it starts serving.
+async fn swap_in_over<T: Database + Send + Sync + 'static>(
+    state: &Arc<ServerState<T>>,
+    db: T,
+    epoch: u64,
+) -> Option<u64> {
+    install(state, db, Some(epoch)).await
+}
+
+async fn install<T: Database + Send + Sync + 'static>(
+    state: &Arc<ServerState<T>>,
+    mut db: T,
+    replaces: Option<u64>,
+) -> Option<u64> {
+    let (grace, policy) = {
+        let config = state.config.borrow();
+        (config.epoch_grace(), config.swap)
+    };
+    state.swapping.store(true, Ordering::Release);
+    // Both are held across the swap, so a request for the old epoch always finds it
+    let mut previous = state.previous.write().await;
+    let mut current = state.db.write().await;
+    if let Some(replaces) = replaces {
+        if current.epoch() != replaces {
+            state.swapping.store(false, Ordering::Release);
+            return None;
+        }
+        for &row in current.dead_rows() {
+            if let Err(e) = db.delete(&current.document_ids()[row]) {
+                eprintln!("Error carrying over deletion of row {}: {:?}", row, e);
+            }
+        }
+    }
+    let old = std::mem::replace(&mut *current, db);
+    let epoch = current.epoch();
+    let pending = state.arrivals.advance(&state.epoch, epoch);
+    drop(current);
+    state.swapping.store(false, Ordering::Release);
+
+    let retired = old.epoch();
+    let drain = policy == SwapPolicy::Complete && pending > 0;
+    // Nothing was served before the first build
+    if (grace.is_zero() && !drain) || retired == 0 || retired == epoch {
+        *previous = None;
+        return Some(epoch);
+    }
+    *previous = Some(old);
+    let expiry_state = Arc::clone(state);
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        if drain {
+            expiry_state.arrivals.drain(retired).await;
+        }
+        let mut previous = expiry_state.previous.write().await;
+        if previous.as_ref().is_some_and(|db| db.epoch() == retired) {
+            *previous = None;
+        }
+    });
+    Some(epoch)
+}
+
+// Runs `f` on the database serving `epoch`, or the current one for None. Besides the
+// current epoch, only the one it replaced is served, and only during its grace period;
+// any other is gone.
+async fn with_database<T: Database + Send + Sync, R>(
+    state: &ServerState<T>,
+    epoch: Option<u64>,
+    f: impl FnOnce(&T) -> Result<R, StatusCode>,
+) -> Result<R, StatusCode> {
+    {
+        let db = state.db.read().await;
+        if epoch.is_none_or(|epoch| db.epoch() == epoch) {
+            return f(&db);
+        }
+    }
+    match &*state.previous.read().await {
+        Some(db) if Some(db.epoch()) == epoch => f(db),
+        _ => Err(StatusCode::GONE),
+    }
+}
+
+// `with_database` for the compute pool's threads
+fn with_database_blocking<T: Database + Send + Sync, R>(
+    state: &ServerState<T>,
+    epoch: Option<u64>,
+    f: impl FnOnce(&T) -> Result<R, StatusCode>,
+) -> Result<R, StatusCode> {
+    {
+        let db = state.db.blocking_read();
+        if epoch.is_none_or(|epoch| db.epoch() == epoch) {
+            return f(&db);
+        }
+    }
+    match &*state.previous.blocking_read() {
+        Some(db) if Some(db.epoch()) == epoch => f(db),
+        _ => Err(StatusCode::GONE),
+    }
+}
+
+fn routes<T: Database + Send + Sync + 'static>(state: Arc<ServerState<T>>) -> Router {
+    #[cfg(feature = "websocket")]
+    let router = Router::new().route("/ws", axum::routing::get(handle_session::<T>));
+    #[cfg(not(feature = "websocket"))]
+    let router = Router::new();
+    #[cfg(feature = "openapi")]
+    let router = router.merge(SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi()));
+
+    let router = router
+        .route("/query", axum::routing::post(handle_query::<T>))
+        .route("/params", axum::routing::get(handle_params::<T>))
+        .route("/hint", axum::routing::get(handle_hint::<T>))
+        .route("/hint_diff", axum::routing::get(handle_hint_diff::<T>))
+        .route("/a", axum::routing::get(handle_a::<T>))
+        .route("/digest", axum::routing::get(handle_digest::<T>))
+        .route("/centroids", axum::routing::get(handle_centroids::<T>))
+        .route("/documents", axum::routing::get(handle_documents::<T>))
+        .route(
+            "/documents/digest",
+            axum::routing::get(handle_documents_digest::<T>),
+        )
+        .route("/ready", axum::routing::get(handle_ready::<T>))
+        .route("/admin/status", axum::routing::get(handle_status::<T>))
+        .route("/admin/stats", axum::routing::get(handle_stats::<T>))
+        .route("/admin/jobs", axum::routing::get(handle_jobs::<T>))
+        .route("/admin/rebuild", axum::routing::post(handle_rebuild::<T>))
+        .route("/admin/reembed", axum::routing::post(handle_reembed::<T>))
+        .route("/admin/selftest", axum::routing::post(handle_selftest::<T>))
+        .route(
+            "/admin/documents/{id}/delete",
+            axum::routing::post(handle_delete_document::<T>),
+        )
+        .route(
+            "/admin/jobs/{id}/cancel",
+            axum::routing::post(handle_cancel_job::<T>),
+        )
+        .route(
+            "/admin/reload-config",
+            axum::routing::post(handle_reload_config::<T>),
+        )
+        .route("/admin/snapshot", axum::routing::get(handle_snapshot::<T>))
+        .route(
+            "/clusters/{id}/query",
+            axum::routing::post(handle_cluster_query::<T>),
+        )
+        .route(
+            "/clusters/{id}/members",
+            axum::routing::get(handle_cluster_members::<T>),
+        )
+        .route(
+            "/clusters/{id}/params",
+            axum::routing::get(handle_cluster_params::<T>),
+        )
+        .route(
+            "/clusters/{id}/hint",
+            axum::routing::get(handle_cluster_hint::<T>),
+        )
+        .route(
+            "/clusters/{id}/a",
+            axum::routing::get(handle_cluster_a::<T>),
+        )
+        .route(
+            "/clusters/{id}/digest",
+            axum::routing::get(handle_cluster_digest::<T>),
+        )
+        .route("/partitions", axum::routing::get(handle_partitions::<T>))
+        .route(
+            "/partitions/{id}/query",
+            axum::routing::post(handle_partition_query::<T>),
+        )
+        .route(
+            "/partitions/{id}/params",
+            axum::routing::get(handle_partition_params::<T>),
+        )
+        .route(
+            "/partitions/{id}/hint",
+            axum::routing::get(handle_partition_hint::<T>),
+        )
+        .route(
+            "/partitions/{id}/a",
+            axum::routing::get(handle_partition_a::<T>),
+        )
+        .route(
+            "/partitions/{id}/digest",
+            axum::routing::get(handle_partition_digest::<T>),
+        )
+        .route(
+            "/epochs/{epoch}/{*path}",
+            axum::routing::get(handle_epoch_get::<T>).post(handle_epoch_query::<T>),
+        )
+        .route("/hot", axum::routing::get(handle_hot::<T>))
+        .route("/hot/query", axum::routing::post(handle_hot_query::<T>))
+        .route("/hot/params", axum::routing::get(handle_hot_params::<T>))
+        .route("/hot/hint", axum::routing::get(handle_hot_hint::<T>))
+        .route("/hot/a", axum::routing::get(handle_hot_a::<T>))
+        .route("/hot/digest", axum::routing::get(handle_hot_digest::<T>))
+        .route("/hot/packed", axum::routing::get(handle_packed::<T>))
+        .route(
+            "/hot/packed/query",
+            axum::routing::post(handle_packed_query::<T>),
+        )
+        .route(
+            "/hot/packed/params",
+            axum::routing::get(handle_packed_params::<T>),
+        )
+        .route(
+            "/hot/packed/hint",
+            axum::routing::get(handle_packed_hint::<T>),
+        )
+        .route("/hot/packed/a", axum::routing::get(handle_packed_a::<T>))
+        .route(
+            "/hot/packed/digest",
+            axum::routing::get(handle_packed_digest::<T>),
+        )
+        .route("/membership", axum::routing::get(handle_membership::<T>))
+        .route(
+            "/membership/query",
+            axum::routing::post(handle_membership_query::<T>),
+        )
+        .route(
+            "/membership/params",
+            axum::routing::get(handle_membership_params::<T>),
+        )
+        .route(
+            "/membership/hint",
+            axum::routing::get(handle_membership_hint::<T>),
+        )
+        .route(
+            "/membership/a",
+            axum::routing::get(handle_membership_a::<T>),
+        )
+        .route(
+            "/membership/digest",
+            axum::routing::get(handle_membership_digest::<T>),
+        )
+        .route("/blocks", axum::routing::get(handle_blocks::<T>))
+        .route(
+            "/blocks/query",
+            axum::routing::post(handle_blocks_query::<T>),
+        )
+        .route(
+            "/blocks/params",
+            axum::routing::get(handle_blocks_params::<T>),
+        )
+        .route("/blocks/hint", axum::routing::get(handle_blocks_hint::<T>))
+        .route("/blocks/a", axum::routing::get(handle_blocks_a::<T>))
+        .route(
+            "/blocks/digest",
+            axum::routing::get(handle_blocks_digest::<T>),
+        );
+    // Reveals which document sits in each row, so it is only mounted when asked for
+    let router = if state.debug_rows {
+        router.route("/debug/rows", axum::routing::get(handle_debug_rows::<T>))
+    } else {
+        router
+    };
+    // Replaces the whole index, so like the row listing it needs a policy to protect it
+    let router = if state.auth.is_some() {
+        router.route(
+            "/admin/restore",
+            axum::routing::post(handle_restore::<T>)
+                .get(handle_restore_status::<T>)
+                .layer(DefaultBodyLimit::max(MAX_RESTORE_CHUNK)),
+        )
+    } else {
+        router
+    };
+
+    router
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            route_session::<T>,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            require_database::<T>,
+        ))
+        .layer(middleware::from_fn(negotiate_format))
+        .layer(middleware::from_fn(enforce_deadline))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            authorize::<T>,
+        ))
+        .layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            REQUEST_TIMEOUT,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            audit::<T>,
+        ))
+        .with_state(state)
+}
+
+// Appends every request to the audit log, if one is open: who asked, how much was sent
+// each way and how long it took. Query contents are sealed and never reach the record.
+async fn audit<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(log) = &state.audit else {
+        return next.run(request).await;
+    };
+    let started = Instant::now();
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let key = request
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let tenant = state
+        .auth
+        .as_ref()
+        .zip(key)
+        .and_then(|(auth, key)| auth.tenant(key));
+    let request_bytes = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .unwrap_or_else(|| request.body().size_hint().lower());
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+
+    let response = next.run(request).await;
+    let record = AccessRecord {
+        timestamp_ms,
+        tenant,
+        namespace: log.namespace().to_string(),
+        method,
+        path,
+        status: response.status().as_u16(),
+        request_bytes,
+        response_bytes: response.body().size_hint().lower(),
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+    };
+    if let Err(e) = log.record(&record) {
+        eprintln!("Error writing audit log: {:?}", e);
+    }
+    response
+}
+
+// Checks the request's API key against the server's policy, if it has one, and
+// charges the response to the key's tenant. Denials are only counted, never logged.
+async fn authorize<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(auth) = &state.auth else {
+        return next.run(request).await;
+    };
+    let key = request
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    match auth.authorize(key.as_deref(), request.uri().path()) {
+        Ok(()) => {
+            let response = next.run(request).await;
+            // Streamed bodies count only what they promise up front
+            if let Some(key) = &key {
+                auth.charge(key, response.body().size_hint().lower());
+            }
+            response
+        }
+        Err(Denial::MissingKey | Denial::UnknownKey | Denial::Expired) => {
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+        Err(Denial::Forbidden) => StatusCode::FORBIDDEN.into_response(),
+        Err(Denial::OverQuota) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        )
+            .into_response(),
+    }
+}
+
+// Answers 503 to everything but the admin routes, `/ready` and the API docs until the
+// first rebuild succeeds, rather than serving params and answers of an empty database
+async fn require_database<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let exempt = Operation::of_path(path) == Operation::Admin
+        || ["/ready", "/docs", "/openapi.json"]
+            .iter()
+            .any(|prefix| path.starts_with(prefix));
+    if exempt || *state.epoch.borrow() != 0 {
+        return next.run(request).await;
+    }
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        "No database has been built yet",
+    )
+        .into_response()
+}
+
+// Whichever of the server's databases a session query names
+#[cfg(feature = "websocket")]
+fn session_database<T: Database>(db: &T, target: SessionTarget) -> Result<&SimplePirDatabase> {
+    let missing = || PirError::Database(format!("{:?} database not served", target));
+    let database = match target {
+        SessionTarget::Main => db.database(),
+        SessionTarget::Cluster(id) => db.cluster(id).ok_or_else(missing)?,
+        SessionTarget::Partition(id) => db.partition(id).ok_or_else(missing)?,
+        SessionTarget::Membership => &db.membership().ok_or_else(missing)?.db,
+        SessionTarget::Hot => &db.hot().ok_or_else(missing)?.db,
+        SessionTarget::Packed => {
+            &db.hot()
+                .and_then(|hot| hot.packed.as_ref())
+                .ok_or_else(missing)?
+                .db
+        }
+        SessionTarget::Blocks => &db.blocks().ok_or_else(missing)?.db,
+    };
+    Ok(database)
+}
+
+// Answers a session query from the database it names. Queries encrypted elsewhere may
+// not fit it, as over HTTP.
+#[cfg(feature = "websocket")]
+fn respond_to<T: Database>(
+    db: &T,
+    target: SessionTarget,
+    query: &DVector<BigInt>,
+) -> Result<DVector<BigInt>> {
+    let database = session_database(db, target)?;
+    let cols = database.dims().1;
+    if query.len() != cols {
+        return Err(PirError::InvalidInput(format!(
+            "Query of {} elements does not fit {} columns",
+            query.len(),
+            cols
+        ))
+        .into());
+    }
+    database.respond(query)
+}
+
+#[cfg(feature = "websocket")]
+async fn handle_session<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> axum::response::Response {
+    // The upgrade itself was authorized; every query frame is admitted again below
+    let key = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    upgrade.on_upgrade(move |socket| serve_session(socket, state, key))
+}
+
+// One persistent connection: params up front, then query/answer frames, with fresh
+// params pushed whenever a rebuild or compaction changes the epoch. Each query frame
+// counts against the tenant's quotas like a request to `/query`, and its answer is
+// charged to the tenant's bandwidth.
+#[cfg(feature = "websocket")]
+async fn serve_session<T: Database + Send + Sync + 'static>(
+    mut socket: WebSocket,
+    state: Arc<ServerState<T>>,
+    key: Option<String>,
+) {
+    let mut epochs = state.epoch.subscribe();
+    loop {
+        let frame = {
+            let db = state.db.read().await;
+            SessionFrame::Params {
+                epoch: db.epoch(),
+                params: Box::new(main_params(&*db)),
+            }
+        };
+        if send_frame(&mut socket, &frame).await.is_err() {
+            return;
+        }
+
+        loop {
+            let message = tokio::select! {
+                changed = epochs.changed() => match changed {
+                    Ok(()) => break,
+                    Err(_) => return,
+                },
+                message = socket.recv() => message,
+            };
+            let text = match message {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(_)) => continue,
+                _ => return,
+            };
+
+            let reply = match serde_json::from_str::<SessionRequest>(&text) {
+                Ok(request) => {
+                    let admitted = match &state.auth {
+                        Some(auth) => auth.authorize(key.as_deref(), "/query"),
+                        None => Ok(()),
+                    };
+                    let response = match admitted {
+                        Ok(()) => answer_frame(&state, &request).await,
+                        Err(denial) => Err(PirError::InvalidInput(format!(
+                            "Query denied: {:?}",
+                            denial
+                        ))
+                        .into()),
+                    };
+                    match response {
+                        Ok(response) => SessionFrame::Answer {
+                            id: request.id,
+                            response: serialize_vector(&response),
+                        },
+                        Err(e) => SessionFrame::Error {
+                            id: Some(request.id),
+                            message: e.to_string(),
+                        },
+                    }
+                }
+                Err(e) => SessionFrame::Error {
+                    id: None,
+                    message: e.to_string(),
+                },
+            };
+            let Ok(sent) = send_frame(&mut socket, &reply).await else {
+                return;
+            };
+            if let (Some(auth), Some(key), SessionFrame::Answer { .. }) =
+                (&state.auth, &key, &reply)
+            {
+                auth.charge(key, sent as u64);
+            }
+        }
+    }
+}
+
+// Answers one session query on the compute pool, from the epoch it pins if any
+#[cfg(feature = "websocket")]
+async fn answer_frame<T: Database + Send + Sync + 'static>(
+    state: &Arc<ServerState<T>>,
+    request: &SessionRequest,
+) -> Result<DVector<BigInt>> {
+    let query = deserialize_vector(request.query.expose())?;
+    let (epoch, target) = (request.epoch, request.target);
+    let pool_state = Arc::clone(state);
+    state
+        .pool
+        .run(move || {
+            with_database_blocking(&pool_state, epoch, |db| Ok(respond_to(db, target, &query)))
+                .unwrap_or_else(|_| {
+                    Err(PirError::Database(format!(
+                        "Epoch {} is no longer served",
+                        epoch.unwrap_or_default()
+                    ))
+                    .into())
+                })
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.into()))
+}
+
+// Sends `frame` and returns how many bytes it took
+#[cfg(feature = "websocket")]
+async fn send_frame(socket: &mut WebSocket, frame: &SessionFrame) -> Result<usize> {
+    let text = serde_json::to_string(frame)?;
+    let len = text.len();
+    socket
+        .send(Message::Text(text.into()))
+        .await
+        .map_err(|e| PirError::Database(format!("Session send failed: {}", e)))?;
+    Ok(len)
+}
+
+// Node that answered a session's first request to a server. Clients pass it back as is,
+// and load balancers can route on it. Which epoch a request is answered from is up to
+// pinning (`/epochs/{epoch}`), so the token does not carry one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionToken {
+    pub node: String,
+}
+
+impl fmt::Display for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.node)
+    }
+}
+
+impl FromStr for SessionToken {
+    type Err = PirError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(PirError::InvalidInput(format!(
+                "Invalid session token '{}'",
+                s
+            )));
+        }
+        Ok(Self {
+            node: s.to_string(),
+        })
+    }
+}
+
+// Tags responses with a session token for this node, and answers 421 to requests whose
+// session belongs to another node, so a load balancer that sent them here can retry on
+// the right one instead of mixing two nodes' epochs in one query. Malformed tokens are
+// ignored.
+async fn route_session<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(node) = &state.node else {
+        return next.run(request).await;
+    };
+    let session = request
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<SessionToken>().ok());
+    if let Some(session) = session.filter(|session| &session.node != node) {
+        return (
+            StatusCode::MISDIRECTED_REQUEST,
+            format!("Session belongs to node {}", session.node),
+        )
+            .into_response();
+    }
+    let mut response = next.run(request).await;
+    let token = SessionToken { node: node.clone() };
+    if let Ok(value) = HeaderValue::from_str(&token.to_string()) {
+        response.headers_mut().insert(SESSION_HEADER, value);
+    }
+    response
+}
+
+// Answers 408 once the client's deadline has passed, dropping requests that are still
+// waiting for a compute worker. A `respond` already running is synchronous and runs
+// to completion, but nothing queued behind it is computed for a client that has gone.
+async fn enforce_deadline(request: Request, next: Next) -> Response {
+    let budget = request
+        .headers()
+        .get(DEADLINE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_millis);
+    match budget {
+        Some(budget) => tokio::time::timeout(budget, next.run(request))
+            .await
+            .unwrap_or_else(|_| StatusCode::REQUEST_TIMEOUT.into_response()),
+        None => next.run(request).await,
+    }
+}
+
+fn has_media_type(value: Option<&HeaderValue>, media_type: &str) -> bool {
+    value
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|part| part.split(';').next().unwrap_or_default().trim() == media_type)
+        })
+}
+
+// Reads a body and re-encodes it from JSON to MessagePack or back
+async fn transcode(body: Body, to_msgpack: bool) -> Option<Body> {
+    let bytes = to_bytes(body, usize::MAX).await.ok()?;
+    let encoded = if to_msgpack {
+        rmp_serde::to_vec(&serde_json::from_slice::<serde_json::Value>(&bytes).ok()?).ok()?
+    } else {
+        serde_json::to_vec(&rmp_serde::from_slice::<serde_json::Value>(&bytes).ok()?).ok()?
+    };
+    Some(Body::from(encoded))
+}
+
+// Content negotiation between JSON and MessagePack. Handlers only speak JSON, so
+// MessagePack request bodies are transcoded on the way in, and JSON responses on the
+// way out when the client accepts MessagePack.
+async fn negotiate_format(request: Request, next: Next) -> Response {
+    let msgpack_response = has_media_type(request.headers().get(ACCEPT), MSGPACK_CONTENT_TYPE);
+    let request = if has_media_type(request.headers().get(CONTENT_TYPE), MSGPACK_CONTENT_TYPE) {
+        let (mut parts, body) = request.into_parts();
+        let Some(body) = transcode(body, false).await else {
+            return (StatusCode::BAD_REQUEST, "Malformed MessagePack body").into_response();
+        };
+        parts
+            .headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        parts.headers.remove(CONTENT_LENGTH);
+        Request::from_parts(parts, body)
+    } else {
+        request
+    };
+
+    let response = next.run(request).await;
+    if !msgpack_response
+        || !has_media_type(response.headers().get(CONTENT_TYPE), "application/json")
+    {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Some(body) = transcode(body, true).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(MSGPACK_CONTENT_TYPE));
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, body)
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/query",
+    tag = "pir",
+    request_body(
+        content = QueryRequest,
+        description = "Or the query alone as little-endian u64 words, sent as application/x-tiptoe-words"
+    ),
+    responses(
+        (status = 200, body = QueryResponse),
+        (status = 400, description = "Malformed query or secret, or a query of the wrong length"),
+        (status = 403, description = "Plaintext query to a server not in plaintext mode"),
+        (status = 415, description = "Word query to a database whose modulus exceeds 64 bits"),
+        (status = 421, description = "The session token names another node"),
+        (status = 503, body = EpochChangingResponse, description = "Compute pool full, or the database is being replaced (with an `epoch_changing` body); retry after Retry-After seconds")
+    )
+))]
+async fn handle_query<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    body: QueryBody,
+) -> Result<Json<QueryResponse>, QueryError> {
+    answer(&state, body, |db| Ok(db.database())).await
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/params",
+    tag = "pir",
+    responses(
+        (status = 200, body = ParamsData)
+    )
+))]
+async fn handle_params<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<ParamsData> {
+    let db = state.db.read().await;
+    Json(main_params(&*db))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hint",
+    tag = "pir",
+    responses(
+        (status = 200, body = MatrixResponse)
+    )
+))]
+async fn handle_hint<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<MatrixResponse> {
+    let db = state.db.read().await;
+    Json(serialize_matrix(db.hint()))
+}
+
+#[derive(Deserialize)]
+struct HintDiffParams {
+    from: u64,
+}
+
+// The rows of the hint that changed since the client's epoch, or null when A has been
+// regenerated since or that epoch is too old, and the full hint must be fetched again
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hint_diff",
+    tag = "pir",
+    params(("from" = u64, Query, description = "Epoch of the hint the client holds")),
+    responses(
+        (status = 200, body = Option<HintDiff>)
+    )
+))]
+async fn handle_hint_diff<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Query(HintDiffParams { from }): Query<HintDiffParams>,
+) -> Json<Option<HintDiff>> {
+    let db = state.db.read().await;
+    let database = db.database();
+    Json(database.changed_rows(from).map(|rows| {
+        let hint = database.hint();
+        let values = DMatrix::from_fn(rows.len(), hint.ncols(), |i, col| {
+            hint[(rows[i], col)].clone()
+        });
+        HintDiff {
+            from,
+            epoch: database.epoch(),
+            rows,
+            values: serialize_matrix(&values),
+        }
+    }))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/a",
+    tag = "pir",
+    responses(
+        (status = 200, body = MatrixResponse)
+    )
+))]
+async fn handle_a<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<MatrixResponse> {
+    let db = state.db.read().await;
+    Json(serialize_matrix(db.a()))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/digest",
+    tag = "pir",
+    responses(
+        (status = 200, body = DatabaseDigest)
+    )
+))]
+async fn handle_digest<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<DatabaseDigest> {
+    let db = state.db.read().await;
+    Json(db.digest().clone())
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/centroids",
+    tag = "clusters",
+    responses(
+        (status = 200, body = Option<CentroidsData>)
+    )
+))]
+async fn handle_centroids<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<Option<CentroidsData>> {
+    let db = state.db.read().await;
+    Json(centroids_data(&*db))
+}
+
+pub(crate) fn centroids_data<T: Database>(db: &T) -> Option<CentroidsData> {
+    db.clustering().map(|clustering| CentroidsData {
+        centroids: clustering.centroids.clone(),
+        sizes: clustering.sizes(),
+        dim: clustering.centroids.first().map_or(0, Vec::len),
+        metric: clustering.metric,
+    })
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/documents",
+    tag = "documents",
+    responses(
+        (status = 200, body = DocumentsResponse)
+    )
+))]
+async fn handle_documents<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<DocumentsResponse> {
+    let db = state.db.read().await;
+    Json(documents_response(&*db))
+}
+
+fn documents_response<T: Database>(db: &T) -> DocumentsResponse {
+    DocumentsResponse {
+        epoch: db.epoch(),
+        digest: format!("{:016x}", mapping_digest(db.document_ids())),
+        ids: db.document_ids().to_vec(),
+        dead_rows: db.dead_rows().clone(),
+    }
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/documents/digest",
+    tag = "documents",
+    responses(
+        (status = 200, body = DigestResponse)
+    )
+))]
+async fn handle_documents_digest<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<DigestResponse> {
+    let db = state.db.read().await;
+    Json(DigestResponse {
+        epoch: db.epoch(),
+        digest: format!("{:016x}", mapping_digest(db.document_ids())),
+    })
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/admin/status",
+    tag = "admin",
+    responses(
+        (status = 200, body = StatusResponse)
+    )
+))]
+async fn handle_status<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<StatusResponse> {
+    let db = state.db.read().await;
+    Json(StatusResponse {
+        epoch: db.epoch(),
+        clusters: db.cluster_dims().len(),
+        cluster_quality: db.cluster_quality(),
+        distance_fallback: distance_fallback().map(str::to_string),
+        rebuild: state.jobs.latest(),
+        denials: state.auth.as_ref().map(Authorizer::denials),
+        tenants: state
+            .auth
+            .as_ref()
+            .map(Authorizer::tenants)
+            .unwrap_or_default(),
+        rebuilds: state.rebuilds.lock().unwrap().clone(),
+    })
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/ready",
+    tag = "admin",
+    responses(
+        (status = 200, body = ReadyResponse),
+        (status = 503, body = ReadyResponse, description = "Nothing built yet, or too many failed rebuilds")
+    )
+))]
+async fn handle_ready<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> (StatusCode, Json<ReadyResponse>) {
+    let epoch = *state.epoch.borrow();
+    let rebuilds = state.rebuilds.lock().unwrap().clone();
+    let ready = epoch != 0
+        && state
+            .max_rebuild_failures
+            .is_none_or(|max| rebuilds.consecutive_failures <= max);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(ReadyResponse {
+            ready,
+            epoch,
+            rebuilding: state
+                .jobs
+                .latest()
+                .is_some_and(|job| matches!(job.status, JobStatus::Running { .. })),
+            rebuilds,
+            node: state.node.clone(),
+        }),
+    )
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/admin/stats",
+    tag = "admin",
+    responses(
+        (status = 200, body = StatsResponse)
+    )
+))]
+async fn handle_stats<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<StatsResponse> {
+    let db = state.db.read().await;
+    let databases = db.stats();
+    let total_bytes = databases
+        .iter()
+        .map(|stats| stats.data_bytes + stats.hint_bytes + stats.a_bytes)
+        .sum();
+    Json(StatsResponse {
+        databases,
+        total_bytes,
+    })
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/admin/jobs",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<JobInfo>)
+    )
+))]
+async fn handle_jobs<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<Vec<JobInfo>> {
+    Json(state.jobs.list())
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/admin/rebuild",
+    tag = "admin",
+    responses(
+        (status = 200, body = JobInfo)
+    )
+))]
+async fn handle_rebuild<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<JobInfo> {
+    Json(state.jobs.enqueue())
+}
+
+// Rebuilds every database with documents embedded by another model. The current epoch
+// keeps serving until the new one is swapped in, and the new model then stays in use
+// for later rebuilds until the server restarts.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/admin/reembed",
+    tag = "admin",
+    request_body = EmbeddingModel,
+    responses(
+        (status = 200, body = JobInfo),
+        (status = 400, description = "Empty model id"),
+        (status = 404, description = "This server does not embed documents")
+    )
+))]
+async fn handle_reembed<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Json(model): Json<EmbeddingModel>,
+) -> Result<Json<JobInfo>, (StatusCode, String)> {
+    if state.db.read().await.embedding_model().is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "This server does not embed documents".to_string(),
+        ));
+    }
+    if model.model.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Empty model id".to_string()));
+    }
+    Ok(Json(state.jobs.enqueue_reembed(model)))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/admin/selftest",
+    tag = "admin",
+    responses(
+        (status = 200, body = selftest::SelfTestReport),
+        (status = 500, description = "A database recovered the wrong values"),
+        (status = 503, description = "Compute pool full; retry after Retry-After seconds")
+    )
+))]
+async fn handle_selftest<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Response {
+    let pool_state = Arc::clone(&state);
+    match state
+        .pool
+        .run(move || selftest::run(&*pool_state.db.blocking_read()))
+        .await
+    {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Err(e) => QueryError::from(e).into_response(),
+    }
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/admin/jobs/{id}/cancel",
+    tag = "admin",
+    params(("id" = u64, Path, description = "Job id")),
+    responses(
+        (status = 200, body = JobInfo),
+        (status = 404),
+        (status = 409, description = "Job already finished")
+    )
+))]
+async fn handle_cancel_job<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<u64>,
+) -> Result<Json<JobInfo>, StatusCode> {
+    let job = state.jobs.get(id).ok_or(StatusCode::NOT_FOUND)?;
+    if !job.cancel() {
+        return Err(StatusCode::CONFLICT);
+    }
+    Ok(Json(job.info()))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/admin/reload-config",
+    tag = "admin",
+    responses(
+        (status = 200, body = ServerConfig),
+        (status = 400, description = "Config is unreadable or invalid; the old one stays in effect")
+    )
+))]
+async fn handle_reload_config<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<ServerConfig>, StatusCode> {
+    reload_config(&state).map(Json).map_err(|e| {
+        eprintln!("Error reloading config: {:?}", e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+// The current databases' snapshot bundle and its ETag. Built once per version of the
+// databases and kept for later requests, so a download resumed with Range gets the
+// same bytes.
+async fn snapshot_bundle<T: Database + Send + Sync + 'static>(
+    state: &Arc<ServerState<T>>,
+) -> Result<(String, Bytes), StatusCode> {
+    let build_state = Arc::clone(state);
+    tokio::task::spawn_blocking(move || {
+        let db = build_state.db.blocking_read();
+        if db.epoch() == 0 {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        // Deletions and hot refreshes change the bundle but not the epoch
+        let etag = format!(
+            "\"{}-{}-{}\"",
+            db.epoch(),
+            db.hot().map_or(0, |hot| hot.db.epoch()),
+            db.dead_rows().len()
+        );
+        let mut cached = build_state.snapshot.lock().unwrap();
+        if let Some((tag, bytes)) = cached.as_ref().filter(|(tag, _)| *tag == etag) {
+            return Ok((tag.clone(), bytes.clone()));
+        }
+        let bytes = db
+            .snapshot()
+            .and_then(|snapshot| snapshot.to_bytes())
+            .map_err(|e| {
+                eprintln!("Error taking snapshot: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        let bytes = Bytes::from(bytes);
+        *cached = Some((etag.clone(), bytes.clone()));
+        Ok((etag, bytes))
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+}
+
+// Every database of the current epoch with its hint and A, plus the row -> document
+// mapping, as one MessagePack bundle. `Range: bytes=N-` resumes an interrupted
+// download; with `If-Range` set to the ETag it started with, a bundle that changed in
+// the meantime is sent whole instead.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/admin/snapshot",
+    tag = "admin",
+    responses(
+        (status = 200, description = "The snapshot bundle", content_type = "application/x-tiptoe-snapshot"),
+        (status = 206, description = "The requested range of the bundle", content_type = "application/x-tiptoe-snapshot"),
+        (status = 416, description = "Range past the end of the bundle"),
+        (status = 503, description = "No database has been built yet")
+    )
+))]
+async fn handle_snapshot<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    headers: HeaderMap,
+) -> Response {
+    let (etag, bytes) = match snapshot_bundle(&state).await {
+        Ok(bundle) => bundle,
+        Err(status) => return status.into_response(),
+    };
+    let same_bundle = headers
+        .get(IF_RANGE)
+        .is_none_or(|value| value.as_bytes() == etag.as_bytes());
+    let range = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| same_bundle);
+    let mut response = match range {
+        None => bytes.into_response(),
+        Some(range) => match parse_range(range, bytes.len()) {
+            Some((start, end)) => {
+                let content_range = format!("bytes {}-{}/{}", start, end - 1, bytes.len());
+                (
+                    StatusCode::PARTIAL_CONTENT,
+                    [(CONTENT_RANGE, content_range)],
+                    bytes.slice(start..end),
+                )
+                    .into_response()
+            }
+            None => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(CONTENT_RANGE, format!("bytes */{}", bytes.len()))],
+            )
+                .into_response(),
+        },
+    };
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(SNAPSHOT_CONTENT_TYPE),
+    );
+    response_headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        response_headers.insert(ETAG, etag);
+    }
+    response
+}
+
+fn restore_status(upload: &Upload, restored: bool, epoch: u64) -> RestoreStatus {
+    RestoreStatus {
+        received: upload.received(),
+        total: upload.total(),
+        restored,
+        epoch,
+    }
+}
+
+// Loads a bundle from `/admin/snapshot`, possibly taken on another server, and serves
+// it in place of the current databases. Sent whole, or in chunks of at most 64 MiB
+// each with `Content-Range: bytes start-end/total`; a chunk that does not continue
+// the upload gets 409 and the status to resume from. A rebuild that succeeds later
+// replaces the restored databases, as it would any others.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/admin/restore",
+    tag = "admin",
+    request_body(content = Vec<u8>, content_type = "application/x-tiptoe-snapshot"),
+    responses(
+        (status = 200, body = RestoreStatus, description = "Restored and serving"),
+        (status = 202, body = RestoreStatus, description = "Chunk stored; more expected"),
+        (status = 400, description = "Malformed Content-Range, or a bundle that cannot be restored on this server"),
+        (status = 409, body = RestoreStatus, description = "Chunk does not continue the upload")
+    )
+))]
+async fn handle_restore<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let epoch = *state.epoch.borrow();
+    let (offset, total) = match headers.get(CONTENT_RANGE) {
+        Some(value) => match value.to_str().ok().and_then(parse_content_range) {
+            Some(range) => range,
+            None => return (StatusCode::BAD_REQUEST, "Malformed Content-Range").into_response(),
+        },
+        None => (0, body.len()),
+    };
+    let bundle = {
+        let mut upload = state.upload.lock().unwrap();
+        if upload.append(offset, total, &body).is_err() {
+            return (
+                StatusCode::CONFLICT,
+                Json(restore_status(&upload, false, epoch)),
+            )
+                .into_response();
+        }
+        if !upload.is_complete() {
+            return (
+                StatusCode::ACCEPTED,
+                Json(restore_status(&upload, false, epoch)),
+            )
+                .into_response();
+        }
+        upload.take()
+    };
+
+    let restored = tokio::task::spawn_blocking(move || {
+        let snapshot = Snapshot::from_bytes(&bundle)?;
+        println!("Restoring snapshot of namespace {}", snapshot.namespace);
+        let mut instance = T::new()?;
+        instance.restore(snapshot)?;
+        Ok::<_, anyhow::Error>(instance)
+    })
+    .await;
+    let instance = match restored {
+        Ok(Ok(instance)) => instance,
+        Ok(Err(e)) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Restore panicked: {:?}", e),
+            )
+                .into_response()
+        }
+    };
+
+    let epoch = swap_in(&state, instance).await;
+    *state.snapshot.lock().unwrap() = None;
+    // Serving restored databases counts as a good build for `/ready`
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    state
+        .rebuilds
+        .lock()
+        .unwrap()
+        .record(&JobStatus::Completed, now);
+    println!("Snapshot restored, serving epoch {}", epoch);
+    Json(RestoreStatus {
+        received: total,
+        total: Some(total),
+        restored: true,
+        epoch,
+    })
+    .into_response()
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/admin/restore",
+    tag = "admin",
+    responses(
+        (status = 200, body = RestoreStatus)
+    )
+))]
+async fn handle_restore_status<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<RestoreStatus> {
+    let epoch = *state.epoch.borrow();
+    Json(restore_status(&state.upload.lock().unwrap(), false, epoch))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/debug/rows",
+    tag = "admin",
+    responses(
+        (status = 200, body = DebugRowsResponse),
+        (status = 404, description = "Not enabled on this server")
+    )
+))]
+async fn handle_debug_rows<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<DebugRowsResponse> {
+    let db = state.db.read().await;
+    let clustering = db.clustering();
+    let rows = db
+        .document_ids()
+        .iter()
+        .zip(db.source_keys())
+        .enumerate()
+        .map(|(row, (id, key))| DebugRow {
+            row,
+            id: *id,
+            key: key.clone(),
+            cluster: clustering.and_then(|clustering| clustering.assignments.get(row).copied()),
+            dead: db.dead_rows().contains(&row),
+        })
+        .collect();
+    Json(DebugRowsResponse {
+        epoch: db.epoch(),
+        rows,
+    })
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/admin/documents/{id}/delete",
+    tag = "admin",
+    params(("id" = String, Path, description = "Document id")),
+    responses(
+        (status = 200, body = DeleteResponse),
+        (status = 400, description = "Malformed document id"),
+        (status = 404)
+    )
+))]
+async fn handle_delete_document<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<String>,
+) -> Result<Json<DeleteResponse>, StatusCode> {
+    let id: DocumentId = id.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let mut db = state.db.write().await;
+    if !db
+        .delete(&id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let compaction_pending = db.needs_compaction();
+    if compaction_pending {
+        state.compaction.notify_one();
+    }
+    Ok(Json(DeleteResponse {
+        dead_rows: db.dead_rows().len(),
+        compaction_pending,
+    }))
+}
+
+// The cluster id in the path is the only part of a clustered query the server sees
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/clusters/{id}/query",
+    tag = "clusters",
+    params(("id" = usize, Path, description = "Cluster id")),
+    request_body(
+        content = QueryRequest,
+        description = "Or the query alone as little-endian u64 words, sent as application/x-tiptoe-words"
+    ),
+    responses(
+        (status = 200, body = QueryResponse),
+        (status = 400, description = "Malformed query or secret, or a query of the wrong length"),
+        (status = 403, description = "Plaintext query to a server not in plaintext mode"),
+        (status = 415, description = "Word query to a database whose modulus exceeds 64 bits"),
+        (status = 404),
+        (status = 503, description = "Compute pool full; retry after Retry-After seconds")
+    )
+))]
+async fn handle_cluster_query<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+    body: QueryBody,
+) -> Result<Json<QueryResponse>, QueryError> {
+    answer(&state, body, move |db| {
+        db.cluster(id).ok_or(StatusCode::NOT_FOUND)
+    })
+    .await
+}
+
+// Rows of the documents in the cluster, in the order its answers score them. Fetched
+// by a client about to query the cluster, which the query reveals anyway.
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/clusters/{id}/members",
+    tag = "clusters",
+    params(("id" = usize, Path, description = "Cluster id")),
+    responses(
+        (status = 200, body = Vec<usize>),
+        (status = 404)
+    )
+))]
+async fn handle_cluster_members<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+) -> Result<Json<Vec<usize>>, StatusCode> {
+    let db = state.db.read().await;
+    cluster_members(&*db, id).map(Json)
+}
+
+fn cluster_members<T: Database>(db: &T, id: usize) -> Result<Vec<usize>, StatusCode> {
+    db.clustering()
+        .filter(|clustering| id < clustering.centroids.len())
+        .map(|clustering| clustering.members_of(id))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/clusters/{id}/params",
+    tag = "clusters",
+    params(("id" = usize, Path, description = "Cluster id")),
+    responses(
+        (status = 200, body = ParamsData),
+        (status = 404)
+    )
+))]
+async fn handle_cluster_params<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+) -> Result<Json<ParamsData>, StatusCode> {
+    let db = state.db.read().await;
+    let cluster = db.cluster(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_params(
+        cluster.params(),
+        cluster.epoch(),
+        Vec::new(),
+    )))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/clusters/{id}/hint",
+    tag = "clusters",
+    params(("id" = usize, Path, description = "Cluster id")),
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_cluster_hint<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let cluster = db.cluster(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(cluster.hint())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/clusters/{id}/a",
+    tag = "clusters",
+    params(("id" = usize, Path, description = "Cluster id")),
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_cluster_a<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let cluster = db.cluster(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(cluster.a())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/clusters/{id}/digest",
+    tag = "clusters",
+    params(("id" = usize, Path, description = "Cluster id")),
+    responses(
+        (status = 200, body = DatabaseDigest),
+        (status = 404)
+    )
+))]
+async fn handle_cluster_digest<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+) -> Result<Json<DatabaseDigest>, StatusCode> {
+    let db = state.db.read().await;
+    let cluster = db.cluster(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(cluster.digest().clone()))
+}
+
+// Time windows and the rows in each, or null if the database is not partitioned
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/partitions",
+    tag = "partitions",
+    responses(
+        (status = 200, body = Option<PartitionManifest>)
+    )
+))]
+async fn handle_partitions<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<Option<PartitionManifest>> {
+    let db = state.db.read().await;
+    Json(db.partitions().cloned())
+}
+
+// As with clusters, the partition id in the path is all the server learns: the
+// time windows a query's date filter covers
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/partitions/{id}/query",
+    tag = "partitions",
+    params(("id" = usize, Path, description = "Partition id")),
+    request_body(
+        content = QueryRequest,
+        description = "Or the query alone as little-endian u64 words, sent as application/x-tiptoe-words"
+    ),
+    responses(
+        (status = 200, body = QueryResponse),
+        (status = 400, description = "Malformed query or secret, or a query of the wrong length"),
+        (status = 403, description = "Plaintext query to a server not in plaintext mode"),
+        (status = 415, description = "Word query to a database whose modulus exceeds 64 bits"),
+        (status = 404),
+        (status = 503, description = "Compute pool full; retry after Retry-After seconds")
+    )
+))]
+async fn handle_partition_query<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+    body: QueryBody,
+) -> Result<Json<QueryResponse>, QueryError> {
+    answer(&state, body, move |db| {
+        db.partition(id).ok_or(StatusCode::NOT_FOUND)
+    })
+    .await
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/partitions/{id}/params",
+    tag = "partitions",
+    params(("id" = usize, Path, description = "Partition id")),
+    responses(
+        (status = 200, body = ParamsData),
+        (status = 404)
+    )
+))]
+async fn handle_partition_params<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+) -> Result<Json<ParamsData>, StatusCode> {
+    let db = state.db.read().await;
+    let partition = db.partition(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_params(
+        partition.params(),
+        partition.epoch(),
+        Vec::new(),
+    )))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/partitions/{id}/hint",
+    tag = "partitions",
+    params(("id" = usize, Path, description = "Partition id")),
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_partition_hint<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let partition = db.partition(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(partition.hint())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/partitions/{id}/a",
+    tag = "partitions",
+    params(("id" = usize, Path, description = "Partition id")),
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_partition_a<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let partition = db.partition(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(partition.a())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/partitions/{id}/digest",
+    tag = "partitions",
+    params(("id" = usize, Path, description = "Partition id")),
+    responses(
+        (status = 200, body = DatabaseDigest),
+        (status = 404)
+    )
+))]
+async fn handle_partition_digest<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path(id): Path<usize>,
+) -> Result<Json<DatabaseDigest>, StatusCode> {
+    let db = state.db.read().await;
+    let partition = db.partition(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(partition.digest().clone()))
+}
+
+// Fields and epoch of the hot tier, or null if this server has none
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot",
+    tag = "hot",
+    responses(
+        (status = 200, body = Option<HotInfo>)
+    )
+))]
+async fn handle_hot<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<Option<HotInfo>> {
+    let db = state.db.read().await;
+    Json(db.hot().map(|hot| hot.info()))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/hot/query",
+    tag = "hot",
+    request_body(
+        content = QueryRequest,
+        description = "Or the query alone as little-endian u64 words, sent as application/x-tiptoe-words"
+    ),
+    responses(
+        (status = 200, body = QueryResponse),
+        (status = 400, description = "Malformed query or secret, or a query of the wrong length"),
+        (status = 403, description = "Plaintext query to a server not in plaintext mode"),
+        (status = 415, description = "Word query to a database whose modulus exceeds 64 bits"),
+        (status = 404),
+        (status = 503, description = "Compute pool full; retry after Retry-After seconds")
+    )
+))]
+async fn handle_hot_query<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    body: QueryBody,
+) -> Result<Json<QueryResponse>, QueryError> {
+    answer(&state, body, |db| {
+        db.hot().map(|hot| &hot.db).ok_or(StatusCode::NOT_FOUND)
+    })
+    .await
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot/params",
+    tag = "hot",
+    responses(
+        (status = 200, body = ParamsData),
+        (status = 404)
+    )
+))]
+async fn handle_hot_params<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<ParamsData>, StatusCode> {
+    let db = state.db.read().await;
+    let hot = db.hot().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_params(
+        hot.db.params(),
+        hot.db.epoch(),
+        Vec::new(),
+    )))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot/hint",
+    tag = "hot",
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_hot_hint<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let hot = db.hot().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(hot.db.hint())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot/a",
+    tag = "hot",
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_hot_a<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let hot = db.hot().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(hot.db.a())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot/digest",
+    tag = "hot",
+    responses(
+        (status = 200, body = DatabaseDigest),
+        (status = 404)
+    )
+))]
+async fn handle_hot_digest<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<DatabaseDigest>, StatusCode> {
+    let db = state.db.read().await;
+    let hot = db.hot().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(hot.db.digest().clone()))
+}
+
+// Slot layout of the packed numeric database, or null if this server has none
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot/packed",
+    tag = "hot",
+    responses(
+        (status = 200, body = Option<PackedLayout>)
+    )
+))]
+async fn handle_packed<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<Option<PackedLayout>> {
+    let db = state.db.read().await;
+    Json(
+        db.hot()
+            .and_then(|hot| hot.packed.as_ref())
+            .map(|packed| packed.layout.clone()),
+    )
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/hot/packed/query",
+    tag = "hot",
+    request_body(
+        content = QueryRequest,
+        description = "Or the query alone as little-endian u64 words, sent as application/x-tiptoe-words"
+    ),
+    responses(
+        (status = 200, body = QueryResponse),
+        (status = 400, description = "Malformed query or secret, or a query of the wrong length"),
+        (status = 403, description = "Plaintext query to a server not in plaintext mode"),
+        (status = 415, description = "Word query to a database whose modulus exceeds 64 bits"),
+        (status = 404),
+        (status = 503, description = "Compute pool full; retry after Retry-After seconds")
+    )
+))]
+async fn handle_packed_query<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    body: QueryBody,
+) -> Result<Json<QueryResponse>, QueryError> {
+    answer(&state, body, |db| {
+        db.hot()
+            .and_then(|hot| hot.packed.as_ref())
+            .map(|packed| &packed.db)
+            .ok_or(StatusCode::NOT_FOUND)
+    })
+    .await
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot/packed/params",
+    tag = "hot",
+    responses(
+        (status = 200, body = ParamsData),
+        (status = 404)
+    )
+))]
+async fn handle_packed_params<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<ParamsData>, StatusCode> {
+    let db = state.db.read().await;
+    let packed = db
+        .hot()
+        .and_then(|hot| hot.packed.as_ref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_params(
+        packed.db.params(),
+        packed.db.epoch(),
+        Vec::new(),
+    )))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot/packed/hint",
+    tag = "hot",
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_packed_hint<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let packed = db
+        .hot()
+        .and_then(|hot| hot.packed.as_ref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(packed.db.hint())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot/packed/a",
+    tag = "hot",
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_packed_a<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let packed = db
+        .hot()
+        .and_then(|hot| hot.packed.as_ref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(packed.db.a())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/hot/packed/digest",
+    tag = "hot",
+    responses(
+        (status = 200, body = DatabaseDigest),
+        (status = 404)
+    )
+))]
+async fn handle_packed_digest<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<DatabaseDigest>, StatusCode> {
+    let db = state.db.read().await;
+    let packed = db
+        .hot()
+        .and_then(|hot| hot.packed.as_ref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(packed.db.digest().clone()))
+}
+
+// Shape of the membership filter, or null if this server has none
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/membership",
+    tag = "membership",
+    responses(
+        (status = 200, body = Option<BloomParams>)
+    )
+))]
+async fn handle_membership<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<Option<BloomParams>> {
+    let db = state.db.read().await;
+    Json(db.membership().map(|membership| membership.params))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/membership/query",
+    tag = "membership",
+    request_body(
+        content = QueryRequest,
+        description = "Or the query alone as little-endian u64 words, sent as application/x-tiptoe-words"
+    ),
+    responses(
+        (status = 200, body = QueryResponse),
+        (status = 400, description = "Malformed query or secret, or a query of the wrong length"),
+        (status = 403, description = "Plaintext query to a server not in plaintext mode"),
+        (status = 415, description = "Word query to a database whose modulus exceeds 64 bits"),
+        (status = 404),
+        (status = 503, description = "Compute pool full; retry after Retry-After seconds")
+    )
+))]
+async fn handle_membership_query<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    body: QueryBody,
+) -> Result<Json<QueryResponse>, QueryError> {
+    answer(&state, body, |db| {
+        db.membership()
+            .map(|membership| &membership.db)
+            .ok_or(StatusCode::NOT_FOUND)
+    })
+    .await
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/membership/params",
+    tag = "membership",
+    responses(
+        (status = 200, body = ParamsData),
+        (status = 404)
+    )
+))]
+async fn handle_membership_params<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<ParamsData>, StatusCode> {
+    let db = state.db.read().await;
+    let membership = db.membership().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_params(
+        membership.db.params(),
+        membership.db.epoch(),
+        Vec::new(),
+    )))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/membership/hint",
+    tag = "membership",
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_membership_hint<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let membership = db.membership().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(membership.db.hint())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/membership/a",
+    tag = "membership",
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_membership_a<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let membership = db.membership().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(membership.db.a())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/membership/digest",
+    tag = "membership",
+    responses(
+        (status = 200, body = DatabaseDigest),
+        (status = 404)
+    )
+))]
+async fn handle_membership_digest<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<DatabaseDigest>, StatusCode> {
+    let db = state.db.read().await;
+    let membership = db.membership().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(membership.db.digest().clone()))
+}
+
+// Layout of the block database, or null if this server has none
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/blocks",
+    tag = "blocks",
+    responses(
+        (status = 200, body = Option<BlockLayout>)
+    )
+))]
+async fn handle_blocks<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Json<Option<BlockLayout>> {
+    let db = state.db.read().await;
+    Json(db.blocks().map(|blocks| blocks.layout.clone()))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/blocks/query",
+    tag = "blocks",
+    request_body(
+        content = QueryRequest,
+        description = "Or the query alone as little-endian u64 words, sent as application/x-tiptoe-words"
+    ),
+    responses(
+        (status = 200, body = QueryResponse),
+        (status = 400, description = "Malformed query or secret, or a query of the wrong length"),
+        (status = 403, description = "Plaintext query to a server not in plaintext mode"),
+        (status = 415, description = "Word query to a database whose modulus exceeds 64 bits"),
+        (status = 404),
+        (status = 503, description = "Compute pool full; retry after Retry-After seconds")
+    )
+))]
+async fn handle_blocks_query<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    body: QueryBody,
+) -> Result<Json<QueryResponse>, QueryError> {
+    answer(&state, body, |db| {
+        db.blocks()
+            .map(|blocks| &blocks.db)
+            .ok_or(StatusCode::NOT_FOUND)
+    })
+    .await
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/blocks/params",
+    tag = "blocks",
+    responses(
+        (status = 200, body = ParamsData),
+        (status = 404)
+    )
+))]
+async fn handle_blocks_params<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<ParamsData>, StatusCode> {
+    let db = state.db.read().await;
+    let blocks = db.blocks().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_params(
+        blocks.db.params(),
+        blocks.db.epoch(),
+        Vec::new(),
+    )))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/blocks/hint",
+    tag = "blocks",
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_blocks_hint<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let blocks = db.blocks().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(blocks.db.hint())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/blocks/a",
+    tag = "blocks",
+    responses(
+        (status = 200, body = MatrixResponse),
+        (status = 404)
+    )
+))]
+async fn handle_blocks_a<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let db = state.db.read().await;
+    let blocks = db.blocks().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(serialize_matrix(blocks.db.a())))
+}
+
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/blocks/digest",
+    tag = "blocks",
+    responses(
+        (status = 200, body = DatabaseDigest),
+        (status = 404)
+    )
+))]
+async fn handle_blocks_digest<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+) -> Result<Json<DatabaseDigest>, StatusCode> {
+    let db = state.db.read().await;
+    let blocks = db.blocks().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(blocks.db.digest().clone()))
 }
 
-// Request/Response types
-#[derive(Serialize, Deserialize)]
-pub struct QueryRequest {
-    query: Vec<String>, // Serialized BigInt vector
+// The PIR database served under the route prefix `database`, e.g. `clusters/3` or
+// `hot/packed`; empty is the main one
+fn select_database<'a, T: Database>(
+    db: &'a T,
+    database: &str,
+) -> Result<&'a SimplePirDatabase, StatusCode> {
+    let segments: Vec<&str> = database.split('/').filter(|s| !s.is_empty()).collect();
+    let id = |id: &str| id.parse::<usize>().map_err(|_| StatusCode::BAD_REQUEST);
+    let database = match segments.as_slice() {
+        [] => Some(db.database()),
+        ["clusters", cluster] => db.cluster(id(cluster)?),
+        ["partitions", partition] => db.partition(id(partition)?),
+        ["membership"] => db.membership().map(|membership| &membership.db),
+        ["hot"] => db.hot().map(|hot| &hot.db),
+        ["hot", "packed"] => db
+            .hot()
+            .and_then(|hot| hot.packed.as_ref())
+            .map(|packed| &packed.db),
+        ["blocks"] => db.blocks().map(|blocks| &blocks.db),
+        _ => None,
+    };
+    database.ok_or(StatusCode::NOT_FOUND)
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct QueryResponse {
-    response: Vec<String>,
+// Any public resource of the database that served `epoch`, e.g.
+// `/epochs/{epoch}/clusters/3/hint` or `/epochs/{epoch}/documents`, so a client can
+// finish a query against the hints and row mapping it started with after a rebuild
+#[cfg_attr(feature = "openapi", utoipa::path(
+    get,
+    path = "/epochs/{epoch}/{path}",
+    tag = "epochs",
+    params(
+        ("epoch" = u64, Path, description = "Epoch of the main database"),
+        ("path" = String, Path, description = "Route of the resource, e.g. params or clusters/3/hint")
+    ),
+    responses(
+        (status = 200, description = "As the resource's own route"),
+        (status = 404),
+        (status = 410, description = "Neither the current epoch nor the previous one within its grace period")
+    )
+))]
+async fn handle_epoch_get<T: Database + Send + Sync>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path((epoch, path)): Path<(u64, String)>,
+) -> Result<Response, StatusCode> {
+    let (database, resource) = path.rsplit_once('/').unwrap_or(("", path.as_str()));
+    with_database(&state, Some(epoch), |db| {
+        let response = match (database, resource) {
+            ("", "params") => Json(main_params(db)).into_response(),
+            ("", "documents") => Json(documents_response(db)).into_response(),
+            ("", "centroids") => Json(centroids_data(db)).into_response(),
+            ("", "partitions") => Json(db.partitions().cloned()).into_response(),
+            ("", "membership") => {
+                Json(db.membership().map(|membership| membership.params)).into_response()
+            }
+            ("", "hot") => Json(db.hot().map(|hot| hot.info())).into_response(),
+            ("hot", "packed") => Json(
+                db.hot()
+                    .and_then(|hot| hot.packed.as_ref())
+                    .map(|packed| packed.layout.clone()),
+            )
+            .into_response(),
+            ("", "blocks") => Json(db.blocks().map(|blocks| blocks.layout.clone())).into_response(),
+            (database, "members") => {
+                let id = database
+                    .strip_prefix("clusters/")
+                    .ok_or(StatusCode::NOT_FOUND)?
+                    .parse()
+                    .map_err(|_| StatusCode::BAD_REQUEST)?;
+                Json(cluster_members(db, id)?).into_response()
+            }
+            (database, "params") => {
+                let selected = select_database(db, database)?;
+                Json(serialize_params(
+                    selected.params(),
+                    selected.epoch(),
+                    Vec::new(),
+                ))
+                .into_response()
+            }
+            (database, "hint") => {
+                Json(serialize_matrix(select_database(db, database)?.hint())).into_response()
+            }
+            (database, "a") => {
+                Json(serialize_matrix(select_database(db, database)?.a())).into_response()
+            }
+            (database, "digest") => {
+                Json(select_database(db, database)?.digest().clone()).into_response()
+            }
+            _ => return Err(StatusCode::NOT_FOUND),
+        };
+        Ok(response)
+    })
+    .await
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ParamsData {
-    m: usize,
-    n: usize,
-    q: String,
-    p: String,
+#[cfg_attr(feature = "openapi", utoipa::path(
+    post,
+    path = "/epochs/{epoch}/{database}/query",
+    tag = "epochs",
+    params(
+        ("epoch" = u64, Path, description = "Epoch of the main database"),
+        ("database" = String, Path, description = "Route prefix of the database, e.g. clusters/3; omitted for the main one")
+    ),
+    request_body(
+        content = QueryRequest,
+        description = "Or the query alone as little-endian u64 words, sent as application/x-tiptoe-words"
+    ),
+    responses(
+        (status = 200, body = QueryResponse),
+        (status = 400, description = "Malformed query or secret, or a query of the wrong length"),
+        (status = 403, description = "Plaintext query to a server not in plaintext mode"),
+        (status = 404),
+        (status = 410, description = "Neither the current epoch nor the previous one within its grace period"),
+        (status = 503, description = "Compute pool full; retry after Retry-After seconds")
+    )
+))]
+async fn handle_epoch_query<T: Database + Send + Sync + 'static>(
+    State(state): State<Arc<ServerState<T>>>,
+    Path((epoch, path)): Path<(u64, String)>,
+    body: QueryBody,
+) -> Result<Json<QueryResponse>, QueryError> {
+    let database = path
+        .strip_suffix("query")
+        .ok_or(StatusCode::NOT_FOUND)?
+        .to_string();
+    answer_at(&state, Some(epoch), body, move |db| {
+        select_database(db, &database)
+    })
+    .await
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct MatrixResponse {
-    rows: usize,
-    cols: usize,
-    data: Vec<String>,
+// Remote database implementation that connects to server
+#[async_trait]
+pub trait AsyncDatabase: Send + Sync {
+    async fn respond(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>>;
+    // Sends the query's secret along so the server returns the recovered answer and the
+    // client needs no hint. Reveals the query to the server.
+    async fn respond_assisted(
+        &self,
+        query: &DVector<BigInt>,
+        secret: &DVector<BigInt>,
+    ) -> Result<DVector<BigInt>>;
+    // Sends the plaintext query vector and returns the server's plaintext answer, in
+    // plaintext mode. Reveals the query to the server.
+    async fn respond_plaintext(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>>;
+    async fn get_params(&self) -> Result<SimplePIRParams>;
+    async fn get_hint(&self) -> Result<DMatrix<BigInt>>;
+    // Changes to the hint of epoch `from`, if the server can still describe them
+    async fn get_hint_diff(&self, from: u64) -> Result<Option<HintDiff>>;
+    async fn get_a(&self) -> Result<DMatrix<BigInt>>;
+    async fn get_epoch(&self) -> Result<u64>;
+    // Epoch of the server's main database, which `at_epoch` takes. Nested databases
+    // report their own in `get_epoch`.
+    async fn get_server_epoch(&self) -> Result<u64>;
+    // Params with the epoch of the database they belong to, in one request
+    async fn get_versioned_params(&self) -> Result<(u64, SimplePIRParams)>;
+    async fn get_digest(&self) -> Result<DatabaseDigest>;
+    async fn get_quantization(&self) -> Result<Option<Quantization>>;
+    async fn get_calibration(&self) -> Result<Option<Calibration>>;
+    async fn get_query_correction(&self) -> Result<Option<QueryCorrection>>;
+    async fn get_query_prefix(&self) -> Result<Option<String>>;
+    async fn get_query_dim(&self) -> Result<Option<usize>>;
+    async fn get_embedding_model(&self) -> Result<Option<EmbeddingModel>>;
+    async fn get_centroids(&self) -> Result<Option<CentroidsData>>;
+    // Rows of the documents in this cluster database, for one served under `cluster`
+    async fn get_members(&self) -> Result<Vec<usize>>;
+    // The per-cluster database served under `/clusters/{id}`
+    fn cluster(&self, id: usize) -> Box<dyn AsyncDatabase>;
+    async fn get_partitions(&self) -> Result<Option<PartitionManifest>>;
+    // The per-window database served under `/partitions/{id}`
+    fn partition(&self, id: usize) -> Box<dyn AsyncDatabase>;
+    async fn get_membership(&self) -> Result<Option<BloomParams>>;
+    // The epoch together with its document ids, in one request
+    async fn get_documents(&self) -> Result<(u64, Vec<DocumentId>)>;
+    async fn get_dead_rows(&self) -> Result<BTreeSet<usize>>;
+    // The Bloom filter database served under `/membership`
+    fn membership(&self) -> Box<dyn AsyncDatabase>;
+    async fn get_hot(&self) -> Result<Option<HotInfo>>;
+    // The hot tier database served under `/hot`
+    fn hot(&self) -> Box<dyn AsyncDatabase>;
+    async fn get_packed(&self) -> Result<Option<PackedLayout>>;
+    // The packed numeric database served under `/packed`, relative to the hot tier
+    fn packed(&self) -> Box<dyn AsyncDatabase>;
+    async fn get_blocks(&self) -> Result<Option<BlockLayout>>;
+    // The block database served under `/blocks`
+    fn blocks(&self) -> Box<dyn AsyncDatabase>;
+    // This database as of the main database's `epoch`, served under `/epochs/{epoch}`
+    // until the server's grace period after the next rebuild runs out. Its queries
+    // fail with 410 Gone rather than being answered by a newer epoch.
+    fn at_epoch(&self, epoch: u64) -> Box<dyn AsyncDatabase>;
+    // This same database, e.g. for a background download to own
+    fn boxed_clone(&self) -> Box<dyn AsyncDatabase>;
+    fn origin(&self) -> Option<String>;
+    // Route of the database on its server, e.g. `/clusters/3`; empty for the main one
+    fn path(&self) -> &str;
 }
 
-// Helper functions for serialization
-fn serialize_vector(vec: &DVector<BigInt>) -> Vec<String> {
-    vec.iter().map(|x| x.to_string()).collect()
+pub(crate) trait WithDeadline {
+    fn with_deadline(self) -> Self;
 }
 
-fn deserialize_vector(vec: &[String]) -> DVector<BigInt> {
-    let values: Vec<BigInt> = vec.iter().map(|x| x.parse().unwrap()).collect();
-    DVector::from_vec(values)
+impl WithDeadline for RequestBuilder {
+    // Stops waiting once the current query's deadline passes and tells the server how
+    // long it has left
+    fn with_deadline(self) -> Self {
+        match DEADLINE.try_with(|deadline| deadline.saturating_duration_since(Instant::now())) {
+            Ok(remaining) => self
+                .timeout(remaining)
+                .header(DEADLINE_HEADER, remaining.as_millis().to_string()),
+            Err(_) => self,
+        }
+    }
 }
 
-fn serialize_matrix(matrix: &DMatrix<BigInt>) -> MatrixResponse {
-    MatrixResponse {
-        rows: matrix.nrows(),
-        cols: matrix.ncols(),
-        data: matrix.iter().map(|x| x.to_string()).collect(),
-    }
+// Runs `query` as one session: once a server issues a session token, every later
+// request `query` makes to that server carries it, so all of a query's requests to one
+// server reach the same node. Each server's token only goes back to that server, as the
+// embedding and encoding servers may name their nodes differently.
+pub async fn in_session<F: Future>(query: F) -> F::Output {
+    SESSION.scope(RefCell::new(BTreeMap::new()), query).await
 }
 
-fn deserialize_matrix(response: &MatrixResponse) -> DMatrix<BigInt> {
-    let data: Vec<BigInt> = response.data.iter().map(|x| x.parse().unwrap()).collect();
-    DMatrix::from_vec(response.rows, response.cols, data)
+// Tokens the current session was issued, by any server
+pub(crate) fn session_tokens() -> Vec<SessionToken> {
+    SESSION
+        .try_with(|session| {
+            session
+                .borrow()
+                .values()
+                .filter_map(|token| token.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
-fn serialize_params(params: &SimplePIRParams) -> ParamsData {
-    ParamsData {
-        m: params.m,
-        n: params.n,
-        q: params.q.to_string(),
-        p: params.p.to_string(),
+// How a `RemoteDatabase` reaches its server. `database` is the route prefix of the PIR
+// database being used: empty for the main one, `/clusters/{id}`, `/partitions/{id}`,
+// `/membership`, `/hot` or `/hot/packed` for the others.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send_query(&self, database: &str, request: &QueryRequest) -> Result<QueryResponse>;
+    async fn get_params(&self, database: &str) -> Result<ParamsData>;
+    async fn get_hint(&self, database: &str) -> Result<MatrixResponse>;
+    async fn get_a(&self, database: &str) -> Result<MatrixResponse>;
+    // Any other public JSON resource (centroids, documents, membership and hot tier info)
+    async fn get_json(&self, path: &str) -> Result<serde_json::Value>;
+    // Server this transport talks to, under which its signing key is pinned
+    fn origin(&self) -> Option<String> {
+        None
     }
 }
 
-fn deserialize_params(data: &ParamsData) -> SimplePIRParams {
-    let p = BigInt::from_str(&data.p).unwrap();
-    let mod_power = (p.bits() - 1) as u32;
-    gen_params(data.m, data.n, mod_power)
+// One pooled client per process, so the embedding, encoding, cluster and membership
+// databases share connections instead of each opening their own. Both servers speak
+// HTTP/2 without TLS, so it is used from the first request; pings keep idle
+// connections open between queries.
+fn shared_http_client() -> HttpClient {
+    static CLIENT: OnceLock<HttpClient> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| {
+            HttpClient::builder()
+                .http2_prior_knowledge()
+                .http2_adaptive_window(true)
+                .http2_keep_alive_interval(KEEP_ALIVE_INTERVAL)
+                .http2_keep_alive_timeout(KEEP_ALIVE_TIMEOUT)
+                .http2_keep_alive_while_idle(true)
+                .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+                .tcp_keepalive(KEEP_ALIVE_INTERVAL)
+                .tcp_nodelay(true)
+                .build()
+                .unwrap()
+        })
+        .clone()
 }
 
-pub async fn run_server<T: Database + Send + Sync + 'static>(db: T, port: u16) {
-    let state = Arc::new(ServerState {
-        db: RwLock::new(db),
-    });
+// Default transport: plain JSON over HTTP
+pub struct HttpTransport {
+    client: HttpClient,
+    base_url: String,
+    // Sent with every request to servers that enforce an authorization policy
+    api_key: Option<String>,
+}
 
-    let update_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(15));
-        loop {
-            interval.tick().await;
-            println!("Starting database update...");
-
-            let new_db = match tokio::task::spawn_blocking(|| {
-                // Build a new instance using T::new() followed by T::update()
-                T::new().and_then(|mut instance| {
-                    instance.update()?;
-                    Ok(instance)
-                })
-            })
-            .await
-            {
-                Ok(Ok(new_instance)) => new_instance,
-                Ok(Err(e)) => {
-                    eprintln!("Error building new database: {:?}", e);
-                    continue;
-                }
-                Err(e) => {
-                    eprintln!("Blocking task panicked: {:?}", e);
-                    continue;
-                }
-            };
+impl HttpTransport {
+    pub fn new(base_url: String) -> Self {
+        Self::with_client(base_url, shared_http_client())
+    }
 
-            {
-                let mut db_lock = update_state.db.write().await;
-                *db_lock = new_db;
-            }
-            println!("Database update complete!");
+    // For servers behind proxies that need different connection settings
+    pub fn with_client(base_url: String, client: HttpClient) -> Self {
+        Self {
+            client,
+            base_url,
+            api_key: None,
         }
-    });
+    }
 
-    let app = Router::new()
-        .route("/query", axum::routing::post(handle_query::<T>))
-        .route("/params", axum::routing::get(handle_params::<T>))
-        .route("/hint", axum::routing::get(handle_hint::<T>))
-        .route("/a", axum::routing::get(handle_a::<T>))
-        .with_state(state);
+    pub fn with_api_key(mut self, key: String) -> Self {
+        self.api_key = Some(key);
+        self
+    }
 
-    let addr = format!("0.0.0.0:{}", port).parse().unwrap();
-    println!("Starting server on {}", addr);
+    fn authenticated(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(key) => request.header(API_KEY_HEADER, key),
+            None => request,
+        }
+    }
 
-    axum_server::bind(addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
-}
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
 
-async fn handle_query<T: Database + Send + Sync>(
-    State(state): State<Arc<ServerState<T>>>,
-    Json(request): Json<QueryRequest>,
-) -> Json<QueryResponse> {
-    let query = deserialize_vector(&request.query);
-    let db = state.db.read().await;
-    let response = db.respond(&query).unwrap();
-    Json(QueryResponse {
-        response: serialize_vector(&response),
-    })
-}
+    // Sends `request` within the current session, keeping the first token this server
+    // issues the session
+    async fn send(&self, request: RequestBuilder) -> Result<reqwest::Response> {
+        let token = SESSION
+            .try_with(|session| session.borrow().get(&self.base_url).cloned())
+            .ok()
+            .flatten();
+        let request = match token {
+            Some(token) => request.header(SESSION_HEADER, token),
+            None => request,
+        };
+        let response = self.authenticated(request).with_deadline().send().await?;
+        if response.status() == StatusCode::MISDIRECTED_REQUEST {
+            return Err(PirError::Database(format!(
+                "Session routed to the wrong node: {}",
+                response.text().await.unwrap_or_default()
+            ))
+            .into());
+        }
+        if let Some(issued) = response
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+        {
+            let _ = SESSION.try_with(|session| {
+                session
+                    .borrow_mut()
+                    .entry(self.base_url.clone())
+                    .or_insert_with(|| issued.to_string());
+            });
+        }
+        Ok(response)
+    }
 
-async fn handle_params<T: Database + Send + Sync>(
-    State(state): State<Arc<ServerState<T>>>,
-) -> Json<ParamsData> {
-    let db = state.db.read().await;
-    Json(serialize_params(db.params()))
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        Ok(self
+            .send(self.client.get(self.url(path)))
+            .await?
+            .json()
+            .await?)
+    }
 }
 
-async fn handle_hint<T: Database + Send + Sync>(
-    State(state): State<Arc<ServerState<T>>>,
-) -> Json<MatrixResponse> {
-    let db = state.db.read().await;
-    Json(serialize_matrix(db.hint()))
-}
+#[async_trait]
+impl Transport for HttpTransport {
+    async fn send_query(&self, database: &str, request: &QueryRequest) -> Result<QueryResponse> {
+        let response = self
+            .send(
+                self.client
+                    .post(self.url(&format!("{}/query", database)))
+                    .json(request),
+            )
+            .await?;
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after_secs = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(RETRY_AFTER_SECS);
+            if let Ok(changing) = response.json::<EpochChangingResponse>().await {
+                if changing.error == EPOCH_CHANGING {
+                    return Err(PirError::EpochChanging {
+                        epoch: changing.epoch,
+                        retry_after_secs,
+                    }
+                    .into());
+                }
+            }
+            return Err(PirError::Database(format!(
+                "Server unavailable; retry after {}s",
+                retry_after_secs
+            ))
+            .into());
+        }
+        Ok(response.json().await?)
+    }
 
-async fn handle_a<T: Database + Send + Sync>(
-    State(state): State<Arc<ServerState<T>>>,
-) -> Json<MatrixResponse> {
-    let db = state.db.read().await;
-    Json(serialize_matrix(db.a()))
-}
+    async fn get_params(&self, database: &str) -> Result<ParamsData> {
+        self.get(&format!("{}/params", database)).await
+    }
 
-// Remote database implementation that connects to server
-#[async_trait]
-pub trait AsyncDatabase {
-    async fn respond(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>>;
-    async fn get_params(&self) -> Result<SimplePIRParams>;
-    async fn get_hint(&self) -> Result<DMatrix<BigInt>>;
-    async fn get_a(&self) -> Result<DMatrix<BigInt>>;
+    async fn get_hint(&self, database: &str) -> Result<MatrixResponse> {
+        self.get(&format!("{}/hint", database)).await
+    }
+
+    async fn get_a(&self, database: &str) -> Result<MatrixResponse> {
+        self.get(&format!("{}/a", database)).await
+    }
+
+    async fn get_json(&self, path: &str) -> Result<serde_json::Value> {
+        self.get(path).await
+    }
+
+    fn origin(&self) -> Option<String> {
+        Some(self.base_url.clone())
+    }
 }
 
 pub struct RemoteDatabase {
-    client: HttpClient,
-    base_url: String,
+    transport: Arc<dyn Transport>,
+    database: String,
 }
 
 impl RemoteDatabase {
     pub fn new(base_url: String) -> Self {
+        Self::with_transport(Arc::new(HttpTransport::new(base_url)))
+    }
+
+    // For servers behind proxies that need different connection settings
+    pub fn with_client(base_url: String, client: HttpClient) -> Self {
+        Self::with_transport(Arc::new(HttpTransport::with_client(base_url, client)))
+    }
+
+    pub fn with_transport(transport: Arc<dyn Transport>) -> Self {
         Self {
-            client: HttpClient::builder().build().unwrap(),
-            base_url,
+            transport,
+            database: String::new(),
         }
     }
+
+    // Database served under `path`, reached through the same transport
+    fn nested(&self, path: String) -> Box<dyn AsyncDatabase> {
+        Box::new(Self {
+            transport: Arc::clone(&self.transport),
+            database: format!("{}/{}", self.database, path),
+        })
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let value = self
+            .transport
+            .get_json(&format!("{}/{}", self.database, path))
+            .await?;
+        Ok(serde_json::from_value(value)?)
+    }
 }
 
 #[async_trait]
 impl AsyncDatabase for RemoteDatabase {
     async fn respond(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>> {
-        let response: QueryResponse = self
-            .client
-            .post(format!("{}/query", self.base_url))
-            .json(&QueryRequest {
-                query: serialize_vector(query),
-            })
-            .send()
-            .await?
-            .json()
-            .await?;
+        let request = QueryRequest {
+            query: Sealed::new(serialize_vector(query)),
+            secret: None,
+            plaintext: false,
+        };
+        let response = self.transport.send_query(&self.database, &request).await?;
+        deserialize_vector(&response.response)
+    }
+
+    async fn respond_assisted(
+        &self,
+        query: &DVector<BigInt>,
+        secret: &DVector<BigInt>,
+    ) -> Result<DVector<BigInt>> {
+        let request = QueryRequest {
+            query: Sealed::new(serialize_vector(query)),
+            secret: Some(Sealed::new(serialize_vector(secret))),
+            plaintext: false,
+        };
+        let response = self.transport.send_query(&self.database, &request).await?;
+        let recovered = response.recovered.ok_or_else(|| {
+            PirError::Database("Server did not recover the assisted query".to_string())
+        })?;
+        deserialize_vector(&recovered)
+    }
 
-        Ok(deserialize_vector(&response.response))
+    async fn respond_plaintext(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>> {
+        let request = QueryRequest {
+            query: Sealed::new(serialize_vector(query)),
+            secret: None,
+            plaintext: true,
+        };
+        let response = self.transport.send_query(&self.database, &request).await?;
+        let answer = response.recovered.ok_or_else(|| {
+            PirError::Database("Server did not answer the plaintext query".to_string())
+        })?;
+        let answer = deserialize_vector(&answer)?;
+        // The client ranks by the scores, so they must put the server's best row first
+        // or they were mangled on the way
+        let best = best_row(&answer);
+        if response.best != best {
+            return Err(PirError::Database(format!(
+                "Plaintext scores rank row {:?} first, but the server's best row is {:?}",
+                best, response.best
+            ))
+            .into());
+        }
+        Ok(answer)
     }
 
     async fn get_params(&self) -> Result<SimplePIRParams> {
-        let response: ParamsData = self
-            .client
-            .get(format!("{}/params", self.base_url))
-            .send()
-            .await?
-            .json()
-            .await?;
-        Ok(deserialize_params(&response))
+        let response = self.transport.get_params(&self.database).await?;
+        deserialize_params(&response)
     }
 
     async fn get_hint(&self) -> Result<DMatrix<BigInt>> {
-        let response: MatrixResponse = self
-            .client
-            .get(format!("{}/hint", self.base_url))
-            .send()
-            .await?
-            .json()
-            .await?;
-        Ok(deserialize_matrix(&response))
+        let response = self.transport.get_hint(&self.database).await?;
+        deserialize_matrix(&response)
+    }
+
+    async fn get_hint_diff(&self, from: u64) -> Result<Option<HintDiff>> {
+        self.get(&format!("hint_diff?from={}", from)).await
     }
 
     async fn get_a(&self) -> Result<DMatrix<BigInt>> {
-        let response: MatrixResponse = self
-            .client
-            .get(format!("{}/a", self.base_url))
-            .send()
+        let response = self.transport.get_a(&self.database).await?;
+        deserialize_matrix(&response)
+    }
+
+    async fn get_epoch(&self) -> Result<u64> {
+        Ok(self.transport.get_params(&self.database).await?.epoch)
+    }
+
+    async fn get_server_epoch(&self) -> Result<u64> {
+        Ok(self.transport.get_params("").await?.epoch)
+    }
+
+    async fn get_versioned_params(&self) -> Result<(u64, SimplePIRParams)> {
+        let data = self.transport.get_params(&self.database).await?;
+        Ok((data.epoch, deserialize_params(&data)?))
+    }
+
+    async fn get_digest(&self) -> Result<DatabaseDigest> {
+        self.get("digest").await
+    }
+
+    async fn get_quantization(&self) -> Result<Option<Quantization>> {
+        Ok(self
+            .transport
+            .get_params(&self.database)
             .await?
-            .json()
-            .await?;
-        Ok(deserialize_matrix(&response))
+            .quantization)
+    }
+
+    async fn get_calibration(&self) -> Result<Option<Calibration>> {
+        Ok(self.transport.get_params(&self.database).await?.calibration)
+    }
+
+    async fn get_query_correction(&self) -> Result<Option<QueryCorrection>> {
+        Ok(self.transport.get_params(&self.database).await?.correction)
+    }
+
+    async fn get_query_prefix(&self) -> Result<Option<String>> {
+        Ok(self
+            .transport
+            .get_params(&self.database)
+            .await?
+            .query_prefix)
+    }
+
+    async fn get_query_dim(&self) -> Result<Option<usize>> {
+        Ok(self.transport.get_params(&self.database).await?.query_dim)
+    }
+
+    async fn get_embedding_model(&self) -> Result<Option<EmbeddingModel>> {
+        Ok(self.transport.get_params(&self.database).await?.model)
+    }
+
+    async fn get_centroids(&self) -> Result<Option<CentroidsData>> {
+        self.get("centroids").await
+    }
+
+    async fn get_members(&self) -> Result<Vec<usize>> {
+        self.get("members").await
+    }
+
+    fn cluster(&self, id: usize) -> Box<dyn AsyncDatabase> {
+        self.nested(format!("clusters/{}", id))
+    }
+
+    async fn get_partitions(&self) -> Result<Option<PartitionManifest>> {
+        self.get("partitions").await
+    }
+
+    fn partition(&self, id: usize) -> Box<dyn AsyncDatabase> {
+        self.nested(format!("partitions/{}", id))
+    }
+
+    async fn get_membership(&self) -> Result<Option<BloomParams>> {
+        self.get("membership").await
+    }
+
+    fn membership(&self) -> Box<dyn AsyncDatabase> {
+        self.nested("membership".to_string())
+    }
+
+    async fn get_documents(&self) -> Result<(u64, Vec<DocumentId>)> {
+        let response: DocumentsResponse = self.get("documents").await?;
+        Ok((response.epoch, response.ids))
+    }
+
+    async fn get_dead_rows(&self) -> Result<BTreeSet<usize>> {
+        let response: DocumentsResponse = self.get("documents").await?;
+        Ok(response.dead_rows)
+    }
+
+    async fn get_hot(&self) -> Result<Option<HotInfo>> {
+        self.get("hot").await
+    }
+
+    fn hot(&self) -> Box<dyn AsyncDatabase> {
+        self.nested("hot".to_string())
+    }
+
+    async fn get_packed(&self) -> Result<Option<PackedLayout>> {
+        self.get("packed").await
+    }
+
+    fn packed(&self) -> Box<dyn AsyncDatabase> {
+        self.nested("packed".to_string())
+    }
+
+    async fn get_blocks(&self) -> Result<Option<BlockLayout>> {
+        self.get("blocks").await
+    }
+
+    fn blocks(&self) -> Box<dyn AsyncDatabase> {
+        self.nested("blocks".to_string())
+    }
+
+    fn at_epoch(&self, epoch: u64) -> Box<dyn AsyncDatabase> {
+        Box::new(Self {
+            transport: Arc::clone(&self.transport),
+            database: format!("/epochs/{}{}", epoch, self.database),
+        })
     }
+
+    fn boxed_clone(&self) -> Box<dyn AsyncDatabase> {
+        Box::new(Self {
+            transport: Arc::clone(&self.transport),
+            database: self.database.clone(),
+        })
+    }
+
+    fn origin(&self) -> Option<String> {
+        self.transport.origin()
+    }
+
+    fn path(&self) -> &str {
+        &self.database
+    }
+}
+
+// What a PIR round against one database needs, downloaded once per epoch
+struct PreparedDatabase {
+    data: ParamsData,
+    params: SimplePIRParams,
+    a: DMatrix<BigInt>,
+    hint: DMatrix<BigInt>,
+}
+
+fn same_shape(old: &ParamsData, new: &ParamsData) -> bool {
+    (old.m, old.n, &old.q, &old.p) == (new.m, new.n, &new.q, &new.p)
 }
 
 // Network client implementation
@@ -253,49 +4113,145 @@ pub struct NetworkClient {
     embedder: BertEmbedder,
     embedding_db: RemoteDatabase,
     encoding_db: RemoteDatabase,
+    // Downloads for each database's current epoch. Queries hold their own `Arc`, so
+    // any number of them can run at once and a refresh never stalls one in flight.
+    embedding_state: RwLock<Option<Arc<PreparedDatabase>>>,
+    encoding_state: RwLock<Option<Arc<PreparedDatabase>>>,
 }
 
 impl NetworkClient {
     pub fn new(embedding_url: String, encoding_url: String) -> Result<Self> {
+        Self::from_databases(
+            RemoteDatabase::new(embedding_url),
+            RemoteDatabase::new(encoding_url),
+        )
+    }
+
+    pub fn from_transports(
+        embedding: Arc<dyn Transport>,
+        encoding: Arc<dyn Transport>,
+    ) -> Result<Self> {
+        Self::from_databases(
+            RemoteDatabase::with_transport(embedding),
+            RemoteDatabase::with_transport(encoding),
+        )
+    }
+
+    fn from_databases(embedding_db: RemoteDatabase, encoding_db: RemoteDatabase) -> Result<Self> {
         Ok(Self {
             embedder: BertEmbedder::new()?,
-            embedding_db: RemoteDatabase::new(embedding_url),
-            encoding_db: RemoteDatabase::new(encoding_url),
+            embedding_db,
+            encoding_db,
+            embedding_state: RwLock::new(None),
+            encoding_state: RwLock::new(None),
         })
     }
 
-    fn adjust_embedding(embedding: DVector<BigInt>, m: usize) -> DVector<BigInt> {
-        match embedding.len().cmp(&m) {
-            std::cmp::Ordering::Equal => embedding,
-            std::cmp::Ordering::Less => {
-                let mut new_embedding = DVector::zeros(m);
-                new_embedding
-                    .rows_mut(0, embedding.len())
-                    .copy_from(&embedding);
-                new_embedding
+    // Params, A and hint for the database's current epoch. Only the params are fetched
+    // while the epoch is unchanged. After a rebuild that kept A, the hint is patched
+    // with the rows that changed; otherwise A and the hint are fetched together.
+    async fn prepare(
+        db: &RemoteDatabase,
+        state: &RwLock<Option<Arc<PreparedDatabase>>>,
+    ) -> Result<Arc<PreparedDatabase>> {
+        let data = db.transport.get_params(&db.database).await?;
+        let previous = state.read().await.clone();
+        if let Some(prepared) = previous.as_ref() {
+            if prepared.data.epoch == data.epoch {
+                return Ok(Arc::clone(prepared));
+            }
+        }
+
+        let patched = match previous {
+            // Servers that predate hint diffs answer with an error, and get the full
+            // download like any diff that cannot be applied
+            Some(prepared) if same_shape(&prepared.data, &data) => {
+                Self::patch(db, &prepared, data.epoch).await.unwrap_or(None)
             }
-            std::cmp::Ordering::Greater => embedding.rows(0, m).into(),
+            _ => None,
+        };
+        let (a, hint) = match patched {
+            Some(patched) => patched,
+            None => tokio::try_join!(db.get_a(), db.get_hint())?,
+        };
+        let prepared = Arc::new(PreparedDatabase {
+            params: deserialize_params(&data)?,
+            data,
+            a,
+            hint,
+        });
+        // Queries racing through a rebuild may each download; any of them is current
+        *state.write().await = Some(Arc::clone(&prepared));
+        Ok(prepared)
+    }
+
+    // A and the hint of `epoch` from those of `prepared`, if the server still has the
+    // changes in between and A is unchanged
+    async fn patch(
+        db: &RemoteDatabase,
+        prepared: &PreparedDatabase,
+        epoch: u64,
+    ) -> Result<Option<(DMatrix<BigInt>, DMatrix<BigInt>)>> {
+        let Some(diff) = db.get_hint_diff(prepared.data.epoch).await? else {
+            return Ok(None);
+        };
+        // A rebuild may have landed since the params were fetched
+        if diff.epoch != epoch {
+            return Ok(None);
         }
+        let mut hint = prepared.hint.clone();
+        diff.apply(&mut hint)?;
+        Ok(Some((prepared.a.clone(), hint)))
     }
 
     pub async fn query(&self, query: &str) -> Result<DVector<BigInt>> {
-        let embedding = self.embedder.embed_text(query)?;
-
-        let embedding_params = self.embedding_db.get_params().await?;
-        let adjusted_embedding = Self::adjust_embedding(embedding, embedding_params.m);
-        let (s_embedding, query_embedding) = generate_query(
-            &embedding_params,
-            &adjusted_embedding,
-            &self.embedding_db.get_a().await?,
-        );
+        in_session(self.query_in_session(query)).await
+    }
 
-        let response_embedding = self.embedding_db.respond(&query_embedding).await?;
-        let result_embedding = recover(
-            &self.embedding_db.get_hint().await?,
-            &s_embedding,
-            &response_embedding,
-            &embedding_params,
-        );
+    async fn query_in_session(&self, query: &str) -> Result<DVector<BigInt>> {
+        // The embedding params open the session, so everything after reaches the same
+        // node. The encoding round doesn't depend on the embedding round until its
+        // query is built, so its database is prepared during the embedding round.
+        let embedding_db = Self::prepare(&self.embedding_db, &self.embedding_state).await?;
+        check_model(self.embedder.model(), embedding_db.data.model.as_ref())?;
+        let embedding_round = async {
+            let prefix = embedding_db
+                .data
+                .query_prefix
+                .as_deref()
+                .unwrap_or_default();
+            let mut raw_embedding = self.embedder.embed_raw(&format!("{}{}", prefix, query))?;
+            if let Some(correction) = &embedding_db.data.correction {
+                raw_embedding = correction.apply(&raw_embedding)?;
+            }
+            let embedding = embedding_db
+                .data
+                .quantization
+                .unwrap_or_default()
+                .quantize(&raw_embedding);
+            check_query_dim(embedding.len(), embedding_db.data.query_dim)?;
+            let adjusted_embedding = fit_query(embedding, embedding_db.params.m)?;
+            let (s_embedding, query_embedding) =
+                pir::query(&embedding_db.params, &adjusted_embedding, &embedding_db.a);
+
+            // Answered by the epoch the hint is for, even if a rebuild lands in between
+            let response_embedding = self
+                .embedding_db
+                .at_epoch(embedding_db.data.epoch)
+                .respond(&query_embedding)
+                .await?;
+            let result_embedding = pir::recover(
+                &embedding_db.params,
+                &embedding_db.hint,
+                &s_embedding,
+                &response_embedding,
+            );
+            Ok::<_, anyhow::Error>(result_embedding)
+        };
+        let (result_embedding, encoding_db) = tokio::try_join!(
+            embedding_round,
+            Self::prepare(&self.encoding_db, &self.encoding_state),
+        )?;
 
         let result_vec = {
             let mut vec = DVector::zeros(result_embedding.len());
@@ -309,21 +4265,15 @@ impl NetworkClient {
             vec
         };
 
-        let encoding_params = self.encoding_db.get_params().await?;
-        let adjusted_result = Self::adjust_embedding(result_vec, encoding_params.m);
-        let (s, query) = generate_query(
-            &encoding_params,
-            &adjusted_result,
-            &self.encoding_db.get_a().await?,
-        );
+        let adjusted_result = fit_query(result_vec, encoding_db.params.m)?;
+        let (s, query) = pir::query(&encoding_db.params, &adjusted_result, &encoding_db.a);
 
-        let response = self.encoding_db.respond(&query).await?;
-        let result = recover(
-            &self.encoding_db.get_hint().await?,
-            &s,
-            &response,
-            &encoding_params,
-        );
+        let response = self
+            .encoding_db
+            .at_epoch(encoding_db.data.epoch)
+            .respond(&query)
+            .await?;
+        let result = pir::recover(&encoding_db.params, &encoding_db.hint, &s, &response);
 
         Ok(result)
     }
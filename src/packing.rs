@@ -0,0 +1,242 @@
+use anyhow::Result;
+use nalgebra::{DMatrix, DVector};
+use num_bigint::BigInt;
+use num_traits::Zero;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ops::Range;
+
+use crate::{error::PirError, server::SimplePirDatabase};
+
+// Numeric field packed into its own database; set it empty to disable packing
+const PACKED_FIELD_ENV_VAR: &str = "TIPTOE_PACKED_FIELD";
+const DEFAULT_PACKED_FIELD: &str = "currentPrice";
+// Values stored in each column, so one PIR round returns this many of them
+pub const VALUES_PER_COLUMN: usize = 64;
+// Adjacent records stacked in each column of the block database; unset or 0 disables it
+const BLOCK_SIZE_ENV_VAR: &str = "TIPTOE_BLOCK_SIZE";
+
+pub fn packed_field() -> Option<String> {
+    let field =
+        std::env::var(PACKED_FIELD_ENV_VAR).unwrap_or_else(|_| DEFAULT_PACKED_FIELD.to_string());
+    let field = field.trim();
+    (!field.is_empty()).then(|| field.to_string())
+}
+
+pub fn block_size() -> Result<Option<usize>> {
+    match std::env::var(BLOCK_SIZE_ENV_VAR) {
+        Ok(size) => {
+            let size: usize = size.trim().parse().map_err(|_| {
+                PirError::InvalidInput(format!("Invalid {}: {}", BLOCK_SIZE_ENV_VAR, size))
+            })?;
+            Ok((size > 0).then_some(size))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+// Public layout of a packed database: the symbol stored in each slot. Published in
+// full so clients find a symbol's slot without revealing which one they want.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PackedLayout {
+    pub field: String,
+    pub symbols: Vec<String>,
+    pub values_per_column: usize,
+}
+
+impl PackedLayout {
+    // (column, row) holding `symbol`'s value
+    pub fn locate(&self, symbol: &str) -> Option<(usize, usize)> {
+        let slot = self.symbols.iter().position(|s| s == symbol)?;
+        Some((slot / self.values_per_column, slot % self.values_per_column))
+    }
+}
+
+// Stored as the bits of an f64; missing and non-numeric values are stored as NaN
+pub fn pack_value(value: Option<&Value>) -> BigInt {
+    BigInt::from(value.and_then(Value::as_f64).unwrap_or(f64::NAN).to_bits())
+}
+
+pub fn unpack_value(element: &BigInt) -> Option<f64> {
+    let value = f64::from_bits(u64::try_from(element).ok()?);
+    (!value.is_nan()).then_some(value)
+}
+
+// One numeric field of every document, many values per column, instead of a whole
+// JSON record per column
+pub struct PackedValues {
+    pub layout: PackedLayout,
+    pub db: SimplePirDatabase,
+}
+
+impl PackedValues {
+    pub fn build(symbols: Vec<String>, documents: &[Value], field: &str) -> Result<Self> {
+        let columns = documents.len().div_ceil(VALUES_PER_COLUMN).max(1);
+        // Queries are as long as the matrix is tall, so it is kept square
+        let side = columns.max(VALUES_PER_COLUMN);
+        let mut data = DMatrix::from_element(side, side, pack_value(None));
+        for (slot, document) in documents.iter().enumerate() {
+            data[(slot % VALUES_PER_COLUMN, slot / VALUES_PER_COLUMN)] =
+                pack_value(document.get(field));
+        }
+
+        let mut db = SimplePirDatabase::new(DMatrix::zeros(1, 1));
+        db.update_db(data)?;
+        Ok(Self {
+            layout: PackedLayout {
+                field: field.to_string(),
+                symbols,
+                values_per_column: VALUES_PER_COLUMN,
+            },
+            db,
+        })
+    }
+}
+
+// Public layout of a block database: records `block * block_size` up to the next
+// block share column `block`, each taking `record_words` rows
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct BlockLayout {
+    pub block_size: usize,
+    pub record_words: usize,
+    pub records: usize,
+}
+
+impl BlockLayout {
+    pub fn block_of(&self, row: usize) -> usize {
+        row / self.block_size
+    }
+
+    // Rows of the encoding database stored in `block`
+    pub fn rows(&self, block: usize) -> Range<usize> {
+        let start = (block * self.block_size).min(self.records);
+        start..(start + self.block_size).min(self.records)
+    }
+
+    // (column, first matrix row) of the record in `row`
+    fn locate(&self, row: usize) -> (usize, usize) {
+        (
+            row / self.block_size,
+            (row % self.block_size) * self.record_words,
+        )
+    }
+
+    // Splits a recovered block into its records, each encoded as the encoding database
+    // stores it, in row order
+    pub fn split(&self, block: usize, column: &DVector<BigInt>) -> Vec<DVector<BigInt>> {
+        self.rows(block)
+            .map(|row| {
+                let (_, offset) = self.locate(row);
+                column.rows(offset, self.record_words).into_owned()
+            })
+            .collect()
+    }
+}
+
+// Records of the encoding database, several adjacent ones per column, so one PIR round
+// returns a record together with its neighbours
+pub struct RecordBlocks {
+    pub layout: BlockLayout,
+    pub db: SimplePirDatabase,
+}
+
+impl RecordBlocks {
+    // `records` holds one encoded record per column, like the encoding database; only
+    // its first `count` columns are records
+    pub fn build(records: &DMatrix<BigInt>, count: usize, block_size: usize) -> Result<Self> {
+        // Rows past the longest record are padding
+        let record_words = (0..records.nrows())
+            .rev()
+            .find(|&row| (0..count).any(|col| !records[(row, col)].is_zero()))
+            .map_or(1, |row| row + 1);
+        let layout = BlockLayout {
+            block_size,
+            record_words,
+            records: count,
+        };
+
+        let columns = count.div_ceil(block_size).max(1);
+        let side = columns.max(block_size * record_words);
+        let mut data = DMatrix::zeros(side, side);
+        for record in 0..count {
+            let (column, offset) = layout.locate(record);
+            for word in 0..record_words {
+                data[(offset + word, column)] = records[(word, record)].clone();
+            }
+        }
+
+        let mut db = SimplePirDatabase::new(DMatrix::zeros(1, 1));
+        db.update_db(data)?;
+        Ok(Self { layout, db })
+    }
+
+    // Rebuilds the blocks from only the records in `keep`, which close up in order
+    pub fn compact(&self, keep: &[usize]) -> Result<Self> {
+        let data = self.db.data();
+        let mut records = DMatrix::zeros(self.layout.record_words, keep.len().max(1));
+        for (i, &row) in keep.iter().enumerate() {
+            let (column, offset) = self.layout.locate(row);
+            for word in 0..self.layout.record_words {
+                records[(word, i)] = data[(offset + word, column)].clone();
+            }
+        }
+        Self::build(&records, keep.len(), self.layout.block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_packed_slots() {
+        let layout = PackedLayout {
+            field: "currentPrice".to_string(),
+            symbols: (0..130).map(|i| format!("Asset {}", i)).collect(),
+            values_per_column: VALUES_PER_COLUMN,
+        };
+        assert_eq!(layout.locate("Asset 0"), Some((0, 0)));
+        assert_eq!(layout.locate("Asset 65"), Some((1, 1)));
+        assert_eq!(layout.locate("Asset 130"), None);
+
+        let price = json!(250.1);
+        assert_eq!(unpack_value(&pack_value(Some(&price))), Some(250.1));
+        assert_eq!(unpack_value(&pack_value(Some(&json!("N/A")))), None);
+        assert_eq!(unpack_value(&pack_value(None)), None);
+    }
+
+    #[test]
+    fn test_record_blocks() -> Result<()> {
+        // Five records of up to two words, one per column
+        let records = DMatrix::from_fn(5, 5, |word, record| {
+            BigInt::from(if word < 2 { 10 * record + word + 1 } else { 0 })
+        });
+        let blocks = RecordBlocks::build(&records, 5, 2)?;
+        assert_eq!(blocks.layout.record_words, 2);
+        assert_eq!(blocks.layout.block_of(3), 1);
+        assert_eq!(blocks.layout.rows(1), 2..4);
+        assert_eq!(blocks.layout.rows(2), 4..5);
+
+        let column = |blocks: &RecordBlocks, block: usize| -> DVector<BigInt> {
+            blocks.db.data().column(block).into_owned()
+        };
+        let record =
+            |record: usize| -> DVector<BigInt> { records.column(record).rows(0, 2).into_owned() };
+        assert_eq!(
+            blocks.layout.split(1, &column(&blocks, 1)),
+            vec![record(2), record(3)]
+        );
+        assert_eq!(blocks.layout.split(2, &column(&blocks, 2)), vec![record(4)]);
+
+        let compacted = blocks.compact(&[0, 2, 3])?;
+        assert_eq!(compacted.layout.records, 3);
+        assert_eq!(
+            compacted.layout.split(1, &column(&compacted, 1)),
+            vec![record(3)]
+        );
+        Ok(())
+    }
+}
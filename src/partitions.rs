@@ -0,0 +1,228 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::error::PirError;
+
+const DAY_SECS: u64 = 86_400;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Window {
+    Daily,
+    // Monday to Sunday, UTC
+    Weekly,
+}
+
+impl Window {
+    pub fn secs(self) -> u64 {
+        match self {
+            Self::Daily => DAY_SECS,
+            Self::Weekly => 7 * DAY_SECS,
+        }
+    }
+
+    // Start of the window `time` falls in
+    pub fn start_of(self, time: u64) -> u64 {
+        // 1970-01-01 was a Thursday, so weeks are counted from 3 days before it. The
+        // first partial week starts at 0.
+        let offset = match self {
+            Self::Daily => 0,
+            Self::Weekly => 3 * DAY_SECS,
+        };
+        ((time + offset) / self.secs() * self.secs()).saturating_sub(offset)
+    }
+}
+
+// Splits a server's documents into one embedding database per time window, e.g. for
+// news or price history, and drops windows past `retain` at the next rebuild
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct PartitionConfig {
+    pub window: Window,
+    // Windows kept, counting the current one
+    pub retain: u64,
+    // Unix time in seconds each document is dated by. Undated documents never expire
+    // and belong to no partition.
+    #[serde(default = "default_time_field")]
+    pub time_field: String,
+}
+
+fn default_time_field() -> String {
+    "quotedAt".to_string()
+}
+
+impl PartitionConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.retain == 0 {
+            return Err(PirError::InvalidInput(
+                "Partitions must retain at least one window".to_string(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // Documents dated before this have expired. It only moves at window boundaries, so
+    // the embedding and encoding servers drop the same documents unless their
+    // rebuilds straddle one.
+    pub fn cutoff(&self, now: u64) -> u64 {
+        let retained = (self.retain - 1).saturating_mul(self.window.secs());
+        self.window.start_of(now).saturating_sub(retained)
+    }
+
+    fn time(&self, document: &Value) -> Option<u64> {
+        document[&self.time_field].as_u64()
+    }
+
+    // Drops expired documents, returning how many were dropped
+    pub fn expire(&self, documents: &mut Vec<Value>, now: u64) -> usize {
+        let cutoff = self.cutoff(now);
+        let before = documents.len();
+        documents.retain(|document| self.time(document).is_none_or(|time| time >= cutoff));
+        before - documents.len()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Partition {
+    // Unix seconds; holds the documents dated in [start, end)
+    pub start: u64,
+    pub end: u64,
+    // Rows of the main database, in order
+    pub rows: Vec<usize>,
+}
+
+// Public partition layout of a server, served at `/partitions`. Partition `i` is the
+// database under `/partitions/{i}`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PartitionManifest {
+    pub namespace: String,
+    pub window: Window,
+    // Documents dated before this have expired
+    pub retained_from: u64,
+    // Oldest first; windows without documents are left out
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionManifest {
+    pub fn build(
+        config: &PartitionConfig,
+        namespace: String,
+        documents: &[Value],
+        now: u64,
+    ) -> Self {
+        let mut windows: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for (row, document) in documents.iter().enumerate() {
+            if let Some(time) = config.time(document) {
+                windows
+                    .entry(config.window.start_of(time))
+                    .or_default()
+                    .push(row);
+            }
+        }
+        Self {
+            namespace,
+            window: config.window,
+            retained_from: config.cutoff(now),
+            partitions: windows
+                .into_iter()
+                .map(|(start, rows)| Partition {
+                    start,
+                    end: start + config.window.secs(),
+                    rows,
+                })
+                .collect(),
+        }
+    }
+
+    // Ids of the partitions holding documents dated in [after, before)
+    pub fn covering(&self, after: Option<u64>, before: Option<u64>) -> Vec<usize> {
+        self.partitions
+            .iter()
+            .enumerate()
+            .filter(|(_, partition)| {
+                after.is_none_or(|after| partition.end > after)
+                    && before.is_none_or(|before| partition.start < before)
+            })
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    // Follows compaction of the main database: `moved[row]` is where a row went, or
+    // None if it was deleted. Partitions left empty are dropped.
+    pub fn compact(&mut self, moved: &[Option<usize>]) {
+        for partition in &mut self.partitions {
+            partition.rows = partition
+                .rows
+                .iter()
+                .filter_map(|&row| moved[row])
+                .collect();
+        }
+        self.partitions
+            .retain(|partition| !partition.rows.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_partitions_expire_and_cover_dates() {
+        // 2024-06-05 was a Wednesday; its week started on Monday 2024-06-03
+        let wednesday = 1_717_545_600;
+        assert_eq!(Window::Daily.start_of(wednesday + 3600), wednesday);
+        assert_eq!(Window::Weekly.start_of(wednesday), wednesday - 2 * DAY_SECS);
+        assert_eq!(Window::Weekly.start_of(DAY_SECS), 0);
+
+        let config = PartitionConfig {
+            window: Window::Daily,
+            retain: 2,
+            time_field: default_time_field(),
+        };
+        let now = wednesday + 3600;
+        assert_eq!(config.cutoff(now), wednesday - DAY_SECS);
+
+        let mut documents = vec![
+            json!({"name": "Old", "quotedAt": wednesday - 3 * DAY_SECS}),
+            json!({"name": "Tuesday", "quotedAt": wednesday - 60}),
+            json!({"name": "Undated"}),
+            json!({"name": "Wednesday", "quotedAt": wednesday + 60}),
+            json!({"name": "Also Wednesday", "quotedAt": wednesday + 120}),
+        ];
+        assert_eq!(config.expire(&mut documents, now), 1);
+        assert_eq!(documents.len(), 4);
+
+        let manifest = PartitionManifest::build(&config, "news".to_string(), &documents, now);
+        assert_eq!(manifest.retained_from, wednesday - DAY_SECS);
+        assert_eq!(manifest.partitions.len(), 2);
+        assert_eq!(manifest.partitions[0].rows, [0]);
+        assert_eq!(manifest.partitions[1].start, wednesday);
+        assert_eq!(manifest.partitions[1].rows, [2, 3]);
+
+        assert_eq!(manifest.covering(None, None), [0, 1]);
+        assert_eq!(manifest.covering(Some(wednesday), None), [1]);
+        assert_eq!(manifest.covering(None, Some(wednesday)), [0]);
+        assert!(manifest
+            .covering(Some(wednesday + DAY_SECS), None)
+            .is_empty());
+
+        let mut compacted = manifest.clone();
+        compacted.compact(&[None, Some(0), Some(1), Some(2)]);
+        assert_eq!(compacted.partitions.len(), 1);
+        assert_eq!(compacted.partitions[0].rows, [1, 2]);
+
+        let config = PartitionConfig {
+            retain: 0,
+            ..config
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use nalgebra::{DMatrix, DVector};
+use num_bigint::BigInt;
+
+// Every call into simplepir goes through here, so a change to its API only has to be
+// absorbed in this module. Covers the gen_params / gen_hint / generate_query /
+// process_query / recover generation, the one this crate is built against.
+pub use simplepir::SimplePIRParams;
+
+// Params for queries of `m` entries, one per database column, under an LWE secret of
+// `n` entries, with plaintext modulus 2^mod_power. A is `m` x `n`.
+pub fn params(m: usize, n: usize, mod_power: u32) -> SimplePIRParams {
+    simplepir::gen_params(m, n, mod_power)
+}
+
+// Params for serving `data`. The LWE secret is as long as a query, as it was when
+// every database was square, so the number of rows never changes the params.
+pub fn params_for(data: &DMatrix<BigInt>, mod_power: u32) -> SimplePIRParams {
+    params(data.ncols(), data.ncols(), mod_power)
+}
+
+// The (hint, A) pair published for `data`
+pub fn setup(
+    params: &SimplePIRParams,
+    data: &DMatrix<BigInt>,
+) -> (DMatrix<BigInt>, DMatrix<BigInt>) {
+    simplepir::gen_hint(params, data)
+}
+
+// `hint` with the rows in `rows` recomputed for `data` under the A it was made with.
+// Every other row is kept as is, so an update touching few rows costs little.
+pub fn patch_hint(
+    params: &SimplePIRParams,
+    data: &DMatrix<BigInt>,
+    a: &DMatrix<BigInt>,
+    mut hint: DMatrix<BigInt>,
+    rows: &[usize],
+) -> DMatrix<BigInt> {
+    let q = BigInt::from(params.q);
+    for &row in rows {
+        for col in 0..a.ncols() {
+            let dot: BigInt = data
+                .row(row)
+                .iter()
+                .zip(a.column(col).iter())
+                .map(|(x, y)| x * y)
+                .sum();
+            hint[(row, col)] = dot % &q;
+        }
+    }
+    hint
+}
+
+// Encrypts the plaintext query `v`, returning the client's secret and the query to send
+pub fn query(
+    params: &SimplePIRParams,
+    v: &DVector<BigInt>,
+    a: &DMatrix<BigInt>,
+) -> (DVector<BigInt>, DVector<BigInt>) {
+    simplepir::generate_query(params, v, a)
+}
+
+// The server's answer to `query` over `data`
+pub fn answer(
+    params: &SimplePIRParams,
+    data: &DMatrix<BigInt>,
+    query: &DVector<BigInt>,
+) -> DVector<BigInt> {
+    simplepir::process_query(data, query, params.q)
+}
+
+// Decrypts an answer with the secret its query was made with
+pub fn recover(
+    params: &SimplePIRParams,
+    hint: &DMatrix<BigInt>,
+    secret: &DVector<BigInt>,
+    answer: &DVector<BigInt>,
+) -> DVector<BigInt> {
+    simplepir::recover(hint, secret, answer, params)
+}
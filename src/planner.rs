@@ -0,0 +1,153 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::error::PirError;
+
+// Plaintext modulus sizes (log2 p) the planner considers
+pub const MOD_POWERS: [u32; 4] = [8, 16, 32, 64];
+// Extra ciphertext bits over the plaintext modulus reserved for LWE noise
+const NOISE_BITS: u32 = 32;
+// LWE secret dimension; the hint has this many columns
+const LWE_DIMENSION: usize = 1024;
+
+// What the planner is asked to fit
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct PlanRequest {
+    pub num_records: usize,
+    pub record_bytes: usize,
+    // Upper bound on bytes moved per query
+    pub budget_bytes: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerConfig {
+    pub rows: usize,
+    pub cols: usize,
+    // log2 of the plaintext modulus, as passed to `pir::params`
+    pub mod_power: u32,
+    // Records stacked in each column; one query returns all of them
+    pub records_per_column: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClientConfig {
+    // Keep the hint across queries within an epoch instead of fetching it per query
+    pub cache_hint: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+    pub upload_bytes: usize,
+    pub download_bytes: usize,
+    pub hint_bytes: usize,
+}
+
+impl Plan {
+    // Bytes a query costs under this plan, counting the hint unless it is cached
+    pub fn per_query_bytes(&self) -> usize {
+        let hint = if self.client.cache_hint {
+            0
+        } else {
+            self.hint_bytes
+        };
+        self.upload_bytes + self.download_bytes + hint
+    }
+}
+
+fn layout(request: &PlanRequest, mod_power: u32, records_per_column: usize) -> Plan {
+    let elements_per_record = (request.record_bytes * 8)
+        .div_ceil(mod_power as usize)
+        .max(1);
+    let rows = elements_per_record * records_per_column;
+    let cols = request.num_records.div_ceil(records_per_column);
+    let element_bytes = (mod_power + NOISE_BITS).div_ceil(8) as usize;
+
+    Plan {
+        server: ServerConfig {
+            rows,
+            cols,
+            mod_power,
+            records_per_column,
+        },
+        client: ClientConfig { cache_hint: false },
+        // The query has one element per column, the answer one per row
+        upload_bytes: cols * element_bytes,
+        download_bytes: rows * element_bytes,
+        hint_bytes: rows * LWE_DIMENSION * element_bytes,
+    }
+}
+
+// Chooses the cheapest matrix shape and plaintext modulus that fits the budget.
+// Plans that fetch the hint with every query are preferred; if none fits, the
+// client is told to cache the hint and only online traffic is counted.
+pub fn plan(request: &PlanRequest) -> Result<Plan> {
+    if request.num_records == 0 || request.record_bytes == 0 {
+        return Err(PirError::InvalidInput("Corpus must be non-empty".to_string()).into());
+    }
+
+    let candidates: Vec<Plan> = MOD_POWERS
+        .iter()
+        .flat_map(|&mod_power| {
+            std::iter::successors(Some(1usize), |&r| Some(r * 2))
+                .take_while(|&r| r <= request.num_records.next_power_of_two())
+                .map(move |r| layout(request, mod_power, r.min(request.num_records)))
+        })
+        .collect();
+
+    [false, true]
+        .into_iter()
+        .find_map(|cache_hint| {
+            candidates
+                .iter()
+                .map(|plan| Plan {
+                    client: ClientConfig { cache_hint },
+                    ..*plan
+                })
+                .filter(|plan| plan.per_query_bytes() <= request.budget_bytes)
+                .min_by_key(|plan| plan.per_query_bytes())
+        })
+        .ok_or_else(|| {
+            PirError::InvalidInput(format!(
+                "No configuration fits within {} bytes per query",
+                request.budget_bytes
+            ))
+            .into()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_respects_budget() -> Result<()> {
+        let request = PlanRequest {
+            num_records: 10_000,
+            record_bytes: 128,
+            budget_bytes: 2 << 20,
+        };
+        let chosen = plan(&request)?;
+        assert!(chosen.per_query_bytes() <= request.budget_bytes);
+        assert!(
+            chosen.server.rows * chosen.server.cols * chosen.server.mod_power as usize
+                >= 10_000 * 128 * 8
+        );
+
+        // Too tight to ship the hint per query, but fine once it is cached
+        let tight = plan(&PlanRequest {
+            budget_bytes: 64 << 10,
+            ..request
+        })?;
+        assert!(tight.client.cache_hint);
+        assert!(tight.per_query_bytes() <= 64 << 10);
+
+        assert!(plan(&PlanRequest {
+            budget_bytes: 16,
+            ..request
+        })
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::error::PirError;
+
+// Computations run at once; defaults to the number of cores
+const WORKERS_ENV_VAR: &str = "TIPTOE_COMPUTE_WORKERS";
+// Computations allowed to wait for a worker before new ones are refused
+const QUEUE_ENV_VAR: &str = "TIPTOE_COMPUTE_QUEUE";
+// Queue length per worker unless configured
+const DEFAULT_QUEUE_PER_WORKER: usize = 4;
+// Threads a rebuild's embedding and hint generation use; defaults to every core.
+// Only read at startup.
+const REBUILD_THREADS_ENV_VAR: &str = "TIPTOE_REBUILD_THREADS";
+// Longest a rebuild waits at one checkpoint for queries to drain, so steady query
+// load slows rebuilds down without stalling them
+const MAX_YIELD: Duration = Duration::from_secs(5);
+const YIELD_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Error, Debug)]
+pub enum PoolError {
+    #[error("Every compute worker is busy and the queue is full")]
+    Saturated,
+
+    #[error("Computation panicked")]
+    Panicked,
+}
+
+// Runs PIR computations on the blocking thread pool, so answering queries never
+// starves the runtime threads serving I/O. At most `workers` run at once and `queue`
+// more wait; anything beyond that is refused straight away instead of piling up.
+#[derive(Clone)]
+pub struct ComputePool {
+    workers: Arc<Semaphore>,
+    // One permit per running or waiting computation
+    admitted: Arc<Semaphore>,
+    capacity: usize,
+}
+
+impl ComputePool {
+    pub fn new(workers: usize, queue: usize) -> Self {
+        let workers = workers.max(1);
+        Self {
+            workers: Arc::new(Semaphore::new(workers)),
+            admitted: Arc::new(Semaphore::new(workers + queue)),
+            capacity: workers + queue,
+        }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let workers = match env_usize(WORKERS_ENV_VAR)? {
+            Some(workers) => workers,
+            None => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        };
+        let queue = env_usize(QUEUE_ENV_VAR)?.unwrap_or(DEFAULT_QUEUE_PER_WORKER * workers);
+        Ok(Self::new(workers, queue))
+    }
+
+    // Computations that can still be admitted
+    pub fn available(&self) -> usize {
+        self.admitted.available_permits()
+    }
+
+    // Computations running or waiting for a worker
+    pub fn in_flight(&self) -> usize {
+        self.capacity - self.available()
+    }
+
+    // Runs `compute` once a worker is free. Dropping the future while it waits gives
+    // up its place in the queue; once started, the computation runs to completion.
+    pub async fn run<F, R>(&self, compute: F) -> Result<R, PoolError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let admitted = Arc::clone(&self.admitted)
+            .try_acquire_owned()
+            .map_err(|_| PoolError::Saturated)?;
+        // The semaphores are never closed
+        let worker = Arc::clone(&self.workers)
+            .acquire_owned()
+            .await
+            .map_err(|_| PoolError::Saturated)?;
+        tokio::task::spawn_blocking(move || {
+            let _permits = (admitted, worker);
+            compute()
+        })
+        .await
+        .map_err(|_| PoolError::Panicked)
+    }
+}
+
+// Threads rebuilds compute with. Candle's matrix products and rayon's parallel
+// iterators run on the rayon pool their caller was installed in, so capped rebuilds run
+// inside a pool of their own and the global one is left alone.
+#[derive(Clone, Default)]
+pub struct RebuildThreads {
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl RebuildThreads {
+    pub fn from_env() -> Result<Self> {
+        let Some(threads) = env_usize(REBUILD_THREADS_ENV_VAR)? else {
+            return Ok(Self::default());
+        };
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.max(1))
+            .thread_name(|i| format!("tiptoe-rebuild-{}", i))
+            .build()
+            .map_err(|e| {
+                PirError::InvalidInput(format!("Invalid {}: {}", REBUILD_THREADS_ENV_VAR, e))
+            })?;
+        Ok(Self {
+            pool: Some(Arc::new(pool)),
+        })
+    }
+
+    // Runs `build` on the rebuild threads, or on the caller's thread when uncapped
+    pub fn install<R: Send>(&self, build: impl FnOnce() -> R + Send) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(build),
+            None => build(),
+        }
+    }
+}
+
+// How hard rebuilds may compete with queries for the CPU
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct RebuildLimits {
+    // Share of wall-clock time a rebuild spends working, in (0, 1]. Below 1 it sleeps
+    // at each checkpoint in proportion to the work done since the last one.
+    pub duty_cycle: f64,
+    // Pause rebuilds at their checkpoints while at least this many queries are
+    // running or waiting for a compute worker; unset never pauses
+    pub yield_to_queries: Option<usize>,
+}
+
+impl Default for RebuildLimits {
+    fn default() -> Self {
+        Self {
+            duty_cycle: 1.0,
+            yield_to_queries: None,
+        }
+    }
+}
+
+impl RebuildLimits {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.duty_cycle > 0.0 && self.duty_cycle <= 1.0) {
+            return Err(PirError::InvalidInput(format!(
+                "Rebuild duty cycle must be in (0, 1], got {}",
+                self.duty_cycle
+            ))
+            .into());
+        }
+        if self.yield_to_queries == Some(0) {
+            return Err(PirError::InvalidInput(
+                "Rebuilds can only yield to at least 1 query".to_string(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+// Applies `RebuildLimits` to one rebuild against the pool answering queries
+pub struct Throttle {
+    limits: RebuildLimits,
+    pool: ComputePool,
+    // When the rebuild last left a checkpoint
+    resumed: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(limits: RebuildLimits, pool: ComputePool) -> Self {
+        Self {
+            limits,
+            pool,
+            resumed: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Called by the rebuild at each checkpoint; blocks the calling thread
+    pub fn pause(&self) {
+        let mut resumed = self.resumed.lock().unwrap();
+        let duty_cycle = self.limits.duty_cycle;
+        if duty_cycle < 1.0 {
+            std::thread::sleep(resumed.elapsed().mul_f64((1.0 - duty_cycle) / duty_cycle));
+        }
+        if let Some(limit) = self.limits.yield_to_queries {
+            let waiting = Instant::now();
+            while self.pool.in_flight() >= limit && waiting.elapsed() < MAX_YIELD {
+                std::thread::sleep(YIELD_POLL);
+            }
+        }
+        *resumed = Instant::now();
+    }
+}
+
+fn env_usize(name: &str) -> Result<Option<usize>> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value.trim().parse().map_err(|_| {
+            PirError::InvalidInput(format!("Invalid {}: {}", name, value))
+        })?)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_saturated_pool_refuses_work() {
+        let pool = ComputePool::new(1, 0);
+        let (release, wait) = std::sync::mpsc::channel::<()>();
+        let running = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(move || wait.recv().is_ok()).await }
+        });
+        while pool.available() > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(pool.run(|| ()).await, Err(PoolError::Saturated)));
+        assert_eq!(pool.in_flight(), 1);
+        release.send(()).unwrap();
+        assert!(running.await.unwrap().unwrap());
+        assert_eq!(pool.run(|| 2 + 2).await.unwrap(), 4);
+        assert_eq!(pool.available(), 1);
+        let panicked: Result<(), PoolError> = pool.run(|| panic!("bad query")).await;
+        assert!(matches!(panicked, Err(PoolError::Panicked)));
+        assert_eq!(pool.available(), 1);
+
+        // An idle pool leaves only the duty cycle's sleep
+        let limits = RebuildLimits {
+            duty_cycle: 0.5,
+            yield_to_queries: Some(1),
+        };
+        limits.validate().unwrap();
+        let throttle = Throttle::new(limits, pool.clone());
+        std::thread::sleep(Duration::from_millis(20));
+        let paused = Instant::now();
+        throttle.pause();
+        assert!(paused.elapsed() >= Duration::from_millis(20));
+        assert!(paused.elapsed() < MAX_YIELD);
+        let invalid = RebuildLimits {
+            duty_cycle: 0.0,
+            ..RebuildLimits::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
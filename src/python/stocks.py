@@ -1,3 +1,5 @@
+import json
+
 import requests
 
 url = "https://yahoo-finance15.p.rapidapi.com/api/v1/markets/stock/quotes"
@@ -25,10 +27,15 @@ if response.status_code == 200:
             # "symbol": item.get("symbol", "N/A"), # removing the symbol improved the accuracy of retrieval (try to maximize the difference between the search terms)
             "name": item.get("displayName", item.get("shortName", item.get("longName", "N/A"))),
             "currentPrice": item.get("regularMarketPrice", "N/A"),
+            # Kept numeric; the servers add display forms in the configured locale
+            "currency": item.get("currency"),
+            "changePercent": item.get("regularMarketChangePercent"),
         }
         for item in data.get("body", [])
     ]
-    print(str(results * 3).replace("'", '"'))
+    # Fields the provider left out are dropped rather than sent as null
+    results = [{k: v for k, v in r.items() if v is not None} for r in results]
+    print(json.dumps(results * 3))
 
 else:
     print(f"Failed to fetch data: {response.status_code}")
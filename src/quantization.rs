@@ -0,0 +1,269 @@
+use anyhow::Result;
+use nalgebra::{DMatrix, DVector};
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::PirError, planner::MOD_POWERS, similarity::cosine, utils::seeded_rng};
+
+// Overrides the scale of the embedding database; clients pick it up from `/params`
+const SCALE_BITS_ENV_VAR: &str = "TIPTOE_SCALE_BITS";
+// Each embedding value x becomes trunc(clip(x) * SCALE_FACTOR)
+pub const SCALE_BITS: u32 = 23;
+pub const SCALE_FACTOR: f64 = (1u64 << SCALE_BITS) as f64;
+// Embeddings are L2-normalized, so values only exceed this through rounding or a
+// model that doesn't normalize
+const CLIP: f32 = 1.0;
+// Document pairs compared when fitting a calibration
+const CALIBRATION_SAMPLES: usize = 512;
+
+// The float -> integer mapping shared by database rows and query embeddings. Published
+// with the embedding database's params so clients quantize queries like the rows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Quantization {
+    pub scale_bits: u32,
+    // Bits any quantized value fits in, sign included
+    pub value_bits: u32,
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        Self::new(SCALE_BITS)
+    }
+}
+
+impl Quantization {
+    pub fn new(scale_bits: u32) -> Self {
+        Self {
+            scale_bits,
+            value_bits: scale_bits + 2,
+        }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(SCALE_BITS_ENV_VAR) {
+            Ok(bits) => Ok(Self::new(bits.trim().parse().map_err(|_| {
+                PirError::InvalidInput(format!("Invalid {}: {}", SCALE_BITS_ENV_VAR, bits))
+            })?)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn scale_factor(&self) -> f64 {
+        2f64.powi(self.scale_bits as i32)
+    }
+
+    pub fn quantize_value(&self, value: f32) -> BigInt {
+        if value.is_nan() {
+            panic!("Cannot quantize NaN");
+        }
+
+        // Scaling an f32 by a power of two is exact in an f64; the result is truncated
+        // toward zero
+        let scaled = (value.clamp(-CLIP, CLIP) as f64 * self.scale_factor()).trunc();
+        BigInt::from_f64(scaled).expect("clipped values are finite")
+    }
+
+    pub fn quantize(&self, values: &[f32]) -> DVector<BigInt> {
+        DVector::from_iterator(
+            values.len(),
+            values.iter().map(|&value| self.quantize_value(value)),
+        )
+    }
+
+    // Packs quantized embeddings into the rows of a matrix, one per embedding
+    pub fn quantize_rows(&self, embeddings: &[Vec<f32>]) -> DMatrix<BigInt> {
+        let dim = embeddings.iter().map(Vec::len).max().unwrap_or(0).max(1);
+        let mut out = DMatrix::zeros(embeddings.len().max(1), dim);
+
+        for (i, embedding) in embeddings.iter().enumerate() {
+            for (j, &value) in embedding.iter().enumerate() {
+                out[(i, j)] = self.quantize_value(value);
+            }
+        }
+
+        out
+    }
+
+    // Bits a score, the inner product of two `dim`-long quantized vectors, can take up
+    pub fn score_bits(&self, dim: usize) -> u32 {
+        2 * (self.value_bits - 1) + dim.max(1).next_power_of_two().ilog2() + 1
+    }
+
+    // Checks that a score can't wrap around a plaintext modulus of 2^mod_power
+    pub fn validate(&self, dim: usize, mod_power: u32) -> Result<()> {
+        let score_bits = self.score_bits(dim);
+        if score_bits > mod_power {
+            return Err(PirError::InvalidInput(format!(
+                "Scores of {} bits overflow the {}-bit plaintext modulus; lower the scale from 2^{}",
+                score_bits, mod_power, self.scale_bits
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    // Smallest plaintext modulus, as log2, that `dim`-long scores fit in. A smaller
+    // modulus leaves more of the ciphertext modulus for noise, shrinking the matrices.
+    pub fn mod_power(&self, dim: usize) -> Result<u32> {
+        let largest = MOD_POWERS[MOD_POWERS.len() - 1];
+        self.validate(dim, largest)?;
+        let score_bits = self.score_bits(dim);
+        Ok(MOD_POWERS
+            .into_iter()
+            .find(|&power| power >= score_bits)
+            .unwrap_or(largest))
+    }
+}
+
+// Linear map from recovered scores to cosine similarity, fitted when the embedding
+// database is built and published with its params. Lets clients threshold scores
+// without knowing the quantizer.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Calibration {
+    pub slope: f64,
+    pub intercept: f64,
+    // Pairs the fit was made on; 0 when derived from the scale alone
+    pub samples: usize,
+}
+
+impl Calibration {
+    // Ignores truncation and clipping: a score is two scaled values multiplied together
+    pub fn from_scale(quantization: &Quantization) -> Self {
+        Self {
+            slope: quantization.scale_factor().powi(-2),
+            intercept: 0.0,
+            samples: 0,
+        }
+    }
+
+    // Least-squares fit of plaintext cosine against the score PIR would recover, over
+    // random pairs of `embeddings`. Falls back to `from_scale` when the scores don't
+    // vary, e.g. for a single document.
+    pub fn fit(embeddings: &[Vec<f32>], quantization: &Quantization, seed: Option<u64>) -> Self {
+        if embeddings.is_empty() {
+            return Self::from_scale(quantization);
+        }
+
+        let mut rng = seeded_rng(seed);
+        let pairs: Vec<(f64, f64)> = (0..CALIBRATION_SAMPLES)
+            .map(|_| {
+                let a = &embeddings[rng.random_range(0..embeddings.len())];
+                let b = &embeddings[rng.random_range(0..embeddings.len())];
+                (score(quantization, a, b), cosine(a, b))
+            })
+            .collect();
+
+        // Compared exactly, since float error in the variance of identical scores
+        // around 2^46 dwarfs any absolute threshold
+        let identical = pairs.iter().all(|(x, _)| *x == pairs[0].0);
+        if identical {
+            return Self::from_scale(quantization);
+        }
+
+        let n = pairs.len() as f64;
+        let mean_score = pairs.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_cosine = pairs.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let (covariance, variance) = pairs.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+            let dx = x - mean_score;
+            (cov + dx * (y - mean_cosine), var + dx * dx)
+        });
+        let slope = covariance / variance;
+        Self {
+            slope,
+            intercept: mean_cosine - slope * mean_score,
+            samples: pairs.len(),
+        }
+    }
+
+    // Approximate cosine similarity for a recovered score
+    pub fn cosine(&self, score: &BigInt) -> f64 {
+        self.slope * score.to_f64().unwrap_or(f64::NAN) + self.intercept
+    }
+
+    // The score that `cosine` maps to `cosine`, rounded, e.g. to rank fused cosines
+    // among scores
+    pub fn score(&self, cosine: f64) -> BigInt {
+        BigInt::from_f64(((cosine - self.intercept) / self.slope).round()).unwrap_or_default()
+    }
+}
+
+// Inner product of the quantized vectors, as PIR recovers it
+fn score(quantization: &Quantization, a: &[f32], b: &[f32]) -> f64 {
+    let score: BigInt = a
+        .iter()
+        .zip(b)
+        .map(|(&x, &y)| quantization.quantize_value(x) * quantization.quantize_value(y))
+        .sum();
+    score.to_f64().unwrap_or(f64::NAN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantization_scale_clip_and_budget() -> Result<()> {
+        let quantization = Quantization::default();
+        assert_eq!(quantization.scale_factor(), SCALE_FACTOR);
+
+        let quantized = quantization.quantize(&[0.5, -0.3, 1.0, 0.0, 1.5, f32::NEG_INFINITY]);
+        assert_eq!(quantized[0], BigInt::from(1 << 22));
+        assert_eq!(
+            quantized[1],
+            BigInt::from(-((0.3f32 as f64 * SCALE_FACTOR) as i64))
+        );
+        assert_eq!(quantized[2], BigInt::from(1 << 23));
+        assert_eq!(quantized[3], BigInt::from(0));
+        assert_eq!(quantized[4], quantized[2]);
+        assert_eq!(quantized[5], -quantized[2].clone());
+
+        let rows = quantization.quantize_rows(&[vec![0.5, -0.3, 1.0]]);
+        assert_eq!(rows.shape(), (1, 3));
+        assert_eq!(rows.row(0).transpose(), quantized.rows(0, 3));
+
+        quantization.validate(384, 64)?;
+        assert!(quantization.validate(384, 32).is_err());
+        assert!(Quantization::new(30).validate(384, 64).is_err());
+
+        assert_eq!(quantization.mod_power(384)?, 64);
+        assert_eq!(Quantization::new(10).mod_power(384)?, 32);
+        assert_eq!(Quantization::new(1).mod_power(4)?, 8);
+        assert!(Quantization::new(30).mod_power(384).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_calibration_recovers_cosine() {
+        let quantization = Quantization::default();
+        let mut rng = seeded_rng(Some(0));
+        let embeddings: Vec<Vec<f32>> = (0..50)
+            .map(|_| {
+                let v: Vec<f32> = (0..32).map(|_| rng.random_range(-1.0..1.0)).collect();
+                let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                v.into_iter().map(|x| x / norm).collect()
+            })
+            .collect();
+
+        let calibration = Calibration::fit(&embeddings, &quantization, Some(0));
+        assert_eq!(calibration.samples, CALIBRATION_SAMPLES);
+        let (a, b) = (&embeddings[3], &embeddings[7]);
+        let recovered = quantization.quantize(a).dot(&quantization.quantize(b));
+        assert!((calibration.cosine(&recovered) - cosine(a, b)).abs() < 1e-3);
+        assert!(
+            (calibration.cosine(&recovered)
+                - Calibration::from_scale(&quantization).cosine(&recovered))
+            .abs()
+                < 1e-3
+        );
+
+        let score = calibration.score(calibration.cosine(&recovered));
+        assert!((score - &recovered).magnitude().to_f64().unwrap() <= 1.0);
+
+        let single = Calibration::fit(&embeddings[..1], &quantization, Some(0));
+        assert_eq!(single, Calibration::from_scale(&quantization));
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use bhttp::{Message, Mode};
+use ohttp::ClientRequest;
+use reqwest::{Client as HttpClient, Url};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{io::Cursor, sync::Arc};
+
+use crate::{
+    error::PirError,
+    network::{
+        HttpTransport, MatrixResponse, ParamsData, QueryRequest, QueryResponse, Transport,
+        WithDeadline,
+    },
+};
+
+const REQUEST_CONTENT_TYPE: &str = "message/ohttp-req";
+const RESPONSE_CONTENT_TYPE: &str = "message/ohttp-res";
+
+// Oblivious HTTP relay in front of a gateway. Each request is encapsulated to the
+// gateway's key, so the relay sees who is asking but not what, and the gateway, which
+// forwards it to the PIR server, sees what but not who.
+pub struct Relay {
+    client: HttpClient,
+    relay_url: String,
+    // Encoded key configuration of the gateway, obtained out of band
+    key_config: Vec<u8>,
+}
+
+impl Relay {
+    pub fn new(relay_url: String, key_config: Vec<u8>) -> Result<Self> {
+        // Fail on a bad key configuration now rather than on the first query
+        ClientRequest::from_encoded_config(&key_config)
+            .map_err(|e| PirError::InvalidInput(format!("Invalid OHTTP key config: {}", e)))?;
+        Ok(Self {
+            client: HttpClient::new(),
+            relay_url,
+            key_config,
+        })
+    }
+
+    // Fetches the gateway's key configuration from `keys_url`. The keys should come from
+    // somewhere other than the relay, or the relay could hand out its own.
+    pub async fn discover(relay_url: String, keys_url: &str) -> Result<Self> {
+        let key_config = HttpClient::new()
+            .get(keys_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Self::new(relay_url, key_config.to_vec())
+    }
+
+    // POSTs `body` as JSON to `url` through the relay and decodes the JSON response
+    pub async fn post_json<B: Serialize, R: DeserializeOwned>(
+        &self,
+        url: &str,
+        body: &B,
+    ) -> Result<R> {
+        let target = Url::parse(url)?;
+        let mut request = Message::request(
+            b"POST".to_vec(),
+            target.scheme().as_bytes().to_vec(),
+            target.authority().as_bytes().to_vec(),
+            target.path().as_bytes().to_vec(),
+        );
+        request.put_header("content-type", "application/json");
+        request.write_content(serde_json::to_vec(body)?);
+        let mut encoded = Vec::new();
+        request
+            .write_bhttp(Mode::KnownLength, &mut encoded)
+            .map_err(|e| PirError::Encoding(format!("Binary HTTP encoding failed: {}", e)))?;
+
+        let (encapsulated, context) = ClientRequest::from_encoded_config(&self.key_config)
+            .and_then(|request| request.encapsulate(&encoded))
+            .map_err(|e| PirError::Encoding(format!("OHTTP encapsulation failed: {}", e)))?;
+
+        let response = self
+            .client
+            .post(&self.relay_url)
+            .header("content-type", REQUEST_CONTENT_TYPE)
+            .body(encapsulated)
+            .with_deadline()
+            .send()
+            .await?
+            .error_for_status()?;
+        let content_type = response.headers().get("content-type");
+        if content_type.and_then(|value| value.to_str().ok()) != Some(RESPONSE_CONTENT_TYPE) {
+            return Err(
+                PirError::Database("Relay did not return an OHTTP response".to_string()).into(),
+            );
+        }
+
+        let decapsulated = context
+            .decapsulate(&response.bytes().await?)
+            .map_err(|e| PirError::Encoding(format!("OHTTP decapsulation failed: {}", e)))?;
+        let response = Message::read_bhttp(&mut Cursor::new(&decapsulated))
+            .map_err(|e| PirError::Encoding(format!("Binary HTTP decoding failed: {}", e)))?;
+        match response.control().status() {
+            Some(status) if status.code() == 200 => Ok(serde_json::from_slice(response.content())?),
+            status => Err(PirError::Database(format!(
+                "Gateway returned status {:?}",
+                status.map(|status| status.code())
+            ))
+            .into()),
+        }
+    }
+}
+
+// HTTP transport whose queries go through a relay. Params, hints and A are public and
+// are still fetched directly.
+pub struct RelayedTransport {
+    http: HttpTransport,
+    relay: Arc<Relay>,
+}
+
+impl RelayedTransport {
+    pub fn new(http: HttpTransport, relay: Arc<Relay>) -> Self {
+        Self { http, relay }
+    }
+}
+
+#[async_trait]
+impl Transport for RelayedTransport {
+    async fn send_query(&self, database: &str, request: &QueryRequest) -> Result<QueryResponse> {
+        let url = self.http.url(&format!("{}/query", database));
+        self.relay.post_json(&url, request).await
+    }
+
+    async fn get_params(&self, database: &str) -> Result<ParamsData> {
+        self.http.get_params(database).await
+    }
+
+    async fn get_hint(&self, database: &str) -> Result<MatrixResponse> {
+        self.http.get_hint(database).await
+    }
+
+    async fn get_a(&self, database: &str) -> Result<MatrixResponse> {
+        self.http.get_a(database).await
+    }
+
+    async fn get_json(&self, path: &str) -> Result<serde_json::Value> {
+        self.http.get_json(path).await
+    }
+
+    fn origin(&self) -> Option<String> {
+        self.http.origin()
+    }
+}
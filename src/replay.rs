@@ -0,0 +1,133 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::error::PirError;
+
+// Clock the replayed snapshots are read against. Starts at the first snapshot and only
+// moves when advanced, so a replay gives the same documents no matter how long
+// updates take.
+#[derive(Debug, Default)]
+pub struct SimulatedClock {
+    // Seconds since the first snapshot
+    elapsed: AtomicU64,
+}
+
+impl SimulatedClock {
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs(self.elapsed.load(Ordering::SeqCst))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.elapsed.fetch_add(by.as_secs(), Ordering::SeqCst);
+    }
+
+    pub fn reset(&self) {
+        self.elapsed.store(0, Ordering::SeqCst);
+    }
+}
+
+// Shared by every replay source in the process, so both databases of an in-process
+// setup always see the same snapshot
+pub fn clock() -> &'static SimulatedClock {
+    static CLOCK: SimulatedClock = SimulatedClock {
+        elapsed: AtomicU64::new(0),
+    };
+    &CLOCK
+}
+
+// One recorded fetch of the corpus
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot {
+    // Unix time in seconds the snapshot was recorded at
+    pub at: u64,
+    pub documents: Vec<Value>,
+}
+
+// Recorded snapshots from a fixture file, a JSON array of `{"at": ..., "documents":
+// [...]}`, served in the order they were recorded as the simulated clock advances
+pub struct ReplaySource {
+    path: PathBuf,
+}
+
+impl ReplaySource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    // Snapshots sorted by time. The fixture is re-read on every load so tests can
+    // record more snapshots as they go.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        read_fixture(&self.path)
+    }
+
+    pub fn load(&self) -> Result<Vec<Value>> {
+        let snapshots = self.snapshots()?;
+        Ok(current(&snapshots, clock().elapsed())?.documents.clone())
+    }
+}
+
+fn read_fixture(path: &Path) -> Result<Vec<Snapshot>> {
+    let mut snapshots: Vec<Snapshot> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    // Stable, so snapshots recorded at the same second keep their file order
+    snapshots.sort_by_key(|snapshot| snapshot.at);
+    Ok(snapshots)
+}
+
+// Latest snapshot recorded no later than `elapsed` after the first one
+fn current(snapshots: &[Snapshot], elapsed: Duration) -> Result<&Snapshot> {
+    let start = snapshots
+        .first()
+        .ok_or_else(|| PirError::Database("Replay fixture has no snapshots".to_string()))?
+        .at;
+    let now = start.saturating_add(elapsed.as_secs());
+    let due = snapshots.partition_point(|snapshot| snapshot.at <= now);
+    Ok(&snapshots[due - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_snapshot_follows_simulated_clock() -> Result<()> {
+        let snapshot = |at: u64, price: f64| Snapshot {
+            at,
+            documents: vec![json!({"name": "Tesla, Inc.", "currentPrice": price})],
+        };
+        let snapshots = [
+            snapshot(1_700_000_000, 250.1),
+            snapshot(1_700_000_060, 251.0),
+            snapshot(1_700_000_060, 251.5),
+            snapshot(1_700_000_300, 249.8),
+        ];
+        let price = |elapsed: u64| -> Result<Value> {
+            Ok(
+                current(&snapshots, Duration::from_secs(elapsed))?.documents[0]["currentPrice"]
+                    .clone(),
+            )
+        };
+        assert_eq!(price(0)?, 250.1);
+        assert_eq!(price(59)?, 250.1);
+        assert_eq!(price(60)?, 251.5);
+        assert_eq!(price(299)?, 251.5);
+        assert_eq!(price(10_000)?, 249.8);
+        assert!(current(&[], Duration::ZERO).is_err());
+
+        let clock = SimulatedClock::default();
+        clock.advance(Duration::from_secs(90));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(clock.elapsed(), Duration::from_secs(90));
+        clock.reset();
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        Ok(())
+    }
+}
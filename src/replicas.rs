@@ -0,0 +1,286 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use std::{
+    cmp::Reverse,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::interval};
+
+use crate::{
+    error::PirError,
+    network::{
+        session_tokens, HttpTransport, MatrixResponse, ParamsData, QueryRequest, QueryResponse,
+        ReadyResponse, SessionToken, Transport,
+    },
+};
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// What a replica's `/ready` said when last polled. Unreachable replicas are not ready.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplicaStatus {
+    pub ready: bool,
+    pub epoch: u64,
+    pub rebuilding: bool,
+    // Node the replica's session tokens name
+    pub node: Option<String>,
+}
+
+// Statuses and the replica requests currently go to
+struct Replicas {
+    statuses: Vec<ReplicaStatus>,
+    preferred: usize,
+}
+
+impl Replicas {
+    // Ready replicas first, then those on the newest epoch, then those not rebuilding.
+    // The preferred replica wins ties, so clients only move when something changes.
+    fn order(&self) -> Vec<usize> {
+        let newest = self
+            .statuses
+            .iter()
+            .filter(|status| status.ready)
+            .map(|status| status.epoch)
+            .max();
+        let mut order: Vec<usize> = (0..self.statuses.len()).collect();
+        order.sort_by_key(|&i| {
+            let status = &self.statuses[i];
+            (
+                Reverse((
+                    status.ready,
+                    Some(status.epoch) == newest,
+                    !status.rebuilding,
+                )),
+                i != self.preferred,
+                i,
+            )
+        });
+        order
+    }
+
+    fn reselect(&mut self) {
+        self.preferred = self.order()[0];
+    }
+
+    // Replicas to try for a request to `database`, best first. Requests of a session go
+    // to a replica on a node that issued it a token before any other, and requests
+    // pinned to an epoch go to the replicas serving it.
+    fn candidates(&self, database: &str, session: &[SessionToken]) -> Vec<usize> {
+        let mut order = self.order();
+        if let Some(epoch) = pinned_epoch(database) {
+            order.sort_by_key(|&i| self.statuses[i].epoch != epoch);
+        }
+        order.sort_by_key(|&i| {
+            !session
+                .iter()
+                .any(|token| self.statuses[i].node.as_ref() == Some(&token.node))
+        });
+        order
+    }
+}
+
+// Epoch of a database path under `/epochs/{epoch}`
+pub(crate) fn pinned_epoch(database: &str) -> Option<u64> {
+    database
+        .strip_prefix("/epochs/")?
+        .split('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+// HTTP transport over several replicas of the same server. Requests stick to one
+// replica, chosen from their `/ready` by `poll`, and move to the next one only when it
+// cannot be reached. Each replica builds its own epochs, so an unpinned hint and the
+// params before it may come from different replicas if one fails in between; the
+// client's pinned queries then fail cleanly and its next query starts over.
+pub struct ReplicatedTransport {
+    replicas: Vec<HttpTransport>,
+    state: Mutex<Replicas>,
+}
+
+impl ReplicatedTransport {
+    pub fn new(replicas: Vec<HttpTransport>) -> Result<Self> {
+        if replicas.is_empty() {
+            return Err(PirError::InvalidInput("No replicas given".to_string()).into());
+        }
+        // Until the first poll every replica is assumed ready, in the order given
+        let statuses = vec![
+            ReplicaStatus {
+                ready: true,
+                ..ReplicaStatus::default()
+            };
+            replicas.len()
+        ];
+        Ok(Self {
+            replicas,
+            state: Mutex::new(Replicas {
+                statuses,
+                preferred: 0,
+            }),
+        })
+    }
+
+    pub fn statuses(&self) -> Vec<ReplicaStatus> {
+        self.state.lock().unwrap().statuses.clone()
+    }
+
+    // Index of the replica unpinned requests go to
+    pub fn preferred(&self) -> usize {
+        self.state.lock().unwrap().preferred
+    }
+
+    // Polls every replica's `/ready` at once and picks the preferred replica again
+    pub async fn poll(&self) {
+        let polled = join_all(self.replicas.iter().map(|replica| async move {
+            let ready = replica.get_json("/ready").await.and_then(|value| {
+                serde_json::from_value::<ReadyResponse>(value).map_err(Into::into)
+            });
+            match ready {
+                Ok(ready) => ReplicaStatus {
+                    ready: ready.ready,
+                    epoch: ready.epoch,
+                    rebuilding: ready.rebuilding,
+                    node: ready.node,
+                },
+                Err(_) => ReplicaStatus::default(),
+            }
+        }))
+        .await;
+        let mut state = self.state.lock().unwrap();
+        state.statuses = polled;
+        state.reselect();
+    }
+
+    // Polls every `period` until the transport is dropped
+    pub fn spawn_polling(self: &Arc<Self>, period: Duration) -> JoinHandle<()> {
+        let transport = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            loop {
+                ticker.tick().await;
+                let Some(transport) = transport.upgrade() else {
+                    return;
+                };
+                transport.poll().await;
+            }
+        })
+    }
+
+    fn mark_unreachable(&self, replica: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.statuses[replica].ready = false;
+        state.reselect();
+    }
+
+    // Sends `request` to the best replica for `database`. Unreachable replicas are
+    // skipped until the next poll; pinned requests also move on when a replica no
+    // longer serves their epoch, since another may still have it.
+    async fn send<'a, R, F, Fut>(&'a self, database: &str, request: F) -> Result<R>
+    where
+        F: Fn(&'a HttpTransport) -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        let session = session_tokens();
+        let candidates = self.state.lock().unwrap().candidates(database, &session);
+        let pinned = pinned_epoch(database).is_some();
+        let mut failure = None;
+        for replica in candidates {
+            match request(&self.replicas[replica]).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    let unreachable = e
+                        .downcast_ref::<reqwest::Error>()
+                        .is_some_and(|e| e.is_connect() || e.is_timeout());
+                    if unreachable {
+                        self.mark_unreachable(replica);
+                    } else if !pinned {
+                        return Err(e);
+                    }
+                    failure = Some(e);
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| PirError::Database("No replica answered".to_string()).into()))
+    }
+}
+
+#[async_trait]
+impl Transport for ReplicatedTransport {
+    async fn send_query(&self, database: &str, request: &QueryRequest) -> Result<QueryResponse> {
+        self.send(database, |replica| replica.send_query(database, request))
+            .await
+    }
+
+    async fn get_params(&self, database: &str) -> Result<ParamsData> {
+        self.send(database, |replica| replica.get_params(database))
+            .await
+    }
+
+    async fn get_hint(&self, database: &str) -> Result<MatrixResponse> {
+        self.send(database, |replica| replica.get_hint(database))
+            .await
+    }
+
+    async fn get_a(&self, database: &str) -> Result<MatrixResponse> {
+        self.send(database, |replica| replica.get_a(database)).await
+    }
+
+    async fn get_json(&self, path: &str) -> Result<serde_json::Value> {
+        self.send(path, |replica| replica.get_json(path)).await
+    }
+
+    fn origin(&self) -> Option<String> {
+        self.replicas[self.preferred()].origin()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replica_order() {
+        let status = |ready, epoch, rebuilding| ReplicaStatus {
+            ready,
+            epoch,
+            rebuilding,
+            node: None,
+        };
+        let mut replicas = Replicas {
+            statuses: vec![
+                status(false, 30, false),
+                status(true, 10, false),
+                status(true, 20, true),
+                status(true, 20, false),
+            ],
+            preferred: 0,
+        };
+        replicas.reselect();
+        assert_eq!(replicas.preferred, 3);
+        assert_eq!(replicas.order(), vec![3, 2, 1, 0]);
+
+        // Ties keep the current replica
+        replicas.statuses[2].rebuilding = false;
+        replicas.reselect();
+        assert_eq!(replicas.preferred, 3);
+
+        assert_eq!(pinned_epoch("/epochs/10/clusters/3"), Some(10));
+        assert_eq!(pinned_epoch("/epochs/10"), Some(10));
+        assert_eq!(pinned_epoch("/clusters/3"), None);
+        assert_eq!(replicas.candidates("/epochs/10/clusters/3", &[])[0], 1);
+        assert_eq!(replicas.candidates("/clusters/3", &[])[0], 3);
+
+        // Sessions stay on the node that issued their token
+        replicas.statuses[1].node = Some("node.b".to_string());
+        let session: SessionToken = "node.b".parse().unwrap();
+        assert_eq!(session.node, "node.b");
+        assert_eq!(session.to_string(), "node.b");
+        assert_eq!(replicas.candidates("/clusters/3", &[session])[0], 1);
+        assert!("".parse::<SessionToken>().is_err());
+        assert!("node b".parse::<SessionToken>().is_err());
+    }
+}
@@ -0,0 +1,333 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{client::QueryStats, clustering::ClusterQuality};
+
+// Directory benchmarks write their JSON reports to; unset writes none
+const REPORTS_ENV_VAR: &str = "TIPTOE_BENCH_REPORTS";
+// Settings left out of fingerprints because they hold secrets
+const SECRET_SUFFIX: &str = "_KEY";
+const SECRET_PREFIX: &str = "TIPTOE_STORE_";
+
+// What a benchmark ran against: the crate version, enabled features, every TIPTOE_*
+// setting except secrets, and a hash of the server config file's contents. Reports are
+// only comparable like for like when their ids match.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub id: String,
+    pub settings: BTreeMap<String, String>,
+}
+
+impl Fingerprint {
+    pub fn current() -> Self {
+        let mut settings: BTreeMap<String, String> = std::env::vars()
+            .filter(|(name, _)| {
+                name.starts_with("TIPTOE_")
+                    && name != REPORTS_ENV_VAR
+                    && !name.ends_with(SECRET_SUFFIX)
+                    && !name.starts_with(SECRET_PREFIX)
+            })
+            .collect();
+        if let Some(config) = settings
+            .get("TIPTOE_CONFIG")
+            .and_then(|path| std::fs::read(path).ok())
+        {
+            settings.insert(
+                "config_hash".to_string(),
+                blake3::hash(&config).to_hex().to_string(),
+            );
+        }
+        settings.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+        let features = [
+            ("baseline", cfg!(feature = "baseline")),
+            ("cuda", cfg!(feature = "cuda")),
+        ];
+        settings.insert(
+            "features".to_string(),
+            features
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(feature, _)| *feature)
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        Self::from_settings(settings)
+    }
+
+    pub fn from_settings(settings: BTreeMap<String, String>) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        for (name, value) in &settings {
+            hasher.update(name.as_bytes());
+            hasher.update(&[0]);
+            hasher.update(value.as_bytes());
+            hasher.update(&[0]);
+        }
+        Self {
+            id: hasher.finalize().to_hex()[..16].to_string(),
+            settings,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub count: usize,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    // Nearest-rank percentiles; all zero without samples
+    pub fn from_samples(samples: &[f64]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let rank =
+            |p: f64| sorted[((p * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len()) - 1];
+        Self {
+            count: sorted.len(),
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p50: rank(0.5),
+            p90: rank(0.9),
+            p99: rank(0.99),
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+// Machine-readable outcome of one benchmark run
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BenchReport {
+    pub name: String,
+    pub fingerprint: Fingerprint,
+    // Share of queries answered correctly, by measure (e.g. "top1", "top3")
+    pub recall: BTreeMap<String, f64>,
+    // Wall-clock time of each query as the client saw it
+    pub latency_ms: Percentiles,
+    // Payload bytes per query, as counted by `QueryStats`
+    pub upload_bytes: Percentiles,
+    pub download_bytes: Percentiles,
+    // Quality of the embedding clustering after the last rebuild, for benchmarks that
+    // cluster, so cluster counts can be tuned against recall
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster_quality: Option<ClusterQuality>,
+}
+
+// Collects per-query measurements into a `BenchReport`
+pub struct ReportBuilder {
+    name: String,
+    recall: BTreeMap<String, (usize, usize)>,
+    latency_ms: Vec<f64>,
+    upload_bytes: Vec<f64>,
+    download_bytes: Vec<f64>,
+    cluster_quality: Option<ClusterQuality>,
+}
+
+impl ReportBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            recall: BTreeMap::new(),
+            latency_ms: Vec::new(),
+            upload_bytes: Vec::new(),
+            download_bytes: Vec::new(),
+            cluster_quality: None,
+        }
+    }
+
+    pub fn record_query(&mut self, latency_ms: f64, stats: &QueryStats) {
+        self.latency_ms.push(latency_ms);
+        self.upload_bytes.push(stats.upload_bytes as f64);
+        self.download_bytes.push(stats.download_bytes as f64);
+    }
+
+    // Counts one attempt at `measure`, failed queries included
+    pub fn record_hit(&mut self, measure: &str, hit: bool) {
+        let (hits, total) = self.recall.entry(measure.to_string()).or_default();
+        *hits += hit as usize;
+        *total += 1;
+    }
+
+    // Replaces any quality recorded before, as each rebuild reclusters
+    pub fn record_cluster_quality(&mut self, quality: ClusterQuality) {
+        self.cluster_quality = Some(quality);
+    }
+
+    pub fn build(&self) -> BenchReport {
+        BenchReport {
+            name: self.name.clone(),
+            fingerprint: Fingerprint::current(),
+            recall: self
+                .recall
+                .iter()
+                .map(|(measure, &(hits, total))| {
+                    (measure.clone(), hits as f64 / total.max(1) as f64)
+                })
+                .collect(),
+            latency_ms: Percentiles::from_samples(&self.latency_ms),
+            upload_bytes: Percentiles::from_samples(&self.upload_bytes),
+            download_bytes: Percentiles::from_samples(&self.download_bytes),
+            cluster_quality: self.cluster_quality,
+        }
+    }
+}
+
+impl BenchReport {
+    // Writes the report to `$TIPTOE_BENCH_REPORTS/{name}.json` if that is set, and
+    // returns where it went
+    pub fn save(&self) -> Result<Option<String>> {
+        let Ok(dir) = std::env::var(REPORTS_ENV_VAR) else {
+            return Ok(None);
+        };
+        std::fs::create_dir_all(&dir)?;
+        let path = Path::new(&dir).join(format!("{}.json", self.name));
+        std::fs::write(&path, serde_json::to_vec_pretty(self)?)?;
+        Ok(Some(path.display().to_string()))
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    // Every metric by a dotted name, e.g. "recall.top1" or "latency_ms.p99"
+    pub fn metrics(&self) -> BTreeMap<String, f64> {
+        let mut metrics: BTreeMap<String, f64> = self
+            .recall
+            .iter()
+            .map(|(measure, value)| (format!("recall.{}", measure), *value))
+            .collect();
+        for (name, percentiles) in [
+            ("latency_ms", &self.latency_ms),
+            ("upload_bytes", &self.upload_bytes),
+            ("download_bytes", &self.download_bytes),
+        ] {
+            metrics.insert(format!("{}.count", name), percentiles.count as f64);
+            metrics.insert(format!("{}.mean", name), percentiles.mean);
+            metrics.insert(format!("{}.p50", name), percentiles.p50);
+            metrics.insert(format!("{}.p90", name), percentiles.p90);
+            metrics.insert(format!("{}.p99", name), percentiles.p99);
+            metrics.insert(format!("{}.max", name), percentiles.max);
+        }
+        if let Some(quality) = self.cluster_quality {
+            metrics.insert(
+                "cluster_quality.inertia".to_string(),
+                quality.inertia as f64,
+            );
+            metrics.insert(
+                "cluster_quality.silhouette".to_string(),
+                quality.silhouette as f64,
+            );
+        }
+        metrics
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MetricChange {
+    pub metric: String,
+    pub before: Option<f64>,
+    pub after: Option<f64>,
+    // Relative change from `before`, when both are set and `before` is not 0
+    pub change: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Comparison {
+    // Settings that differ between the runs, as (before, after)
+    pub settings: BTreeMap<String, (Option<String>, Option<String>)>,
+    pub metrics: Vec<MetricChange>,
+}
+
+// Diffs two reports of the same benchmark, `before` being the reference run
+pub fn compare(before: &BenchReport, after: &BenchReport) -> Comparison {
+    let mut settings = BTreeMap::new();
+    let (old, new) = (&before.fingerprint.settings, &after.fingerprint.settings);
+    for name in old.keys().chain(new.keys()) {
+        if old.get(name) != new.get(name) {
+            settings.insert(
+                name.clone(),
+                (old.get(name).cloned(), new.get(name).cloned()),
+            );
+        }
+    }
+
+    let (old, new) = (before.metrics(), after.metrics());
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    let metrics = names
+        .into_iter()
+        .map(|name| {
+            let (before, after) = (old.get(name).copied(), new.get(name).copied());
+            MetricChange {
+                metric: name.clone(),
+                before,
+                after,
+                change: match (before, after) {
+                    (Some(before), Some(after)) if before != 0.0 => Some((after - before) / before),
+                    _ => None,
+                },
+            }
+        })
+        .collect();
+    Comparison { settings, metrics }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_reports() {
+        let samples: Vec<f64> = (1..=100).map(f64::from).collect();
+        let percentiles = Percentiles::from_samples(&samples);
+        assert_eq!((percentiles.p50, percentiles.p90), (50.0, 90.0));
+        assert_eq!((percentiles.p99, percentiles.max), (99.0, 100.0));
+        assert_eq!(percentiles.mean, 50.5);
+        assert_eq!(Percentiles::from_samples(&[]), Percentiles::default());
+
+        let mut builder = ReportBuilder::new("accuracy");
+        let stats = QueryStats {
+            upload_bytes: 100,
+            download_bytes: 1000,
+            ..QueryStats::default()
+        };
+        builder.record_query(10.0, &stats);
+        builder.record_hit("top1", true);
+        builder.record_hit("top1", false);
+        let mut before = builder.build();
+        before.fingerprint =
+            Fingerprint::from_settings([("TIPTOE_SEED".to_string(), "1".to_string())].into());
+        assert_eq!(before.recall["top1"], 0.5);
+
+        let mut after = before.clone();
+        after.recall.insert("top1".to_string(), 0.75);
+        after.fingerprint =
+            Fingerprint::from_settings([("TIPTOE_SEED".to_string(), "2".to_string())].into());
+        assert_ne!(before.fingerprint.id, after.fingerprint.id);
+
+        let comparison = compare(&before, &after);
+        assert_eq!(
+            comparison.settings["TIPTOE_SEED"],
+            (Some("1".to_string()), Some("2".to_string()))
+        );
+        let top1 = comparison
+            .metrics
+            .iter()
+            .find(|change| change.metric == "recall.top1")
+            .unwrap();
+        assert_eq!(top1.change, Some(0.5));
+        let latency = comparison
+            .metrics
+            .iter()
+            .find(|change| change.metric == "latency_ms.p50")
+            .unwrap();
+        assert_eq!(latency.change, Some(0.0));
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::Result;
+use nalgebra::DVector;
+use num_bigint::BigInt;
+use num_traits::{One, Zero};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+use crate::{
+    error::PirError,
+    network::serialize_words,
+    pir,
+    server::{Database, SimplePirDatabase},
+    utils::fit_query,
+};
+
+// Outcome of a passing self-test
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SelfTestReport {
+    pub epoch: u64,
+    // Databases whose round trips recovered their columns, e.g. "main" or "cluster 3"
+    pub checked: Vec<String>,
+    pub elapsed_ms: f64,
+}
+
+// Queries `column` the way a client would, with the database's own params, A and hint,
+// and checks the recovered values against the plaintext column. Word queries and
+// plaintext mode are checked against the same answer.
+fn check_column(db: &SimplePirDatabase, column: usize) -> Result<bool> {
+    let params = db.params();
+    let mut one_hot = DVector::zeros(column + 1);
+    one_hot[column] = BigInt::one();
+    let one_hot = fit_query(one_hot, params.m)?;
+    let (s, query) = pir::query(params, &one_hot, db.a());
+    let answer = db.respond(&query)?;
+    let recovered = pir::recover(params, db.hint(), &s, &answer);
+
+    // Queries sent as words must be answered the same, modulo q
+    if let Some(words) = serialize_words(&query, params).filter(|_| db.accepts_words()) {
+        if !congruent(&db.respond_words(&words)?, &answer, &BigInt::from(params.q)) {
+            return Ok(false);
+        }
+    }
+    // Values are only defined modulo the plaintext modulus
+    if !congruent(&db.answer_plaintext(&one_hot)?, &recovered, &params.p) {
+        return Ok(false);
+    }
+    Ok(congruent(
+        &recovered,
+        &db.data().column(column).into_owned(),
+        &params.p,
+    ))
+}
+
+fn congruent(got: &DVector<BigInt>, want: &DVector<BigInt>, modulus: &BigInt) -> bool {
+    got.len() == want.len()
+        && got
+            .iter()
+            .zip(want.iter())
+            .all(|(got, want)| ((got - want) % modulus).is_zero())
+}
+
+// Checks the first and last column, covering both ends of the layout
+fn check(name: &str, db: &SimplePirDatabase) -> Result<()> {
+    let (_, cols) = db.dims();
+    for column in [0, cols.saturating_sub(1)] {
+        if !check_column(db, column)? {
+            return Err(PirError::Database(format!(
+                "Self-test failed: {} database recovered the wrong values for column {}",
+                name, column
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// Runs a known-plaintext query through every database `db` serves: the main one, each
+// cluster, and the membership, hot, packed and block databases when present. Catches
+// parameter or layout regressions before a real client recovers garbage.
+pub fn run<T: Database>(db: &T) -> Result<SelfTestReport> {
+    let started = Instant::now();
+    let mut databases = vec![("main".to_string(), db.database())];
+    for id in 0..db.cluster_dims().len() {
+        if let Some(cluster) = db.cluster(id) {
+            databases.push((format!("cluster {}", id), cluster));
+        }
+    }
+    if let Some(membership) = db.membership() {
+        databases.push(("membership".to_string(), &membership.db));
+    }
+    if let Some(hot) = db.hot() {
+        databases.push(("hot".to_string(), &hot.db));
+        if let Some(packed) = &hot.packed {
+            databases.push(("packed".to_string(), &packed.db));
+        }
+    }
+    if let Some(blocks) = db.blocks() {
+        databases.push(("blocks".to_string(), &blocks.db));
+    }
+
+    for (name, database) in &databases {
+        check(name, database)?;
+    }
+    Ok(SelfTestReport {
+        epoch: db.epoch(),
+        checked: databases.into_iter().map(|(name, _)| name).collect(),
+        elapsed_ms: started.elapsed().as_secs_f64() * 1000.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{quantization::Quantization, utils::encode_data};
+
+    #[test]
+    fn test_round_trip_recovers_columns() -> Result<()> {
+        let records = ["Tesla, Inc.", "Apple Inc.", "Bitcoin USD"].map(String::from);
+        let mut db = SimplePirDatabase::new(nalgebra::DMatrix::zeros(1, 1));
+        db.update_db(encode_data(&records)?)?;
+        check("encoding", &db)?;
+        for column in 0..records.len() {
+            assert!(check_column(&db, column)?);
+        }
+
+        // Changing one record keeps A and only recomputes the hint rows it touches
+        let (epoch, a, rows) = (db.epoch(), db.a().clone(), db.dims().0);
+        let changed = ["Tesla, Inc.", "Apple Ltd.", "Bitcoin USD"].map(String::from);
+        db.update_db(encode_data(&changed)?)?;
+        assert_eq!(db.a(), &a);
+        assert!(db.epoch() > epoch);
+        let patched = db.changed_rows(epoch).expect("A was kept");
+        assert!(!patched.is_empty() && patched.len() < rows);
+        assert_eq!(db.changed_rows(db.epoch()), Some(Vec::new()));
+        for column in 0..changed.len() {
+            assert!(check_column(&db, column)?);
+        }
+
+        // Tall enough to span several row blocks of the word matrix
+        let tall = [records[0].repeat(250), records[2].clone()];
+        db.update_db(encode_data(&tall)?)?;
+        assert!(db.dims().0 > 256);
+        assert!(check_column(&db, 0)? && check_column(&db, 1)?);
+        assert_eq!(db.changed_rows(epoch), None);
+
+        // Embedding databases have a row per document, however many there are
+        let quantization = Quantization::default();
+        for documents in [3, 300] {
+            let embeddings: Vec<Vec<f32>> = (0..documents)
+                .map(|i| (0..8).map(|j| ((i * 8 + j) % 7) as f32 / 7.0).collect())
+                .collect();
+            let mut db = SimplePirDatabase::new(nalgebra::DMatrix::zeros(1, 1));
+            db.update_db(quantization.quantize_rows(&embeddings))?;
+            assert_eq!(db.dims(), (documents, 8));
+            check("embedding", &db)?;
+        }
+        Ok(())
+    }
+}
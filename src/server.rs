@@ -1,21 +1,520 @@
 use anyhow::Result;
 use nalgebra::{DMatrix, DVector};
 use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use simplepir::*;
-use std::process::Command;
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::Receiver;
 
-use crate::{embedding::BertEmbedder, error::PirError, utils::encode_data};
+#[cfg(feature = "baseline")]
+use crate::baseline::BaselineIndex;
+use crate::{
+    auth,
+    bloom::Membership,
+    clustering::{
+        balanced_kmeans, default_max_cluster_size, ClusterQuality, ClusterState, Clustering,
+        DistanceMetric, KMeansConfig, MiniBatchKMeans,
+    },
+    config::ServerConfig,
+    correction::QueryCorrection,
+    crypto::RecordKey,
+    dedup::{collapse_duplicates, Deduplicated},
+    documents::{find_row, needs_compaction, DocumentId, Tombstones},
+    embedding::{BertEmbedder, EmbeddingModel},
+    error::PirError,
+    ingest::{spill_path, Ingestor},
+    integrity::{signing_key_from_env, DatabaseDigest},
+    jobs::RebuildJob,
+    market::{annotate, Locale},
+    packing::{block_size, PackedValues, RecordBlocks},
+    partitions::PartitionManifest,
+    pir::{self, SimplePIRParams},
+    quantization::{Calibration, Quantization},
+    snapshot::{
+        check_rows, wrong_kind, Contents, DatabaseImage, EmbeddingImage, EncodingImage, HotImage,
+        MatrixImage, Snapshot,
+    },
+    source::{load_snapshots, load_validated, CorpusSource, SNAPSHOT_DIR},
+    stream::TickStore,
+    templates::TextMapping,
+    tiering::{hot_fields, split, HotTier},
+    utils::{encode_data, env_seed, fit_query, to_word, word_modulus},
+};
+
+// Corpora larger than this are clustered with mini-batch k-means
+const MINI_BATCH_SIZE: usize = 1024;
+// Documents embedded between rebuild checkpoints
+const EMBED_CHECKPOINT: usize = 64;
+// Where the embedding database keeps its cluster assignments between rebuilds
+const CLUSTER_STATE_ENV_VAR: &str = "TIPTOE_CLUSTER_STATE";
+const DEFAULT_CLUSTER_STATE_PATH: &str = "cluster_state.json";
+const TOMBSTONES_PATH: &str = "tombstones.json";
+// Plaintext modulus of a database is 2^MOD_POWER unless it is built with a smaller one
+pub const MOD_POWER: u32 = 64;
+// Epochs a database remembers its hint changes for, so clients that far behind can
+// patch their hint instead of downloading it again
+const MAX_HINT_HISTORY: usize = 16;
+
+// Fetches the corpus, drops deleted documents and collapses duplicates. Both
+// databases load it the same way so their rows stay aligned.
+fn load_documents() -> Result<Deduplicated> {
+    let config = ServerConfig::from_env()?;
+    let mut documents = if config.sources.is_empty() {
+        load_validated(&[CorpusSource::from_env()?], &config.validation)?
+    } else {
+        load_snapshots(Path::new(SNAPSHOT_DIR), &config.sources, &config.validation)
+    };
+    if let Some(partitions) = &config.partitions {
+        let expired = partitions.expire(&mut documents, unix_now());
+        if expired > 0 {
+            println!("Dropped {} expired documents", expired);
+        }
+    }
+    annotate(&mut documents, Locale::from_env()?);
+    let ids = derive_ids(&documents);
+
+    let mut tombstones = Tombstones::load(TOMBSTONES_PATH).unwrap_or_default();
+    if tombstones.prune(&ids) {
+        tombstones.save(TOMBSTONES_PATH)?;
+    }
+    let documents = documents
+        .into_iter()
+        .zip(&ids)
+        .filter(|(_, id)| !tombstones.contains(id))
+        .map(|(document, _)| document)
+        .collect();
+    Ok(collapse_duplicates(documents))
+}
+
+fn cluster_state_path() -> String {
+    std::env::var(CLUSTER_STATE_ENV_VAR).unwrap_or_else(|_| DEFAULT_CLUSTER_STATE_PATH.to_string())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// One database per partition, over its rows of the main database's `data`
+fn partition_databases(
+    manifest: &PartitionManifest,
+    data: &DMatrix<BigInt>,
+    mod_power: u32,
+) -> Result<Vec<SimplePirDatabase>> {
+    manifest
+        .partitions
+        .iter()
+        .map(|partition| {
+            let mut db = SimplePirDatabase::new(DMatrix::zeros(1, 1));
+            db.set_mod_power(mod_power);
+            db.update_db(keep_rows(data, &partition.rows))?;
+            Ok(db)
+        })
+        .collect()
+}
+
+// Stable key for a record across updates; prices change but names don't
+pub(crate) fn document_id(value: &Value) -> String {
+    value
+        .get("name")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .unwrap_or_else(|| value.to_string())
+}
+
+fn derive_ids(documents: &[Value]) -> Vec<DocumentId> {
+    documents
+        .iter()
+        .map(|document| DocumentId::derive(&document_id(document), &document.to_string()))
+        .collect()
+}
+
+// Records a tombstone for `id` and marks its row dead. Returns false if the document
+// is not served or is already dead.
+fn mark_deleted(ids: &[DocumentId], dead: &mut BTreeSet<usize>, id: &DocumentId) -> Result<bool> {
+    let Some(row) = find_row(ids, id) else {
+        return Ok(false);
+    };
+    let mut tombstones = Tombstones::load(TOMBSTONES_PATH).unwrap_or_default();
+    if tombstones.insert(id) {
+        tombstones.save(TOMBSTONES_PATH)?;
+    }
+    Ok(dead.insert(row))
+}
+
+// Encodes one record per column, encrypting them first when a record key is configured
+// so the database only ever stores ciphertext
+pub(crate) fn encode_records(mut records: Vec<String>) -> Result<DMatrix<BigInt>> {
+    if let Some(key) = RecordKey::from_env()? {
+        records = records
+            .iter()
+            .map(|record| key.encrypt(record))
+            .collect::<Result<_>>()?;
+    }
+
+    let encodings = encode_data(&records).map_err(|e| PirError::Encoding(e.to_string()))?;
+    if encodings.nrows() != encodings.ncols() {
+        return Err(PirError::Database("Encoding matrix must be square".to_string()).into());
+    }
+    Ok(encodings)
+}
+
+pub enum HotRefresh {
+    // Same documents in the same rows; carries the new hot tier if one was requested
+    Current(Option<Box<HotTier>>),
+    // Documents were added or removed, so only a full rebuild realigns the rows
+    Stale,
+}
+
+// Re-reads the corpus, with the latest exchange ticks applied, and if it still holds
+// exactly the documents served in `ids`, rebuilds the hot tier over `fields` in the
+// same row order. Dead rows get empty records.
+pub fn refresh_hot_tier(
+    ids: &[DocumentId],
+    dead: &BTreeSet<usize>,
+    fields: Option<&[String]>,
+    ticks: &TickStore,
+) -> Result<HotRefresh> {
+    let mut documents = load_documents()?.documents;
+    if ticks.overlay(&mut documents) > 0 {
+        annotate(&mut documents, Locale::from_env()?);
+    }
+    let by_key: HashMap<u64, &Value> = derive_ids(&documents)
+        .into_iter()
+        .map(|id| id.key)
+        .zip(&documents)
+        .collect();
+    if by_key.len() != ids.len() - dead.len() {
+        return Ok(HotRefresh::Stale);
+    }
+
+    let rows: Option<Vec<Value>> = ids
+        .iter()
+        .enumerate()
+        .map(|(row, id)| {
+            if dead.contains(&row) {
+                Some(Value::Object(Default::default()))
+            } else {
+                by_key.get(&id.key).map(|&document| document.clone())
+            }
+        })
+        .collect();
+    match rows {
+        Some(rows) => Ok(HotRefresh::Current(
+            fields
+                .map(|fields| HotTier::build(&rows, fields).map(Box::new))
+                .transpose()?,
+        )),
+        None => Ok(HotRefresh::Stale),
+    }
+}
+
+// Keeps the given rows of `data`, in order, at full width so queries still fit
+fn keep_rows(data: &DMatrix<BigInt>, keep: &[usize]) -> DMatrix<BigInt> {
+    let mut out = DMatrix::zeros(keep.len().max(1), data.ncols());
+    for (i, &row) in keep.iter().enumerate() {
+        out.set_row(i, &data.row(row));
+    }
+    out
+}
+
+// Keeps the given records (columns) of encoded `data`, in order, re-padded to a square
+// matrix. Trailing all-zero rows are dropped first so the matrix can shrink.
+fn keep_records(data: &DMatrix<BigInt>, keep: &[usize]) -> DMatrix<BigInt> {
+    let height = keep
+        .iter()
+        .filter_map(|&col| {
+            (0..data.nrows())
+                .rev()
+                .find(|&row| !data[(row, col)].is_zero())
+        })
+        .max()
+        .map_or(1, |row| row + 1);
+    let side = keep.len().max(height);
+
+    let mut out = DMatrix::zeros(side, side);
+    for (i, &col) in keep.iter().enumerate() {
+        for row in 0..height {
+            out[(row, i)] = data[(row, col)].clone();
+        }
+    }
+    out
+}
 
 pub trait Database {
     fn new() -> Result<Self>
     where
         Self: Sized;
+    // As `new`, but embedding documents with `model` instead of the configured one
+    fn with_embedding_model(_model: EmbeddingModel) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Err(PirError::InvalidInput("This server does not embed documents".to_string()).into())
+    }
     fn update(&mut self) -> Result<()>;
+    // Same as `update`, reporting progress to `job` and stopping if it is cancelled
+    fn rebuild(&mut self, job: &RebuildJob) -> Result<()> {
+        job.progress(0, 1)?;
+        self.update()
+    }
     fn respond(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>>;
+    // The database answering `/query`
+    fn database(&self) -> &SimplePirDatabase;
     fn params(&self) -> &SimplePIRParams;
     fn hint(&self) -> &DMatrix<BigInt>;
     fn a(&self) -> &DMatrix<BigInt>;
+    // Digest of the current epoch's params, hint and A
+    fn digest(&self) -> &DatabaseDigest;
+    fn epoch(&self) -> u64;
+    fn cluster_dims(&self) -> Vec<(usize, usize)>;
+    fn clustering(&self) -> Option<&Clustering>;
+    fn cluster(&self, id: usize) -> Option<&SimplePirDatabase>;
+    fn cluster_quality(&self) -> Option<ClusterQuality>;
+    fn membership(&self) -> Option<&Membership>;
+    // Time windows the database is split into, if it is partitioned
+    fn partitions(&self) -> Option<&PartitionManifest> {
+        None
+    }
+    fn partition(&self, _id: usize) -> Option<&SimplePirDatabase> {
+        None
+    }
+    // How query embeddings must be quantized, for databases that score embeddings
+    fn quantization(&self) -> Option<Quantization> {
+        None
+    }
+    // Maps this database's scores to cosine similarity
+    fn calibration(&self) -> Option<Calibration> {
+        None
+    }
+    // Applied by clients to query embeddings before quantizing them
+    fn query_correction(&self) -> Option<QueryCorrection> {
+        None
+    }
+    // Put in front of query text before it is embedded
+    fn query_prefix(&self) -> Option<String> {
+        None
+    }
+    // Model the documents were embedded with, for databases that score embeddings
+    fn embedding_model(&self) -> Option<EmbeddingModel> {
+        None
+    }
+    // Width of the embeddings queries must have, for databases that score embeddings
+    fn query_dim(&self) -> Option<usize> {
+        None
+    }
+    // Size of every PIR database this server answers from
+    fn stats(&self) -> Vec<DatabaseStats>;
+    // Stable id of the document in each row, for the current epoch
+    fn document_ids(&self) -> &[DocumentId];
+    // Source key (document name) of each row, aligned with `document_ids`
+    fn source_keys(&self) -> &[String];
+    // Tombstones the document without a rebuild; its row keeps its place but clients
+    // skip it. Returns false if the document is not served.
+    fn delete(&mut self, id: &DocumentId) -> Result<bool>;
+    // Rows deleted since the last rebuild or compaction
+    fn dead_rows(&self) -> &BTreeSet<usize>;
+    // Rewrites the matrices without dead rows, moving later rows up, and bumps the epoch
+    fn compact(&mut self) -> Result<()>;
+    fn needs_compaction(&self) -> bool {
+        needs_compaction(self.dead_rows().len(), self.document_ids().len())
+    }
+    // The small, often rebuilt database of fast-changing fields, if this server has one
+    fn hot(&self) -> Option<&HotTier> {
+        None
+    }
+    // Swaps in a fresh hot tier, leaving the cold database and its hint untouched
+    fn set_hot(&mut self, _tier: HotTier) {}
+    // Adjacent records packed several per column, if this server serves them
+    fn blocks(&self) -> Option<&RecordBlocks> {
+        None
+    }
+    // Makes the next rebuild keep the A of `base`, the served database's, where it can
+    fn set_hint_base(&mut self, base: HintBase);
+    // Every database and the row mapping of the current epoch, for backups
+    fn snapshot(&self) -> Result<Snapshot>;
+    // Serves a snapshot taken on a server of the same kind as it was, epochs included
+    fn restore(&mut self, snapshot: Snapshot) -> Result<()>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DatabaseStats {
+    pub name: String,
+    pub rows: usize,
+    pub cols: usize,
+    // Widest element in the data matrix
+    pub element_bits: u64,
+    // log2 of the plaintext modulus
+    pub mod_power: u32,
+    // Approximate resident size of each matrix
+    pub data_bytes: usize,
+    pub hint_bytes: usize,
+    pub a_bytes: usize,
+    // One multiply and one add per data element to answer a query
+    pub flops_per_query: u64,
+}
+
+// Rows per block of a WordMatrix; a block's u128 sums (4 KiB) stay in L1 while its
+// columns stream past
+const BLOCK_ROWS: usize = 256;
+
+thread_local! {
+    // The query being answered on this thread and its running sums, kept between
+    // queries so that answering one does not allocate them again
+    static QUERY: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+    static SUMS: RefCell<Vec<u128>> = const { RefCell::new(Vec::new()) };
+}
+
+fn check_width(len: usize, cols: usize) -> Result<()> {
+    if len > cols {
+        return Err(PirError::InvalidInput(format!(
+            "Query of {} elements does not fit {} columns",
+            len, cols
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+// Columns whose products can be added to a u128 sum below q before it has to be
+// reduced again. Products of two words are below (q - 1)^2, so for q up to 2^32 a
+// whole query sums without reducing. For q = 2^64 the sums may wrap: 2^128 is a
+// multiple of q, so the wrapped sum is still right modulo q.
+fn reduce_every(q: u128) -> usize {
+    if q == 1 << 64 {
+        return usize::MAX;
+    }
+    let max_product = (q - 1).pow(2).max(1);
+    ((u128::MAX - (q - 1)) / max_product).clamp(1, usize::MAX as u128) as usize
+}
+
+// A data matrix reduced into [0, q) as u64 words, for q <= 2^64. Stored in blocks of
+// BLOCK_ROWS rows, each laid out column-major on its own, so a query reads the words
+// front to back exactly once while only one block's sums are being updated.
+struct WordMatrix {
+    rows: usize,
+    cols: usize,
+    words: Vec<u64>,
+    q: u128,
+    reduce_every: usize,
+}
+
+impl WordMatrix {
+    fn new(data: &DMatrix<BigInt>, q: u128) -> Self {
+        let big_q = BigInt::from(q);
+        let (rows, cols) = data.shape();
+        let mut words = Vec::with_capacity(rows * cols);
+        for start in (0..rows).step_by(BLOCK_ROWS) {
+            let end = (start + BLOCK_ROWS).min(rows);
+            for col in 0..cols {
+                words.extend(
+                    (start..end)
+                        .map(|row| to_word(&data[(row, col)], &big_q).expect("q fits in a word")),
+                );
+            }
+        }
+        Self {
+            rows,
+            cols,
+            words,
+            q,
+            reduce_every: reduce_every(q),
+        }
+    }
+
+    // `self * query` modulo q, accumulated in u128 and reduced only as often as
+    // `reduce_every` requires
+    fn multiply(&self, query: impl Iterator<Item = u64>) -> DVector<BigInt> {
+        let q = self.q;
+        QUERY.with_borrow_mut(|words| {
+            words.clear();
+            words.extend(query.map(|word| (word as u128 % q) as u64));
+            SUMS.with_borrow_mut(|sums| {
+                sums.clear();
+                sums.resize(self.rows, 0);
+                let mut blocks = self.words.as_slice();
+                for block_sums in sums.chunks_mut(BLOCK_ROWS) {
+                    let height = block_sums.len();
+                    let (block, rest) = blocks.split_at(height * self.cols);
+                    blocks = rest;
+                    let mut pending = 0;
+                    for (column, &word) in block.chunks_exact(height).zip(words.iter()) {
+                        if word == 0 {
+                            continue;
+                        }
+                        if pending == self.reduce_every {
+                            block_sums.iter_mut().for_each(|sum| *sum %= q);
+                            pending = 0;
+                        }
+                        for (sum, &x) in block_sums.iter_mut().zip(column) {
+                            *sum = sum.wrapping_add(x as u128 * word as u128);
+                        }
+                        pending += 1;
+                    }
+                    block_sums.iter_mut().for_each(|sum| *sum %= q);
+                }
+                DVector::from_iterator(sums.len(), sums.iter().map(|&sum| BigInt::from(sum)))
+            })
+        })
+    }
+}
+
+fn matrix_bytes(matrix: Option<&DMatrix<BigInt>>) -> usize {
+    matrix.map_or(0, |matrix| {
+        matrix
+            .iter()
+            .map(|x| std::mem::size_of::<BigInt>() + (x.bits() as usize).div_ceil(64) * 8)
+            .sum()
+    })
+}
+
+// Rows whose hint changed going from `epoch` to the next one
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HintChange {
+    pub epoch: u64,
+    pub rows: Vec<usize>,
+}
+
+// An epoch's A and hint, from which the next update of a database of the same shape
+// only recomputes the hint rows of changed data rows
+#[derive(Clone)]
+pub struct HintBase {
+    epoch: u64,
+    mod_power: u32,
+    a: DMatrix<BigInt>,
+    hint: DMatrix<BigInt>,
+    row_hashes: Vec<u64>,
+    history: Vec<HintChange>,
+}
+
+impl HintBase {
+    fn fits(&self, params: &SimplePIRParams, mod_power: u32, row_hashes: &[u64]) -> bool {
+        self.mod_power == mod_power
+            && self.row_hashes.len() == row_hashes.len()
+            && self.a.shape() == (params.m, params.n)
+            && self.hint.shape() == (row_hashes.len(), params.n)
+    }
+}
+
+fn row_hashes(data: &DMatrix<BigInt>) -> Vec<u64> {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    data.row_iter()
+        .map(|row| {
+            let mut hasher = DefaultHasher::new();
+            row.iter().for_each(|x| x.hash(&mut hasher));
+            hasher.finish()
+        })
+        .collect()
 }
 
 pub struct SimplePirDatabase {
@@ -23,6 +522,20 @@ pub struct SimplePirDatabase {
     data: DMatrix<BigInt>,
     hint: Option<DMatrix<BigInt>>,
     a: Option<DMatrix<BigInt>>,
+    digest: Option<DatabaseDigest>,
+    // `data` as fixed-width words, which queries are answered from. None when q does
+    // not fit in 64 bits.
+    words: Option<WordMatrix>,
+    // log2 of the plaintext modulus the next update generates params for
+    mod_power: u32,
+    // Unix timestamp (seconds) of the last successful update, 0 if never updated
+    epoch: u64,
+    // Hash of each data row, to tell which rows the next update changes
+    row_hashes: Vec<u64>,
+    // Hint changes of the epochs since A was last generated, oldest first
+    history: Vec<HintChange>,
+    // The served database the next update keeps A from, when this one is a rebuild
+    base: Option<HintBase>,
 }
 
 impl SimplePirDatabase {
@@ -32,15 +545,71 @@ impl SimplePirDatabase {
             params: None,
             hint: None,
             a: None,
+            digest: None,
+            words: None,
+            mod_power: MOD_POWER,
+            epoch: 0,
+            row_hashes: Vec::new(),
+            history: Vec::new(),
+            base: None,
         }
     }
 
+    // Takes effect on the next update. Clients read the modulus from the params.
+    pub fn set_mod_power(&mut self, mod_power: u32) {
+        self.mod_power = mod_power;
+    }
+
+    pub fn mod_power(&self) -> u32 {
+        self.mod_power
+    }
+
+    // A database of the same shape keeps the A of the one it replaces, its own or
+    // `base`, so its hint only changes in the rows whose data changed
     pub fn update_db(&mut self, data: DMatrix<BigInt>) -> Result<()> {
+        let base = self.base.take().or_else(|| self.hint_base());
         self.data = data;
 
-        let params = gen_params(self.data.nrows(), self.data.ncols(), 64);
-        let (hint, a) = gen_hint(&params, &self.data);
+        let params = pir::params_for(&self.data, self.mod_power);
+        let row_hashes = row_hashes(&self.data);
+        // Epochs name hint versions, so a rebuild must never repeat or precede the one
+        // it replaces, whether or not it keeps A
+        let replaced = base
+            .as_ref()
+            .map_or(self.epoch, |base| base.epoch.max(self.epoch));
+        let epoch = unix_now().max(replaced + 1);
+        let base = base.filter(|base| base.fits(&params, self.mod_power, &row_hashes));
+        let (hint, a) = match base {
+            Some(base) => {
+                let changed: Vec<usize> = (0..row_hashes.len())
+                    .filter(|&row| row_hashes[row] != base.row_hashes[row])
+                    .collect();
+                let hint = pir::patch_hint(&params, &self.data, &base.a, base.hint, &changed);
+                self.history = base.history;
+                self.history.push(HintChange {
+                    epoch: base.epoch,
+                    rows: changed,
+                });
+                let expired = self.history.len().saturating_sub(MAX_HINT_HISTORY);
+                self.history.drain(..expired);
+                (hint, base.a)
+            }
+            None => {
+                self.history.clear();
+                pir::setup(&params, &self.data)
+            }
+        };
 
+        self.epoch = epoch;
+        self.row_hashes = row_hashes;
+        self.digest = Some(DatabaseDigest::compute(
+            self.epoch,
+            &params,
+            &hint,
+            &a,
+            signing_key_from_env()?.as_ref(),
+        ));
+        self.words = word_modulus(&params).map(|q| WordMatrix::new(&self.data, q));
         self.params = Some(params);
         self.hint = Some(hint);
         self.a = Some(a);
@@ -48,70 +617,413 @@ impl SimplePirDatabase {
         Ok(())
     }
 
+    // With a modulus of at most 64 bits the product is taken over fixed-width words in
+    // per-thread buffers reused across queries, so a query allocates little beyond its
+    // answer. Wider moduli fall back to simplepir's BigInt arithmetic.
     pub fn respond(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>> {
         let params = self
             .params
             .as_ref()
             .ok_or_else(|| PirError::Database("Database not initialized".to_string()))?;
-        let answer = process_query(&self.data, query, params.q);
-        Ok(answer)
+        let Some(words) = self.words.as_ref() else {
+            return Ok(pir::answer(params, &self.data, query));
+        };
+        let big_q = BigInt::from(words.q);
+        check_width(query.len(), words.cols)?;
+        // Elements are usually already in [0, q) and convert without a remainder
+        let query = query.iter().map(|x| {
+            x.to_u64()
+                .filter(|&word| (word as u128) < words.q)
+                .or_else(|| to_word(x, &big_q))
+                .expect("q fits in a word")
+        });
+        Ok(words.multiply(query))
     }
 
-    fn params(&self) -> &SimplePIRParams {
-        self.params.as_ref().ok_or(PirError::Database(
-            "Database not initialized".to_string(),
-        )).unwrap()
+    // The matrix times `query` over the integers: what a PIR round for the same query
+    // recovers, before reduction modulo p. Only plaintext mode asks for it, since it
+    // needs the query in the clear.
+    pub fn answer_plaintext(&self, query: &DVector<BigInt>) -> Result<DVector<BigInt>> {
+        let query = fit_query(query.clone(), self.data.ncols())?;
+        Ok(&self.data * query)
     }
 
-    fn hint(&self) -> &DMatrix<BigInt> {
-        self.hint.as_ref().ok_or(PirError::Database(
-            "Database not initialized".to_string(),
-        )).unwrap()
+    pub fn accepts_words(&self) -> bool {
+        self.words.is_some()
     }
 
-    fn a(&self) -> &DMatrix<BigInt> {
-        self.a.as_ref().ok_or(PirError::Database(
-            "Database not initialized".to_string(),
-        )).unwrap()
+    // Like `respond`, for a query of little-endian u64 words, one per column. The words
+    // are read from `query` as they are multiplied, without parsing or allocating a
+    // BigInt per element.
+    pub fn respond_words(&self, query: &[u8]) -> Result<DVector<BigInt>> {
+        let Some(words) = self.words.as_ref() else {
+            return Err(
+                PirError::Database("Database does not accept word queries".to_string()).into(),
+            );
+        };
+        if !query.len().is_multiple_of(8) {
+            return Err(PirError::InvalidInput(format!(
+                "Word query of {} bytes is not a whole number of words",
+                query.len()
+            ))
+            .into());
+        }
+        check_width(query.len() / 8, words.cols)?;
+        let query = query
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().expect("chunks are 8 bytes")));
+        Ok(words.multiply(query))
+    }
+
+    pub fn stats(&self, name: &str) -> DatabaseStats {
+        let (rows, cols) = self.dims();
+        DatabaseStats {
+            name: name.to_string(),
+            rows,
+            cols,
+            element_bits: self.data.iter().map(|x| x.bits()).max().unwrap_or(0),
+            mod_power: self.mod_power,
+            // Including the word copy queries are answered from
+            data_bytes: matrix_bytes(Some(&self.data))
+                + self.words.as_ref().map_or(0, |words| words.words.len() * 8),
+            hint_bytes: matrix_bytes(self.hint.as_ref()),
+            a_bytes: matrix_bytes(self.a.as_ref()),
+            flops_per_query: 2 * rows as u64 * cols as u64,
+        }
+    }
+
+    pub fn data(&self) -> &DMatrix<BigInt> {
+        &self.data
+    }
+
+    pub fn dims(&self) -> (usize, usize) {
+        (self.data.nrows(), self.data.ncols())
+    }
+
+    pub fn params(&self) -> &SimplePIRParams {
+        self.params
+            .as_ref()
+            .ok_or(PirError::Database("Database not initialized".to_string()))
+            .unwrap()
+    }
+
+    pub fn hint(&self) -> &DMatrix<BigInt> {
+        self.hint
+            .as_ref()
+            .ok_or(PirError::Database("Database not initialized".to_string()))
+            .unwrap()
+    }
+
+    pub fn a(&self) -> &DMatrix<BigInt> {
+        self.a
+            .as_ref()
+            .ok_or(PirError::Database("Database not initialized".to_string()))
+            .unwrap()
+    }
+
+    pub fn digest(&self) -> &DatabaseDigest {
+        self.digest
+            .as_ref()
+            .ok_or(PirError::Database("Database not initialized".to_string()))
+            .unwrap()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    // This epoch's A and hint, for a rebuilt database to keep A from
+    pub fn hint_base(&self) -> Option<HintBase> {
+        Some(HintBase {
+            epoch: self.epoch,
+            mod_power: self.mod_power,
+            a: self.a.clone()?,
+            hint: self.hint.clone()?,
+            row_hashes: self.row_hashes.clone(),
+            history: self.history.clone(),
+        })
+        .filter(|base| base.epoch != 0)
+    }
+
+    // Takes effect on the next update, which keeps `base`'s A if the shape still fits
+    pub fn set_hint_base(&mut self, base: HintBase) {
+        self.base = Some(base);
+    }
+
+    // Rows of the hint that changed since `from`, or None if A has been regenerated
+    // since or `from` is too old to be remembered
+    pub fn changed_rows(&self, from: u64) -> Option<Vec<usize>> {
+        if from == self.epoch {
+            return Some(Vec::new());
+        }
+        let start = self
+            .history
+            .iter()
+            .position(|change| change.epoch == from)?;
+        let rows: BTreeSet<usize> = self.history[start..]
+            .iter()
+            .flat_map(|change| change.rows.iter().copied())
+            .collect();
+        Some(rows.into_iter().collect())
+    }
+
+    pub fn image(&self) -> DatabaseImage {
+        DatabaseImage {
+            mod_power: self.mod_power,
+            epoch: self.epoch,
+            data: MatrixImage::of(&self.data),
+            hint: MatrixImage::of(self.hint()),
+            a: MatrixImage::of(self.a()),
+        }
+    }
+
+    // Serves `image` without regenerating A, so hints clients already hold stay valid.
+    // The digest is recomputed and signed with this server's key.
+    pub fn from_image(image: &DatabaseImage) -> Result<Self> {
+        let data = image.data.to_matrix()?;
+        let hint = image.hint.to_matrix()?;
+        let a = image.a.to_matrix()?;
+        let params = pir::params_for(&data, image.mod_power);
+        if a.nrows() != data.ncols()
+            || a.ncols() != params.n
+            || hint.shape() != (data.nrows(), params.n)
+        {
+            return Err(
+                PirError::Database("Snapshot matrices do not fit together".to_string()).into(),
+            );
+        }
+        Ok(Self {
+            digest: Some(DatabaseDigest::compute(
+                image.epoch,
+                &params,
+                &hint,
+                &a,
+                signing_key_from_env()?.as_ref(),
+            )),
+            words: word_modulus(&params).map(|q| WordMatrix::new(&data, q)),
+            params: Some(params),
+            row_hashes: row_hashes(&data),
+            data,
+            hint: Some(hint),
+            a: Some(a),
+            mod_power: image.mod_power,
+            epoch: image.epoch,
+            history: Vec::new(),
+            base: None,
+        })
     }
 }
 
+fn from_images(images: &[DatabaseImage]) -> Result<Vec<SimplePirDatabase>> {
+    images.iter().map(SimplePirDatabase::from_image).collect()
+}
+
 pub struct EmbeddingDatabase {
     db: SimplePirDatabase,
-    embedder: BertEmbedder,
+    embedder: Arc<BertEmbedder>,
+    clustering: Option<Clustering>,
+    // One database per cluster so a query only touches the cluster it names
+    clusters: Vec<SimplePirDatabase>,
+    quality: Option<ClusterQuality>,
+    manifest: Option<PartitionManifest>,
+    // One database per time window, queried for documents dated in it
+    partitions: Vec<SimplePirDatabase>,
+    quantization: Quantization,
+    calibration: Option<Calibration>,
+    correction: Option<QueryCorrection>,
+    // Model the served documents were embedded with, which rebuilds may change
+    model: EmbeddingModel,
+    // Query prefix of the model the documents were embedded for
+    query_prefix: String,
+    // Text the documents were embedded as
+    text: TextMapping,
+    // Width of the document embeddings, which the matrix may be padded past
+    query_dim: Option<usize>,
+    // Ingested document index -> row it was collapsed into, if not since deleted
+    canonical: Vec<Option<usize>>,
+    ids: Vec<DocumentId>,
+    keys: Vec<String>,
+    dead: BTreeSet<usize>,
+    #[cfg(feature = "baseline")]
+    baseline: Option<BaselineIndex>,
 }
 
 impl Database for EmbeddingDatabase {
     fn new() -> Result<Self> {
-        Ok(Self {
-            db: SimplePirDatabase::new(DMatrix::zeros(1, 1)),
-            embedder: BertEmbedder::new().map_err(|e| PirError::Embedding(e.to_string()))?,
-        })
+        Self::with_embedder(EmbeddingModel::for_server().and_then(BertEmbedder::load))
+    }
+
+    fn with_embedding_model(model: EmbeddingModel) -> Result<Self> {
+        Self::with_embedder(BertEmbedder::load(model))
     }
 
     fn update(&mut self) -> Result<()> {
-        let stock_json = Command::new("python")
-            .arg("src/python/stocks.py")
-            .output()
-            .map_err(|e| PirError::CommandFailed(e.to_string()))?;
+        self.rebuild(&RebuildJob::detached())
+    }
 
-        if !stock_json.status.success() {
-            return Err(PirError::CommandFailed("Failed to update database".to_string()).into());
+    fn rebuild(&mut self, job: &RebuildJob) -> Result<()> {
+        job.progress(0, 100)?;
+        let config = ServerConfig::from_env()?;
+        let templates = config.templates;
+        let prefixes = config.prefixes;
+        let documents = load_documents()?;
+        if documents.collapsed() > 0 {
+            println!("Collapsed {} duplicate documents", documents.collapsed());
+        }
+        let stock_json = &documents.documents;
+        // Checked before clustering, so an empty corpus never replaces the saved state
+        if stock_json.is_empty() {
+            return Err(PirError::Database("No documents to build from".to_string()).into());
         }
 
-        let stock_json = String::from_utf8(stock_json.stdout)?;
-        let stock_json: Vec<Value> = serde_json::from_str(&stock_json)?;
+        // Embeddings are L2-normalized, so cluster by direction
+        let kmeans_config = KMeansConfig {
+            metric: DistanceMetric::Cosine,
+            seed: env_seed(),
+            ..Default::default()
+        };
+        let k = kmeans_config.num_clusters(stock_json.len());
+        let max_cluster_size = default_max_cluster_size(stock_json.len(), k);
 
-        let embeddings = self
-            .embedder
-            .embed_json_array(&stock_json)
-            .map_err(|e| PirError::Embedding(e.to_string()))?;
+        let ids: Vec<String> = stock_json.iter().map(document_id).collect();
 
-        if embeddings.nrows() != embeddings.ncols() {
-            return Err(PirError::Database("Embedding matrix must be square".to_string()).into());
+        // Large corpora are fitted batch by batch as the embeddings are produced, then
+        // assigned in one pass. The embeddings themselves are kept: they become the rows.
+        let mut kmeans = (stock_json.len() > MINI_BATCH_SIZE)
+            .then(|| MiniBatchKMeans::new(k, kmeans_config.metric).with_seed(kmeans_config.seed));
+        let mut raw_embeddings = Vec::with_capacity(stock_json.len());
+        for chunk in stock_json.chunks(MINI_BATCH_SIZE) {
+            let mut batch = Vec::with_capacity(chunk.len());
+            // Checkpoint often, so cancelling or throttling the rebuild takes effect quickly
+            for part in chunk.chunks(EMBED_CHECKPOINT) {
+                batch.extend(
+                    self.embedder
+                        .embed_documents_raw(part, &templates, &prefixes)
+                        .map_err(|e| PirError::Embedding(e.to_string()))?,
+                );
+                // Embedding dominates the build, so it accounts for most of the progress
+                job.progress(
+                    80 * (raw_embeddings.len() + batch.len()),
+                    100 * stock_json.len(),
+                )?;
+            }
+            if let Some(kmeans) = kmeans.as_mut() {
+                kmeans.partial_fit(&batch);
+            }
+            raw_embeddings.extend(batch);
         }
 
+        // Reuse the previous assignments unless they have become unbalanced or drifted
+        let state_path = cluster_state_path();
+        let previous = ClusterState::load(&state_path).ok();
+        let reused = previous.as_ref().and_then(|state| {
+            state
+                .reassign(
+                    &ids,
+                    &raw_embeddings,
+                    kmeans_config.metric,
+                    max_cluster_size,
+                )
+                .map(|clustering| {
+                    let updated = state.updated(&clustering, &ids, &raw_embeddings);
+                    (clustering, updated)
+                })
+        });
+        let (clustering, state) = match reused {
+            Some(reused) => reused,
+            None => {
+                let clustering = match kmeans {
+                    Some(kmeans) => kmeans.finish_balanced(&raw_embeddings, max_cluster_size)?,
+                    None => balanced_kmeans(&raw_embeddings, &kmeans_config, max_cluster_size)?,
+                };
+                let state = ClusterState::new(&clustering, &ids, &raw_embeddings);
+                (clustering, state)
+            }
+        };
+        // New documents are recorded too, so the next rebuild keeps them where they are
+        if let Err(e) = state.save(&state_path) {
+            eprintln!("Failed to persist cluster state: {:?}", e);
+        }
+
+        job.progress(85, 100)?;
+
+        // Scores need far less plaintext space than encoded records
+        let dim = raw_embeddings[0].len();
+        let mod_power = self.quantization.mod_power(dim)?;
+        self.calibration = Some(Calibration::fit(
+            &raw_embeddings,
+            &self.quantization,
+            kmeans_config.seed,
+        ));
+        self.correction = match &config.query_correction {
+            Some(correction) => correction.fit(
+                stock_json,
+                &raw_embeddings,
+                &templates,
+                kmeans_config.seed,
+                |query| self.embedder.embed_raw(&prefixes.prefix_query(query)),
+            )?,
+            None => None,
+        };
+        // One row per document, as wide as an embedding
+        let embeddings = self.quantization.quantize_rows(&raw_embeddings);
+
+        let clusters = (0..clustering.centroids.len())
+            .map(|cluster| {
+                let mut rows: Vec<Vec<f32>> = clustering
+                    .members_of(cluster)
+                    .into_iter()
+                    .map(|i| raw_embeddings[i].clone())
+                    .collect();
+                if rows.is_empty() {
+                    rows.push(vec![0.0; dim]);
+                }
+
+                let mut db = SimplePirDatabase::new(DMatrix::zeros(1, 1));
+                db.set_mod_power(mod_power);
+                db.update_db(self.quantization.quantize_rows(&rows))?;
+                Ok(db)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let manifest = config.partitions.map(|partitions| {
+            PartitionManifest::build(&partitions, auth::namespace(), stock_json, unix_now())
+        });
+        let partitions = match &manifest {
+            Some(manifest) => partition_databases(manifest, &embeddings, mod_power)?,
+            None => Vec::new(),
+        };
+        job.progress(95, 100)?;
+
+        self.db.set_mod_power(mod_power);
         self.db.update_db(embeddings)?;
+        #[cfg(feature = "baseline")]
+        {
+            self.baseline = Some(BaselineIndex::build(
+                &raw_embeddings,
+                kmeans_config.metric,
+                kmeans_config.seed,
+            ));
+        }
+        self.quality = Some(ClusterQuality::measure(
+            &raw_embeddings,
+            &clustering,
+            kmeans_config.seed,
+        ));
+        self.clustering = Some(clustering);
+        self.clusters = clusters;
+        self.manifest = manifest;
+        self.partitions = partitions;
+        self.query_dim = Some(dim);
+        self.model = self.embedder.model().clone();
+        self.text = TextMapping {
+            templates,
+            document_prefix: prefixes.document,
+        };
+        self.query_prefix = prefixes.query;
+        self.ids = derive_ids(stock_json);
+        self.keys = ids;
+        self.dead.clear();
+        self.canonical = documents.canonical.into_iter().map(Some).collect();
         Ok(())
     }
 
@@ -119,6 +1031,10 @@ impl Database for EmbeddingDatabase {
         self.db.respond(query)
     }
 
+    fn database(&self) -> &SimplePirDatabase {
+        &self.db
+    }
+
     fn params(&self) -> &SimplePIRParams {
         self.db.params()
     }
@@ -127,48 +1043,379 @@ impl Database for EmbeddingDatabase {
         self.db.hint()
     }
 
+    fn digest(&self) -> &DatabaseDigest {
+        self.db.digest()
+    }
+
     fn a(&self) -> &DMatrix<BigInt> {
         self.db.a()
     }
+
+    fn epoch(&self) -> u64 {
+        self.db.epoch()
+    }
+
+    fn cluster_dims(&self) -> Vec<(usize, usize)> {
+        self.clusters.iter().map(SimplePirDatabase::dims).collect()
+    }
+
+    fn clustering(&self) -> Option<&Clustering> {
+        self.clustering.as_ref()
+    }
+
+    fn cluster(&self, id: usize) -> Option<&SimplePirDatabase> {
+        self.clusters.get(id)
+    }
+
+    fn partitions(&self) -> Option<&PartitionManifest> {
+        self.manifest.as_ref()
+    }
+
+    fn partition(&self, id: usize) -> Option<&SimplePirDatabase> {
+        self.partitions.get(id)
+    }
+
+    fn cluster_quality(&self) -> Option<ClusterQuality> {
+        self.quality
+    }
+
+    fn membership(&self) -> Option<&Membership> {
+        None
+    }
+
+    fn quantization(&self) -> Option<Quantization> {
+        Some(self.quantization)
+    }
+
+    fn calibration(&self) -> Option<Calibration> {
+        self.calibration
+    }
+
+    fn query_correction(&self) -> Option<QueryCorrection> {
+        self.correction.clone()
+    }
+
+    fn query_prefix(&self) -> Option<String> {
+        Some(self.query_prefix.clone())
+    }
+
+    fn embedding_model(&self) -> Option<EmbeddingModel> {
+        Some(self.model.clone())
+    }
+
+    fn query_dim(&self) -> Option<usize> {
+        self.query_dim
+    }
+
+    fn stats(&self) -> Vec<DatabaseStats> {
+        std::iter::once(self.db.stats("embedding"))
+            .chain(
+                self.clusters
+                    .iter()
+                    .enumerate()
+                    .map(|(id, cluster)| cluster.stats(&format!("cluster/{}", id))),
+            )
+            .chain(
+                self.partitions
+                    .iter()
+                    .enumerate()
+                    .map(|(id, partition)| partition.stats(&format!("partition/{}", id))),
+            )
+            .collect()
+    }
+
+    fn document_ids(&self) -> &[DocumentId] {
+        &self.ids
+    }
+
+    fn source_keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    fn delete(&mut self, id: &DocumentId) -> Result<bool> {
+        mark_deleted(&self.ids, &mut self.dead, id)
+    }
+
+    fn dead_rows(&self) -> &BTreeSet<usize> {
+        &self.dead
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        if self.dead.is_empty() {
+            return Ok(());
+        }
+        let keep: Vec<usize> = (0..self.ids.len())
+            .filter(|row| !self.dead.contains(row))
+            .collect();
+        let data = keep_rows(self.db.data(), &keep);
+
+        // Clusters keep their centroids; only their member rows move
+        let mut clustering = self.clustering.clone();
+        let mut clusters = Vec::new();
+        if let Some(clustering) = clustering.as_mut() {
+            clustering.assignments = keep
+                .iter()
+                .map(|&row| clustering.assignments[row])
+                .collect();
+            clusters = (0..clustering.centroids.len())
+                .map(|cluster| {
+                    let mut db = SimplePirDatabase::new(DMatrix::zeros(1, 1));
+                    db.set_mod_power(self.db.mod_power());
+                    db.update_db(keep_rows(&data, &clustering.members_of(cluster)))?;
+                    Ok(db)
+                })
+                .collect::<Result<Vec<_>>>()?;
+        }
+
+        let mut moved = vec![None; self.ids.len()];
+        for (new, &old) in keep.iter().enumerate() {
+            moved[old] = Some(new);
+        }
+        let mut manifest = self.manifest.clone();
+        let mut partitions = Vec::new();
+        if let Some(manifest) = manifest.as_mut() {
+            manifest.compact(&moved);
+            partitions = partition_databases(manifest, &data, self.db.mod_power())?;
+        }
+        self.db.update_db(data)?;
+        self.canonical = self
+            .canonical
+            .iter()
+            .map(|row| row.and_then(|row| moved[row]))
+            .collect();
+        self.ids = keep.iter().map(|&row| self.ids[row]).collect();
+        self.keys = keep.iter().map(|&row| self.keys[row].clone()).collect();
+        self.dead.clear();
+        self.clustering = clustering;
+        self.clusters = clusters;
+        self.manifest = manifest;
+        self.partitions = partitions;
+        // The index still refers to the old rows
+        #[cfg(feature = "baseline")]
+        {
+            self.baseline = None;
+        }
+        Ok(())
+    }
+
+    fn set_hint_base(&mut self, base: HintBase) {
+        self.db.set_hint_base(base);
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot::new(
+            self.ids.clone(),
+            self.keys.clone(),
+            self.dead.clone(),
+            Contents::Embedding(EmbeddingImage {
+                db: self.db.image(),
+                clustering: self.clustering.clone(),
+                clusters: self.clusters.iter().map(SimplePirDatabase::image).collect(),
+                quality: self.quality,
+                quantization: self.quantization,
+                calibration: self.calibration,
+                correction: self.correction.clone(),
+                model: self.model.clone(),
+                query_prefix: self.query_prefix.clone(),
+                text: self.text.clone(),
+                query_dim: self.query_dim,
+                canonical: self.canonical.clone(),
+                manifest: self.manifest.clone(),
+                partitions: self
+                    .partitions
+                    .iter()
+                    .map(SimplePirDatabase::image)
+                    .collect(),
+            }),
+        ))
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> Result<()> {
+        let Contents::Embedding(image) = snapshot.contents else {
+            return Err(wrong_kind("embedding"));
+        };
+        // Everything is checked before any of it is swapped in. Documents are rows, each
+        // with a cluster assignment if the database is clustered.
+        let db = SimplePirDatabase::from_image(&image.db)?;
+        let assigned = image
+            .clustering
+            .as_ref()
+            .map_or(usize::MAX, |clustering| clustering.assignments.len());
+        check_rows(
+            &snapshot.ids,
+            &snapshot.keys,
+            &snapshot.dead,
+            db.dims().0.min(assigned),
+        )?;
+        let clusters = from_images(&image.clusters)?;
+        let partitions = from_images(&image.partitions)?;
+        self.db = db;
+        self.clustering = image.clustering;
+        self.clusters = clusters;
+        self.quality = image.quality;
+        // Rows were quantized with the snapshot's settings, whatever this server's are
+        self.quantization = image.quantization;
+        self.calibration = image.calibration;
+        self.correction = image.correction;
+        self.model = image.model;
+        self.query_prefix = image.query_prefix;
+        self.text = image.text;
+        self.query_dim = image.query_dim;
+        self.canonical = image.canonical;
+        self.manifest = image.manifest;
+        self.partitions = partitions;
+        self.ids = snapshot.ids;
+        self.keys = snapshot.keys;
+        self.dead = snapshot.dead;
+        // Built from embeddings, which snapshots don't keep
+        #[cfg(feature = "baseline")]
+        {
+            self.baseline = None;
+        }
+        Ok(())
+    }
+}
+
+impl EmbeddingDatabase {
+    fn with_embedder(embedder: Result<BertEmbedder>) -> Result<Self> {
+        let embedder = embedder.map_err(|e| PirError::Embedding(e.to_string()))?;
+        Ok(Self {
+            db: SimplePirDatabase::new(DMatrix::zeros(1, 1)),
+            model: embedder.model().clone(),
+            embedder: Arc::new(embedder),
+            clustering: None,
+            clusters: Vec::new(),
+            quality: None,
+            manifest: None,
+            partitions: Vec::new(),
+            quantization: Quantization::from_env()?,
+            calibration: None,
+            correction: None,
+            query_prefix: String::new(),
+            text: TextMapping::default(),
+            query_dim: None,
+            canonical: Vec::new(),
+            ids: Vec::new(),
+            keys: Vec::new(),
+            dead: BTreeSet::new(),
+            #[cfg(feature = "baseline")]
+            baseline: None,
+        })
+    }
+
+    // Rebuilds the database from a stream of documents without holding the whole
+    // corpus in memory. Streamed corpora are served unclustered and unpartitioned.
+    pub async fn ingest(&mut self, documents: Receiver<Value>) -> Result<()> {
+        let config = ServerConfig::from_env()?;
+        let (db, dim) = Ingestor::new(Arc::clone(&self.embedder), spill_path())?
+            .batch_size(MINI_BATCH_SIZE)
+            .quantization(self.quantization)
+            .templates(config.templates.clone())
+            .prefixes(config.prefixes.clone())
+            .ingest(documents)
+            .await?;
+        self.db = db;
+        self.query_dim = Some(dim);
+        self.model = self.embedder.model().clone();
+        self.query_prefix = config.prefixes.query;
+        self.text = TextMapping {
+            templates: config.templates,
+            document_prefix: config.prefixes.document,
+        };
+
+        self.clustering = None;
+        self.clusters.clear();
+        self.quality = None;
+        self.manifest = None;
+        self.partitions.clear();
+        // Streamed embeddings aren't kept around to fit on
+        self.calibration = None;
+        self.correction = None;
+        self.canonical.clear();
+        self.ids.clear();
+        self.keys.clear();
+        self.dead.clear();
+        #[cfg(feature = "baseline")]
+        {
+            self.baseline = None;
+        }
+        Ok(())
+    }
+
+    pub fn text_mapping(&self) -> &TextMapping {
+        &self.text
+    }
+
+    // Row serving the `index`-th ingested document, after duplicates were collapsed
+    pub fn canonical_row(&self, index: usize) -> Option<usize> {
+        self.canonical.get(index).copied().flatten()
+    }
+
+    #[cfg(feature = "baseline")]
+    pub fn baseline(&self) -> Option<&BaselineIndex> {
+        self.baseline.as_ref()
+    }
 }
 
 pub struct EncodingDatabase {
     db: SimplePirDatabase,
+    // Bloom filter over document keys for private existence checks
+    membership: Option<Membership>,
+    // Fast-changing fields, split out of the records in `db` when tiering is enabled
+    hot: Option<HotTier>,
+    blocks: Option<RecordBlocks>,
+    // Source key of each row, to rebuild the filter when rows are compacted away
+    keys: Vec<String>,
+    ids: Vec<DocumentId>,
+    dead: BTreeSet<usize>,
 }
 
 impl Database for EncodingDatabase {
     fn new() -> Result<Self> {
         Ok(Self {
             db: SimplePirDatabase::new(DMatrix::zeros(1, 1)),
+            membership: None,
+            hot: None,
+            blocks: None,
+            keys: Vec::new(),
+            ids: Vec::new(),
+            dead: BTreeSet::new(),
         })
     }
 
     fn update(&mut self) -> Result<()> {
-        let stock_json = Command::new("python")
-            .arg("src/python/stocks.py")
-            .output()
-            .map_err(|e| PirError::CommandFailed(e.to_string()))?;
+        let stock_json = load_documents()?.documents;
 
-        if !stock_json.status.success() {
-            return Err(PirError::CommandFailed("Failed to update database".to_string()).into());
-        }
-
-        let stock_json = String::from_utf8(stock_json.stdout)?;
-        let stock_json: Vec<Value> = serde_json::from_str(&stock_json)?;
-
-        let encodings = encode_data(
-            &stock_json
-                .iter()
-                .map(|v| v.to_string())
-                .collect::<Vec<String>>(),
-        )
-        .map_err(|e| PirError::Encoding(e.to_string()))?;
-
-        if encodings.nrows() != encodings.ncols() {
-            return Err(PirError::Database("Encoding matrix must be square".to_string()).into());
-        }
+        let fields = hot_fields();
+        let records = stock_json
+            .iter()
+            .map(|v| split(v, &fields).0.to_string())
+            .collect::<Vec<String>>();
+        let encodings = encode_records(records)?;
+        let hot = (!fields.is_empty())
+            .then(|| HotTier::build(&stock_json, &fields))
+            .transpose()?;
+        // Blocks hold whole records, hot fields as of this rebuild, so reading one takes
+        // no hot tier rounds
+        let blocks = match block_size()? {
+            Some(size) if fields.is_empty() => {
+                Some(RecordBlocks::build(&encodings, stock_json.len(), size)?)
+            }
+            Some(size) => {
+                let whole = encode_records(stock_json.iter().map(Value::to_string).collect())?;
+                Some(RecordBlocks::build(&whole, stock_json.len(), size)?)
+            }
+            None => None,
+        };
 
         self.db.update_db(encodings)?;
+        self.hot = hot;
+        self.blocks = blocks;
+        let keys: Vec<String> = stock_json.iter().map(document_id).collect();
+        self.membership = Some(Membership::build(&keys)?);
+        self.keys = keys;
+        self.ids = derive_ids(&stock_json);
+        self.dead.clear();
         Ok(())
     }
 
@@ -176,6 +1423,10 @@ impl Database for EncodingDatabase {
         self.db.respond(query)
     }
 
+    fn database(&self) -> &SimplePirDatabase {
+        &self.db
+    }
+
     fn params(&self) -> &SimplePIRParams {
         self.db.params()
     }
@@ -184,7 +1435,228 @@ impl Database for EncodingDatabase {
         self.db.hint()
     }
 
+    fn digest(&self) -> &DatabaseDigest {
+        self.db.digest()
+    }
+
     fn a(&self) -> &DMatrix<BigInt> {
         self.db.a()
     }
+
+    fn epoch(&self) -> u64 {
+        self.db.epoch()
+    }
+
+    fn cluster_dims(&self) -> Vec<(usize, usize)> {
+        Vec::new()
+    }
+
+    fn clustering(&self) -> Option<&Clustering> {
+        None
+    }
+
+    fn cluster(&self, _id: usize) -> Option<&SimplePirDatabase> {
+        None
+    }
+
+    fn cluster_quality(&self) -> Option<ClusterQuality> {
+        None
+    }
+
+    fn membership(&self) -> Option<&Membership> {
+        self.membership.as_ref()
+    }
+
+    fn stats(&self) -> Vec<DatabaseStats> {
+        std::iter::once(self.db.stats("encoding"))
+            .chain(
+                self.membership
+                    .iter()
+                    .map(|membership| membership.db.stats("membership")),
+            )
+            .chain(self.hot.iter().map(|hot| hot.db.stats("hot")))
+            .chain(self.blocks.iter().map(|blocks| blocks.db.stats("blocks")))
+            .collect()
+    }
+
+    fn document_ids(&self) -> &[DocumentId] {
+        &self.ids
+    }
+
+    fn source_keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    // The membership filter keeps reporting deleted keys until compaction rebuilds it
+    fn delete(&mut self, id: &DocumentId) -> Result<bool> {
+        mark_deleted(&self.ids, &mut self.dead, id)
+    }
+
+    fn dead_rows(&self) -> &BTreeSet<usize> {
+        &self.dead
+    }
+
+    fn hot(&self) -> Option<&HotTier> {
+        self.hot.as_ref()
+    }
+
+    fn set_hot(&mut self, tier: HotTier) {
+        self.hot = Some(tier);
+    }
+
+    fn blocks(&self) -> Option<&RecordBlocks> {
+        self.blocks.as_ref()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        if self.dead.is_empty() {
+            return Ok(());
+        }
+        let keep: Vec<usize> = (0..self.ids.len())
+            .filter(|row| !self.dead.contains(row))
+            .collect();
+        // Records are stored one per column
+        let data = keep_records(self.db.data(), &keep);
+        let keys: Vec<String> = keep.iter().map(|&row| self.keys[row].clone()).collect();
+
+        let membership = Membership::build(&keys)?;
+        if let Some(hot) = self.hot.as_mut() {
+            hot.db.update_db(keep_records(hot.db.data(), &keep))?;
+            // Slots are assigned in row order; the next hot refresh repacks them
+            hot.packed = None;
+        }
+        let blocks = self
+            .blocks
+            .as_ref()
+            .map(|blocks| blocks.compact(&keep))
+            .transpose()?;
+        self.db.update_db(data)?;
+        self.blocks = blocks;
+        self.membership = Some(membership);
+        self.keys = keys;
+        self.ids = keep.iter().map(|&row| self.ids[row]).collect();
+        self.dead.clear();
+        Ok(())
+    }
+
+    fn set_hint_base(&mut self, base: HintBase) {
+        self.db.set_hint_base(base);
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot::new(
+            self.ids.clone(),
+            self.keys.clone(),
+            self.dead.clone(),
+            Contents::Encoding(EncodingImage {
+                db: self.db.image(),
+                membership: self
+                    .membership
+                    .as_ref()
+                    .map(|membership| (membership.params, membership.db.image())),
+                hot: self.hot.as_ref().map(|hot| HotImage {
+                    fields: hot.fields.clone(),
+                    db: hot.db.image(),
+                    packed: hot
+                        .packed
+                        .as_ref()
+                        .map(|packed| (packed.layout.clone(), packed.db.image())),
+                }),
+                blocks: self
+                    .blocks
+                    .as_ref()
+                    .map(|blocks| (blocks.layout.clone(), blocks.db.image())),
+            }),
+        ))
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> Result<()> {
+        let Contents::Encoding(image) = snapshot.contents else {
+            return Err(wrong_kind("encoding"));
+        };
+        // Everything is checked before any of it is swapped in. Records are columns, of
+        // the hot tier too.
+        let db = SimplePirDatabase::from_image(&image.db)?;
+        let membership = image
+            .membership
+            .map(|(params, db)| {
+                Ok::<_, anyhow::Error>(Membership {
+                    params,
+                    db: SimplePirDatabase::from_image(&db)?,
+                })
+            })
+            .transpose()?;
+        let hot = image
+            .hot
+            .map(|hot| {
+                let packed = hot
+                    .packed
+                    .map(|(layout, db)| {
+                        Ok::<_, anyhow::Error>(PackedValues {
+                            layout,
+                            db: SimplePirDatabase::from_image(&db)?,
+                        })
+                    })
+                    .transpose()?;
+                Ok::<_, anyhow::Error>(HotTier {
+                    fields: hot.fields,
+                    db: SimplePirDatabase::from_image(&hot.db)?,
+                    packed,
+                })
+            })
+            .transpose()?;
+        let blocks = image
+            .blocks
+            .map(|(layout, db)| {
+                Ok::<_, anyhow::Error>(RecordBlocks {
+                    layout,
+                    db: SimplePirDatabase::from_image(&db)?,
+                })
+            })
+            .transpose()?;
+        let hot_records = hot.as_ref().map_or(usize::MAX, |hot| hot.db.dims().1);
+        check_rows(
+            &snapshot.ids,
+            &snapshot.keys,
+            &snapshot.dead,
+            db.dims().1.min(hot_records),
+        )?;
+        self.db = db;
+        self.membership = membership;
+        self.hot = hot;
+        self.blocks = blocks;
+        self.ids = snapshot.ids;
+        self.keys = snapshot.keys;
+        self.dead = snapshot.dead;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_matrix_matches_bigint_product() {
+        assert_eq!(reduce_every(1 << 64), usize::MAX);
+        assert_eq!(reduce_every((1 << 64) - 1), 1);
+        assert!(reduce_every(1 << 32) > 1 << 60);
+
+        let data = DMatrix::from_fn(BLOCK_ROWS + 3, 5, |row, col| {
+            BigInt::from(row as i64 * 7919 - col as i64 * 104_729) << 40
+        });
+        let query = [u64::MAX, 0, 3, 1 << 63, 12_345];
+        for q in [1u128 << 64, (1 << 64) - 59, 1 << 32, 65_521] {
+            let big_q = BigInt::from(q);
+            let answer = WordMatrix::new(&data, q).multiply(query.into_iter());
+            for (row, got) in answer.iter().enumerate() {
+                let want = query
+                    .iter()
+                    .enumerate()
+                    .map(|(col, &word)| &data[(row, col)] * BigInt::from(word))
+                    .sum::<BigInt>();
+                assert_eq!(BigInt::from(to_word(&want, &big_q).unwrap()), *got);
+            }
+        }
+    }
 }
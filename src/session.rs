@@ -0,0 +1,261 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    net::TcpStream,
+    sync::{oneshot, watch},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    audit::Sealed,
+    error::PirError,
+    network::{HttpTransport, MatrixResponse, ParamsData, QueryRequest, QueryResponse, Transport},
+};
+
+// Which of a server's PIR databases a session query is for
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "database", content = "id", rename_all = "snake_case")]
+pub enum SessionTarget {
+    Main,
+    Cluster(usize),
+    Partition(usize),
+    Membership,
+    Hot,
+    Packed,
+    Blocks,
+}
+
+impl SessionTarget {
+    // Epoch and target of a `Transport` database prefix; pinned databases are under
+    // `/epochs/{epoch}`
+    fn pinned(database: &str) -> Result<(Option<u64>, Self)> {
+        let Some(rest) = database.strip_prefix("/epochs/") else {
+            return Ok((None, Self::from_prefix(database)?));
+        };
+        let (epoch, database) = rest.split_once('/').unwrap_or((rest, ""));
+        let epoch = epoch
+            .parse()
+            .map_err(|_| PirError::InvalidInput(format!("Invalid epoch '{}'", epoch)))?;
+        Ok((Some(epoch), Self::from_prefix(database)?))
+    }
+
+    // Target served under a `Transport` database prefix
+    fn from_prefix(database: &str) -> Result<Self> {
+        let segments: Vec<&str> = database.split('/').filter(|s| !s.is_empty()).collect();
+        match segments.as_slice() {
+            [] => Ok(Self::Main),
+            ["clusters", id] => {
+                Ok(Self::Cluster(id.parse().map_err(|_| {
+                    PirError::InvalidInput(format!("Invalid cluster id '{}'", id))
+                })?))
+            }
+            ["partitions", id] => {
+                Ok(Self::Partition(id.parse().map_err(|_| {
+                    PirError::InvalidInput(format!("Invalid partition id '{}'", id))
+                })?))
+            }
+            ["membership"] => Ok(Self::Membership),
+            ["hot"] => Ok(Self::Hot),
+            ["hot", "packed"] => Ok(Self::Packed),
+            ["blocks"] => Ok(Self::Blocks),
+            _ => {
+                Err(PirError::InvalidInput(format!("No session target for '{}'", database)).into())
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionRequest {
+    pub id: u64,
+    pub target: SessionTarget,
+    // Epoch of the main database to answer from; unset answers from the current one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<u64>,
+    pub query: Sealed<Vec<String>>,
+}
+
+// Frames sent by the server over `/ws`
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionFrame {
+    // Sent on connect and pushed again whenever the served database changes
+    Params { epoch: u64, params: Box<ParamsData> },
+    Answer { id: u64, response: Vec<String> },
+    Error { id: Option<u64>, message: String },
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type Pending = Mutex<HashMap<u64, oneshot::Sender<Result<Vec<String>, String>>>>;
+
+// One WebSocket connection to a server, shared by every database reached through it.
+// Answers are matched to queries by id, so queries may be in flight concurrently.
+struct Session {
+    sink: tokio::sync::Mutex<SplitSink<Socket, Message>>,
+    pending: Pending,
+    params: watch::Receiver<Option<ParamsData>>,
+    next_id: AtomicU64,
+}
+
+impl Session {
+    async fn connect(url: &str) -> Result<Arc<Self>> {
+        let (socket, _) = connect_async(url)
+            .await
+            .map_err(|e| PirError::Database(format!("WebSocket connect failed: {}", e)))?;
+        let (sink, mut stream) = socket.split();
+        let (params_sender, mut params) = watch::channel(None);
+
+        let session = Arc::new(Self {
+            sink: tokio::sync::Mutex::new(sink),
+            pending: Mutex::new(HashMap::new()),
+            params: params.clone(),
+            next_id: AtomicU64::new(1),
+        });
+
+        let reader = Arc::downgrade(&session);
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = stream.next().await {
+                let Some(session) = reader.upgrade() else {
+                    break;
+                };
+                let Ok(text) = message.to_text() else {
+                    continue;
+                };
+                match serde_json::from_str(text) {
+                    Ok(SessionFrame::Params { params, .. }) => {
+                        params_sender.send_replace(Some(*params));
+                    }
+                    Ok(SessionFrame::Answer { id, response }) => session.complete(id, Ok(response)),
+                    Ok(SessionFrame::Error {
+                        id: Some(id),
+                        message,
+                    }) => session.complete(id, Err(message)),
+                    Ok(SessionFrame::Error { id: None, message }) => {
+                        eprintln!("Session error: {}", message)
+                    }
+                    Err(e) => eprintln!("Malformed session frame: {:?}", e),
+                }
+            }
+            // Dropping the senders fails every query still waiting on this connection
+            if let Some(session) = reader.upgrade() {
+                session.pending.lock().unwrap().clear();
+            }
+        });
+
+        params
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| PirError::Database("Session closed before params".to_string()))?;
+        Ok(session)
+    }
+
+    fn complete(&self, id: u64, result: Result<Vec<String>, String>) {
+        if let Some(waiter) = self.pending.lock().unwrap().remove(&id) {
+            let _ = waiter.send(result);
+        }
+    }
+
+    fn current(&self) -> Result<ParamsData> {
+        self.params
+            .borrow()
+            .clone()
+            .ok_or_else(|| PirError::Database("Session has no params".to_string()).into())
+    }
+
+    async fn query(
+        &self,
+        epoch: Option<u64>,
+        target: SessionTarget,
+        query: Vec<String>,
+    ) -> Result<Vec<String>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+
+        let request = serde_json::to_string(&SessionRequest {
+            id,
+            target,
+            epoch,
+            query: Sealed::new(query),
+        })?;
+        if let Err(e) = self.sink.lock().await.send(Message::Text(request)).await {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(PirError::Database(format!("Session send failed: {}", e)).into());
+        }
+
+        Ok(receiver
+            .await
+            .map_err(|_| PirError::Database("Session closed".to_string()))?
+            .map_err(PirError::Database)?)
+    }
+}
+
+// Transport whose queries go over a persistent WebSocket session. Params and epoch of
+// the main database arrive over the session too; hints, A and the public layout are
+// still fetched over HTTP, where they can be cached.
+pub struct SessionTransport {
+    session: Arc<Session>,
+    http: HttpTransport,
+}
+
+impl SessionTransport {
+    pub async fn connect(base_url: String) -> Result<Self> {
+        let ws_url = format!("{}/ws", base_url.replacen("http", "ws", 1));
+        Ok(Self {
+            session: Session::connect(&ws_url).await?,
+            http: HttpTransport::new(base_url),
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for SessionTransport {
+    async fn send_query(&self, database: &str, request: &QueryRequest) -> Result<QueryResponse> {
+        // Session frames carry no secret or plaintext flag, so assisted and plaintext
+        // queries go over HTTP
+        if request.secret.is_some() || request.plaintext {
+            return self.http.send_query(database, request).await;
+        }
+        let (epoch, target) = SessionTarget::pinned(database)?;
+        Ok(QueryResponse {
+            response: self
+                .session
+                .query(epoch, target, request.query.expose().clone())
+                .await?,
+            recovered: None,
+            best: None,
+        })
+    }
+
+    async fn get_params(&self, database: &str) -> Result<ParamsData> {
+        match SessionTarget::pinned(database)? {
+            (None, SessionTarget::Main) => self.session.current(),
+            _ => self.http.get_params(database).await,
+        }
+    }
+
+    async fn get_hint(&self, database: &str) -> Result<MatrixResponse> {
+        self.http.get_hint(database).await
+    }
+
+    async fn get_a(&self, database: &str) -> Result<MatrixResponse> {
+        self.http.get_a(database).await
+    }
+
+    async fn get_json(&self, path: &str) -> Result<serde_json::Value> {
+        self.http.get_json(path).await
+    }
+
+    fn origin(&self) -> Option<String> {
+        self.http.origin()
+    }
+}
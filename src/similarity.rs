@@ -0,0 +1,95 @@
+use anyhow::Result;
+use candle::Tensor;
+use nalgebra::DVector;
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
+
+// Similarity of plaintext and quantized embeddings. Vectors of different lengths are
+// compared over their common prefix, and cosines involving a zero vector are 0.
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+pub fn norm(v: &[f32]) -> f32 {
+    dot(v, v).sqrt()
+}
+
+// Scales `v` to unit length, leaving zero vectors as they are
+pub fn normalize(v: &mut [f32]) {
+    let norm = norm(v);
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+// Accumulates in f64, so it stays accurate for long embeddings
+pub fn cosine(a: &[f32], b: &[f32]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(&x, &y)| x as f64 * y as f64).sum();
+    let norm = |v: &[f32]| v.iter().map(|&x| (x as f64).powi(2)).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+// Inner product of quantized embeddings, the score PIR recovers for a document
+pub fn score(a: &DVector<BigInt>, b: &DVector<BigInt>) -> BigInt {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+// Cosine of quantized embeddings, which does not depend on the quantization's scale
+pub fn cosine_quantized(a: &DVector<BigInt>, b: &DVector<BigInt>) -> f64 {
+    let to_f64 = |x: &BigInt| x.to_f64().unwrap_or(0.0);
+    let norms = to_f64(&score(a, a)).sqrt() * to_f64(&score(b, b)).sqrt();
+    let dot = score(a, b);
+    if norms == 0.0 || dot.is_zero() {
+        0.0
+    } else {
+        to_f64(&dot) / norms
+    }
+}
+
+// Scales each row of a (rows, dim) tensor to unit length
+pub fn normalize_rows(t: &Tensor) -> Result<Tensor> {
+    Ok(t.broadcast_div(&t.sqr()?.sum_keepdim(1)?.sqrt()?)?)
+}
+
+// (rows of a, rows of b) matrix of cosines between the rows of two (rows, dim) tensors
+pub fn cosine_matrix(a: &Tensor, b: &Tensor) -> Result<Tensor> {
+    Ok(normalize_rows(a)?.matmul(&normalize_rows(b)?.t()?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::Device;
+
+    use crate::quantization::Quantization;
+
+    #[test]
+    fn test_similarity_agrees_across_representations() -> Result<()> {
+        let (a, b) = (vec![0.6, 0.8, 0.0], vec![1.0, 0.0, 0.0]);
+        assert!((cosine(&a, &b) - 0.6).abs() < 1e-6);
+        assert_eq!(cosine(&a, &[0.0; 3]), 0.0);
+        let mut scaled = vec![3.0, 4.0];
+        normalize(&mut scaled);
+        assert_eq!(scaled, vec![0.6, 0.8]);
+        assert!((norm(&a) - 1.0).abs() < 1e-6);
+
+        let quantization = Quantization::default();
+        let (qa, qb) = (quantization.quantize(&a), quantization.quantize(&b));
+        assert_eq!(score(&qa, &qb), qa.dot(&qb));
+        assert!((cosine_quantized(&qa, &qb) - 0.6).abs() < 1e-3);
+
+        let device = Device::Cpu;
+        let ta = Tensor::new(&[[3.0f32, 4.0, 0.0]], &device)?;
+        let tb = Tensor::new(&[[2.0f32, 0.0, 0.0], [0.0, 0.0, 1.0]], &device)?;
+        let matrix = cosine_matrix(&ta, &tb)?.to_vec2::<f32>()?;
+        assert!((matrix[0][0] - 0.6).abs() < 1e-6);
+        assert_eq!(matrix[0][1], 0.0);
+        Ok(())
+    }
+}
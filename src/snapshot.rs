@@ -0,0 +1,351 @@
+use anyhow::Result;
+use nalgebra::DMatrix;
+use num_bigint::BigInt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::{
+    auth,
+    bloom::BloomParams,
+    clustering::{ClusterQuality, Clustering},
+    correction::QueryCorrection,
+    documents::DocumentId,
+    embedding::EmbeddingModel,
+    error::PirError,
+    network::parse_matrix,
+    packing::{BlockLayout, PackedLayout},
+    partitions::PartitionManifest,
+    quantization::{Calibration, Quantization},
+    templates::TextMapping,
+};
+
+// Bumped whenever `Snapshot` changes shape; older bundles are refused rather than
+// misread
+pub const SNAPSHOT_VERSION: u32 = 5;
+pub const SNAPSHOT_CONTENT_TYPE: &str = "application/x-tiptoe-snapshot";
+
+// A matrix as decimal strings in column-major order, as on the wire
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MatrixImage {
+    pub rows: usize,
+    pub cols: usize,
+    pub data: Vec<String>,
+}
+
+impl MatrixImage {
+    pub fn of(matrix: &DMatrix<BigInt>) -> Self {
+        Self {
+            rows: matrix.nrows(),
+            cols: matrix.ncols(),
+            data: matrix.iter().map(BigInt::to_string).collect(),
+        }
+    }
+
+    pub fn to_matrix(&self) -> Result<DMatrix<BigInt>> {
+        parse_matrix(self.rows, self.cols, &self.data)
+    }
+}
+
+// One built PIR database. Its params follow from the data's shape and `mod_power`, so
+// only the matrices are kept.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DatabaseImage {
+    pub mod_power: u32,
+    pub epoch: u64,
+    pub data: MatrixImage,
+    pub hint: MatrixImage,
+    pub a: MatrixImage,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EmbeddingImage {
+    pub db: DatabaseImage,
+    pub clustering: Option<Clustering>,
+    pub clusters: Vec<DatabaseImage>,
+    pub quality: Option<ClusterQuality>,
+    pub quantization: Quantization,
+    pub calibration: Option<Calibration>,
+    pub correction: Option<QueryCorrection>,
+    pub model: EmbeddingModel,
+    pub query_prefix: String,
+    pub text: TextMapping,
+    pub query_dim: Option<usize>,
+    pub canonical: Vec<Option<usize>>,
+    pub manifest: Option<PartitionManifest>,
+    pub partitions: Vec<DatabaseImage>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HotImage {
+    pub fields: Vec<String>,
+    pub db: DatabaseImage,
+    pub packed: Option<(PackedLayout, DatabaseImage)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EncodingImage {
+    pub db: DatabaseImage,
+    pub membership: Option<(BloomParams, DatabaseImage)>,
+    pub hot: Option<HotImage>,
+    pub blocks: Option<(BlockLayout, DatabaseImage)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Contents {
+    Embedding(EmbeddingImage),
+    Encoding(EncodingImage),
+}
+
+// Everything a server needs to answer queries for one epoch, without the corpus or
+// the embedding model's output: every PIR database with its hint and A, and the
+// row -> document mapping. Served by `GET /admin/snapshot` as MessagePack and loaded
+// by `POST /admin/restore`.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    // Namespace of the server the snapshot was taken on
+    pub namespace: String,
+    pub ids: Vec<DocumentId>,
+    pub keys: Vec<String>,
+    pub dead: BTreeSet<usize>,
+    pub contents: Contents,
+}
+
+impl Snapshot {
+    pub fn new(
+        ids: Vec<DocumentId>,
+        keys: Vec<String>,
+        dead: BTreeSet<usize>,
+        contents: Contents,
+    ) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            namespace: auth::namespace(),
+            ids,
+            keys,
+            dead,
+            contents,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let snapshot: Self = rmp_serde::from_slice(bytes)
+            .map_err(|e| PirError::InvalidInput(format!("Invalid snapshot: {}", e)))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(PirError::InvalidInput(format!(
+                "Snapshot version {} is not supported, expected {}",
+                snapshot.version, SNAPSHOT_VERSION
+            ))
+            .into());
+        }
+        Ok(snapshot)
+    }
+}
+
+// Checks that a snapshot's row mapping fits a database of `capacity` documents, so
+// every row it names, live or dead, is one the database has
+pub fn check_rows(
+    ids: &[DocumentId],
+    keys: &[String],
+    dead: &BTreeSet<usize>,
+    capacity: usize,
+) -> Result<()> {
+    if keys.len() != ids.len() || ids.len() > capacity {
+        return Err(PirError::InvalidInput(format!(
+            "Snapshot maps {} ids and {} keys onto {} documents",
+            ids.len(),
+            keys.len(),
+            capacity
+        ))
+        .into());
+    }
+    if let Some(row) = dead.last().filter(|&&row| row >= ids.len()) {
+        return Err(PirError::InvalidInput(format!(
+            "Snapshot deletes row {} of {}",
+            row,
+            ids.len()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+// Error for restoring a snapshot into the wrong kind of server
+pub fn wrong_kind(expected: &str) -> anyhow::Error {
+    PirError::InvalidInput(format!("Not a snapshot of an {} server", expected)).into()
+}
+
+// A bundle being uploaded to `POST /admin/restore`, one chunk per request. An
+// interrupted upload resumes from `received`.
+#[derive(Default)]
+pub struct Upload {
+    total: Option<usize>,
+    bytes: Vec<u8>,
+}
+
+impl Upload {
+    // Adds `chunk`, which starts at `offset` of a bundle of `total` bytes. A chunk at
+    // offset 0 starts a new upload; any other must continue the current one exactly.
+    pub fn append(&mut self, offset: usize, total: usize, chunk: &[u8]) -> Result<()> {
+        if offset == 0 {
+            *self = Self::default();
+        }
+        if offset != self.bytes.len() || self.total.is_some_and(|t| t != total) {
+            return Err(PirError::InvalidInput(format!(
+                "Chunk at byte {} of {} does not continue the upload at byte {}",
+                offset,
+                total,
+                self.bytes.len()
+            ))
+            .into());
+        }
+        if offset + chunk.len() > total {
+            return Err(PirError::InvalidInput(format!(
+                "Chunk ends past the bundle's {} bytes",
+                total
+            ))
+            .into());
+        }
+        self.total = Some(total);
+        self.bytes.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    pub fn received(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn total(&self) -> Option<usize> {
+        self.total
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.total == Some(self.bytes.len())
+    }
+
+    // The uploaded bundle, leaving no upload in progress
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self).bytes
+    }
+}
+
+// The byte range [start, end) a `Range: bytes=start-end` header asks for of `len`
+// bytes. Suffix and multi-part ranges are not supported.
+pub fn parse_range(header: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+    let start: usize = start.parse().ok()?;
+    let end = match end {
+        "" => len,
+        end => end.parse::<usize>().ok()?.saturating_add(1).min(len),
+    };
+    (start < end).then_some((start, end))
+}
+
+// (start, total) of a `Content-Range: bytes start-end/total` header
+pub fn parse_content_range(header: &str) -> Option<(usize, usize)> {
+    let (range, total) = header.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.parse().ok()?, total.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_roundtrip_and_resumable_upload() -> Result<()> {
+        let matrix = DMatrix::from_fn(2, 3, |row, col| BigInt::from(row as i64 * 10 - col as i64));
+        let image = MatrixImage::of(&matrix);
+        assert_eq!(image.to_matrix()?, matrix);
+        assert!(MatrixImage {
+            rows: 3,
+            ..image.clone()
+        }
+        .to_matrix()
+        .is_err());
+        // A shape whose size overflows is refused, not wrapped around to the data's length
+        assert!(MatrixImage {
+            rows: usize::MAX / 3 + 1,
+            cols: 3,
+            data: vec!["0".to_string(); 2],
+        }
+        .to_matrix()
+        .is_err());
+
+        let db = DatabaseImage {
+            mod_power: 17,
+            epoch: 1_700_000_000,
+            data: image.clone(),
+            hint: image.clone(),
+            a: image,
+        };
+        let snapshot = Snapshot::new(
+            Vec::new(),
+            vec!["Tesla, Inc.".to_string()],
+            BTreeSet::from([0]),
+            Contents::Encoding(EncodingImage {
+                db: db.clone(),
+                membership: Some((
+                    BloomParams {
+                        num_bits: 64,
+                        num_hashes: 3,
+                    },
+                    db,
+                )),
+                hot: None,
+                blocks: None,
+            }),
+        );
+        let bytes = snapshot.to_bytes()?;
+        let restored = Snapshot::from_bytes(&bytes)?;
+        assert_eq!(restored.keys, snapshot.keys);
+        assert_eq!(restored.dead, snapshot.dead);
+        assert!(matches!(
+            restored.contents,
+            Contents::Encoding(EncodingImage {
+                membership: Some(_),
+                ..
+            })
+        ));
+        assert!(Snapshot::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+
+        // Ids, keys and deletions must all fit the restored database's documents
+        let ids = vec![
+            DocumentId::derive("tsla", "{}"),
+            DocumentId::derive("aapl", "{}"),
+        ];
+        let keys = vec!["Tesla, Inc.".to_string(), "Apple Inc.".to_string()];
+        check_rows(&ids, &keys, &BTreeSet::from([1]), 3)?;
+        assert!(check_rows(&ids, &keys, &BTreeSet::from([1]), 1).is_err());
+        assert!(check_rows(&ids, &keys[..1], &BTreeSet::new(), 3).is_err());
+        assert!(check_rows(&ids, &keys, &BTreeSet::from([2]), 3).is_err());
+
+        // An upload interrupted after its first chunk resumes where it stopped
+        let mut upload = Upload::default();
+        let (first, rest) = bytes.split_at(bytes.len() / 3);
+        upload.append(0, bytes.len(), first)?;
+        assert!(upload.append(1, bytes.len(), rest).is_err());
+        assert!(upload.append(first.len(), bytes.len() + 1, rest).is_err());
+        assert!(!upload.is_complete());
+        upload.append(upload.received(), bytes.len(), rest)?;
+        assert!(upload.is_complete());
+        assert_eq!(upload.take(), bytes);
+        assert_eq!(upload.received(), 0);
+
+        assert_eq!(parse_range("bytes=100-", 1000), Some((100, 1000)));
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 100)));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=-100", 1000), None);
+        assert_eq!(
+            parse_content_range("bytes 1024-2047/4096"),
+            Some((1024, 4096))
+        );
+        assert_eq!(parse_content_range("bytes */4096"), None);
+        Ok(())
+    }
+}
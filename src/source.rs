@@ -0,0 +1,335 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::{
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    config::SourceConfig, error::PirError, fred::FredConfig, replay::ReplaySource,
+    validation::Validation,
+};
+
+// Object URL (s3://, gs://, az://, ...) to read the corpus from instead of running the script
+const CORPUS_URL_ENV_VAR: &str = "TIPTOE_CORPUS_URL";
+const FILE_SCHEME: &str = "file://";
+const REPLAY_SCHEME: &str = "replay://";
+// Variables with this prefix are passed to the object store as options, e.g.
+// TIPTOE_STORE_AWS_REGION=us-east-1 becomes `aws_region`
+#[cfg(feature = "object-store")]
+const STORE_OPTION_PREFIX: &str = "TIPTOE_STORE_";
+// Last successful fetch of each configured source, so a failing source keeps serving
+// its previous documents instead of blocking the others
+pub const SNAPSHOT_DIR: &str = "sources";
+
+// Where a database gets its documents from
+pub enum CorpusSource {
+    // Runs the bundled quotes script and parses its stdout
+    Script(String),
+    // A JSON array of documents on local disk, e.g. a fixed corpus for tests
+    File(PathBuf),
+    // A JSON array of documents in S3, GCS, Azure or any other object_store backend
+    #[cfg(feature = "object-store")]
+    ObjectStore(ObjectStoreSource),
+    // One document per economic indicator from the FRED API
+    Fred(FredConfig),
+    // Recorded snapshots served on a simulated clock, for deterministic tests
+    Replay(ReplaySource),
+}
+
+impl CorpusSource {
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(CORPUS_URL_ENV_VAR) {
+            Ok(url) => Self::from_url(&url),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_url(url: &str) -> Result<Self> {
+        if let Some(path) = url.strip_prefix(FILE_SCHEME) {
+            return Ok(Self::File(PathBuf::from(path)));
+        }
+        if let Some(path) = url.strip_prefix(REPLAY_SCHEME) {
+            return Ok(Self::Replay(ReplaySource::new(path)));
+        }
+        #[cfg(feature = "object-store")]
+        return Ok(Self::ObjectStore(ObjectStoreSource::from_env(url)));
+        #[cfg(not(feature = "object-store"))]
+        Err(PirError::InvalidInput(format!(
+            "Reading the corpus from {} requires the object-store feature",
+            url
+        ))
+        .into())
+    }
+
+    pub fn load(&self) -> Result<Vec<Value>> {
+        match self {
+            Self::Script(path) => {
+                let output = Command::new("python")
+                    .arg(path)
+                    .output()
+                    .map_err(|e| PirError::CommandFailed(e.to_string()))?;
+
+                if !output.status.success() {
+                    return Err(
+                        PirError::CommandFailed("Failed to update database".to_string()).into(),
+                    );
+                }
+
+                let output = String::from_utf8(output.stdout)?;
+                Ok(serde_json::from_str(&output)?)
+            }
+            Self::File(path) => Ok(serde_json::from_str(&fs::read_to_string(path)?)?),
+            #[cfg(feature = "object-store")]
+            Self::ObjectStore(source) => Ok(serde_json::from_slice(&source.fetch()?)?),
+            Self::Fred(fred) => fred.fetch(),
+            Self::Replay(replay) => replay.load(),
+        }
+    }
+}
+
+impl Default for CorpusSource {
+    fn default() -> Self {
+        Self::Script("src/python/stocks.py".to_string())
+    }
+}
+
+// Database updates are synchronous and may already be running inside a runtime, so
+// downloads get a runtime of their own on a separate thread
+pub(crate) fn block_on_thread<T: Send>(
+    download: impl Future<Output = Result<T>> + Send,
+) -> Result<T> {
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| -> Result<T> {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?;
+                runtime.block_on(download)
+            })
+            .join()
+            .map_err(|_| PirError::Database("Download panicked".to_string()))?
+    })
+}
+
+fn snapshot_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.json", name))
+}
+
+// Loads from the first provider that answers with any valid documents, dropping the
+// invalid ones. Fails only if every provider does.
+pub fn load_validated(providers: &[CorpusSource], validation: &Validation) -> Result<Vec<Value>> {
+    let mut last_error = None;
+    for (i, provider) in providers.iter().enumerate() {
+        match provider
+            .load()
+            .and_then(|documents| validation.apply(documents))
+        {
+            Ok(documents) => {
+                if i > 0 {
+                    eprintln!("Served by fallback provider {}", i);
+                }
+                return Ok(documents);
+            }
+            Err(e) => {
+                eprintln!("Provider {} failed: {:?}", i, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| PirError::InvalidInput("No providers configured".to_string()).into()))
+}
+
+// Fetches `source` and saves its validated documents as its snapshot. Returns whether
+// they changed since the last snapshot.
+pub fn refresh_snapshot(
+    dir: &Path,
+    source: &SourceConfig,
+    validation: &Validation,
+) -> Result<bool> {
+    let documents = load_validated(&source.providers()?, validation)?;
+    let path = snapshot_path(dir, &source.name);
+    let previous: Option<Vec<Value>> = fs::read_to_string(&path)
+        .ok()
+        .and_then(|snapshot| serde_json::from_str(&snapshot).ok());
+    if previous.as_ref() == Some(&documents) {
+        return Ok(false);
+    }
+
+    // Written aside and renamed, so a rebuild never reads half a snapshot
+    fs::create_dir_all(dir)?;
+    let partial = path.with_extension("json.partial");
+    fs::write(&partial, serde_json::to_string(&documents)?)?;
+    fs::rename(&partial, &path)?;
+    Ok(true)
+}
+
+fn read_snapshot(dir: &Path, source: &SourceConfig, validation: &Validation) -> Result<Vec<Value>> {
+    let path = snapshot_path(dir, &source.name);
+    if !path.exists() {
+        refresh_snapshot(dir, source, validation)?;
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(&path)?)?)
+}
+
+// Documents of every source, read from their snapshots. A source without one yet is
+// fetched first; one that cannot be fetched or read contributes nothing rather than
+// failing the others.
+pub fn load_snapshots(dir: &Path, sources: &[SourceConfig], validation: &Validation) -> Vec<Value> {
+    let mut documents = Vec::new();
+    for source in sources {
+        match read_snapshot(dir, source, validation) {
+            Ok(snapshot) => documents.extend(snapshot),
+            Err(e) => eprintln!("Skipping source {}: {:?}", source.name, e),
+        }
+    }
+    documents
+}
+
+#[cfg(feature = "object-store")]
+pub use object_store_source::ObjectStoreSource;
+
+#[cfg(feature = "object-store")]
+mod object_store_source {
+    use anyhow::Result;
+    use object_store::parse_url_opts;
+    use std::collections::HashMap;
+    use url::Url;
+
+    use super::{block_on_thread, STORE_OPTION_PREFIX};
+    use crate::error::PirError;
+
+    // A single object in shared storage. Credentials come from the backend's usual
+    // environment variables (AWS_*, GOOGLE_*, AZURE_*) plus any explicit options.
+    pub struct ObjectStoreSource {
+        url: String,
+        options: HashMap<String, String>,
+    }
+
+    impl ObjectStoreSource {
+        pub fn new(url: &str, options: HashMap<String, String>) -> Self {
+            Self {
+                url: url.to_string(),
+                options,
+            }
+        }
+
+        pub fn from_env(url: &str) -> Self {
+            let options = std::env::vars()
+                .filter_map(|(key, value)| {
+                    key.strip_prefix(STORE_OPTION_PREFIX)
+                        .map(|option| (option.to_lowercase(), value))
+                })
+                .collect();
+            Self::new(url, options)
+        }
+
+        // Raw object bytes
+        pub fn fetch(&self) -> Result<Vec<u8>> {
+            let url = Url::parse(&self.url)
+                .map_err(|e| PirError::InvalidInput(format!("Invalid object URL: {}", e)))?;
+            let (store, path) = parse_url_opts(&url, &self.options)
+                .map_err(|e| PirError::Database(format!("Object store setup failed: {}", e)))?;
+
+            block_on_thread(async {
+                let bytes = store.get(&path).await?.bytes().await?;
+                Ok(bytes.to_vec())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProviderConfig;
+
+    fn feed_url(dir: &Path, name: &str) -> String {
+        format!("file://{}", dir.join(format!("{}.feed", name)).display())
+    }
+
+    #[test]
+    fn test_failing_source_keeps_its_snapshot() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("tiptoe-sources-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let feed = |name: &str| SourceConfig {
+            name: name.to_string(),
+            script: None,
+            url: Some(feed_url(&dir, name)),
+            fred: None,
+            interval_secs: 60,
+            fallbacks: Vec::new(),
+            rebuild: false,
+        };
+        let validation = Validation::default();
+        let (prices, news) = (feed("prices"), feed("news"));
+        fs::write(dir.join("prices.feed"), r#"[{"name": "Tesla"}]"#)?;
+        fs::write(dir.join("news.feed"), r#"[{"name": "Rates"}]"#)?;
+
+        let snapshots = dir.join("snapshots");
+        let sources = [prices.clone(), news.clone()];
+        assert_eq!(load_snapshots(&snapshots, &sources, &validation).len(), 2);
+        assert!(!refresh_snapshot(&snapshots, &prices, &validation)?);
+
+        // News goes down: its refresh fails but its last documents are still served
+        fs::remove_file(dir.join("news.feed"))?;
+        fs::write(
+            dir.join("prices.feed"),
+            r#"[{"name": "Tesla"}, {"name": "Apple"}]"#,
+        )?;
+        assert!(refresh_snapshot(&snapshots, &news, &validation).is_err());
+        assert!(refresh_snapshot(&snapshots, &prices, &validation)?);
+        assert_eq!(load_snapshots(&snapshots, &sources, &validation).len(), 3);
+
+        // A source that never loaded is skipped
+        assert_eq!(
+            load_snapshots(&snapshots, &[feed("weather")], &validation).len(),
+            0
+        );
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_falls_back_past_invalid_providers() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("tiptoe-providers-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join("poisoned.feed"),
+            r#"[{"name": "Tesla", "currentPrice": "NaN"}]"#,
+        )?;
+        fs::write(
+            dir.join("backup.feed"),
+            r#"[{"name": "Tesla", "currentPrice": 250.1}, {"name": "Apple", "currentPrice": 0}]"#,
+        )?;
+        let source = SourceConfig {
+            name: "prices".to_string(),
+            script: None,
+            url: Some(feed_url(&dir, "missing")),
+            fred: None,
+            interval_secs: 60,
+            fallbacks: ["poisoned", "backup"]
+                .iter()
+                .map(|name| ProviderConfig {
+                    script: None,
+                    url: Some(feed_url(&dir, name)),
+                    fred: None,
+                })
+                .collect(),
+            rebuild: false,
+        };
+
+        let validation = Validation::default();
+        let documents = load_validated(&source.providers()?, &validation)?;
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0]["currentPrice"], 250.1);
+        assert!(load_validated(&source.providers()?[..2], &validation).is_err());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::server::document_id;
+
+// Exchanges with a public ticker feed we can read without credentials
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Exchange {
+    // Combined 24h ticker streams, e.g. `BTCUSDT`
+    Binance,
+    // The `ticker` channel of the exchange feed, e.g. `BTC-USD`
+    Coinbase,
+}
+
+impl Exchange {
+    pub fn default_url(&self, symbols: &[&str]) -> String {
+        match self {
+            Self::Binance => format!(
+                "wss://stream.binance.com:9443/stream?streams={}",
+                symbols
+                    .iter()
+                    .map(|symbol| format!("{}@ticker", symbol.to_lowercase()))
+                    .collect::<Vec<_>>()
+                    .join("/")
+            ),
+            Self::Coinbase => "wss://ws-feed.exchange.coinbase.com".to_string(),
+        }
+    }
+
+    // Sent once connected; Binance subscribes through the URL instead
+    pub fn subscription(&self, symbols: &[&str]) -> Option<String> {
+        match self {
+            Self::Binance => None,
+            Self::Coinbase => Some(
+                serde_json::json!({
+                    "type": "subscribe",
+                    "product_ids": symbols,
+                    "channels": ["ticker"],
+                })
+                .to_string(),
+            ),
+        }
+    }
+
+    // Symbol and last price of a ticker message. Anything else (subscription
+    // acknowledgements, heartbeats, malformed or non-positive prices) is None.
+    pub fn parse_tick(&self, message: &str) -> Option<(String, f64)> {
+        let message: Value = serde_json::from_str(message).ok()?;
+        let (symbol, price) = match self {
+            Self::Binance => {
+                // Combined streams wrap each event as {"stream": ..., "data": ...}
+                let event = message.get("data").unwrap_or(&message);
+                (event.get("s")?, event.get("c")?)
+            }
+            Self::Coinbase => {
+                if message.get("type")?.as_str()? != "ticker" {
+                    return None;
+                }
+                (message.get("product_id")?, message.get("price")?)
+            }
+        };
+        let symbol = symbol.as_str()?.to_string();
+        let price: f64 = price.as_str()?.parse().ok()?;
+        (price.is_finite() && price > 0.0).then_some((symbol, price))
+    }
+}
+
+struct Tick {
+    field: String,
+    price: f64,
+    received: Instant,
+    max_age: Duration,
+}
+
+// Latest tick per document from every stream, applied over the fetched corpus on
+// each hot refresh
+#[derive(Default)]
+pub struct TickStore {
+    // By document name
+    ticks: Mutex<HashMap<String, Tick>>,
+}
+
+impl TickStore {
+    pub fn record(&self, name: &str, field: &str, price: f64, max_age: Duration) {
+        self.ticks.lock().unwrap().insert(
+            name.to_string(),
+            Tick {
+                field: field.to_string(),
+                price,
+                received: Instant::now(),
+                max_age,
+            },
+        );
+    }
+
+    // Sets each document's price to its latest tick. Ticks older than their stream's
+    // max age are ignored, so a dropped connection falls back to the fetched prices.
+    pub fn overlay(&self, documents: &mut [Value]) -> usize {
+        let ticks = self.ticks.lock().unwrap();
+        let mut applied = 0;
+        for document in documents.iter_mut() {
+            let Some(tick) = ticks.get(&document_id(document)) else {
+                continue;
+            };
+            if tick.received.elapsed() > tick.max_age {
+                continue;
+            }
+            if let Value::Object(object) = document {
+                object.insert(tick.field.clone(), tick.price.into());
+                applied += 1;
+            }
+        }
+        applied
+    }
+}
+
+#[cfg(feature = "exchange-stream")]
+pub use connection::run_stream;
+
+#[cfg(feature = "exchange-stream")]
+mod connection {
+    use anyhow::Result;
+    use futures_util::{SinkExt, StreamExt};
+    use std::{sync::Arc, time::Duration};
+    use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+    use super::TickStore;
+    use crate::{config::StreamConfig, error::PirError};
+
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    // Keeps a connection to the stream's exchange open, reconnecting with exponential
+    // backoff, and records every tick for a configured symbol. Runs until aborted.
+    pub async fn run_stream(config: StreamConfig, ticks: Arc<TickStore>) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = tokio::time::Instant::now();
+            match stream_once(&config, &ticks).await {
+                Ok(()) => eprintln!("Stream {} closed by the exchange", config.name),
+                Err(e) => eprintln!("Stream {} failed: {:?}", config.name, e),
+            }
+            // A connection that stayed up for a while resets the backoff
+            if started.elapsed() > MAX_BACKOFF {
+                backoff = MIN_BACKOFF;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    async fn stream_once(config: &StreamConfig, ticks: &TickStore) -> Result<()> {
+        let symbols: Vec<&str> = config.symbols.keys().map(String::as_str).collect();
+        let url = config
+            .url
+            .clone()
+            .unwrap_or_else(|| config.exchange.default_url(&symbols));
+        let (mut socket, _) = connect_async(url.as_str())
+            .await
+            .map_err(|e| PirError::Database(format!("Cannot connect to {}: {}", url, e)))?;
+        if let Some(subscription) = config.exchange.subscription(&symbols) {
+            socket
+                .send(Message::Text(subscription))
+                .await
+                .map_err(|e| PirError::Database(format!("Cannot subscribe: {}", e)))?;
+        }
+
+        while let Some(message) = socket.next().await {
+            let message =
+                message.map_err(|e| PirError::Database(format!("Stream error: {}", e)))?;
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let Some((symbol, price)) = config.exchange.parse_tick(&text) else {
+                continue;
+            };
+            if let Some(name) = config.symbols.get(&symbol) {
+                ticks.record(name, &config.field, price, config.max_tick_age());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_ticks_override_fresh_prices_only() {
+        let binance = r#"{"stream": "btcusdt@ticker",
+            "data": {"e": "24hrTicker", "s": "BTCUSDT", "c": "67012.50"}}"#;
+        assert_eq!(
+            Exchange::Binance.parse_tick(binance),
+            Some(("BTCUSDT".to_string(), 67012.5))
+        );
+        let coinbase = r#"{"type": "ticker", "product_id": "ETH-USD", "price": "3120.4"}"#;
+        assert_eq!(
+            Exchange::Coinbase.parse_tick(coinbase),
+            Some(("ETH-USD".to_string(), 3120.4))
+        );
+        assert_eq!(
+            Exchange::Coinbase.parse_tick(r#"{"type": "subscriptions"}"#),
+            None
+        );
+        let zero = r#"{"type": "ticker", "product_id": "ETH-USD", "price": "0"}"#;
+        assert_eq!(Exchange::Coinbase.parse_tick(zero), None);
+
+        let store = TickStore::default();
+        store.record(
+            "Bitcoin USD",
+            "currentPrice",
+            67012.5,
+            Duration::from_secs(60),
+        );
+        store.record("Ethereum USD", "currentPrice", 3120.4, Duration::ZERO);
+        let mut documents = vec![
+            json!({"name": "Bitcoin USD", "currentPrice": 66000.0}),
+            json!({"name": "Ethereum USD", "currentPrice": 3000.0}),
+            json!({"name": "Tesla, Inc.", "currentPrice": 250.1}),
+        ];
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(store.overlay(&mut documents), 1);
+        assert_eq!(documents[0]["currentPrice"], 67012.5);
+        assert_eq!(documents[1]["currentPrice"], 3000.0);
+    }
+}
@@ -0,0 +1,334 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::{
+    error::PirError,
+    market::{CHANGE_FIELD, DISPLAY_CHANGE_FIELD, DISPLAY_PRICE_FIELD, PRICE_FIELD},
+};
+
+// Filled in for placeholders naming a field the document does not have
+const MISSING: &str = "n/a";
+
+// Text embedded for each document, in place of its raw JSON. What gets embedded decides
+// which queries find it, so operators can phrase documents the way their users ask.
+//
+// Templates fill `{field}` with any top-level field of the document, plus `{price}`
+// and `{change}` (the localized display forms, falling back to the raw numbers) and
+// `{time}` (`time_field` as a UTC date and time). `{{` and `}}` are literal braces.
+// Documents no template covers embed `fields` when set, and their JSON otherwise.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct TextTemplates {
+    // Field whose value is the document's asset class, e.g. "Cryptocurrency"
+    pub class_field: String,
+    // Asset class to its template
+    pub classes: BTreeMap<String, String>,
+    // For documents of any other class
+    pub default: Option<String>,
+    // Fields embedded, in this order, for documents without a template, as
+    // "field: value" pairs. Dotted names reach into nested objects, and nested objects
+    // are flattened into dotted names, so key order and other fields don't matter.
+    pub fields: Vec<String>,
+    // Field holding the Unix time in seconds a document was quoted at
+    pub time_field: Option<String>,
+}
+
+impl Default for TextTemplates {
+    fn default() -> Self {
+        Self {
+            class_field: "sector".to_string(),
+            classes: BTreeMap::new(),
+            default: None,
+            fields: Vec::new(),
+            time_field: None,
+        }
+    }
+}
+
+// How a database's documents became the text it embedded, kept in its snapshots so
+// the embeddings can be reproduced
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TextMapping {
+    pub templates: TextTemplates,
+    pub document_prefix: String,
+}
+
+// Instructions that asymmetric retrieval models expect in front of each text, e.g.
+// "query: " and "passage: " for e5, or "Represent this sentence for searching relevant
+// passages: " before bge queries. Both default to none, which suits MiniLM.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct Prefixes {
+    pub query: String,
+    pub document: String,
+}
+
+impl Prefixes {
+    pub fn prefix_query(&self, text: &str) -> String {
+        format!("{}{}", self.query, text)
+    }
+
+    pub fn prefix_document(&self, text: &str) -> String {
+        format!("{}{}", self.document, text)
+    }
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+fn parse(template: &str) -> Result<Vec<Part<'_>>> {
+    let invalid = |reason: &str| {
+        PirError::InvalidInput(format!("Invalid text template {:?}: {}", template, reason))
+    };
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        parts.push(Part::Text(&rest[..start]));
+        let brace = &rest[start..];
+        if brace.starts_with("{{") || brace.starts_with("}}") {
+            parts.push(Part::Text(&brace[..1]));
+            rest = &brace[2..];
+            continue;
+        }
+        if brace.starts_with('}') {
+            return Err(invalid("unmatched }").into());
+        }
+        let end = brace.find('}').ok_or_else(|| invalid("unclosed {"))?;
+        let name = &brace[1..end];
+        if name.is_empty() || name.contains('{') {
+            return Err(invalid("bad placeholder").into());
+        }
+        parts.push(Part::Placeholder(name));
+        rest = &brace[end + 1..];
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+fn field_text(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        Value::Null => MISSING.to_string(),
+        other => other.to_string(),
+    }
+}
+
+// `name: value` pairs for `value` under `name`, with objects flattened into dotted
+// names and arrays joined
+fn flatten(name: &str, value: &Value, pairs: &mut Vec<String>) {
+    match value {
+        Value::Null => {}
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                flatten(&format!("{}.{}", name, key), value, pairs);
+            }
+        }
+        Value::Array(values) => {
+            let values: Vec<String> = values
+                .iter()
+                .filter(|value| !value.is_null())
+                .map(field_text)
+                .collect();
+            if !values.is_empty() {
+                pairs.push(format!("{}: {}", name, values.join(", ")));
+            }
+        }
+        value => pairs.push(format!("{}: {}", name, field_text(value))),
+    }
+}
+
+// "2024-05-01 13:45 UTC"
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let (hour, minute) = (secs % 86_400 / 3600, secs % 3600 / 60);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year, month, day, hour, minute
+    )
+}
+
+// Fails if `template` is not a valid text template
+pub fn validate_template(template: &str) -> Result<()> {
+    parse(template).map(|_| ())
+}
+
+impl TextTemplates {
+    pub fn validate(&self) -> Result<()> {
+        for template in self.classes.values().chain(&self.default) {
+            parse(template)?;
+        }
+        if self
+            .fields
+            .iter()
+            .any(|field| field.split('.').any(str::is_empty))
+        {
+            return Err(PirError::InvalidInput(format!(
+                "Invalid embedded fields {:?}",
+                self.fields
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    fn template(&self, document: &Value) -> Option<&str> {
+        document
+            .get(&self.class_field)
+            .and_then(Value::as_str)
+            .and_then(|class| self.classes.get(class))
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+
+    fn placeholder(&self, document: &Value, name: &str) -> String {
+        let first = |fields: [&str; 2]| {
+            fields
+                .iter()
+                .find_map(|field| document.get(*field).filter(|value| !value.is_null()))
+                .map_or(MISSING.to_string(), field_text)
+        };
+        match name {
+            "price" => first([DISPLAY_PRICE_FIELD, PRICE_FIELD]),
+            "change" => first([DISPLAY_CHANGE_FIELD, CHANGE_FIELD]),
+            "time" => self
+                .time_field
+                .as_ref()
+                .and_then(|field| document.get(field))
+                .and_then(Value::as_u64)
+                .map_or(MISSING.to_string(), format_time),
+            field => document.get(field).map_or(MISSING.to_string(), field_text),
+        }
+    }
+
+    // Text to embed for `document`
+    pub fn render(&self, document: &Value) -> String {
+        let Some(template) = self.template(document) else {
+            if self.fields.is_empty() {
+                return document.to_string();
+            }
+            return self.render_fields(document);
+        };
+        // Templates are checked when the config is parsed
+        self.render_with(template, document)
+            .unwrap_or_else(|_| document.to_string())
+    }
+
+    // `fields` of `document` as "field: value" pairs, skipping missing ones
+    fn render_fields(&self, document: &Value) -> String {
+        let mut pairs = Vec::new();
+        for field in &self.fields {
+            let value = field
+                .split('.')
+                .try_fold(document, |value, key| value.get(key));
+            if let Some(value) = value {
+                flatten(field, value, &mut pairs);
+            }
+        }
+        pairs.join("; ")
+    }
+
+    // Fills `template` in from `document`, with the same placeholders as its own templates
+    pub fn render_with(&self, template: &str, document: &Value) -> Result<String> {
+        Ok(parse(template)?
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.to_string(),
+                Part::Placeholder(name) => self.placeholder(document, name),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_by_asset_class() -> Result<()> {
+        let templates = TextTemplates {
+            classes: BTreeMap::from([(
+                "Cryptocurrency".to_string(),
+                "{name} ({symbol}) trades at {price}, {change} as of {time}".to_string(),
+            )]),
+            default: Some("{name} {{{sector}}} closed at {price}; P/E {trailingPE}".to_string()),
+            time_field: Some("quotedAt".to_string()),
+            ..TextTemplates::default()
+        };
+        templates.validate()?;
+
+        let bitcoin = json!({
+            "name": "Bitcoin USD",
+            "symbol": "BTC-USD",
+            "sector": "Cryptocurrency",
+            "currentPrice": 67012.5,
+            "displayPrice": "$67,012.50",
+            "changePercent": -1.2,
+            "quotedAt": 1_714_571_100
+        });
+        assert_eq!(
+            templates.render(&bitcoin),
+            "Bitcoin USD (BTC-USD) trades at $67,012.50, -1.2 as of 2024-05-01 13:45 UTC"
+        );
+        let tesla =
+            json!({"name": "Tesla, Inc.", "sector": "Consumer Cyclical", "currentPrice": 250.1});
+        assert_eq!(
+            templates.render(&tesla),
+            "Tesla, Inc. {Consumer Cyclical} closed at 250.1; P/E n/a"
+        );
+        assert_eq!(TextTemplates::default().render(&tesla), tesla.to_string());
+
+        for invalid in ["{name", "name}", "{}", "{na{me}"] {
+            let templates = TextTemplates {
+                default: Some(invalid.to_string()),
+                ..TextTemplates::default()
+            };
+            assert!(templates.validate().is_err(), "{}", invalid);
+        }
+        let fields = TextTemplates {
+            fields: vec!["name".to_string(), "summary".to_string()],
+            ..TextTemplates::default()
+        };
+        fields.validate()?;
+        let record = json!({
+            "summary": {"headline": "Deliveries beat", "tags": ["ev", null, "auto"]},
+            "currentPrice": 250.1,
+            "name": "Tesla, Inc."
+        });
+        assert_eq!(
+            fields.render(&record),
+            "name: Tesla, Inc.; summary.headline: Deliveries beat; summary.tags: ev, auto"
+        );
+        let nested = TextTemplates {
+            fields: vec!["summary.headline".to_string(), "ceo".to_string()],
+            ..TextTemplates::default()
+        };
+        assert_eq!(nested.render(&record), "summary.headline: Deliveries beat");
+        let empty = TextTemplates {
+            fields: vec!["summary.".to_string()],
+            ..TextTemplates::default()
+        };
+        assert!(empty.validate().is_err());
+
+        assert_eq!(format_time(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_time(951_825_600), "2000-02-29 12:00 UTC");
+        Ok(())
+    }
+}
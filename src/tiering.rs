@@ -0,0 +1,135 @@
+use anyhow::Result;
+use nalgebra::DMatrix;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    crypto::RecordKey,
+    error::PirError,
+    packing::{packed_field, PackedValues},
+    server::{document_id, encode_records, SimplePirDatabase},
+};
+
+// Comma-separated fields served from the hot tier; set it empty to disable tiering
+const HOT_FIELDS_ENV_VAR: &str = "TIPTOE_HOT_FIELDS";
+const DEFAULT_HOT_FIELDS: [&str; 4] = [
+    "currentPrice",
+    "changePercent",
+    "displayPrice",
+    "displayChange",
+];
+
+pub fn hot_fields() -> Vec<String> {
+    match std::env::var(HOT_FIELDS_ENV_VAR) {
+        Ok(fields) => fields
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(_) => DEFAULT_HOT_FIELDS
+            .iter()
+            .map(|field| field.to_string())
+            .collect(),
+    }
+}
+
+// Splits a document into its cold part (everything but `fields`) and its hot part
+// (only `fields`). Non-object documents are entirely cold.
+pub fn split(document: &Value, fields: &[String]) -> (Value, Value) {
+    let Value::Object(object) = document else {
+        return (document.clone(), Value::Object(Map::new()));
+    };
+    let (hot, cold): (Map<String, Value>, Map<String, Value>) = object
+        .clone()
+        .into_iter()
+        .partition(|(key, _)| fields.contains(key));
+    (Value::Object(cold), Value::Object(hot))
+}
+
+// Inverse of `split`: overlays the hot fields on the cold record
+pub fn merge(cold: &str, hot: &str) -> Result<String> {
+    let cold: Value = serde_json::from_str(cold.trim_end_matches('\0'))?;
+    let hot: Value = serde_json::from_str(hot.trim_end_matches('\0'))?;
+    let (Value::Object(mut document), Value::Object(hot)) = (cold, hot) else {
+        return Err(PirError::Encoding("Tiered records must be JSON objects".to_string()).into());
+    };
+    document.extend(hot);
+    Ok(Value::Object(document).to_string())
+}
+
+// What clients need to know about the hot tier
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct HotInfo {
+    pub epoch: u64,
+    pub fields: Vec<String>,
+}
+
+// Small database holding only the fast-changing fields, row-aligned with the cold
+// database. Rebuilding it leaves the cold database and its much larger hint alone.
+pub struct HotTier {
+    pub fields: Vec<String>,
+    pub db: SimplePirDatabase,
+    // One numeric field packed many values per column, for cheap single-value lookups
+    pub packed: Option<PackedValues>,
+}
+
+impl HotTier {
+    pub fn build(documents: &[Value], fields: &[String]) -> Result<Self> {
+        let records = documents
+            .iter()
+            .map(|document| split(document, fields).1.to_string())
+            .collect();
+        let mut db = SimplePirDatabase::new(DMatrix::zeros(1, 1));
+        db.update_db(encode_records(records)?)?;
+
+        // Packed values are stored in the clear, so they are skipped when records are encrypted
+        let packed = match packed_field() {
+            Some(field) if RecordKey::from_env()?.is_none() => {
+                let symbols = documents.iter().map(document_id).collect();
+                Some(PackedValues::build(symbols, documents, &field)?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            fields: fields.to_vec(),
+            db,
+            packed,
+        })
+    }
+
+    pub fn info(&self) -> HotInfo {
+        HotInfo {
+            epoch: self.db.epoch(),
+            fields: self.fields.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_split_and_merge_roundtrip() -> Result<()> {
+        let fields = vec!["currentPrice".to_string()];
+        let document = json!({"name": "Tesla, Inc.", "currentPrice": 250.1});
+
+        let (cold, hot) = split(&document, &fields);
+        assert_eq!(cold, json!({"name": "Tesla, Inc."}));
+        assert_eq!(hot, json!({"currentPrice": 250.1}));
+
+        let merged: Value = serde_json::from_str(&merge(
+            &format!("{}\0\0", cold),
+            &json!({"currentPrice": 251.7}).to_string(),
+        )?)?;
+        assert_eq!(
+            merged,
+            json!({"name": "Tesla, Inc.", "currentPrice": 251.7})
+        );
+        assert!(merge("\"text\"", &hot.to_string()).is_err());
+        Ok(())
+    }
+}
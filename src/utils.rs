@@ -1,9 +1,32 @@
 use anyhow::Result;
 use nalgebra::{DMatrix, DVector};
 use num_bigint::BigInt;
-use num_traits::ops::bytes::ToBytes;
+use num_traits::{Signed, ToPrimitive};
+use rand::{rngs::StdRng, SeedableRng};
 
-use crate::error::PirError;
+use crate::{error::PirError, pir::SimplePIRParams};
+
+// Set to make clustering and other randomized build steps reproducible
+const SEED_ENV_VAR: &str = "TIPTOE_SEED";
+
+// Deterministic when seeded, otherwise drawn from OS entropy
+pub fn seeded_rng(seed: Option<u64>) -> StdRng {
+    seed.map_or_else(StdRng::from_os_rng, StdRng::seed_from_u64)
+}
+
+pub fn env_seed() -> Option<u64> {
+    std::env::var(SEED_ENV_VAR).ok()?.parse().ok()
+}
+
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// FNV-1a, for hashes that server and client must agree on across builds
+pub fn fnv1a(bytes: &[u8], seed: u64) -> u64 {
+    bytes.iter().fold(seed, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
 
 pub fn encode_input(text: &str) -> Result<DVector<u64>> {
     let bytes = text.as_bytes();
@@ -18,12 +41,20 @@ pub fn encode_input(text: &str) -> Result<DVector<u64>> {
     Ok(DVector::from_vec(tmp))
 }
 
+// A record as the column PIR recovers it, i.e. the inverse of `decode_input`
+pub fn encode_record(text: &str) -> Result<DVector<BigInt>> {
+    Ok(encode_input(text)?.map(BigInt::from))
+}
+
 #[allow(dead_code)]
 pub fn decode_input(data: &DVector<BigInt>) -> Result<String> {
-    let bytes = data
-        .iter()
-        .flat_map(|x| x.to_le_bytes())
-        .collect::<Vec<u8>>();
+    let mut bytes = Vec::with_capacity(data.len() * 8);
+    for word in data.iter() {
+        let word = word.to_u64().ok_or_else(|| {
+            PirError::Encoding(format!("Record word {} does not fit in 64 bits", word))
+        })?;
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
 
     let s = String::from_utf8(bytes)?;
     Ok(s.replace('\0', ""))
@@ -77,9 +108,57 @@ pub fn decode_data(data: &DMatrix<BigInt>) -> Result<Vec<String>> {
         .collect()
 }
 
+// The ciphertext modulus q of `params`, if queries and answers fit in u64 words
+pub fn word_modulus(params: &SimplePIRParams) -> Option<u128> {
+    params.q.to_u128().filter(|&q| q > 0 && q <= 1 << 64)
+}
+
+// `x` reduced into [0, q) as a u64; None unless q <= 2^64
+pub fn to_word(x: &BigInt, q: &BigInt) -> Option<u64> {
+    let reduced = x % q;
+    let reduced = if reduced.is_negative() {
+        reduced + q
+    } else {
+        reduced
+    };
+    reduced.to_u64()
+}
+
+// Pads a plaintext query with zeros to the `m` columns of the database it is sent to.
+// Longer queries are refused: dropping their tail would silently score against part
+// of the embedding or select a row the database doesn't have.
+pub fn fit_query(query: DVector<BigInt>, m: usize) -> Result<DVector<BigInt>> {
+    if query.len() > m {
+        return Err(PirError::InvalidInput(format!(
+            "Query has {} entries but the database only has {} columns",
+            query.len(),
+            m
+        ))
+        .into());
+    }
+    let mut fitted = DVector::zeros(m);
+    fitted.rows_mut(0, query.len()).copy_from(&query);
+    Ok(fitted)
+}
+
+// Checks a query embedding against the width the server embedded its documents at.
+// Servers that don't publish one are taken at their word.
+pub fn check_query_dim(dim: usize, expected: Option<usize>) -> Result<()> {
+    match expected {
+        Some(expected) if expected != dim => Err(PirError::Embedding(format!(
+            "Query embedding has {} dimensions but the server expects {}; client and \
+             server must use the same model",
+            dim, expected
+        ))
+        .into()),
+        _ => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_encode_decode() -> Result<()> {
@@ -99,4 +178,64 @@ mod tests {
         assert_eq!(data, decoded);
         Ok(())
     }
+
+    // Records never contain NUL, which pads them
+    fn record() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[^\\x00]{0,64}",
+            "[^\\x00]{1000,2000}",
+            prop::collection::vec(any::<char>().prop_filter("NUL", |c| *c != '\0'), 0..32)
+                .prop_map(|chars| chars.into_iter().collect()),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_encode_input_round_trips(text in record()) {
+            let encoded = encode_record(&text).unwrap();
+            prop_assert_eq!(decode_input(&encoded).unwrap(), text);
+        }
+
+        #[test]
+        fn prop_encode_data_round_trips(records in prop::collection::vec(record(), 1..8)) {
+            // Empty records decode to nothing, so only the others come back
+            let expected: Vec<String> =
+                records.iter().filter(|r| !r.is_empty()).cloned().collect();
+            let encoded = encode_data(&records).unwrap();
+            prop_assert_eq!(decode_data(&encoded).unwrap(), expected);
+        }
+
+        #[test]
+        fn prop_decode_input_never_panics(words in prop::collection::vec(any::<i128>(), 0..16)) {
+            let words = DVector::from_iterator(words.len(), words.into_iter().map(BigInt::from));
+            let _ = decode_input(&words);
+        }
+    }
+
+    #[test]
+    fn test_encode_edge_cases() -> Result<()> {
+        assert!(encode_data(&[]).is_err());
+        assert!(decode_data(&encode_data(&["".to_string(), "".to_string()])?)?.is_empty());
+        assert_eq!(decode_input(&encode_record("")?)?, "");
+        // Multi-byte characters split across 8-byte words
+        let text = "€uro ¥en 📈 ".repeat(5);
+        assert_eq!(decode_input(&encode_record(&text)?)?, text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fit_query() -> Result<()> {
+        let query = DVector::from_vec(vec![BigInt::from(3), BigInt::from(-1)]);
+        assert_eq!(fit_query(query.clone(), 2)?, query);
+        assert_eq!(
+            fit_query(query.clone(), 4)?,
+            DVector::from_vec(vec![3, -1, 0, 0].into_iter().map(BigInt::from).collect())
+        );
+        assert!(fit_query(query, 1).is_err());
+
+        check_query_dim(384, Some(384))?;
+        check_query_dim(768, None)?;
+        assert!(check_query_dim(768, Some(384)).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,165 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{error::PirError, server::document_id};
+
+// Checks applied to every document a provider returns before it is encoded, so one
+// bad response cannot poison the database
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct Validation {
+    // Fields that must hold a positive, finite number (or a string parsing as one)
+    // wherever they are present
+    pub price_fields: Vec<String>,
+    // Field holding the Unix time in seconds a document was quoted at. Unset skips
+    // the staleness check; set, documents without it are rejected.
+    pub timestamp_field: Option<String>,
+    pub max_age_secs: u64,
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Self {
+            price_fields: vec!["currentPrice".to_string()],
+            timestamp_field: None,
+            max_age_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Invalid {
+    Price(String),
+    MissingTimestamp,
+    // Age in seconds
+    Stale(u64),
+}
+
+fn price(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+impl Validation {
+    pub fn check(&self, document: &Value, now: u64) -> Result<(), Invalid> {
+        for field in &self.price_fields {
+            let Some(value) = document.get(field) else {
+                continue;
+            };
+            if !price(value).is_some_and(|price| price.is_finite() && price > 0.0) {
+                return Err(Invalid::Price(field.clone()));
+            }
+        }
+
+        if let Some(field) = &self.timestamp_field {
+            let quoted = document
+                .get(field)
+                .and_then(Value::as_u64)
+                .ok_or(Invalid::MissingTimestamp)?;
+            let age = now.saturating_sub(quoted);
+            if age > self.max_age_secs {
+                return Err(Invalid::Stale(age));
+            }
+        }
+        Ok(())
+    }
+
+    // Drops invalid documents. A response in which nothing is valid is an error, so the
+    // caller can fall back to another provider.
+    pub fn apply(&self, documents: Vec<Value>) -> Result<Vec<Value>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let total = documents.len();
+        let mut rejected = Vec::new();
+        let valid: Vec<Value> = documents
+            .into_iter()
+            .filter(|document| match self.check(document, now) {
+                Ok(()) => true,
+                Err(reason) => {
+                    rejected.push((document_id(document), reason));
+                    false
+                }
+            })
+            .collect();
+
+        if let Some((id, reason)) = rejected.first() {
+            eprintln!(
+                "Rejected {} of {} documents, e.g. {}: {:?}",
+                rejected.len(),
+                total,
+                id,
+                reason
+            );
+        }
+        if valid.is_empty() && total > 0 {
+            return Err(
+                PirError::Database(format!("All {} documents failed validation", total)).into(),
+            );
+        }
+        Ok(valid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_rejects_bad_prices_and_stale_quotes() -> Result<()> {
+        let validation = Validation {
+            timestamp_field: Some("quotedAt".to_string()),
+            max_age_secs: 60,
+            ..Validation::default()
+        };
+        let now = 1_700_000_000;
+        let quote = |price: Value, quoted: u64| {
+            json!({
+                "name": "Tesla, Inc.",
+                "currentPrice": price,
+                "quotedAt": quoted
+            })
+        };
+
+        assert_eq!(validation.check(&quote(json!(250.1), now - 5), now), Ok(()));
+        assert_eq!(validation.check(&quote(json!("250.1"), now), now), Ok(()));
+        for bad in [
+            json!(0),
+            json!(-3.5),
+            json!("NaN"),
+            json!("inf"),
+            json!("N/A"),
+        ] {
+            assert_eq!(
+                validation.check(&quote(bad, now), now),
+                Err(Invalid::Price("currentPrice".to_string()))
+            );
+        }
+        assert_eq!(
+            validation.check(&quote(json!(250.1), now - 600), now),
+            Err(Invalid::Stale(600))
+        );
+        assert_eq!(
+            validation.check(&json!({"name": "Tesla, Inc."}), now),
+            Err(Invalid::MissingTimestamp)
+        );
+
+        let lenient = Validation::default();
+        let documents = vec![
+            json!({"name": "Tesla, Inc.", "currentPrice": 250.1}),
+            json!({"name": "Apple Inc.", "currentPrice": -1}),
+            json!({"name": "Rates"}),
+        ];
+        assert_eq!(lenient.apply(documents)?.len(), 2);
+        assert!(lenient
+            .apply(vec![json!({"name": "Apple Inc.", "currentPrice": 0})])
+            .is_err());
+        assert!(lenient.apply(Vec::new())?.is_empty());
+        Ok(())
+    }
+}
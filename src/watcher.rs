@@ -0,0 +1,107 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::interval;
+
+use crate::{client::Client, error::PirError, utils::decode_input};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Threshold {
+    Above(f64),
+    Below(f64),
+}
+
+impl Threshold {
+    fn is_met(&self, value: f64) -> bool {
+        match *self {
+            Self::Above(limit) => value > limit,
+            Self::Below(limit) => value < limit,
+        }
+    }
+}
+
+// Periodically re-queries a record through PIR and reports threshold crossings.
+// The server only ever sees ordinary PIR queries, so it cannot tell which record is watched.
+pub struct Watcher<'a> {
+    client: &'a Client,
+    query: String,
+    field: String,
+    threshold: Threshold,
+    interval: Duration,
+}
+
+impl<'a> Watcher<'a> {
+    pub(crate) fn new(client: &'a Client, query: &str, field: &str, threshold: Threshold) -> Self {
+        Self {
+            client,
+            query: query.to_string(),
+            field: field.to_string(),
+            threshold,
+            interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    pub fn every(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    // Fetches the watched record once and returns the current value of the field
+    pub async fn poll(&self) -> Result<f64> {
+        let result = self.client.query(&self.query).await?;
+        let record = decode_input(&result.data)?;
+        extract_field(&record, &self.field)
+    }
+
+    // Polls forever, calling `on_cross` each time the field moves across the threshold.
+    // Failed polls are logged and retried on the next tick.
+    pub async fn run<F: FnMut(f64)>(self, mut on_cross: F) {
+        let mut ticker = interval(self.interval);
+        let mut was_met = false;
+        loop {
+            ticker.tick().await;
+            match self.poll().await {
+                Ok(value) => {
+                    let is_met = self.threshold.is_met(value);
+                    if is_met && !was_met {
+                        on_cross(value);
+                    }
+                    was_met = is_met;
+                }
+                Err(e) => eprintln!("Watcher poll failed: {:?}", e),
+            }
+        }
+    }
+}
+
+fn extract_field(record: &str, field: &str) -> Result<f64> {
+    let json: Value = serde_json::from_str(record)?;
+    let value = &json[field];
+    value
+        .as_f64()
+        .or_else(|| value.as_str().and_then(|s| s.trim().parse().ok()))
+        .ok_or_else(|| {
+            PirError::InvalidInput(format!("Field '{}' is not numeric in {}", field, record)).into()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_field_and_threshold() -> Result<()> {
+        let record = r#"{"name": "Bitcoin USD", "currentPrice": 101250.5, "volume": "42"}"#;
+
+        let price = extract_field(record, "currentPrice")?;
+        assert_eq!(price, 101250.5);
+        assert_eq!(extract_field(record, "volume")?, 42.0);
+        assert!(extract_field(record, "name").is_err());
+
+        assert!(Threshold::Above(100_000.0).is_met(price));
+        assert!(!Threshold::Below(100_000.0).is_met(price));
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use nalgebra::{DMatrix, DVector};
+use num_bigint::BigInt;
+use serde_json::{json, Value};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tiptoe_rs::{
+    cache::ResultCache,
+    client::{Client, PartialResults},
+    external::ExternalDatabase,
+    in_process::InProcessTransport,
+    network::{
+        router, AsyncDatabase, HintDiff, MatrixResponse, NetworkClient, ParamsData, QueryRequest,
+        QueryResponse, RemoteDatabase, Transport,
+    },
+    selftest,
+    server::{Database, EmbeddingDatabase, EncodingDatabase},
+    snapshot::Snapshot,
+};
+use tower::ServiceExt;
+
+fn corpus() -> Value {
+    json!([
+        {"name": "Tesla, Inc.", "symbol": "TSLA", "sector": "Consumer Cyclical", "currentPrice": 250.1},
+        {"name": "Apple Inc.", "symbol": "AAPL", "sector": "Technology", "currentPrice": 180.2},
+        {"name": "Micron Technology, Inc.", "symbol": "MU", "sector": "Technology", "currentPrice": 95.4},
+        {"name": "Bitcoin USD", "symbol": "BTC-USD", "sector": "Cryptocurrency", "currentPrice": 67012.5},
+        {"name": "EUR/USD", "symbol": "EURUSD=X", "sector": "Currency", "currentPrice": 1.08},
+        {"name": "CBOE Volatility Index", "symbol": "^VIX", "sector": "Index", "currentPrice": 14.2}
+    ])
+}
+
+// Builds both databases from `corpus()` and serves their routers without opening
+// any sockets
+fn in_process_transports() -> Result<(Arc<dyn Transport>, Arc<dyn Transport>)> {
+    let path = std::env::temp_dir().join("tiptoe_in_process_corpus.json");
+    std::fs::write(&path, corpus().to_string())?;
+    std::env::set_var("TIPTOE_CORPUS_URL", format!("file://{}", path.display()));
+    std::env::set_var("TIPTOE_BLOCK_SIZE", "2");
+
+    let mut embedding_db = EmbeddingDatabase::new()?;
+    embedding_db.update()?;
+    let mut encoding_db = EncodingDatabase::new()?;
+    encoding_db.update()?;
+    selftest::run(&embedding_db)?;
+    let report = selftest::run(&encoding_db)?;
+    assert!(report.checked.iter().any(|name| name == "blocks"));
+
+    // Restored from a snapshot, a database serves the same epoch without the corpus
+    let bundle = encoding_db.snapshot()?.to_bytes()?;
+    let mut restored = EncodingDatabase::new()?;
+    restored.restore(Snapshot::from_bytes(&bundle)?)?;
+    assert_eq!(restored.digest(), encoding_db.digest());
+    assert_eq!(restored.document_ids(), encoding_db.document_ids());
+    selftest::run(&restored)?;
+
+    Ok((
+        Arc::new(InProcessTransport::new(router(embedding_db))),
+        Arc::new(InProcessTransport::new(router(encoding_db))),
+    ))
+}
+
+// Answers only the first `queries` PIR queries sent through it
+struct FlakyTransport {
+    inner: Arc<dyn Transport>,
+    queries: AtomicUsize,
+}
+
+#[async_trait]
+impl Transport for FlakyTransport {
+    async fn send_query(&self, database: &str, request: &QueryRequest) -> Result<QueryResponse> {
+        let answered = self
+            .queries
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if answered.is_err() {
+            return Err(anyhow!("connection reset"));
+        }
+        self.inner.send_query(database, request).await
+    }
+
+    async fn get_params(&self, database: &str) -> Result<ParamsData> {
+        self.inner.get_params(database).await
+    }
+
+    async fn get_hint(&self, database: &str) -> Result<MatrixResponse> {
+        self.inner.get_hint(database).await
+    }
+
+    async fn get_a(&self, database: &str) -> Result<MatrixResponse> {
+        self.inner.get_a(database).await
+    }
+
+    async fn get_json(&self, path: &str) -> Result<Value> {
+        self.inner.get_json(path).await
+    }
+}
+
+async fn query_document(client: &Client, query: &str) -> Result<Value> {
+    let mut text = String::new();
+    client
+        .query_stream(query, |chunk| text.push_str(chunk))
+        .await?;
+    Ok(serde_json::from_str(text.trim_end_matches('\0'))?)
+}
+
+#[tokio::test]
+async fn test_client_against_in_process_servers() -> Result<()> {
+    let (embedding, encoding) = in_process_transports()?;
+    let client = Client::from_transports(Arc::clone(&embedding), Arc::clone(&encoding))?
+        .with_cache(ResultCache::in_memory(16))
+        .with_degraded_mode(2);
+
+    let tesla = query_document(&client, "What is the latest price of Tesla?").await?;
+    assert_eq!(tesla["name"], "Tesla, Inc.");
+    assert_eq!(tesla["currentPrice"], 250.1);
+    // Both rounds of the first query were recovered by the server
+    assert_eq!(client.assisted_rounds(), 0);
+    let diagnostics = client
+        .last_diagnostics()
+        .expect("PIR queries record diagnostics");
+    assert!(diagnostics.cause.is_none());
+    let cached = client.query("What is the latest price of Tesla?").await?;
+    assert_eq!(client.last_stats().rounds, 0);
+    assert!(cached.id.is_some());
+    assert_eq!(diagnostics.top[0].id, cached.id);
+    assert!(client.last_diagnostics().is_none());
+
+    let bitcoin = query_document(&client, "How is Bitcoin performing today?").await?;
+    assert_eq!(bitcoin["symbol"], "BTC-USD");
+
+    let top = client.query_top_k("Tell me about Apple", 3).await?;
+    assert_eq!(top.len(), 3);
+    let id = top[0].id.expect("in-process results carry document ids");
+    assert_eq!(client.fetch_by_id(&id).await?.data, top[0].data);
+
+    // Two records per block, so the best match comes with one neighbour
+    let block = client.query_block("Tell me about Apple").await?;
+    assert_eq!(block.len(), 2);
+    assert!(block.iter().any(|result| result.id == top[0].id));
+    assert_eq!(client.last_stats().rounds, 2);
+
+    let flaky = Client::from_transports(
+        Arc::clone(&embedding),
+        Arc::new(FlakyTransport {
+            inner: Arc::clone(&encoding),
+            queries: AtomicUsize::new(2),
+        }),
+    )?;
+    let error = flaky
+        .query_top_k("Tell me about Apple", 3)
+        .await
+        .expect_err("the third fetch fails");
+    let partial = error
+        .downcast_ref::<PartialResults>()
+        .expect("failed fetches report the others");
+    assert_eq!(partial.results.len(), 2);
+    assert_eq!(partial.failed.len(), 1);
+    assert!(partial.failed[0].1.contains("connection reset"));
+
+    let first = client.query_page("Tell me about Apple", 0, 2).await?;
+    let second = client.query_page("Tell me about Apple", 1, 2).await?;
+    assert_eq!(first[0].id, top[0].id);
+    assert!(second
+        .iter()
+        .all(|result| result.id != first[0].id && result.id != first[1].id));
+    assert_eq!(client.last_stats().embed_ms, 0.0);
+    assert!(client
+        .query_page("Tell me about Apple", 10, 2)
+        .await?
+        .is_empty());
+
+    // The next page was fetched ahead of time and costs no PIR round
+    let prefetching =
+        Client::from_transports(Arc::clone(&embedding), Arc::clone(&encoding))?.with_prefetch(2);
+    assert_eq!(prefetching.prefetch().await?, 0);
+    prefetching.query_page("Tell me about Apple", 0, 2).await?;
+    assert_eq!(prefetching.prefetch().await?, 2);
+    let next = prefetching.query_page("Tell me about Apple", 1, 2).await?;
+    assert_eq!(prefetching.last_stats().rounds, 0);
+    assert_eq!(
+        next.iter().map(|result| result.id).collect::<Vec<_>>(),
+        second.iter().map(|result| result.id).collect::<Vec<_>>()
+    );
+
+    let scores = client.score_all("Tell me about Apple").await?;
+    assert!(!scores.is_empty());
+    assert!(scores.iter().all(|(index, _)| *index < 6));
+    let calibration = client.calibration().await?;
+    assert!(calibration.samples > 0);
+    assert!(scores
+        .iter()
+        .all(|(_, score)| calibration.cosine(score).abs() < 1.1));
+
+    let fused = client
+        .query_fused(&[
+            "How is Micron performing today?".to_string(),
+            "Micron Technology".to_string(),
+        ])
+        .await?;
+    assert_eq!(fused.id, client.query("Micron Technology").await?.id);
+
+    assert!(client.contains("Micron Technology, Inc.").await?);
+    assert_eq!(client.query_value("Apple Inc.").await?, Some(180.2));
+
+    // Queries in flight together share the client's downloads
+    let network_client = NetworkClient::from_transports(embedding, encoding)?;
+    let (tesla, bitcoin) = tokio::try_join!(
+        network_client.query("What is the latest price of Tesla?"),
+        network_client.query("How is Bitcoin performing today?"),
+    )?;
+    assert_ne!(tesla, bitcoin);
+    assert_eq!(
+        network_client
+            .query("What is the latest price of Tesla?")
+            .await?,
+        tesla
+    );
+    Ok(())
+}
+
+fn parse_matrix(matrix: &MatrixResponse) -> Result<DMatrix<BigInt>> {
+    let data = matrix
+        .data
+        .iter()
+        .map(|x| x.parse())
+        .collect::<Result<Vec<BigInt>, _>>()?;
+    Ok(DMatrix::from_vec(matrix.rows, matrix.cols, data))
+}
+
+#[tokio::test]
+async fn test_externally_encrypted_queries() -> Result<()> {
+    let (_, encoding) = in_process_transports()?;
+    let external = ExternalDatabase::with_transport(Arc::clone(&encoding), "");
+    let (params, a) = external.setup().await?;
+    let hint = parse_matrix(&external.hint().await?)?;
+    // A diff whose values are malformed or don't fill their shape is refused, not applied
+    for data in [vec![], vec!["x".to_string(); hint.ncols()]] {
+        let diff = HintDiff {
+            from: params.epoch,
+            epoch: params.epoch,
+            rows: vec![0],
+            values: MatrixResponse {
+                rows: 1,
+                cols: hint.ncols(),
+                data,
+            },
+        };
+        let mut patched = hint.clone();
+        assert!(diff.apply(&mut patched).is_err());
+        assert_eq!(patched, hint);
+    }
+
+    // simplepir itself stands in for another implementation, fed only the wire format
+    let mod_power = (params.p.parse::<BigInt>()?.bits() - 1) as u32;
+    let pir_params = simplepir::gen_params(params.m, params.n, mod_power);
+    let one_hot = DVector::from_fn(params.m, |i, _| BigInt::from(u8::from(i == 0)));
+    let (secret, query) = simplepir::generate_query(&pir_params, &one_hot, &parse_matrix(&a)?);
+    let answer = external
+        .submit(query.iter().map(BigInt::to_string).collect())
+        .await?;
+    assert_eq!(answer.len(), hint.nrows());
+    let answer: DVector<BigInt> = DVector::from_vec(
+        answer
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<Vec<_>, _>>()?,
+    );
+    let remote = RemoteDatabase::with_transport(Arc::clone(&encoding));
+    assert_eq!(remote.respond(&query).await?, answer);
+    // Pinned to the current epoch the same query is answered; an epoch the server
+    // never served is gone
+    assert_eq!(remote.at_epoch(params.epoch).respond(&query).await?, answer);
+    assert!(remote
+        .at_epoch(params.epoch + 1)
+        .respond(&query)
+        .await
+        .is_err());
+    let pinned_blocks =
+        ExternalDatabase::with_transport(encoding, &format!("/epochs/{}/blocks", params.epoch));
+    assert_eq!(
+        pinned_blocks.hint().await?.data,
+        remote
+            .blocks()
+            .get_hint()
+            .await?
+            .iter()
+            .map(BigInt::to_string)
+            .collect::<Vec<_>>()
+    );
+    let recovered = simplepir::recover(&hint, &secret, &answer, &pir_params);
+    assert!(recovered.iter().any(|x| *x != BigInt::from(0)));
+
+    assert!(external.submit(vec!["1".to_string()]).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_requires_policy() -> Result<()> {
+    // Without an authorization policy anyone could replace the index, so the route is
+    // not there to answer
+    let response = router(EncodingDatabase::new()?)
+        .oneshot(Request::post("/admin/restore").body(Body::empty())?)
+        .await?;
+    assert!(matches!(
+        response.status(),
+        StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+    ));
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tiptoe_rs::{
+    client::Client,
+    in_process::InProcessTransport,
+    network::router,
+    replay,
+    server::{Database, EmbeddingDatabase, EncodingDatabase},
+};
+
+fn recording() -> Value {
+    let snapshot = |at: u64, tesla: f64, bitcoin: f64| {
+        json!({
+            "at": at,
+            "documents": [
+                {"name": "Tesla, Inc.", "symbol": "TSLA", "sector": "Consumer Cyclical",
+                    "currentPrice": tesla, "currency": "USD"},
+                {"name": "Apple Inc.", "symbol": "AAPL", "sector": "Technology",
+                    "currentPrice": 180.2, "currency": "USD"},
+                {"name": "Bitcoin USD", "symbol": "BTC-USD", "sector": "Cryptocurrency",
+                    "currentPrice": bitcoin, "currency": "USD"}
+            ]
+        })
+    };
+    json!([
+        snapshot(1_700_000_000, 250.1, 67012.5),
+        snapshot(1_700_000_060, 252.4, 66850.0),
+    ])
+}
+
+// Rebuilds both databases from the snapshot due on the simulated clock, as the
+// servers' update loop would
+fn update_cycle() -> Result<Client> {
+    let mut embedding_db = EmbeddingDatabase::new()?;
+    embedding_db.update()?;
+    let mut encoding_db = EncodingDatabase::new()?;
+    encoding_db.update()?;
+
+    Client::from_transports(
+        Arc::new(InProcessTransport::new(router(embedding_db))),
+        Arc::new(InProcessTransport::new(router(encoding_db))),
+    )
+}
+
+async fn query_document(client: &Client, query: &str) -> Result<Value> {
+    let mut text = String::new();
+    client
+        .query_stream(query, |chunk| text.push_str(chunk))
+        .await?;
+    Ok(serde_json::from_str(text.trim_end_matches('\0'))?)
+}
+
+#[tokio::test]
+async fn test_replayed_updates_reach_clients() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("tiptoe_replay_{}.json", std::process::id()));
+    std::fs::write(&path, recording().to_string())?;
+    std::env::set_var("TIPTOE_CORPUS_URL", format!("replay://{}", path.display()));
+    std::env::set_var("TIPTOE_SEED", "7");
+    replay::clock().reset();
+
+    let client = update_cycle()?;
+    let tesla = query_document(&client, "What is the latest price of Tesla?").await?;
+    assert_eq!(tesla["currentPrice"], 250.1);
+    assert_eq!(tesla["displayPrice"], "$250.10");
+
+    // Not yet due
+    replay::clock().advance(Duration::from_secs(30));
+    let client = update_cycle()?;
+    let tesla = query_document(&client, "What is the latest price of Tesla?").await?;
+    assert_eq!(tesla["currentPrice"], 250.1);
+
+    replay::clock().advance(Duration::from_secs(30));
+    let client = update_cycle()?;
+    let tesla = query_document(&client, "What is the latest price of Tesla?").await?;
+    assert_eq!(tesla["currentPrice"], 252.4);
+    let bitcoin = query_document(&client, "How is Bitcoin performing today?").await?;
+    assert_eq!(bitcoin["currentPrice"], 66850.0);
+    assert_eq!(client.query_value("Apple Inc.").await?, Some(180.2));
+    Ok(())
+}